@@ -0,0 +1,90 @@
+use std::net::UdpSocket;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+use log::{error, info, warn};
+use signal_hook::iterator::Signals;
+use signal_hook::SIGHUP;
+
+use crate::dhcp::DhcpServer;
+use crate::util;
+
+/** 管理コマンドを受け付けるアドレス。.envのADMIN_ADDRで上書きできる */
+const DEFAULT_ADMIN_ADDR: &str = "127.0.0.1:6767";
+
+/** SIGHUPを受け取るたびに設定を再読み込みするスレッドを起動する */
+pub fn spawn_sighup_handler(dhcp_servers: Vec<Arc<DhcpServer>>) -> Result<(), failure::Error> {
+    let signals = Signals::new([SIGHUP])?;
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("received SIGHUP, reloading configuration");
+            for dhcp_server in dhcp_servers.iter() {
+                if let Err(e) = dhcp_server.reload_config() {
+                    error!("Failed to reload configuration of {}, keep running with the current one: {}", scope_name(dhcp_server), e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/** ログや管理コマンドの応答でスコープを示す名前 */
+fn scope_name(dhcp_server: &DhcpServer) -> &str {
+    dhcp_server.interface().unwrap_or("*")
+}
+
+/** ローカルホストから管理コマンドを受け付けるスレッドを起動する
+ * 1データグラムが1コマンドで、結果を送信元に返す
+ *   reload : 設定を再読み込みする
+ *   status : 現在のネットワーク、アドレスプールの残数、頻度制限などのカウンタを返す
+ */
+pub fn spawn_admin_listener(dhcp_servers: Vec<Arc<DhcpServer>>) -> Result<(), failure::Error> {
+    let admin_addr = util::load_env()?.get("ADMIN_ADDR").cloned().unwrap_or_else(|| DEFAULT_ADMIN_ADDR.to_string());
+    let socket = UdpSocket::bind(&admin_addr)?;
+    info!("admin commands are accepted on {}", admin_addr);
+
+    thread::spawn(move || loop {
+        let mut buf = [0u8; 64];
+        let (size, src) = match socket.recv_from(&mut buf) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Could not receive an admin command: {}", e);
+                continue;
+            }
+        };
+        if !src.ip().is_loopback() {
+            warn!("ignored admin command from {}", src);
+            continue;
+        }
+
+        // インターフェースごとのスコープの結果を1行ずつ返す
+        let command = String::from_utf8_lossy(&buf[..size]).trim().to_string();
+        let lines: Vec<String> = dhcp_servers
+            .iter()
+            .map(|dhcp_server| match command.as_str() {
+                "reload" => match dhcp_server.reload_config() {
+                    Ok(_) => format!("{}: ok", scope_name(dhcp_server)),
+                    Err(e) => format!("{}: error: {}", scope_name(dhcp_server), e),
+                },
+                "status" => format!(
+                    "{}: network: {}, pool: {}, rate limited (mac/source): {}/{}, offers capped: {}, offers expired: {}",
+                    scope_name(dhcp_server),
+                    dhcp_server.config().network_addr,
+                    dhcp_server.pool_size(),
+                    dhcp_server.stats.rate_limited_by_mac.load(Ordering::Relaxed),
+                    dhcp_server.stats.rate_limited_by_source.load(Ordering::Relaxed),
+                    dhcp_server.stats.offers_capped.load(Ordering::Relaxed),
+                    dhcp_server.stats.offers_expired.load(Ordering::Relaxed),
+                ),
+                command => format!("error: unknown command {}", command),
+            })
+            .collect();
+        let response = lines.join("\n");
+        if let Err(e) = socket.send_to(response.as_bytes(), src) {
+            error!("Failed to reply to admin command: {}", e);
+        }
+    });
+    Ok(())
+}
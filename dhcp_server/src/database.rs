@@ -1,6 +1,82 @@
+use std::net::Ipv4Addr;
+
+use log::info;
+use pnet::util::MacAddr;
+use rusqlite::{params, Connection, Rows, Transaction, NO_PARAMS};
+
+/** lease_entriesテーブルの1レコード */
+pub struct LeaseEntry {
+    pub ip_addr: Ipv4Addr,
+    pub deleted: bool,
+    /** リースの期限(UNIX時刻) */
+    pub expires_at: i64,
+}
+
+impl LeaseEntry {
+    /** 論理削除されているか、期限を過ぎたリースは延長も再確認もしない */
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.deleted || self.expires_at <= now
+    }
+}
+
+/** テーブルがなければ作成し、リース期限のカラムがない古いデータベースにはカラムを追加する
+ * 既存のリースは期限が分からないため、移行した時点からリース時間いっぱいまで有効とする
+ */
+pub fn migrate(con: &Connection, lease_seconds: u32, now: i64) -> Result<(), failure::Error> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS lease_entries (mac_addr TEXT PRIMARY KEY, ip_addr TEXT NOT NULL, deleted INTEGER NOT NULL DEFAULT 0, expires_at INTEGER NOT NULL DEFAULT 0)",
+        NO_PARAMS,
+    )?;
+
+    let mut stmnt = con.prepare("PRAGMA table_info(lease_entries)")?;
+    let mut columns = stmnt.query(NO_PARAMS)?;
+    while let Some(column) = columns.next()? {
+        let name: String = column.get(1)?;
+        if name == "expires_at" {
+            return Ok(());
+        }
+    }
+    info!("adding expires_at column to lease_entries");
+    con.execute("ALTER TABLE lease_entries ADD COLUMN expires_at INTEGER NOT NULL DEFAULT 0", NO_PARAMS)?;
+    let extended = con.execute("UPDATE lease_entries SET expires_at = ?1 WHERE deleted = 0", params![now + i64::from(lease_seconds)])?;
+    info!("{} existing leases are valid for {} seconds", extended, lease_seconds);
+    Ok(())
+}
+
+/** 指定のMACアドレスを持つエントリ（論理削除されているものも含めて）を返す */
+pub fn select_lease(con: &Connection, mac_addr: MacAddr) -> Result<Option<LeaseEntry>, failure::Error> {
+    let mut stmnt = con.prepare("SELECT ip_addr, deleted, expires_at FROM lease_entries WHERE mac_addr = ?1")?;
+
+    let mut row = stmnt.query(params![mac_addr.to_string()])?;
+    if let Some(entry) = row.next()? {
+        let ip_string: String = entry.get(0)?;
+        let deleted: i64 = entry.get(1)?;
+        Ok(Some(LeaseEntry {
+            ip_addr: ip_string.parse()?,
+            deleted: deleted != 0,
+            expires_at: entry.get(2)?,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+/** 指定のIPアドレスを貸し出している（論理削除されていない）クライアントのMACアドレスを返す */
+pub fn select_mac_addr_by_ip(con: &Connection, ip_addr: Ipv4Addr) -> Result<Option<MacAddr>, failure::Error> {
+    let mut stmnt = con.prepare("SELECT mac_addr FROM lease_entries WHERE ip_addr = ?1 AND deleted = 0")?;
+
+    let mut row = stmnt.query(params![ip_addr.to_string()])?;
+    if let Some(entry) = row.next()? {
+        let mac_string: String = entry.get(0)?;
+        Ok(Some(mac_string.parse()?))
+    } else {
+        Ok(None)
+    }
+}
+
 /** 指定のMACアドレスを持つエントリ（論理削除されているものも含めて）のIPアドレスを返す */
 pub fn select_entry(con: &Connection, mac_addr: MacAddr) -> Result<Option<Ipv4Addr>, failure::Error> {
-    let mut stmnt = con.parse("SELECT ip_addr FROM lease_entries WHERE mac_addr = ?1")?;
+    let mut stmnt = con.prepare("SELECT ip_addr FROM lease_entries WHERE mac_addr = ?1")?;
 
     let mut row = stmnt.query(params![mac_addr.to_string()])?;
     if let Some(entry) = row.next()? {
@@ -24,7 +100,7 @@ fn get_addresses_from_row(mut ip_addrs: Rows) -> Result<Vec<Ipv4Addr>, failure::
                 ip_string.parse()?
             }
             Err(_) => continue,
-        }
+        };
         leased_addrs.push(ip_addr);
     };
     Ok(leased_addrs)
@@ -40,13 +116,27 @@ pub fn select_addresses(con: &Connection, deleted: Option<u8>) -> Result<Vec<Ipv
         get_addresses_from_row(ip_addrs)
     } else {
         let mut statement = con.prepare("SELECT ip_addr FROM lease_entries")?;
-        let ip_addrs = statement.query(NO_PARAMD)?;
+        let ip_addrs = statement.query(NO_PARAMS)?;
         get_addresses_from_row(ip_addrs)
     }
 }
 
+/** 論理削除されていないリースを(MACアドレス, IPアドレス)の組で返す */
+pub fn select_leases(con: &Connection) -> Result<Vec<(MacAddr, Ipv4Addr)>, failure::Error> {
+    let mut statement = con.prepare("SELECT mac_addr, ip_addr FROM lease_entries WHERE deleted = 0")?;
+    let mut rows = statement.query(NO_PARAMS)?;
+
+    let mut leases = Vec::new();
+    while let Some(entry) = rows.next()? {
+        let mac_string: String = entry.get(0)?;
+        let ip_string: String = entry.get(1)?;
+        leases.push((mac_string.parse()?, ip_string.parse()?));
+    }
+    Ok(leases)
+}
+
 /** 指定のMACアドレスを持つレコード件数を返す */
-pub fn counbt_records_by_mac_addr(tx: &Transaction, mac_addr: MacAddr) -> Reuslt<u8, failure::Error> {
+pub fn counbt_records_by_mac_addr(tx: &Transaction, mac_addr: MacAddr) -> Result<u8, failure::Error> {
     let mut stmnt = tx.prepare("SELECT COUNT (*) FROM lease_entries WHERE mac_addr = ?")?;
     let mut count_result = stmnt.query(params![mac_addr.to_string()])?;
 
@@ -61,14 +151,14 @@ pub fn counbt_records_by_mac_addr(tx: &Transaction, mac_addr: MacAddr) -> Reuslt
 }
 
 /** バインディングの追加 */
-pub fn insert_entry(tx: &Transaction, mac_addr: MacAddr, ip_addr: Ipv4Addr) -> Result<(), failure::Error> {
-    tx.execute("INSERT INTO lease_entries (mac_addr, ip_addr) VALUES (?1, ?2)", params![mac_addr.to_string(), ip_addr.to_string()])?;
+pub fn insert_entry(tx: &Transaction, mac_addr: MacAddr, ip_addr: Ipv4Addr, expires_at: i64) -> Result<(), failure::Error> {
+    tx.execute("INSERT INTO lease_entries (mac_addr, ip_addr, expires_at) VALUES (?1, ?2, ?3)", params![mac_addr.to_string(), ip_addr.to_string(), expires_at])?;
     Ok(())
 }
 
 /** バインディングの更新 */
-pub fn update_entry(tx: &Transaction, mac_addr: MacAddr, ip_addr: Ipv4Addr, deleted: u8) -> Result<(), failure::Error> {
-    tx.execute("UPDATE lease_entries SET ip_addr = ?2, deleted = ?3 WHERE mac_addr = ?1", params![mac_addr.to_string(), ip_addr.to_string(), deleted.to_string()])?;
+pub fn update_entry(tx: &Transaction, mac_addr: MacAddr, ip_addr: Ipv4Addr, deleted: u8, expires_at: i64) -> Result<(), failure::Error> {
+    tx.execute("UPDATE lease_entries SET ip_addr = ?2, deleted = ?3, expires_at = ?4 WHERE mac_addr = ?1", params![mac_addr.to_string(), ip_addr.to_string(), deleted.to_string(), expires_at])?;
     Ok(())
 }
 
@@ -76,4 +166,55 @@ pub fn update_entry(tx: &Transaction, mac_addr: MacAddr, ip_addr: Ipv4Addr, dele
 pub fn delete_entry(tx: &Transaction, mac_addr: MacAddr) -> Result<(), failure::Error> {
     tx.execute("UPDATE lease_entries SET deleted = ?1 WHERE mac_addr = ?2", params![1.to_string(), mac_addr.to_string()])?;
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_600_000_000;
+
+    fn mac(last: u8) -> MacAddr {
+        MacAddr::new(0x02, 0, 0, 0, 0, last)
+    }
+
+    /** expires_atのカラムを追加する前のテーブル */
+    fn legacy_database() -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute("CREATE TABLE lease_entries (mac_addr TEXT PRIMARY KEY, ip_addr TEXT NOT NULL, deleted INTEGER NOT NULL DEFAULT 0)", NO_PARAMS).unwrap();
+        con.execute("INSERT INTO lease_entries (mac_addr, ip_addr) VALUES (?1, '192.168.0.10')", params![mac(1).to_string()]).unwrap();
+        con.execute("INSERT INTO lease_entries (mac_addr, ip_addr, deleted) VALUES (?1, '192.168.0.11', 1)", params![mac(2).to_string()]).unwrap();
+        con
+    }
+
+    #[test]
+    fn migration_keeps_existing_leases_valid() {
+        let con = legacy_database();
+        migrate(&con, 3600, NOW).unwrap();
+
+        let active = select_lease(&con, mac(1)).unwrap().unwrap();
+        assert_eq!(active.ip_addr, "192.168.0.10".parse::<Ipv4Addr>().unwrap());
+        assert_eq!(active.expires_at, NOW + 3600);
+        assert!(!active.is_expired(NOW + 3599));
+        assert!(active.is_expired(NOW + 3600));
+
+        let released = select_lease(&con, mac(2)).unwrap().unwrap();
+        assert_eq!(released.expires_at, 0);
+        assert!(released.is_expired(NOW));
+    }
+
+    #[test]
+    fn migration_runs_once() {
+        let con = legacy_database();
+        migrate(&con, 3600, NOW).unwrap();
+        // 2回目はカラムがあるので、リースの期限を延ばさない
+        migrate(&con, 3600, NOW + 100).unwrap();
+        assert_eq!(select_lease(&con, mac(1)).unwrap().unwrap().expires_at, NOW + 3600);
+
+        let mut fresh = Connection::open_in_memory().unwrap();
+        migrate(&fresh, 3600, NOW).unwrap();
+        let tx = fresh.transaction().unwrap();
+        insert_entry(&tx, mac(3), "192.168.0.12".parse().unwrap(), NOW + 60).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(select_lease(&fresh, mac(3)).unwrap().unwrap().expires_at, NOW + 60);
+    }
+}
@@ -1,3 +1,19 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use ipnetwork::Ipv4Network;
+use log::{debug, error, info, warn};
+use pnet::util::MacAddr;
+use rusqlite::Connection;
+
+use crate::database;
+use crate::options::DhcpOptions;
+use crate::ratelimit::RateLimiter;
+use crate::util;
+
 const OP: usize = 0;
 const HTYPE: usize = 1;
 const HLEN: usize = 2;
@@ -7,13 +23,19 @@ const SECS: usize = 8;
 const FLAGS: usize = 10;
 const CIADDR: usize = 12;
 const YIADDR: usize = 16;
-const SIADDR: usize = 20;
+// const SIADDR: usize = 20;
 const GIADDR: usize = 24;
 const CHADDR: usize = 28;
-const SNAME: usize = 44;
+// const SNAME: usize = 44;
 // const FILE: usize = 108;
 pub const OPTIONS: usize = 236;
 
+/** 固定長部とマジッククッキーを合わせた長さ */
+const DHCP_MINIMUM_SIZE: usize = OPTIONS + 4;
+pub const OPTION_END: u8 = 255;
+/** オプション部の先頭に置かれるマジッククッキー(RFC2131 3章) */
+const MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+
 pub struct DhcpPacket {
     buffer: Vec<u8>
 }
@@ -21,15 +43,66 @@ pub struct DhcpPacket {
 pub struct DhcpServer {
     address_pool: RwLock<Vec<Ipv4Addr>>,
     pub db_connection: Mutex<Connection>,
+    config: RwLock<ServerConfig>,
+    /** 設定を補うインターフェース。再読み込み時にもそのアドレスを参照する */
+    interface: Option<String>,
+    /** DHCPOFFERを送ったがまだDHCPREQUESTで確定していないアドレス
+     * このロックを保持したまま設定、データベース、アドレスプールのロックを取らないこと
+     */
+    pending_offers: Mutex<HashMap<MacAddr, PendingOffer>>,
+    mac_rate_limiter: RateLimiter<MacAddr>,
+    source_rate_limiter: RateLimiter<IpAddr>,
+    pub stats: Stats,
+}
+
+struct PendingOffer {
+    ip_addr: Ipv4Addr,
+    offered_at: Instant,
+}
+
+/** 制限が働いた回数など、管理コマンドで参照するカウンタ */
+#[derive(Default)]
+pub struct Stats {
+    pub rate_limited_by_mac: AtomicUsize,
+    pub rate_limited_by_source: AtomicUsize,
+    pub offers_capped: AtomicUsize,
+    pub offers_expired: AtomicUsize,
+}
+
+/** .envから読み込まれるサーバーの設定。SIGHUPや管理コマンドで再読み込みされる */
+#[derive(Clone)]
+pub struct ServerConfig {
     pub network_addr: Ipv4Network,
     pub server_address: Ipv4Addr,
     pub default_gateway: Ipv4Addr,
     pub subnet_mask: Ipv4Addr,
     pub dns_server: Ipv4Addr,
+    pub lease_seconds: u32,
     pub lease_time: Vec<u8>,
+    /** T1 (リース時間の1/2) */
+    pub renewal_time: Vec<u8>,
+    /** T2 (リース時間の7/8) */
+    pub rebinding_time: Vec<u8>,
+    /** OFFERしたアドレスを確保しておく時間 */
+    pub offer_timeout: Duration,
+    /** 同時に保持するOFFERの上限 */
+    pub max_outstanding_offers: usize,
+    /** MACアドレスごと、送信元アドレスごとの1秒あたりの受け付けメッセージ数（0なら無制限） */
+    pub rate_limit_per_mac: u32,
+    pub rate_limit_per_source: u32,
+    /** 払い出す前にICMP echoでアドレスの重複を確認するか */
+    pub ping_check: bool,
 }
 
 impl DhcpPacket {
+    /** 固定長部とマジッククッキーを含まないバッファはDHCPメッセージとして扱わない */
+    pub fn new(buf: Vec<u8>) -> Option<DhcpPacket> {
+        if buf.len() >= DHCP_MINIMUM_SIZE {
+            let packet = DhcpPacket { buffer: buf };
+            return Some(packet);
+        }
+        None
+    }
 
     pub fn get_buffer(&self) -> &[u8] {
         self.buffer.as_ref()
@@ -43,13 +116,74 @@ impl DhcpPacket {
         &self.buffer[OPTIONS..]
     }
 
+    pub fn get_xid(&self) -> &[u8] {
+        &self.buffer[XID..SECS]
+    }
+
+    pub fn get_flags(&self) -> &[u8] {
+        &self.buffer[FLAGS..CIADDR]
+    }
+
+    pub fn get_giaddr(&self) -> Ipv4Addr {
+        let b = &self.buffer[GIADDR..CHADDR];
+        Ipv4Addr::new(b[0], b[1], b[2], b[3])
+    }
+
+    pub fn get_chaddr(&self) -> MacAddr {
+        let b = &self.buffer[CHADDR..CHADDR + 6];
+        MacAddr::new(b[0], b[1], b[2], b[3], b[4], b[5])
+    }
+
+    pub fn get_ciaddr(&self) -> Ipv4Addr {
+        let b = &self.buffer[CIADDR..YIADDR];
+        Ipv4Addr::new(b[0], b[1], b[2], b[3])
+    }
+
+    #[cfg(test)]
+    pub fn get_yiaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.buffer[YIADDR], self.buffer[YIADDR + 1], self.buffer[YIADDR + 2], self.buffer[YIADDR + 3])
+    }
+
+    pub fn set_op(&mut self, op: u8) {
+        self.buffer[OP] = op;
+    }
+
+    pub fn set_htype(&mut self, htype: u8) {
+        self.buffer[HTYPE] = htype;
+    }
+
+    pub fn set_hlen(&mut self, hlen: u8) {
+        self.buffer[HLEN] = hlen;
+    }
+
+    pub fn set_xid(&mut self, xid: &[u8]) {
+        self.buffer[XID..SECS].copy_from_slice(xid);
+    }
+
+    pub fn set_flags(&mut self, flags: &[u8]) {
+        self.buffer[FLAGS..CIADDR].copy_from_slice(flags);
+    }
+
+    pub fn set_ciaddr(&mut self, ciaddr: Ipv4Addr) {
+        self.buffer[CIADDR..YIADDR].copy_from_slice(&ciaddr.octets());
+    }
+
+    pub fn set_yiaddr(&mut self, yiaddr: Ipv4Addr) {
+        self.buffer[YIADDR..YIADDR + 4].copy_from_slice(&yiaddr.octets());
+    }
+
+    pub fn set_magic_cookie(&mut self, cursor: &mut usize) {
+        self.buffer[*cursor..*cursor + 4].copy_from_slice(&MAGIC_COOKIE);
+        *cursor += 4;
+    }
+
     pub fn set_giaddr(&mut self, giaddr: Ipv4Addr) {
         self.buffer[GIADDR..CHADDR].copy_from_slice(&giaddr.octets())
     }
 
     pub fn set_chaddr(&mut self, chaddr: MacAddr) {
-        let t = chaddr.to_primitive_values();
-        let macaddr_value = [t.0, t.1, t.2, t.3, t.4, t.5];
+        let MacAddr(a, b, c, d, e, f) = chaddr;
+        let macaddr_value = [a, b, c, d, e, f];
 
         self.buffer[CHADDR..CHADDR + 6].copy_from_slice(&macaddr_value);
     }
@@ -69,27 +203,20 @@ impl DhcpPacket {
         *cursor += len;
     }
 
-    pub fn get_option(&self, option_code: u8) -> Option<Vec<u8>> {
-        let mut index: usize = 4;
+    /** マジッククッキーに続くオプションを順に返す。クッキーが違えばオプションはないものとする */
+    pub fn options(&self) -> DhcpOptions<'_> {
         let options = self.get_options();
-        while options[index] == option_code {
-            if options[index] == option_code {
-                let len = options[index + 1];
-                let buf_index = index + 2;
-                let v = options[buf_index..buf_index + len as usize].to_vec();
-                return Some(v);
-            } else if options[index] == 0 {
-                index += 1;
-            } else {
-                index += 1;
-                let len = options[index];
-                index += 1;
-                index += len as usize;
-            }
-        }
-        None
+        let options = if options[..4] == MAGIC_COOKIE { &options[4..] } else { &[] };
+        DhcpOptions::new(options)
     }
 
+    /** 最初に現れたoption_codeの値 */
+    pub fn get_option(&self, option_code: u8) -> Option<Vec<u8>> {
+        self.options().find(|(code, _)| *code == option_code).map(|(_, value)| value.to_vec())
+    }
+}
+
+impl DhcpServer {
     // アドレスプールからIPアドレスを引き抜く
     pub fn pick_available_ip(&self) -> Option<Ipv4Addr> {
         let mut lock = self.address_pool.write().unwrap();
@@ -110,59 +237,349 @@ impl DhcpPacket {
     // ベクタの先頭にアドレスを返す　取り出しは後方から行われるため、返されたアドレスは当方他のアドレスに割り当てられない
     pub fn release_address(&self, released_ip: Ipv4Addr) {
         let mut lock = self.address_pool.write().unwrap();
-        lock.insert(0, release_ip);
+        lock.insert(0, released_ip);
     }
 
-    pub fn new() -> Result<DhcpServer, failure::Error> {
-        let env = util::load_env();
-
-        let static_address = util::obtain_static_addresses(&env)?;
-
-        let network_addr_with_prefix: Ipv4Network = Ipv4Network::new(static_address["network_addr"], ipnetwork::ipv4_mask_to_prefix(static_addresses["subnet_mask"])?)?;
+    pub fn new(interface: Option<String>) -> Result<DhcpServer, failure::Error> {
+        let config = ServerConfig::load(interface.as_deref())?;
         let con = Connection::open("dhcp.db")?;
+        let mut dhcp_server = Self::with_connection(config, con)?;
+        dhcp_server.interface = interface;
+        Ok(dhcp_server)
+    }
 
-        let addr_pool = Self::init_address_pool(&con, &static_addresses, network_addr_with_prefix)?;
-        info!("There are {} addresses in the address pool", addr_pool.len());
+    /** 設定とデータベース接続を指定して起動する。テストではインメモリのデータベースを渡す */
+    pub fn with_connection(config: ServerConfig, con: Connection) -> Result<DhcpServer, failure::Error> {
+        database::migrate(&con, config.lease_seconds, util::now_secs())?;
 
-        let lease_time = util::make_big_endian_vec_from_u32(env.get("LEASE_TIME").expect("Missing lease_time")).parse()?;
+        let addr_pool = Self::init_address_pool(&con, &config)?;
+        info!("There are {} addresses in the address pool", addr_pool.len());
 
         Ok(DhcpServer {
             address_pool: RwLock::new(addr_pool),
             db_connection: Mutex::new(con),
-            network_addr: network_addr_with_prefix,
+            config: RwLock::new(config),
+            interface: None,
+            pending_offers: Mutex::new(HashMap::new()),
+            mac_rate_limiter: RateLimiter::new(),
+            source_rate_limiter: RateLimiter::new(),
+            stats: Stats::default(),
+        })
+    }
+
+    // 新たなホストに割り当て可能なアドレスプール初期化
+    fn init_address_pool(con: &Connection, config: &ServerConfig) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        let mut used_ip_addrs = database::select_addresses(con, Some(0))?;
+        used_ip_addrs.extend(config.reserved_addresses());
+
+        // ネットワークのすべてのIPアドレスから、使用されているIPアドレス除いたものをアドレスプールとする。
+        let mut addr_pool: Vec<Ipv4Addr> = config.network_addr.iter().filter(|addr| !used_ip_addrs.contains(addr)).collect();
+
+        addr_pool.reverse();
+
+        Ok(addr_pool)
+    }
+
+    /** 現在の設定のスナップショットを返す
+     * データベースのロックを取得する前に呼び出すこと（再読み込み処理とのデッドロックを避けるため）
+     */
+    pub fn config(&self) -> ServerConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /** このスコープが担当するインターフェース。--interfaceの指定がなければNone */
+    pub fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    /** アドレスプールに残っているアドレス数 */
+    pub fn pool_size(&self) -> usize {
+        self.address_pool.read().unwrap().len()
+    }
+
+    /** 送信元アドレスごとの頻度制限。制限を超えていればfalse
+     * アドレスを持たないクライアントは0.0.0.0から送ってくるため、全員で1つのバケットを共有しないよう
+     * 0.0.0.0とブロードキャストアドレスは制限せず、MACアドレスごとの制限に任せる
+     */
+    pub fn allow_source(&self, src: IpAddr) -> bool {
+        let shared = match src {
+            IpAddr::V4(addr) => addr.is_unspecified() || addr.is_broadcast(),
+            IpAddr::V6(addr) => addr.is_unspecified(),
+        };
+        if shared || self.source_rate_limiter.allow(src, self.config().rate_limit_per_source) {
+            return true;
+        }
+        if self.stats.rate_limited_by_source.fetch_add(1, Ordering::Relaxed).is_multiple_of(100) {
+            warn!("rate limit exceeded by source {}", src);
+        }
+        false
+    }
+
+    /** MACアドレスごとの頻度制限。制限を超えていればfalse */
+    pub fn allow_mac_addr(&self, mac_addr: MacAddr) -> bool {
+        if self.mac_rate_limiter.allow(mac_addr, self.config().rate_limit_per_mac) {
+            return true;
+        }
+        if self.stats.rate_limited_by_mac.fetch_add(1, Ordering::Relaxed).is_multiple_of(100) {
+            warn!("rate limit exceeded by {}", mac_addr);
+        }
+        false
+    }
+
+    /** 有効期限内のOFFERがあればそのアドレスを返す。DISCOVERの再送で別のアドレスを消費しないようにする */
+    pub fn pending_offer(&self, mac_addr: MacAddr) -> Option<Ipv4Addr> {
+        let timeout = self.config().offer_timeout;
+        let offers = self.pending_offers.lock().unwrap();
+        offers.get(&mac_addr).filter(|offer| offer.offered_at.elapsed() < timeout).map(|offer| offer.ip_addr)
+    }
+
+    /** 保持中のOFFERが上限に達していなければtrue */
+    pub fn can_hold_offer(&self) -> bool {
+        let max = self.config().max_outstanding_offers;
+        if self.pending_offers.lock().unwrap().len() < max {
+            return true;
+        }
+        if self.stats.offers_capped.fetch_add(1, Ordering::Relaxed).is_multiple_of(100) {
+            warn!("too many outstanding offers (max {}), ignoring DHCPDISCOVER", max);
+        }
+        false
+    }
+
+    /** OFFERしたアドレスを一時的に確保する */
+    pub fn hold_offer(&self, mac_addr: MacAddr, ip_addr: Ipv4Addr) {
+        let offer = PendingOffer {
+            ip_addr,
+            offered_at: Instant::now(),
+        };
+        // return_offered_addressはデータベースとプールのロックを取るため、pending_offersのロックを先に解放する
+        let previous = self.pending_offers.lock().unwrap().insert(mac_addr, offer);
+        if let Some(previous) = previous {
+            if previous.ip_addr != ip_addr {
+                self.return_offered_address(previous.ip_addr);
+            }
+        }
+    }
+
+    /** DHCPREQUESTでOFFERが確定したので、保持を解く */
+    pub fn confirm_offer(&self, mac_addr: MacAddr) {
+        self.pending_offers.lock().unwrap().remove(&mac_addr);
+    }
+
+    /** クライアントが他のサーバーを選んだ場合など、OFFERしたアドレスをプールへ戻す */
+    pub fn cancel_offer(&self, mac_addr: MacAddr) {
+        let offer = self.pending_offers.lock().unwrap().remove(&mac_addr);
+        if let Some(offer) = offer {
+            self.return_offered_address(offer.ip_addr);
+        }
+    }
+
+    /** 期限切れのOFFERをプールへ戻し、使われていないレートリミッタのエントリを捨てる */
+    pub fn expire_offers(&self) {
+        let timeout = self.config().offer_timeout;
+        let expired: Vec<(MacAddr, Ipv4Addr)> = {
+            let mut offers = self.pending_offers.lock().unwrap();
+            let expired = offers.iter().filter(|(_, offer)| offer.offered_at.elapsed() >= timeout).map(|(mac_addr, offer)| (*mac_addr, offer.ip_addr)).collect();
+            offers.retain(|_, offer| offer.offered_at.elapsed() < timeout);
+            expired
+        };
+        for (mac_addr, ip_addr) in expired {
+            debug!("offer of {} to {} expired", ip_addr, mac_addr);
+            self.stats.offers_expired.fetch_add(1, Ordering::Relaxed);
+            self.return_offered_address(ip_addr);
+        }
+
+        self.mac_rate_limiter.prune(Duration::from_secs(60));
+        self.source_rate_limiter.prune(Duration::from_secs(60));
+    }
+
+    /** OFFERしたアドレスを、まだ誰にも貸し出されておらず現在のネットワークに含まれていればプールへ戻す
+     * 既存のリースを再OFFERした場合や、設定の再読み込みでネットワークから外れた場合は戻さない
+     */
+    fn return_offered_address(&self, ip_addr: Ipv4Addr) {
+        let config = self.config();
+        if !config.network_addr.contains(ip_addr) || config.reserved_addresses().contains(&ip_addr) {
+            return;
+        }
+        let leased = {
+            let con = self.db_connection.lock().unwrap();
+            database::select_mac_addr_by_ip(&con, ip_addr)
+        };
+        match leased {
+            Ok(None) => {}
+            Ok(Some(_)) => return,
+            Err(e) => {
+                error!("Failed to look up lease of {}: {}", ip_addr, e);
+                return;
+            }
+        }
+        let mut pool = self.address_pool.write().unwrap();
+        if !pool.contains(&ip_addr) {
+            pool.insert(0, ip_addr);
+        }
+    }
+
+    /** .envを読み直し、リースを保ったまま設定とアドレスプールを差し替える
+     * 新しい設定の読み込みに失敗した場合は、現在の設定のまま動作を続ける
+     */
+    pub fn reload_config(&self) -> Result<(), failure::Error> {
+        let new_config = ServerConfig::load(self.interface.as_deref())?;
+
+        // ロックの取得順は 設定 -> データベース -> アドレスプール
+        let mut config = self.config.write().unwrap();
+        let new_reserved = new_config.reserved_addresses();
+        let old_reserved = config.reserved_addresses();
+
+        let mut con = self.db_connection.lock().unwrap();
+        {
+            // このスコープのリースのうち、新しいネットワークに含まれない、または予約されたアドレスのものは論理削除する
+            // データベースは他のインターフェースのスコープと共有しているため、現在のネットワーク外のリースには触れない
+            let tx = con.transaction()?;
+            for (mac_addr, ip_addr) in database::select_leases(&tx)? {
+                if config.network_addr.contains(ip_addr) && (!new_config.network_addr.contains(ip_addr) || new_reserved.contains(&ip_addr)) {
+                    database::delete_entry(&tx, mac_addr)?;
+                    info!("lease {} for {} is no longer valid, released", ip_addr, mac_addr);
+                }
+            }
+            tx.commit()?;
+        }
+        let used_ip_addrs = database::select_addresses(&con, Some(0))?;
+
+        let mut pool = self.address_pool.write().unwrap();
+        let before = pool.len();
+
+        // 新しいネットワークから外れたアドレスと、新たに予約されたアドレスを取り除く
+        pool.retain(|addr| new_config.network_addr.contains(*addr) && !new_reserved.contains(addr));
+        let removed = before - pool.len();
+
+        // 新たにネットワークに加わったアドレスと、予約が外れたアドレスをプールに加える
+        // 払い出し中（OFFER済みでACK前）のアドレスは旧ネットワークに含まれるため、ここでは追加されない
+        let added: Vec<Ipv4Addr> = new_config
+            .network_addr
+            .iter()
+            .filter(|addr| !config.network_addr.contains(*addr) || old_reserved.contains(addr))
+            .filter(|addr| !new_reserved.contains(addr) && !used_ip_addrs.contains(addr) && !pool.contains(addr))
+            .collect();
+        for addr in added.iter() {
+            pool.insert(0, *addr);
+        }
+
+        info!(
+            "configuration reloaded: network {} -> {}, {} addresses added, {} removed, {} in pool",
+            config.network_addr,
+            new_config.network_addr,
+            added.len(),
+            removed,
+            pool.len()
+        );
+        *config = new_config;
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    /** .envから設定を読み込む
+     * インターフェースが指定されていれば、省略されたサーバー識別子とネットワークをそのアドレスから補う
+     */
+    pub fn load(interface: Option<&str>) -> Result<ServerConfig, failure::Error> {
+        let mut env = util::load_env()?;
+        if let Some(name) = interface {
+            crate::interface::fill_env(&mut env, name)?;
+        }
+        Self::from_env(&env)
+    }
+
+    pub fn from_env(env: &HashMap<String, String>) -> Result<ServerConfig, failure::Error> {
+        let static_addresses = util::obtain_static_addresses(env)?;
+
+        let network_addr = Ipv4Network::new(static_addresses["network_addr"], ipnetwork::ipv4_mask_to_prefix(static_addresses["subnet_mask"])?)?;
+
+        let lease_seconds: u32 = env.get("LEASE_TIME").ok_or_else(|| failure::err_msg("Missing lease_time"))?.parse()?;
+        let lease_time = util::make_big_endian_vec_from_u32(lease_seconds)?;
+        let renewal_time = util::make_big_endian_vec_from_u32(lease_seconds / 2)?;
+        let rebinding_time = util::make_big_endian_vec_from_u32((u64::from(lease_seconds) * 7 / 8) as u32)?;
+
+        Ok(ServerConfig {
+            network_addr,
             server_address: static_addresses["dhcp_server_addr"],
             default_gateway: static_addresses["default_gateway"],
             subnet_mask: static_addresses["subnet_mask"],
             dns_server: static_addresses["dns_addr"],
+            lease_seconds,
             lease_time,
+            renewal_time,
+            rebinding_time,
+            offer_timeout: Duration::from_secs(util::get_env_or(env, "OFFER_TIMEOUT", 10)?),
+            max_outstanding_offers: util::get_env_or(env, "MAX_OUTSTANDING_OFFERS", 64)?,
+            rate_limit_per_mac: util::get_env_or(env, "RATE_LIMIT_PER_MAC", 5)?,
+            rate_limit_per_source: util::get_env_or(env, "RATE_LIMIT_PER_SOURCE", 100)?,
+            ping_check: util::get_env_or(env, "PING_CHECK", true)?,
         })
     }
 
-    // 新たなホストに割り当て可能なアドレスプール初期化
-    fn init_address_pool(
-        con: &Connection,
-        static_addresses: &HashMap<String, Ipv4Addr>,
-        network_addr_with_prefix: Ipv4Network
-    ) -> Result<Vec<Ipv4Addr>, failure::Error> {
-        let network_addr = static_addresses.get("network_addr").unwrap()
-        let default_gateway = static_addresses.get("default_gateway").unwrap()
-        let dhcp_server_addr = static_addresses.get("dhcp_server_addr").unwrap()
-        let dns_addr = static_addresses.get("dns_addr").unwrap()
-        let broadcast = network_addr_with_prefix.broadcast();
+    /** ネットワークアドレスやサーバー自身など、クライアントに割り当ててはいけないアドレス */
+    pub fn reserved_addresses(&self) -> Vec<Ipv4Addr> {
+        vec![
+            self.network_addr.network(),
+            self.default_gateway,
+            self.server_address,
+            self.dns_server,
+            self.network_addr.broadcast(),
+        ]
+    }
+}
 
-        let mut used_ip_addrs = database::select_addresses(con, Some(0))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        used_ip_addrs.push(*network_addr);
-        used_ip_addrs.push(*default_gateway);
-        used_ip_addrs.push(*dhcp_server_addr);
-        used_ip_addrs.push(*dns_server_addr);
-        used_ip_addrs.push(broadcast);
+    /** packet-captureのapplication.rsのテストと同じオプション列を使う */
+    fn with_options(options: &[u8]) -> DhcpPacket {
+        let mut buffer = vec![0u8; OPTIONS];
+        buffer.extend_from_slice(&MAGIC_COOKIE);
+        buffer.extend_from_slice(options);
+        DhcpPacket::new(buffer).unwrap()
+    }
 
-        // ネットワークのすべてのIPアドレスから、使用されているIPアドレス除いたものをアドレスプールとする。
-        let mut addr_pool: Vec<Ipv4Addr> = network_addr_with_prefix.iter().filter(|addr| !used_ip_addrs.contains(addr)).collect();
+    fn codes(packet: &DhcpPacket) -> Vec<u8> {
+        packet.options().map(|(code, _)| code).collect()
+    }
 
-        addr_pool.reverse();
+    #[test]
+    fn skips_padding_and_stops_at_end() {
+        let packet = with_options(&[0, 53, 1, 3, 0, 0, 50, 4, 192, 168, 0, 10, 255, 54, 4, 192, 168, 0, 1]);
+        assert_eq!(codes(&packet), vec![53, 50]);
+        assert_eq!(packet.get_option(50), Some(vec![192, 168, 0, 10]));
+        assert_eq!(packet.get_option(54), None);
+    }
 
-        Ok(addr_pool)
+    #[test]
+    fn stops_at_an_option_longer_than_the_buffer() {
+        let packet = with_options(&[53, 1, 1, 12, 200, b'h', b'o', b's', b't']);
+        assert_eq!(codes(&packet), vec![53]);
+        assert_eq!(packet.get_option(12), None);
+
+        // 長さのバイトがない
+        assert_eq!(codes(&with_options(&[53, 1, 1, 61])), vec![53]);
+    }
+
+    #[test]
+    fn reads_to_the_end_without_an_end_option() {
+        let packet = with_options(&[53, 1, 5, 51, 4, 0, 0, 14, 16, 12, 0]);
+        assert_eq!(codes(&packet), vec![53, 51, 12]);
+        assert_eq!(packet.get_option(12), Some(vec![]));
+    }
+
+    #[test]
+    fn first_occurrence_wins() {
+        let packet = with_options(&[53, 1, 1, 53, 1, 3, 255]);
+        assert_eq!(packet.get_option(53), Some(vec![1]));
+    }
+
+    #[test]
+    fn no_options_without_the_magic_cookie() {
+        let mut buffer = with_options(&[53, 1, 1, 255]).get_buffer().to_vec();
+        buffer[OPTIONS] = 0;
+        let packet = DhcpPacket::new(buffer).unwrap();
+        assert_eq!(packet.get_option(53), None);
     }
 }
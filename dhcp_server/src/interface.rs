@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::Arc;
+
+use ipnetwork::Ipv4Network;
+use log::info;
+use pnet::datalink;
+
+use crate::util::DhcpSocket;
+
+/** --interfaceで指定された、DHCPを提供するインターフェース */
+#[derive(Clone)]
+pub struct ServingInterface {
+    pub name: String,
+    pub index: u32,
+}
+
+/** インターフェース名を解決する。存在しない名前が含まれていればエラーにする */
+pub fn lookup(names: &[String]) -> Result<Vec<ServingInterface>, failure::Error> {
+    let interfaces = datalink::interfaces();
+    names
+        .iter()
+        .map(|name| {
+            interfaces
+                .iter()
+                .find(|iface| iface.name == *name)
+                .map(|iface| ServingInterface { name: iface.name.clone(), index: iface.index })
+                .ok_or_else(|| failure::err_msg(format!("No such interface: {}", name)))
+        })
+        .collect()
+}
+
+/** インターフェースに付与された最初のIPv4アドレスとそのネットワーク */
+pub fn ipv4_network_of(name: &str) -> Result<(Ipv4Addr, Ipv4Network), failure::Error> {
+    let iface = datalink::interfaces().into_iter().find(|iface| iface.name == name).ok_or_else(|| failure::err_msg(format!("No such interface: {}", name)))?;
+    for ip in iface.ips.iter() {
+        if let IpAddr::V4(addr) = ip.ip() {
+            let network = Ipv4Network::new(addr, ip.prefix())?;
+            return Ok((addr, Ipv4Network::new(network.network(), ip.prefix())?));
+        }
+    }
+    Err(failure::err_msg(format!("{} has no IPv4 address", name)))
+}
+
+/** .envで省略されたサーバー識別子、ネットワークアドレス、サブネットマスクをインターフェースのアドレスから補う
+ * インターフェース名を前に付けた項目があれば、共通の項目より優先する
+ * 設定の再読み込みのたびに呼ばれるため、インターフェースのアドレス変更も反映される
+ */
+pub fn fill_env(env: &mut HashMap<String, String>, name: &str) -> Result<(), failure::Error> {
+    // eth0.DEFAULT_GATEWAY のようにインターフェース名を前に付けた項目は、そのインターフェースでだけ使う
+    let prefix = format!("{}.", name);
+    let overrides: Vec<(String, String)> = env.iter().filter_map(|(key, value)| key.strip_prefix(&prefix).map(|key| (key.to_string(), value.clone()))).collect();
+    env.extend(overrides);
+
+    let (addr, network) = ipv4_network_of(name)?;
+
+    let derived = [
+        ("SERVER_IDENTIFIER", addr.to_string()),
+        ("NETWORK_ADDR", network.network().to_string()),
+        ("SUBNET_MASK", network.mask().to_string()),
+    ];
+    for (key, value) in derived.iter() {
+        if !env.contains_key(*key) {
+            info!("{} = {} (derived from {})", key, value, name);
+            env.insert(key.to_string(), value.clone());
+        }
+    }
+    Ok(())
+}
+
+/** 受信したインターフェースが分かるDHCPサーバーのソケット
+ * IP_PKTINFOで受信インターフェースを取得し、応答も同じインターフェースから送信する
+ */
+pub struct InterfaceSocket {
+    socket: UdpSocket,
+    /** 空ならすべてのインターフェースで応答する */
+    serving: Vec<ServingInterface>,
+    names: HashMap<u32, String>,
+}
+
+impl InterfaceSocket {
+    pub fn bind(addr: &str, serving: Vec<ServingInterface>) -> Result<InterfaceSocket, failure::Error> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_broadcast(true)?;
+        set_int_option(&socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)?;
+
+        // 1つだけ指定された場合はカーネルにも他のインターフェースのパケットを捨てさせる
+        if serving.len() == 1 {
+            let name = &serving[0].name;
+            let ret = unsafe { libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE, name.as_ptr() as *const libc::c_void, name.len() as libc::socklen_t) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        let names = datalink::interfaces().into_iter().map(|iface| (iface.index, iface.name)).collect();
+        Ok(InterfaceSocket { socket, serving, names })
+    }
+
+    /** 受信したインターフェースがDHCPを提供する対象か */
+    pub fn is_serving(&self, ifindex: u32) -> bool {
+        self.serving.is_empty() || self.serving.iter().any(|iface| iface.index == ifindex)
+    }
+
+    pub fn interface_name(&self, ifindex: u32) -> String {
+        self.names.get(&ifindex).cloned().unwrap_or_else(|| format!("if{}", ifindex))
+    }
+
+    /** データグラムを受信し、サイズ、送信元、受信インターフェースのインデックスを返す */
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u32)> {
+        let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // cmsghdrのアラインメントを満たすためにu64の配列を使う
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut libc::sockaddr_in as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let size = unsafe { libc::recvmsg(self.socket.as_raw_fd(), &mut msg, 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut ifindex = 0;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::IPPROTO_IP && (*cmsg).cmsg_type == libc::IP_PKTINFO {
+                    let info = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo);
+                    ifindex = info.ipi_ifindex as u32;
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        let src = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))), u16::from_be(addr.sin_port));
+        Ok((size as usize, src, ifindex))
+    }
+
+    /** 受信したインターフェースから応答を送るソケットを作る */
+    pub fn reply_socket(self: &Arc<Self>, ifindex: u32) -> ReplySocket {
+        ReplySocket {
+            socket: self.clone(),
+            ifindex,
+            name: self.interface_name(ifindex),
+        }
+    }
+}
+
+/** 1つのリクエストに対する応答用のソケット。受信インターフェースの情報を持つ */
+pub struct ReplySocket {
+    socket: Arc<InterfaceSocket>,
+    ifindex: u32,
+    name: String,
+}
+
+impl DhcpSocket for ReplySocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let addr = match addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "DHCP replies must be IPv4")),
+        };
+        let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+        sin.sin_family = libc::AF_INET as libc::sa_family_t;
+        sin.sin_port = addr.port().to_be();
+        sin.sin_addr = libc::in_addr { s_addr: u32::from(*addr.ip()).to_be() };
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut sin as *mut libc::sockaddr_in as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+
+        // 送信インターフェースをIP_PKTINFOで指定する（ブロードキャストの送出先を受信インターフェースに揃える）
+        unsafe {
+            msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<libc::in_pktinfo>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::IPPROTO_IP;
+            (*cmsg).cmsg_type = libc::IP_PKTINFO;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::in_pktinfo>() as u32) as _;
+            let info = libc::in_pktinfo {
+                ipi_ifindex: self.ifindex as libc::c_int,
+                ipi_spec_dst: libc::in_addr { s_addr: 0 },
+                ipi_addr: libc::in_addr { s_addr: 0 },
+            };
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo, info);
+        }
+
+        let size = unsafe { libc::sendmsg(self.socket.socket.as_raw_fd(), &msg, 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(size as usize)
+    }
+
+    fn ingress_interface(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+fn set_int_option(socket: &UdpSocket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe { libc::setsockopt(socket.as_raw_fd(), level, name, &value as *const libc::c_int as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -1,8 +1,37 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+use ipnetwork::Ipv4Network;
+use log::{debug, error, info, warn};
+use pnet::util::MacAddr;
+
+use dhcp::{DhcpPacket, DhcpServer, ServerConfig};
+use util::DhcpSocket;
+
+mod admin;
+mod database;
+mod dhcp;
+mod interface;
+mod options;
+mod ratelimit;
+#[cfg(test)]
+mod replay_tests;
+mod util;
+
+#[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 enum Code {
     MessageType = 53,
     IPAddressLeaseTime = 51,
-    ServerIdentifier = 54
+    ServerIdentifier = 54,
     RequestedIpAddress = 50,
+    RenewalTime = 58,
+    RebindingTime = 59,
     SubnetMask = 1,
     Router = 3,
     DNS = 6,
@@ -12,30 +41,69 @@ enum Code {
 const DHCPDISCOVER: u8 = 1;
 const DHCPOFFER: u8 = 2;
 const DHCPREQUEST: u8 = 3;
-const DHCPPACK: u8 = 5;
+const DHCPACK: u8 = 5;
 const DHCPNAK: u8 = 6;
 const DHCPRELEASE: u8 = 7;
 
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHER: u8 = 1;
+/** 送信するDHCPメッセージのサイズ。オプション部を含めてクライアントが必ず受け付ける最小の長さ(RFC2131 2章) */
+const DHCP_SIZE: usize = 548;
+
 fn main() {
     env::set_var("RUST_LOG", "debug");
     env_logger::init();
 
-    let server_socket = UdpSocket::bind("0.0.0.0:67").expect("Failed to bind socket");
-    server_socket.set_broadcast(true).unwrap();
+    let args: Vec<String> = env::args().collect();
+    let interface_names = parse_interface_option(&args).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+    let serving_interfaces = interface::lookup(&interface_names).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+
+    let server_socket = Arc::new(interface::InterfaceSocket::bind("0.0.0.0:67", serving_interfaces.clone()).expect("Failed to bind socket"));
 
-    let dhcp_server = Arc::new(DhcpServer::new().unwrap_or_else(|e| panic!("Failed to start dhcp server. {:?\}", e)));
+    // インターフェースごとに、そのアドレスから.envで省略された設定を補ったスコープを用意する
+    let scopes = start_scopes(&serving_interfaces).unwrap_or_else(|e| panic!("Failed to start dhcp server. {:?}", e));
+    let dhcp_servers: Vec<Arc<DhcpServer>> = scopes.values().cloned().collect();
+
+    // SIGHUPと管理コマンドによる設定の再読み込み
+    admin::spawn_sighup_handler(dhcp_servers.clone()).unwrap_or_else(|e| error!("Failed to install SIGHUP handler: {}", e));
+    admin::spawn_admin_listener(dhcp_servers.clone()).unwrap_or_else(|e| error!("Failed to start admin listener: {}", e));
+
+    // 確定しなかったOFFERのアドレスを定期的にプールへ戻す
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        for dhcp_server in dhcp_servers.iter() {
+            dhcp_server.expire_offers();
+        }
+    });
 
     loop {
         let mut recv_buf = [0u8; 1024];
-        match server_socket.rec_from(&mut recv_buf) {
-            Ok((size, src)) => {
-                debug!("received data from {}, size: {}", src, size);
-                let transmission_socket = server_socket.try_clone().expect("Failed to create client socket");
+        match server_socket.recv_from(&mut recv_buf) {
+            Ok((size, src, ifindex)) => {
+                debug!("received data from {} on {}, size: {}", src, server_socket.interface_name(ifindex), size);
+                if !server_socket.is_serving(ifindex) {
+                    continue;
+                }
+                let dhcp_server = match scopes.get(&ifindex).or_else(|| scopes.get(&ANY_INTERFACE)) {
+                    Some(dhcp_server) => dhcp_server,
+                    None => continue,
+                };
+                if !dhcp_server.allow_source(src.ip()) {
+                    continue;
+                }
+                let transmission_socket = server_socket.reply_socket(ifindex);
 
                 let cloned_dhcp_server = dhcp_server.clone();
 
                 thread::spawn(move || {
-                    if Some(dhcp_server) = DhcpPacket::new(recv_buf[..size].to_vec()) {
+                    if let Some(dhcp_packet) = DhcpPacket::new(recv_buf[..size].to_vec()) {
 
                         if dhcp_packet.get_op() != BOOTREQUEST {
                             return;
@@ -53,57 +121,126 @@ fn main() {
     }
 }
 
+/** --interfaceの指定がない時に、すべてのインターフェースで共有するスコープのキー。0は実在するインターフェースのインデックスにならない */
+const ANY_INTERFACE: u32 = 0;
+
+/** 受信インターフェースのインデックスをキーに、インターフェースごとのDHCPサーバーを起動する
+ * アドレスプールとOFFERはインターフェースごとに分け、リースのデータベースは共有する
+ */
+fn start_scopes(serving_interfaces: &[interface::ServingInterface]) -> Result<HashMap<u32, Arc<DhcpServer>>, failure::Error> {
+    let mut scopes = HashMap::new();
+    if serving_interfaces.is_empty() {
+        scopes.insert(ANY_INTERFACE, Arc::new(DhcpServer::new(None)?));
+        return Ok(scopes);
+    }
+
+    let mut networks: Vec<(&str, Ipv4Network)> = Vec::new();
+    for iface in serving_interfaces.iter() {
+        let dhcp_server = DhcpServer::new(Some(iface.name.clone()))?;
+        let network = dhcp_server.config().network_addr;
+        // .envに書いたネットワークはすべてのインターフェースに適用されるので、同じアドレスを二重に払い出さないよう重なりを拒否する
+        if let Some((other, _)) = networks.iter().find(|(_, other)| other.contains(network.network()) || network.contains(other.network())) {
+            return Err(failure::err_msg(format!(
+                "{} and {} serve overlapping networks ({}). Omit NETWORK_ADDR from .env or set {}.NETWORK_ADDR per interface",
+                other, iface.name, network, iface.name
+            )));
+        }
+        networks.push((&iface.name, network));
+        info!("serving {} on {}", network, iface.name);
+        scopes.insert(iface.index, Arc::new(dhcp_server));
+    }
+    Ok(scopes)
+}
+
+/** --interface eth0 または --interface eth0,eth1 の形式で指定されたインターフェース名を返す
+ * 複数回指定してもよい。指定がなければすべてのインターフェースで応答する
+ */
+fn parse_interface_option(args: &[String]) -> Result<Vec<String>, failure::Error> {
+    let mut names = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--interface" | "-i" => {
+                let value = iter.next().ok_or_else(|| failure::err_msg("--interface requires an interface name"))?;
+                names.extend(value.split(',').filter(|name| !name.is_empty()).map(str::to_string));
+            }
+            _ => return Err(failure::err_msg(format!("Unknown argument: {}. Usage: dhcp_server [--interface name[,name...]]", arg))),
+        }
+    }
+    Ok(names)
+}
+
 fn make_dhcp_packet(
     received_packet: &DhcpPacket,
     dhcp_server: &Arc<DhcpServer>,
     message_type: u8,
     ip_to_be_leased: Ipv4Addr
 ) -> Result<DhcpPacket, failure::Error> {
-    /** パケット本体となるバッファ */
+    // パケット本体となるバッファ
     let buffer = vec![0u8; DHCP_SIZE];
     let mut dhcp_packet = DhcpPacket::new(buffer).unwrap();
 
-    /** 各種フィールドの設定 */
-    dhcp_server.set_op(BOOTREPLY);
-    dhcp_server.set_htype(HTYPE_ETHER);
+    // 各種フィールドの設定
+    dhcp_packet.set_op(BOOTREPLY);
+    dhcp_packet.set_htype(HTYPE_ETHER);
     dhcp_packet.set_hlen(6);
-    dhcp_server.set_xid(received_packet.get_xid())
+    dhcp_packet.set_xid(received_packet.get_xid());
 
     if message_type == DHCPACK {
-        dhcp_packet.set_ciaddr(received_packet.get_ciaddr())
+        dhcp_packet.set_ciaddr(received_packet.get_ciaddr());
     }
     dhcp_packet.set_yiaddr(ip_to_be_leased);
     dhcp_packet.set_flags(received_packet.get_flags());
-    dhcp_server.set_giaddr(received_packet.get_giaddr());
-    dhcp_server.set_chaddr(received_packet.get_chaddr());
+    dhcp_packet.set_giaddr(received_packet.get_giaddr());
+    dhcp_packet.set_chaddr(received_packet.get_chaddr());
 
-    /** 各種オプションの設定 */
+    // 各種オプションの設定
+    let config = dhcp_server.config();
     let mut cursor = dhcp::OPTIONS;
     dhcp_packet.set_magic_cookie(&mut cursor);
     dhcp_packet.set_option(&mut cursor, Code::MessageType as u8, 1, Some(&[message_type]));
-    dhcp_packet.set_option(&mut cursor, Code::IPAddressLeaseTime as u8, 4, Some(&dhcp_server.lease_time));
-    dhcp_packet.set_option(&mut cursor, Code::ServerIdentifier as u8, 4, Some(&dhcp_server.server_address.octets()));
-    dhcp_packet.set_option(&mut cursor, Code::SubnetMask as u8, 4, Some(&dhcp_server.subnet_mask.octets()));
-    dhcp_packet.set_option(&mut cursor, Code::Router as u8, 4, Some(&dhcp_server.default_gateway.octets()));
-    dhcp_packet.set_option(&mut cursor, Code::DNS as u8, 4, Some(&dhcp_server.dns_server.octets()));
-    dhcp_packet.set_option(&mut cursor, Code:: as u8, 0, None);
+    dhcp_packet.set_option(&mut cursor, Code::ServerIdentifier as u8, 4, Some(&config.server_address.octets()));
+    if message_type == DHCPNAK {
+        // NAKにはリース時間や設定情報を含めない(RFC2131 表3)
+        dhcp_packet.set_option(&mut cursor, Code::End as u8, 0, None);
+        return Ok(dhcp_packet);
+    }
+    dhcp_packet.set_option(&mut cursor, Code::IPAddressLeaseTime as u8, 4, Some(&config.lease_time));
+    dhcp_packet.set_option(&mut cursor, Code::RenewalTime as u8, 4, Some(&config.renewal_time));
+    dhcp_packet.set_option(&mut cursor, Code::RebindingTime as u8, 4, Some(&config.rebinding_time));
+    dhcp_packet.set_option(&mut cursor, Code::SubnetMask as u8, 4, Some(&config.subnet_mask.octets()));
+    dhcp_packet.set_option(&mut cursor, Code::Router as u8, 4, Some(&config.default_gateway.octets()));
+    dhcp_packet.set_option(&mut cursor, Code::DNS as u8, 4, Some(&config.dns_server.octets()));
+    dhcp_packet.set_option(&mut cursor, Code::End as u8, 0, None);
     Ok(dhcp_packet)
 }
 
-fn dhcp_handler(packet: &DhcpPacket, soc: &UdpSocket, dhcp_server: Arc<DhcpServer>) -> Result<(), failure::Error> {
-    let message = packet.get_option(Code::MessageType as u8).ok_or_else(|| failure::err_msg("spacified option was not found"))?;
-    let message_type = message[0];
+fn dhcp_handler(packet: &DhcpPacket, soc: &dyn DhcpSocket, dhcp_server: Arc<DhcpServer>) -> Result<(), failure::Error> {
+    let message_type = packet.get_option(Code::MessageType as u8).and_then(|message| message.first().cloned()).ok_or_else(|| failure::err_msg("spacified option was not found"))?;
     let transaction_id = BigEndian::read_u32(packet.get_xid());
     let client_macaddr = packet.get_chaddr();
+    let mut options = packet.options();
+    options.by_ref().for_each(drop);
+    if options.truncated() {
+        debug!("{:x}: ignored options after a truncated option", transaction_id);
+    }
+    if let Some(interface) = soc.ingress_interface() {
+        debug!("{:x}: message from {} on {}", transaction_id, client_macaddr, interface);
+    }
+
+    if !dhcp_server.allow_mac_addr(client_macaddr) {
+        debug!("{:x}: dropped message from {}", transaction_id, client_macaddr);
+        return Ok(());
+    }
 
     match message_type {
-        DHCPDISCOVER => dhcp_discover_message_handler(transaction_id, dhcp_server, &packet, soc),
+        DHCPDISCOVER => dhcp_discover_message_handler(transaction_id, dhcp_server, packet, soc),
         DHCPREQUEST => match packet.get_option(Code::ServerIdentifier as u8) {
-            Some(server_id) => dhcp_request_message_handler_responded_to_offer(transaction_id, dhcp_server, &packet, client_macaddr, soc, server_id),
-            None => dhcp_request_message_handler_to_reallocate(transaction_id, dhcp_server, &packet, client_macaddr, soc),
+            Some(server_id) => util::dhcp_request_message_handler_responded_to_offer(transaction_id, dhcp_server, packet, client_macaddr, soc, server_id),
+            None => dhcp_request_message_handler_to_reallocate(transaction_id, dhcp_server, packet, client_macaddr, soc),
         },
         DHCPRELEASE => {
-            dhcp_release_message_handler(transaction_id, dhcp_server, &packet, client_macaddr)
+            dhcp_release_message_handler(transaction_id, dhcp_server, packet, client_macaddr)
         }
         _ => {
             let msg = format!("{:x}: received unimplemented message, message_type:{}", transaction_id, message_type);
@@ -112,35 +249,49 @@ fn dhcp_handler(packet: &DhcpPacket, soc: &UdpSocket, dhcp_server: Arc<DhcpServe
     }
 }
 
-fn dhcp_discover_message_handler(xid: u32, dhcp_server: Arc<DhcpServer>, received_packet: &DhcpPacket, soc: &UdpSocket) -> Result<(), failure::Error> {
+fn dhcp_discover_message_handler(xid: u32, dhcp_server: Arc<DhcpServer>, received_packet: &DhcpPacket, soc: &dyn DhcpSocket) -> Result<(), failure::Error> {
     info!("{:x}: received DHCPDISCOVER", xid);
-    let ip_to_be_leased = select_lease_ip(&dhcp_server, &received_packet)?;
-    let dhcp_packet = make_dhcp_packet(&received, &dhcp_server, DHCPOFFER, ip_to_be_leased)?;
+    let client_macaddr = received_packet.get_chaddr();
+
+    // 再送されたDISCOVERには同じアドレスをOFFERし、プールを消費しない
+    let ip_to_be_leased = match dhcp_server.pending_offer(client_macaddr) {
+        Some(ip) => ip,
+        None => {
+            if !dhcp_server.can_hold_offer() {
+                return Ok(());
+            }
+            let ip = select_lease_ip(&dhcp_server, received_packet)?;
+            dhcp_server.hold_offer(client_macaddr, ip);
+            ip
+        }
+    };
+    let dhcp_packet = make_dhcp_packet(received_packet, &dhcp_server, DHCPOFFER, ip_to_be_leased)?;
     util::send_dhcp_brodcast_response(soc, dhcp_packet.get_buffer())?;
     info!("{:x}: sent DHCPOFFER", xid);
     Ok(())
 }
 
 fn select_lease_ip(dhcp_server: &Arc<DhcpServer>, received_packet: &DhcpPacket) -> Result<Ipv4Addr, failure::Error> {
+    let config = dhcp_server.config();
     {
         let con = dhcp_server.db_connection.lock().unwrap();
         if let Some(ip_from_used) = database::select_entry(&con, received_packet.get_chaddr())? {
             // IPアドレスが重複していないか
             // .envに記載されたネットワークアドレスの変更があった時のために、現在のネットワークに含まれているかを合わせて確認する
-            if dhcp_server.network_addr.contains(ip_from_used) && util::is_ipaddr_available(ip_from_used).is_ok() {
+            if config.network_addr.contains(ip_from_used) && util::is_ipaddr_free(&config, ip_from_used) {
                 return Ok(ip_from_used);
             }
         }
     }
 
     // Request Ip Addrオプションがあり、利用可能ならばそのIPアドレスを返却
-    if let Some(ip_to_be_leased) = obtain_avaliable_ip_from_requested_option(dhcp_server, &received_packet) {
+    if let Some(ip_to_be_leased) = obtain_avaliable_ip_from_requested_option(dhcp_server, received_packet) {
         return Ok(ip_to_be_leased)
     }
 
     // アドレスプールからの取得
-    while let Some(ip_addr) = dhcp_server.pick_avaliable_ip() {
-        if util::is_ipaddr_avaliable(ip_addr).is_ok() {
+    while let Some(ip_addr) = dhcp_server.pick_available_ip() {
+        if util::is_ipaddr_free(&config, ip_addr) {
             return Ok(ip_addr);
         }
     }
@@ -148,57 +299,200 @@ fn select_lease_ip(dhcp_server: &Arc<DhcpServer>, received_packet: &DhcpPacket)
     Err(failure::err_msg("Cloud not obtain avaliable ip address."))
 }
 
-fn obtain_avaliable_ip_from_requested_option(dhcp_server &Arc<DhcpServer>, received_packet: &DhcpPacket) -> Option<Ipv4Addr> {
+fn obtain_avaliable_ip_from_requested_option(dhcp_server: &Arc<DhcpServer>, received_packet: &DhcpPacket) -> Option<Ipv4Addr> {
     let ip = received_packet.get_option(Code::RequestedIpAddress as u8)?;
 
-    let request_ip = util::u8_to_ipv$addr(&ip)?;
-    let ip_from_pool = dhcp_server.pick_avaliable_ip(request_ip)?;
+    let request_ip = util::u8_to_ipv4addr(&ip)?;
+    let ip_from_pool = dhcp_server.pick_specified_ip(request_ip)?;
 
-    if util::is_ipaddr_available(ip_from_pool).is_ok() {
-        return Some(requested_ip);
+    if util::is_ipaddr_free(&dhcp_server.config(), ip_from_pool) {
+        return Some(request_ip);
     }
     None
 }
 
-fn dhcp_request_message_handler_to_reallocate(xid: u32, dhcp_server: Arc<DhcpServer>, received_packet: &DhcpPacket, client_macaddr: MacAddr, soc: &UdpSocket) -> Result<(), failure::Error> {
+/** DHCPRELEASE: クライアントが貸し出したアドレスを返却した時
+ * ciaddrがそのクライアントのリースと一致する場合だけリースを論理削除し、アドレスをプールへ戻す
+ */
+fn dhcp_release_message_handler(xid: u32, dhcp_server: Arc<DhcpServer>, received_packet: &DhcpPacket, client_macaddr: MacAddr) -> Result<(), failure::Error> {
+    info!("{:x}: received DHCPRELEASE", xid);
+
+    let ip_to_be_released = received_packet.get_ciaddr();
+    {
+        let mut con = dhcp_server.db_connection.lock().unwrap();
+        match database::select_lease(&con, client_macaddr)? {
+            Some(ref lease) if !lease.deleted && lease.ip_addr == ip_to_be_released => {}
+            _ => {
+                info!("{:x}: {} is not leased to {}, ignored", xid, ip_to_be_released, client_macaddr);
+                return Ok(());
+            }
+        }
+        let tx = con.transaction()?;
+        database::delete_entry(&tx, client_macaddr)?;
+        tx.commit()?;
+    }
+
+    dhcp_server.release_address(ip_to_be_released);
+    info!("{:x}: released {} from {}", xid, ip_to_be_released, client_macaddr);
+    Ok(())
+}
+
+/** server_idを含まないDHCPREQUESTを送ってきたクライアントの状態(RFC2131 4.3.2) */
+#[derive(Debug, PartialEq)]
+enum ClientState {
+    InitReboot,
+    Renewing,
+    Rebinding,
+}
+
+/** server_idを含まないDHCPREQUESTから、クライアントの状態を判定する
+ * RENEWINGはユニキャスト、REBINDINGはブロードキャストで送られるが、0.0.0.0で待ち受けているため宛先は分からない。
+ * そこでリースの経過時間から判定し、T2(リース時間の7/8)を過ぎていればREBINDINGとみなす
+ */
+fn classify_client_state(received_packet: &DhcpPacket, lease: Option<&database::LeaseEntry>, config: &ServerConfig) -> Option<ClientState> {
+    if received_packet.get_option(Code::RequestedIpAddress as u8).is_some() {
+        return Some(ClientState::InitReboot);
+    }
+    if received_packet.get_ciaddr().is_unspecified() {
+        return None;
+    }
+    match lease {
+        Some(lease) if util::now_secs() < lease.expires_at - i64::from(config.lease_seconds / 8) => Some(ClientState::Renewing),
+        _ => Some(ClientState::Rebinding),
+    }
+}
+
+fn dhcp_request_message_handler_to_reallocate(xid: u32, dhcp_server: Arc<DhcpServer>, received_packet: &DhcpPacket, client_macaddr: MacAddr, soc: &dyn DhcpSocket) -> Result<(), failure::Error> {
     info!("{:x}: received DHCPREQUEST without server_id", xid);
 
-    if let Some(requested_ip) = received_packet.get_option(Code::RequestedIpAddress as u8) {
-        debug!("client is in INIT-REBOOT");
-        //クライアントが以前割り当てられたIPアドレスを記憶していて先起動状態にある時
-        let requested_ip = util::u8_to_ipv4addr(&requested_ip).ok_or_else(|| failure::err_msg("Failed to convert ip addr"))?;
+    let config = dhcp_server.config();
+    let lease = {
         let con = dhcp_server.db_connection.lock().unwrap();
-        match database::select_entry(&con, client_macaddr)? {
-            Some(ip) => {
-                if ip == requested_ip && dhcp_server.network_addr.contains(ip) {
-                    //以前割り当てたIPアドレスと要求されたIPアドレスが一致されており、ネットワークに含まれている時はACKを返す
-                    let dhcp_packet = make_dhcp_packet(&received_packet, &dhcp_server, DHCPACK, ip)?;
-                    util::send_dhcp_brodcast_response(soc, dhcp_packet.get_buffer())?;
-                    info!("{:x}: sent DHCPACK", xid);
-                    Ok(())
-                } else {
-                    //不適切なIPアドレスが要求されるとNAKを返す
-                    let dhcp_packet = make_dhcp_packet(&received_packet, &dhcp_server, DHCPNAK, "0.0.0.0".parse()?)?;
-                    util::send_dhcp_brodcast_response(soc, dhcp_packet.get_buffer())?;
-                    info!("{:x}: sent DHCPACK", xid);
-                    Ok(())
-                }
-            }
-            None => {
-                Ok(())
-            }
+        database::select_lease(&con, client_macaddr)?
+    };
+
+    let state = classify_client_state(received_packet, lease.as_ref(), &config)
+        .ok_or_else(|| failure::err_msg("Invalid DHCPREQUEST. Neither requested ip addr nor ciaddr was specified."))?;
+    debug!("{:x}: client is in {:?}", xid, state);
+
+    match state {
+        ClientState::InitReboot => dhcp_request_message_handler_in_init_reboot(xid, &dhcp_server, received_packet, lease, &config, soc),
+        ClientState::Renewing | ClientState::Rebinding => dhcp_request_message_handler_to_extend(xid, &dhcp_server, received_packet, client_macaddr, lease, state, &config, soc),
+    }
+}
+
+/** INIT-REBOOT: クライアントが以前割り当てられたIPアドレスを記憶していて再起動した時 */
+fn dhcp_request_message_handler_in_init_reboot(
+    xid: u32,
+    dhcp_server: &Arc<DhcpServer>,
+    received_packet: &DhcpPacket,
+    lease: Option<database::LeaseEntry>,
+    config: &ServerConfig,
+    soc: &dyn DhcpSocket,
+) -> Result<(), failure::Error> {
+    let requested_ip = received_packet.get_option(Code::RequestedIpAddress as u8).unwrap();
+    let requested_ip = util::u8_to_ipv4addr(&requested_ip).ok_or_else(|| failure::err_msg("Failed to convert ip addr"))?;
+
+    let lease = match lease {
+        Some(lease) => lease,
+        None => {
+            // リースの記録がないクライアントには応答してはならない
+            info!("{:x}: no record of the client, remain silent", xid);
+            return Ok(());
         }
-    } else {
-        debug!("client is in RENEWING or REBINDING");
-        //リース延長要求、リース切れによる再要求
-        //本来はこれらの状態で処理を分けるべきだが、簡略化のため同じ処理をする
-        let ip_from_client = received_packet.get_ciaddr();
-        if !dhcp_server.network_addr.contains(ip_from_client) {
-            return Err(failure::err_msg("Invalid ciaddr. Mismatched network address."));
+    };
+
+    if !config.network_addr.contains(requested_ip) {
+        // 別のネットワークに移動してきたクライアント
+        return send_nak(xid, dhcp_server, received_packet, soc, "requested ip addr is not on this network");
+    }
+    if lease.deleted || lease.ip_addr != requested_ip {
+        return send_nak(xid, dhcp_server, received_packet, soc, "requested ip addr does not match the lease");
+    }
+    if lease.is_expired(util::now_secs()) {
+        return send_nak(xid, dhcp_server, received_packet, soc, "lease has expired");
+    }
+
+    lease_and_send_ack(xid, dhcp_server, received_packet, requested_ip, soc, false)
+}
+
+/** RENEWING / REBINDING: リース延長要求、リース切れによる再要求
+ * RENEWINGはこのサーバーに宛てたものなので不適切なら必ずNAKを返す。
+ * REBINDINGは他のサーバーが割り当てたリースかもしれないため、記録がなければ応答しない
+ */
+#[allow(clippy::too_many_arguments)]
+fn dhcp_request_message_handler_to_extend(
+    xid: u32,
+    dhcp_server: &Arc<DhcpServer>,
+    received_packet: &DhcpPacket,
+    client_macaddr: MacAddr,
+    lease: Option<database::LeaseEntry>,
+    state: ClientState,
+    config: &ServerConfig,
+    soc: &dyn DhcpSocket,
+) -> Result<(), failure::Error> {
+    let ip_from_client = received_packet.get_ciaddr();
+    if !config.network_addr.contains(ip_from_client) {
+        return send_nak(xid, dhcp_server, received_packet, soc, "ciaddr is not on this network");
+    }
+
+    let owner = {
+        let con = dhcp_server.db_connection.lock().unwrap();
+        database::select_mac_addr_by_ip(&con, ip_from_client)?
+    };
+    if let Some(owner) = owner {
+        if owner != client_macaddr {
+            warn!("{:x}: {} is leased to {}, not {}", xid, ip_from_client, owner, client_macaddr);
+            return send_nak(xid, dhcp_server, received_packet, soc, "ciaddr is leased to another client");
+        }
+    }
+
+    let lease = match lease {
+        Some(lease) if !lease.deleted && lease.ip_addr == ip_from_client => lease,
+        _ if state == ClientState::Rebinding => {
+            info!("{:x}: no record of the lease, remain silent", xid);
+            return Ok(());
+        }
+        _ => return send_nak(xid, dhcp_server, received_packet, soc, "no lease for the client"),
+    };
+
+    if lease.is_expired(util::now_secs()) {
+        return send_nak(xid, dhcp_server, received_packet, soc, "lease has expired");
+    }
+
+    lease_and_send_ack(xid, dhcp_server, received_packet, ip_from_client, soc, true)
+}
+
+/** リースを記録(延長)してDHCPACKを返す
+ * ciaddrが設定されているクライアントにはユニキャストで返す
+ */
+fn lease_and_send_ack(xid: u32, dhcp_server: &Arc<DhcpServer>, received_packet: &DhcpPacket, ip_to_be_leased: Ipv4Addr, soc: &dyn DhcpSocket, unicast: bool) -> Result<(), failure::Error> {
+    let client_macaddr = received_packet.get_chaddr();
+    let expires_at = util::now_secs() + i64::from(dhcp_server.config().lease_seconds);
+    {
+        let mut con = dhcp_server.db_connection.lock().unwrap();
+        let tx = con.transaction()?;
+        match database::counbt_records_by_mac_addr(&tx, client_macaddr)? {
+            0 => database::insert_entry(&tx, client_macaddr, ip_to_be_leased, expires_at)?,
+            _ => database::update_entry(&tx, client_macaddr, ip_to_be_leased, 0, expires_at)?,
         }
-        let dhcp_packet = make_dhcp_packet(&received_packet, &dhcp_server, DHCPACK, received_packet.get_ciaddr())?;
+        tx.commit()?;
+    }
+
+    let dhcp_packet = make_dhcp_packet(received_packet, dhcp_server, DHCPACK, ip_to_be_leased)?;
+    if unicast {
+        util::send_dhcp_unicast_response(soc, dhcp_packet.get_buffer(), received_packet.get_ciaddr())?;
+    } else {
         util::send_dhcp_brodcast_response(soc, dhcp_packet.get_buffer())?;
-        info!("{:x}: sent DHCPACK", xid);
-        Ok(())
     }
+    info!("{:x}: sent DHCPACK, leased {} until {}", xid, ip_to_be_leased, expires_at);
+    Ok(())
+}
+
+/** DHCPNAKを返す */
+fn send_nak(xid: u32, dhcp_server: &Arc<DhcpServer>, received_packet: &DhcpPacket, soc: &dyn DhcpSocket, reason: &str) -> Result<(), failure::Error> {
+    let dhcp_packet = make_dhcp_packet(received_packet, dhcp_server, DHCPNAK, Ipv4Addr::UNSPECIFIED)?;
+    util::send_dhcp_brodcast_response(soc, dhcp_packet.get_buffer())?;
+    info!("{:x}: sent DHCPNAK, {}", xid, reason);
+    Ok(())
 }
@@ -0,0 +1,50 @@
+const OPTION_PAD: u8 = 0;
+const OPTION_END: u8 = 255;
+
+/** オプション部を先頭から読む。PADは飛ばし、ENDか、長さが残りのバイト数を超えるオプションで止まる
+ * packet-captureのDHCPデコーダもこのファイルを取り込んで同じ規則で読む
+ */
+pub struct DhcpOptions<'a> {
+    options: &'a [u8],
+    index: usize,
+    truncated: bool,
+}
+
+impl<'a> DhcpOptions<'a> {
+    /** マジッククッキーより後のオプション部を渡す */
+    pub fn new(options: &'a [u8]) -> DhcpOptions<'a> {
+        DhcpOptions { options, index: 0, truncated: false }
+    }
+
+    /** 長さが残りのバイト数を超えるオプションで止まったか */
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl<'a> Iterator for DhcpOptions<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        while let Some(&code) = self.options.get(self.index) {
+            match code {
+                OPTION_PAD => {
+                    self.index += 1;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => {}
+            }
+            let start = self.index + 2;
+            let value = self.options.get(self.index + 1).and_then(|len| self.options.get(start..start + *len as usize));
+            if let Some(value) = value {
+                self.index = start + value.len();
+                return Some((code, value));
+            }
+            self.truncated = true;
+            break;
+        }
+        self.index = self.options.len();
+        None
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/** キーごとのトークンバケット */
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/** MACアドレスや送信元アドレスごとにメッセージの受け付け頻度を制限する */
+pub struct RateLimiter<K> {
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new() -> RateLimiter<K> {
+        RateLimiter {
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /** 1メッセージ分のトークンを消費できればtrueを返す
+     * rateは1秒あたりに補充されるトークン数、バーストはその2倍まで許す。rateが0なら制限しない
+     */
+    pub fn allow(&self, key: K, rate: u32) -> bool {
+        if rate == 0 {
+            return true;
+        }
+        let rate = f64::from(rate);
+        let burst = rate * 2.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /** 一定時間使われていないバケットを捨てる
+     * ランダムなMACアドレスを大量に送られてもマップが際限なく大きくならないようにする
+     */
+    pub fn prune(&self, idle: Duration) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, bucket| now.duration_since(bucket.last_refill) < idle);
+    }
+}
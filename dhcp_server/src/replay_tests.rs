@@ -0,0 +1,523 @@
+//! 記録したDHCPのやり取り(pcap)をdhcp_handlerに流し込み、生成された応答を検証する
+//! testdata/pcap以下のキャプチャを順に再生する。サーバーの設定はキャプチャ内のサーバーの応答から組み立てる
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use rusqlite::Connection;
+
+use super::*;
+use crate::dhcp::{DhcpPacket, DhcpServer, ServerConfig};
+use crate::util::DhcpSocket;
+
+const PCAP_DIR: &str = "testdata/pcap";
+
+/** 送信されたデータグラムを記録するだけのソケット */
+struct MemorySocket {
+    sent: Mutex<Vec<(SocketAddr, Vec<u8>)>>,
+}
+
+impl MemorySocket {
+    fn new() -> MemorySocket {
+        MemorySocket { sent: Mutex::new(Vec::new()) }
+    }
+
+    fn take(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.sent.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl DhcpSocket for MemorySocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.sent.lock().unwrap().push((addr, buf.to_vec()));
+        Ok(buf.len())
+    }
+}
+
+/** pcap(pcapngは未対応)からEthernetフレームを取り出す */
+fn read_pcap(data: &[u8]) -> Vec<Vec<u8>> {
+    assert!(data.len() >= 24, "too short for a pcap global header");
+    let little_endian = match &data[..4] {
+        [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => true,
+        [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => false,
+        magic => panic!("not a pcap file, magic: {:?}", magic),
+    };
+    let read_u32 = |buf: &[u8]| if little_endian { LittleEndian::read_u32(buf) } else { BigEndian::read_u32(buf) };
+    assert_eq!(read_u32(&data[20..24]), 1, "only Ethernet captures are supported");
+
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset + 16 <= data.len() {
+        let caplen = read_u32(&data[offset + 8..offset + 12]) as usize;
+        offset += 16;
+        if offset + caplen > data.len() {
+            break;
+        }
+        frames.push(data[offset..offset + caplen].to_vec());
+        offset += caplen;
+    }
+    frames
+}
+
+/** EthernetフレームからUDPの宛先アドレス、送信元ポート、宛先ポート、ペイロードを取り出す */
+fn udp_payload(frame: &[u8]) -> Option<(Ipv4Addr, u16, u16, Vec<u8>)> {
+    let ethernet = EthernetPacket::new(frame)?;
+    if ethernet.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    let ipv4 = Ipv4Packet::new(ethernet.payload())?;
+    if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    let udp = UdpPacket::new(ipv4.payload())?;
+    Some((ipv4.get_destination(), udp.get_source(), udp.get_destination(), udp.payload().to_vec()))
+}
+
+fn message_type(packet: &DhcpPacket) -> Option<u8> {
+    packet.get_option(Code::MessageType as u8).map(|v| v[0])
+}
+
+fn test_env(server_address: &str, network_addr: &str, subnet_mask: &str, default_gateway: &str, dns_server: &str, lease_time: u32) -> HashMap<String, String> {
+    let mut env = HashMap::new();
+    env.insert("SERVER_IDENTIFIER".to_string(), server_address.to_string());
+    env.insert("NETWORK_ADDR".to_string(), network_addr.to_string());
+    env.insert("SUBNET_MASK".to_string(), subnet_mask.to_string());
+    env.insert("DEFAULT_GATEWAY".to_string(), default_gateway.to_string());
+    env.insert("DNS_SERVER".to_string(), dns_server.to_string());
+    env.insert("LEASE_TIME".to_string(), lease_time.to_string());
+    // テストではICMPによる重複確認と頻度制限を行わない
+    env.insert("PING_CHECK".to_string(), "false".to_string());
+    env.insert("RATE_LIMIT_PER_MAC".to_string(), "0".to_string());
+    env.insert("RATE_LIMIT_PER_SOURCE".to_string(), "0".to_string());
+    env
+}
+
+fn test_server(env: &HashMap<String, String>) -> Arc<DhcpServer> {
+    let config = ServerConfig::from_env(env).unwrap();
+    Arc::new(DhcpServer::with_connection(config, Connection::open_in_memory().unwrap()).unwrap())
+}
+
+/** キャプチャに記録されたサーバーの最初のOFFER/ACKから、同じ設定の環境を組み立てる */
+fn env_from_capture(replies: &[(Ipv4Addr, DhcpPacket)]) -> Option<HashMap<String, String>> {
+    let reply = replies.iter().map(|(_, reply)| reply).find(|p| p.get_option(Code::ServerIdentifier as u8).is_some() && p.get_option(Code::SubnetMask as u8).is_some())?;
+    let ip = |code: Code| reply.get_option(code as u8).and_then(|v| util::u8_to_ipv4addr(&v[..4]));
+
+    let subnet_mask = ip(Code::SubnetMask)?;
+    let yiaddr = reply.get_yiaddr();
+    let network_addr = Ipv4Addr::from(u32::from(yiaddr) & u32::from(subnet_mask));
+    let server_address = ip(Code::ServerIdentifier)?;
+    let lease_time = reply.get_option(Code::IPAddressLeaseTime as u8).map(|v| BigEndian::read_u32(&v)).unwrap_or(3600);
+
+    Some(test_env(
+        &server_address.to_string(),
+        &network_addr.to_string(),
+        &subnet_mask.to_string(),
+        &ip(Code::Router).unwrap_or(server_address).to_string(),
+        &ip(Code::DNS).unwrap_or(server_address).to_string(),
+        lease_time,
+    ))
+}
+
+/** DISCOVERにはOFFER、REQUESTにはACKかNAKが対応する。同じxidのDISCOVERとREQUESTを区別するのに使う */
+fn is_reply_to(request: &DhcpPacket, reply: &DhcpPacket) -> bool {
+    matches!(
+        (message_type(request), message_type(reply)),
+        (Some(DHCPDISCOVER), Some(DHCPOFFER)) | (Some(DHCPREQUEST), Some(DHCPACK)) | (Some(DHCPREQUEST), Some(DHCPNAK))
+    )
+}
+
+/** 応答に共通して成り立つべき性質を確認する */
+fn assert_well_formed_reply(request: &DhcpPacket, reply: &DhcpPacket, config: &ServerConfig) {
+    assert_eq!(reply.get_op(), BOOTREPLY);
+    assert_eq!(reply.get_xid(), request.get_xid());
+    assert_eq!(reply.get_chaddr(), request.get_chaddr());
+    assert_eq!(reply.get_option(Code::ServerIdentifier as u8), Some(config.server_address.octets().to_vec()));
+
+    match message_type(reply) {
+        Some(DHCPOFFER) | Some(DHCPACK) => {
+            assert!(config.network_addr.contains(reply.get_yiaddr()), "yiaddr {} is out of {}", reply.get_yiaddr(), config.network_addr);
+            let lease = BigEndian::read_u32(&reply.get_option(Code::IPAddressLeaseTime as u8).unwrap());
+            let t1 = BigEndian::read_u32(&reply.get_option(Code::RenewalTime as u8).unwrap());
+            let t2 = BigEndian::read_u32(&reply.get_option(Code::RebindingTime as u8).unwrap());
+            assert!(t1 < t2 && t2 < lease, "T1 {} < T2 {} < lease {}", t1, t2, lease);
+            assert!(reply.get_option(Code::SubnetMask as u8).is_some());
+        }
+        Some(DHCPNAK) => {
+            assert_eq!(reply.get_yiaddr(), Ipv4Addr::UNSPECIFIED);
+            assert!(reply.get_option(Code::IPAddressLeaseTime as u8).is_none());
+        }
+        other => panic!("unexpected reply message type {:?}", other),
+    }
+}
+
+/** 記録されたサーバーの応答と同じアドレス、フラグ、リース時間を、同じ宛先に返していることを確認する */
+fn assert_same_as_recorded(path: &Path, request: &DhcpPacket, reply: &DhcpPacket, destination: SocketAddr, recorded: &DhcpPacket, recorded_destination: Ipv4Addr) {
+    let context = format!("{}: xid {:x?}", path.display(), request.get_xid());
+    assert_eq!(message_type(reply), message_type(recorded), "{}", context);
+    assert_eq!(reply.get_yiaddr(), recorded.get_yiaddr(), "{}: yiaddr", context);
+    assert_eq!(reply.get_ciaddr(), recorded.get_ciaddr(), "{}: ciaddr", context);
+    assert_eq!(reply.get_flags(), recorded.get_flags(), "{}: flags", context);
+    assert_eq!(reply.get_flags(), request.get_flags(), "{}: flags", context);
+    assert_eq!(reply.get_giaddr(), request.get_giaddr(), "{}: giaddr", context);
+    for code in [Code::ServerIdentifier, Code::IPAddressLeaseTime, Code::RenewalTime, Code::RebindingTime, Code::SubnetMask, Code::Router, Code::DNS] {
+        assert_eq!(reply.get_option(code as u8), recorded.get_option(code as u8), "{}: option {}", context, code as u8);
+    }
+    assert_eq!(destination, SocketAddr::new(IpAddr::V4(recorded_destination), 68), "{}: destination", context);
+}
+
+/** キャプチャを再生し、記録されたサーバーと同じ応答を返すことを確認する */
+fn replay(path: &Path) {
+    let frames = read_pcap(&fs::read(path).unwrap());
+    let mut requests = Vec::new();
+    let mut recorded_replies = Vec::new();
+    for (destination, src_port, dst_port, payload) in frames.iter().filter_map(|frame| udp_payload(frame)) {
+        match (src_port, dst_port) {
+            (68, 67) => requests.extend(DhcpPacket::new(payload)),
+            (67, 68) => recorded_replies.extend(DhcpPacket::new(payload).map(|reply| (destination, reply))),
+            _ => {}
+        }
+    }
+
+    let env = env_from_capture(&recorded_replies).unwrap_or_else(|| panic!("{}: no server reply to derive the configuration from", path.display()));
+    let dhcp_server = test_server(&env);
+    let config = dhcp_server.config();
+    let socket = MemorySocket::new();
+
+    for request in requests.iter() {
+        if request.get_op() != BOOTREQUEST {
+            continue;
+        }
+        let result = dhcp_handler(request, &socket, dhcp_server.clone());
+
+        let replies = socket.take();
+        assert!(replies.len() <= 1, "{}: more than one reply to a request", path.display());
+        let recorded = recorded_replies.iter().find(|(_, r)| r.get_xid() == request.get_xid() && is_reply_to(request, r));
+        if let Err(e) = result {
+            // 記録されたサーバーも応答していないメッセージ(DHCPINFORMなど)はエラーでもよい
+            assert!(recorded.is_none(), "{}: {}", path.display(), e);
+            continue;
+        }
+
+        match (replies.first(), recorded) {
+            (Some((destination, buf)), Some((recorded_destination, recorded))) => {
+                let reply = DhcpPacket::new(buf.clone()).unwrap();
+                assert_well_formed_reply(request, &reply, &config);
+                assert_same_as_recorded(path, request, &reply, *destination, recorded, *recorded_destination);
+            }
+            (Some((_, buf)), None) => {
+                // DISCOVERへのOFFERは記録されたサーバーが他に選ばれた場合にも送る
+                let reply = DhcpPacket::new(buf.clone()).unwrap();
+                assert_well_formed_reply(request, &reply, &config);
+            }
+            (None, Some((_, recorded))) => {
+                panic!("{}: no reply, recorded server sent message type {:?}", path.display(), message_type(recorded));
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+/** クライアントのメッセージを組み立てる */
+fn client_packet(message_type: u8, xid: u32, chaddr: MacAddr, ciaddr: Ipv4Addr, options: &[(Code, &[u8])]) -> DhcpPacket {
+    let mut buffer = vec![0u8; 548];
+    buffer[0] = BOOTREQUEST;
+    buffer[1] = 1;
+    buffer[2] = 6;
+    BigEndian::write_u32(&mut buffer[4..8], xid);
+    buffer[12..16].copy_from_slice(&ciaddr.octets());
+    let MacAddr(a, b, c, d, e, f) = chaddr;
+    buffer[28..34].copy_from_slice(&[a, b, c, d, e, f]);
+    buffer[dhcp::OPTIONS..dhcp::OPTIONS + 4].copy_from_slice(&[99, 130, 83, 99]);
+
+    let mut cursor = dhcp::OPTIONS + 4;
+    let mut put = |code: u8, value: &[u8]| {
+        buffer[cursor] = code;
+        buffer[cursor + 1] = value.len() as u8;
+        buffer[cursor + 2..cursor + 2 + value.len()].copy_from_slice(value);
+        cursor += 2 + value.len();
+    };
+    put(Code::MessageType as u8, &[message_type]);
+    for (code, value) in options {
+        put(*code as u8, value);
+    }
+    buffer[cursor] = Code::End as u8;
+    DhcpPacket::new(buffer).unwrap()
+}
+
+fn only_reply(socket: &MemorySocket) -> (SocketAddr, DhcpPacket) {
+    let mut replies = socket.take();
+    assert_eq!(replies.len(), 1);
+    let (addr, buf) = replies.remove(0);
+    (addr, DhcpPacket::new(buf).unwrap())
+}
+
+fn lab_env() -> HashMap<String, String> {
+    test_env("192.168.0.1", "192.168.0.0", "255.255.255.0", "192.168.0.1", "192.168.0.1", 3600)
+}
+
+#[test]
+fn replay_recorded_captures() {
+    let dir = fs::read_dir(PCAP_DIR).unwrap_or_else(|e| panic!("{}: {}", PCAP_DIR, e));
+    let mut replayed = 0;
+    for entry in dir {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "pcap") {
+            replay(&path);
+            replayed += 1;
+        }
+    }
+    // キャプチャが足りなければ何も検証していないので失敗させる
+    assert!(replayed >= 5, "only {} captures found in {}", replayed, PCAP_DIR);
+}
+
+#[test]
+fn discover_then_request_is_acked() {
+    let dhcp_server = test_server(&lab_env());
+    let config = dhcp_server.config();
+    let socket = MemorySocket::new();
+    let mac = MacAddr::new(0x02, 0, 0, 0, 0, 1);
+
+    let discover = client_packet(DHCPDISCOVER, 0x1234, mac, Ipv4Addr::UNSPECIFIED, &[]);
+    dhcp_handler(&discover, &socket, dhcp_server.clone()).unwrap();
+    let (addr, offer) = only_reply(&socket);
+    assert_eq!(addr, "255.255.255.255:68".parse().unwrap());
+    assert_eq!(message_type(&offer), Some(DHCPOFFER));
+    assert_well_formed_reply(&discover, &offer, &config);
+
+    // 再送されたDISCOVERには同じアドレスをOFFERする
+    dhcp_handler(&discover, &socket, dhcp_server.clone()).unwrap();
+    assert_eq!(only_reply(&socket).1.get_yiaddr(), offer.get_yiaddr());
+
+    let request = client_packet(
+        DHCPREQUEST,
+        0x1234,
+        mac,
+        Ipv4Addr::UNSPECIFIED,
+        &[(Code::ServerIdentifier, &config.server_address.octets()), (Code::RequestedIpAddress, &offer.get_yiaddr().octets())],
+    );
+    dhcp_handler(&request, &socket, dhcp_server.clone()).unwrap();
+    let (_, ack) = only_reply(&socket);
+    assert_eq!(message_type(&ack), Some(DHCPACK));
+    assert_eq!(ack.get_yiaddr(), offer.get_yiaddr());
+    assert_well_formed_reply(&request, &ack, &config);
+}
+
+#[test]
+fn request_for_address_not_offered_is_naked() {
+    let dhcp_server = test_server(&lab_env());
+    let config = dhcp_server.config();
+    let socket = MemorySocket::new();
+    let mac = MacAddr::new(0x02, 0, 0, 0, 0, 7);
+    let pool_size = dhcp_server.pool_size();
+
+    let discover = client_packet(DHCPDISCOVER, 8, mac, Ipv4Addr::UNSPECIFIED, &[]);
+    dhcp_handler(&discover, &socket, dhcp_server.clone()).unwrap();
+    assert_eq!(message_type(&only_reply(&socket).1), Some(DHCPOFFER));
+    assert_eq!(dhcp_server.pool_size(), pool_size - 1);
+
+    // OFFERしたものと異なるアドレスや予約されたアドレスを要求されたらNAKを返し、OFFERしたアドレスはプールへ戻す
+    for requested in [Ipv4Addr::new(192, 168, 0, 200), config.server_address] {
+        let request = client_packet(
+            DHCPREQUEST,
+            8,
+            mac,
+            Ipv4Addr::UNSPECIFIED,
+            &[(Code::ServerIdentifier, &config.server_address.octets()), (Code::RequestedIpAddress, &requested.octets())],
+        );
+        dhcp_handler(&request, &socket, dhcp_server.clone()).unwrap();
+        assert_eq!(message_type(&only_reply(&socket).1), Some(DHCPNAK));
+        assert_eq!(dhcp_server.pool_size(), pool_size);
+    }
+
+    // 改めてOFFERされたアドレスを要求すればACKし、そのアドレスはプールから外れたままになる
+    dhcp_handler(&discover, &socket, dhcp_server.clone()).unwrap();
+    let offered = only_reply(&socket).1.get_yiaddr();
+    let request = client_packet(
+        DHCPREQUEST,
+        8,
+        mac,
+        Ipv4Addr::UNSPECIFIED,
+        &[(Code::ServerIdentifier, &config.server_address.octets()), (Code::RequestedIpAddress, &offered.octets())],
+    );
+    dhcp_handler(&request, &socket, dhcp_server.clone()).unwrap();
+    assert_eq!(message_type(&only_reply(&socket).1), Some(DHCPACK));
+    assert_eq!(dhcp_server.pool_size(), pool_size - 1);
+}
+
+#[test]
+fn request_for_another_server_is_ignored() {
+    let dhcp_server = test_server(&lab_env());
+    let socket = MemorySocket::new();
+    let mac = MacAddr::new(0x02, 0, 0, 0, 0, 2);
+
+    let request = client_packet(
+        DHCPREQUEST,
+        1,
+        mac,
+        Ipv4Addr::UNSPECIFIED,
+        &[(Code::ServerIdentifier, &[192, 168, 0, 254]), (Code::RequestedIpAddress, &[192, 168, 0, 10])],
+    );
+    dhcp_handler(&request, &socket, dhcp_server).unwrap();
+    assert!(socket.take().is_empty());
+}
+
+#[test]
+fn init_reboot_without_lease_is_silent() {
+    let dhcp_server = test_server(&lab_env());
+    let socket = MemorySocket::new();
+    let mac = MacAddr::new(0x02, 0, 0, 0, 0, 3);
+
+    let request = client_packet(DHCPREQUEST, 2, mac, Ipv4Addr::UNSPECIFIED, &[(Code::RequestedIpAddress, &[192, 168, 0, 10])]);
+    dhcp_handler(&request, &socket, dhcp_server).unwrap();
+    assert!(socket.take().is_empty());
+}
+
+#[test]
+fn renewing_from_other_network_is_naked() {
+    let dhcp_server = test_server(&lab_env());
+    let config = dhcp_server.config();
+    let socket = MemorySocket::new();
+    let mac = MacAddr::new(0x02, 0, 0, 0, 0, 4);
+
+    let request = client_packet(DHCPREQUEST, 3, mac, "10.0.0.5".parse().unwrap(), &[]);
+    dhcp_handler(&request, &socket, dhcp_server).unwrap();
+    let (_, nak) = only_reply(&socket);
+    assert_eq!(message_type(&nak), Some(DHCPNAK));
+    assert_well_formed_reply(&request, &nak, &config);
+}
+
+#[test]
+fn renewing_lease_is_acked_by_unicast() {
+    let dhcp_server = test_server(&lab_env());
+    let config = dhcp_server.config();
+    let socket = MemorySocket::new();
+    let mac = MacAddr::new(0x02, 0, 0, 0, 0, 5);
+
+    let discover = client_packet(DHCPDISCOVER, 4, mac, Ipv4Addr::UNSPECIFIED, &[]);
+    dhcp_handler(&discover, &socket, dhcp_server.clone()).unwrap();
+    let leased = only_reply(&socket).1.get_yiaddr();
+    let request = client_packet(
+        DHCPREQUEST,
+        4,
+        mac,
+        Ipv4Addr::UNSPECIFIED,
+        &[(Code::ServerIdentifier, &config.server_address.octets()), (Code::RequestedIpAddress, &leased.octets())],
+    );
+    dhcp_handler(&request, &socket, dhcp_server.clone()).unwrap();
+    socket.take();
+
+    let renew = client_packet(DHCPREQUEST, 5, mac, leased, &[]);
+    dhcp_handler(&renew, &socket, dhcp_server.clone()).unwrap();
+    let (addr, ack) = only_reply(&socket);
+    assert_eq!(message_type(&ack), Some(DHCPACK));
+    assert_eq!(addr, SocketAddr::new(leased.into(), 68));
+
+    // 他のクライアントが同じアドレスの延長を求めてもNAKを返す
+    let other = client_packet(DHCPREQUEST, 6, MacAddr::new(0x02, 0, 0, 0, 0, 6), leased, &[]);
+    dhcp_handler(&other, &socket, dhcp_server).unwrap();
+    assert_eq!(message_type(&only_reply(&socket).1), Some(DHCPNAK));
+}
+
+/** expires_atのカラムがない古いデータベースに、192.168.0.20を貸し出したリースを1件記録して起動する */
+fn server_upgraded_from_legacy_database(mac: MacAddr) -> Arc<DhcpServer> {
+    let con = Connection::open_in_memory().unwrap();
+    con.execute("CREATE TABLE lease_entries (mac_addr TEXT PRIMARY KEY, ip_addr TEXT NOT NULL, deleted INTEGER NOT NULL DEFAULT 0)", rusqlite::NO_PARAMS).unwrap();
+    con.execute("INSERT INTO lease_entries (mac_addr, ip_addr) VALUES (?1, '192.168.0.20')", &[mac.to_string()]).unwrap();
+    let config = ServerConfig::from_env(&lab_env()).unwrap();
+    Arc::new(DhcpServer::with_connection(config, con).unwrap())
+}
+
+#[test]
+fn leases_from_before_the_upgrade_are_renewed() {
+    let mac = MacAddr::new(0x02, 0, 0, 0, 0, 7);
+    let leased: Ipv4Addr = "192.168.0.20".parse().unwrap();
+    let dhcp_server = server_upgraded_from_legacy_database(mac);
+    let socket = MemorySocket::new();
+
+    let renew = client_packet(DHCPREQUEST, 7, mac, leased, &[]);
+    dhcp_handler(&renew, &socket, dhcp_server.clone()).unwrap();
+    assert_eq!(message_type(&only_reply(&socket).1), Some(DHCPACK));
+
+    let reboot = client_packet(DHCPREQUEST, 8, mac, Ipv4Addr::UNSPECIFIED, &[(Code::RequestedIpAddress, &leased.octets())]);
+    dhcp_handler(&reboot, &socket, dhcp_server).unwrap();
+    assert_eq!(message_type(&only_reply(&socket).1), Some(DHCPACK));
+}
+
+#[test]
+fn expired_lease_is_naked_in_every_state() {
+    let mac = MacAddr::new(0x02, 0, 0, 0, 0, 8);
+    let leased: Ipv4Addr = "192.168.0.20".parse().unwrap();
+    let dhcp_server = server_upgraded_from_legacy_database(mac);
+    let socket = MemorySocket::new();
+    dhcp_server
+        .db_connection
+        .lock()
+        .unwrap()
+        .execute("UPDATE lease_entries SET expires_at = ?1", [util::now_secs() - 1])
+        .unwrap();
+
+    // 期限切れのリースはREBINDINGに分類される
+    let rebind = client_packet(DHCPREQUEST, 9, mac, leased, &[]);
+    dhcp_handler(&rebind, &socket, dhcp_server.clone()).unwrap();
+    assert_eq!(message_type(&only_reply(&socket).1), Some(DHCPNAK));
+
+    let reboot = client_packet(DHCPREQUEST, 10, mac, Ipv4Addr::UNSPECIFIED, &[(Code::RequestedIpAddress, &leased.octets())]);
+    dhcp_handler(&reboot, &socket, dhcp_server).unwrap();
+    assert_eq!(message_type(&only_reply(&socket).1), Some(DHCPNAK));
+}
+
+#[test]
+fn unassigned_source_is_limited_by_mac_addr_only() {
+    let mut env = lab_env();
+    env.insert("RATE_LIMIT_PER_SOURCE".to_string(), "1".to_string());
+    let dhcp_server = test_server(&env);
+
+    // アドレスを持たないクライアントは0.0.0.0から送るので、送信元では制限しない
+    for _ in 0..10 {
+        assert!(dhcp_server.allow_source("0.0.0.0".parse().unwrap()));
+        assert!(dhcp_server.allow_source("255.255.255.255".parse().unwrap()));
+    }
+    let source = "192.168.0.10".parse().unwrap();
+    assert!((0..10).any(|_| !dhcp_server.allow_source(source)));
+}
+
+#[test]
+fn get_option_skips_preceding_options() {
+    let packet = client_packet(DHCPREQUEST, 7, MacAddr::zero(), Ipv4Addr::UNSPECIFIED, &[(Code::RequestedIpAddress, &[192, 168, 0, 20]), (Code::ServerIdentifier, &[192, 168, 0, 1])]);
+    assert_eq!(packet.get_option(Code::ServerIdentifier as u8), Some(vec![192, 168, 0, 1]));
+    assert_eq!(packet.get_option(Code::Router as u8), None);
+}
+
+#[test]
+fn malformed_options_are_rejected_without_reply() {
+    let dhcp_server = test_server(&lab_env());
+    let socket = MemorySocket::new();
+    let mac = MacAddr::new(0x02, 0, 0, 0, 0, 7);
+    let discover = client_packet(DHCPDISCOVER, 7, mac, Ipv4Addr::UNSPECIFIED, &[]);
+
+    let start = dhcp::OPTIONS + 4;
+    // 長さ0のメッセージタイプ
+    let mut empty = discover.get_buffer().to_vec();
+    empty[start..start + 3].copy_from_slice(&[53, 0, 255]);
+    // パディングの後、バッファの末尾で長さのバイトがないメッセージタイプ
+    let mut truncated = discover.get_buffer().to_vec();
+    truncated[start..].iter_mut().for_each(|b| *b = 0);
+    *truncated.last_mut().unwrap() = 53;
+
+    for buffer in [empty, truncated] {
+        let packet = DhcpPacket::new(buffer).unwrap();
+        assert!(dhcp_handler(&packet, &socket, dhcp_server.clone()).is_err());
+        assert!(socket.take().is_empty());
+    }
+}
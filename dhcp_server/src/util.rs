@@ -1,3 +1,26 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use log::{debug, info, warn};
+use pnet::packet::icmp::echo_request::{EchoRequestPacket, MutableEchoRequestPacket};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::Packet;
+use pnet::transport::{self, icmp_packet_iter, TransportChannelType, TransportProtocol::Ipv4};
+use pnet::util::{checksum, MacAddr};
+
+use crate::database;
+use crate::dhcp::{DhcpPacket, DhcpServer, ServerConfig};
+use crate::{make_dhcp_packet, send_nak, Code, DHCPACK};
+
 pub fn is_ipaddr_available(target_ip: Ipv4Addr) -> Result<(), failure::Error> {
     let icmp_buf = create_default_icmp_buffer();
 
@@ -13,15 +36,8 @@ pub fn is_ipaddr_available(target_ip: Ipv4Addr) -> Result<(), failure::Error> {
         let mut iter = icmp_packet_iter(&mut transport_receiver);
         let (packet, _) = iter.next().unwrap();
 
-        if packet.get_icmp_type() == IcmpTypes::EchoReply {
-            match sender.send(true) {
-                Err(_) => {
-                    info!("icmp timeout");
-                }
-                _ => {
-                    return;
-                }
-            }
+        if packet.get_icmp_type() == IcmpTypes::EchoReply && sender.send(true).is_err() {
+            info!("icmp timeout");
         }
     });
 
@@ -36,45 +52,108 @@ pub fn is_ipaddr_available(target_ip: Ipv4Addr) -> Result<(), failure::Error> {
     }
 }
 
-pub fn send_dhcp_brodcast_response(soc: &UdpSocket, data: &[u8]) -> Result<(), failure::Error> {
-    let destination: SocketAddr = "255.255.255.255.68".parse()?;
+/** 応答を送るソケット。テストではUdpSocketの代わりに送信内容を記録するものを使う */
+pub trait DhcpSocket: Send + Sync {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /** リクエストを受信したインターフェース名（分かる場合） */
+    fn ingress_interface(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl DhcpSocket for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+}
+
+/** 払い出そうとしているアドレスが他のホストに使われていないか確認する。設定で無効にできる */
+pub fn is_ipaddr_free(config: &ServerConfig, target_ip: Ipv4Addr) -> bool {
+    !config.ping_check || is_ipaddr_available(target_ip).is_ok()
+}
+
+pub fn send_dhcp_brodcast_response(soc: &dyn DhcpSocket, data: &[u8]) -> Result<(), failure::Error> {
+    let destination: SocketAddr = "255.255.255.255:68".parse()?;
+    soc.send_to(data, destination)?;
+    Ok(())
+}
+
+/** RENEWING, REBINDING状態のクライアントへ、ciaddr宛にユニキャストで応答する */
+pub fn send_dhcp_unicast_response(soc: &dyn DhcpSocket, data: &[u8], ciaddr: Ipv4Addr) -> Result<(), failure::Error> {
+    let destination = SocketAddr::new(IpAddr::V4(ciaddr), 68);
     soc.send_to(data, destination)?;
     Ok(())
 }
 
-fn dhcp_request_message_handler_responded_to_offer(xid: u32, dhcp_server: Arc<DhcpServer>, received_packet: &DhcpPacket, client_macaddr: MacAddr, soc: &UdpSocket, server_id: Vec<u8>) -> Result<(), failure::Error> {
+/** SELECTING: 自身が送ったDHCPOFFERに対するDHCPREQUEST */
+pub fn dhcp_request_message_handler_responded_to_offer(xid: u32, dhcp_server: Arc<DhcpServer>, received_packet: &DhcpPacket, client_macaddr: MacAddr, soc: &dyn DhcpSocket, server_id: Vec<u8>) -> Result<(), failure::Error> {
     info!("{:x}: received DHCPREQUEST with server_id", xid);
 
-    let server_ip = util::u8_to_ipv4addr(&server_id).ok_or_else(|| failure::err_msg("Failed to convert ip addr"))?;
+    let server_ip = u8_to_ipv4addr(&server_id).ok_or_else(|| failure::err_msg("Failed to convert ip addr"))?;
 
-    if server_ip != dhcp_server.server_address {
+    if server_ip != dhcp_server.config().server_address {
         info!("Client has chosen another dhco server.");
+        dhcp_server.cancel_offer(client_macaddr);
         return Ok(());
     }
 
     // DHCPOFFERメッセージに対する応答の場合、必ずrequest Ip addressに割り当て予定のIPアドレスが含まれる
-    let ip_bin = received_packet.get_option(Code::RequestedIpAddress as u8).unwrap();
-    let ip_to_be_leased = util::u8_to_ipv4addr(&ip_bin).ok_or_else(|| failure::err_msg("FAiled to convert ip addr"))?;
+    let ip_bin = received_packet.get_option(Code::RequestedIpAddress as u8).ok_or_else(|| failure::err_msg("Missing requested ip addr in SELECTING"))?;
+    let ip_to_be_leased = u8_to_ipv4addr(&ip_bin).ok_or_else(|| failure::err_msg("FAiled to convert ip addr"))?;
+
+    // このクライアントにOFFERして確保しているアドレス以外は割り当てない
+    if dhcp_server.pending_offer(client_macaddr) != Some(ip_to_be_leased) {
+        dhcp_server.cancel_offer(client_macaddr);
+        return send_nak(xid, &dhcp_server, received_packet, soc, "requested ip addr was not offered to the client");
+    }
+
+    // 要求されたアドレスがこのネットワークのもので、予約されておらず、他のクライアントに貸し出されていないこと
+    let config = dhcp_server.config();
+    if !config.network_addr.contains(ip_to_be_leased) {
+        dhcp_server.cancel_offer(client_macaddr);
+        return send_nak(xid, &dhcp_server, received_packet, soc, "requested ip addr is not on this network");
+    }
+    if config.reserved_addresses().contains(&ip_to_be_leased) {
+        dhcp_server.cancel_offer(client_macaddr);
+        return send_nak(xid, &dhcp_server, received_packet, soc, "requested ip addr is reserved");
+    }
+    let owner = {
+        let con = dhcp_server.db_connection.lock().unwrap();
+        database::select_mac_addr_by_ip(&con, ip_to_be_leased)?
+    };
+    if owner.is_some_and(|owner| owner != client_macaddr) {
+        dhcp_server.cancel_offer(client_macaddr);
+        return send_nak(xid, &dhcp_server, received_packet, soc, "requested ip addr is leased to another client");
+    }
+
+    let expires_at = now_secs() + i64::from(config.lease_seconds);
+    // 設定の読み込みはデータベースのロックより先に行う
+    let dhcp_packet = make_dhcp_packet(received_packet, &dhcp_server, DHCPACK, ip_to_be_leased)?;
 
     let mut con = dhcp_server.db_connection.lock().unwrap();
     let count = {
         //トランザクションのクリティカルセクションを短く保つためにブロックにする
         let tx = con.transaction()?;
-        let count {
+        let count = database::counbt_records_by_mac_addr(&tx, client_macaddr)?;
+        match count {
             //レコードがない場合はInsert
-            0 => database::insert_entry(&tx, client_macaddr, ip_to_be_leased)?,
-            _ => database::update_entry(&tx, client_macaddr, ip_to_be_leased, 0)?,
+            0 => database::insert_entry(&tx, client_macaddr, ip_to_be_leased, expires_at)?,
+            _ => database::update_entry(&tx, client_macaddr, ip_to_be_leased, 0, expires_at)?,
         }
 
-        let dhcp_packet = make_dhcp_packet(&received_packet, &dhcp_packet, DHCPACK, ip_to_be_leased)?;
-        util::send_dhcp_brodcast_response(soc, dhcp_packet.get_buffer())?;
+        send_dhcp_brodcast_response(soc, dhcp_packet.get_buffer())?;
         info!("{:x}: sent DHCPACK", xid);
 
         tx.commit()?;
         count
     };
 
-    debug!("{:x}: leased address: {}", xid, ip_to_be_leased);
+    drop(con);
+    // OFFERの後に設定の再読み込みなどでプールへ戻っていても、他のクライアントに払い出さないよう取り除く
+    dhcp_server.pick_specified_ip(ip_to_be_leased);
+    dhcp_server.confirm_offer(client_macaddr);
+    debug!("{:x}: leased address: {} until {}", xid, ip_to_be_leased, expires_at);
     match count {
         0 => debug!("{:x}: inserted into DB", xid),
         _ => debug!("{:x}: updated DB", xid),
@@ -103,9 +182,9 @@ pub fn u8_to_ipv4addr(buf: &[u8]) -> Option<Ipv4Addr> {
 }
 
 /** .envから環境情報を読んでハッシュマップを返す */
-pub fn load_env() -> HashMap<String, String> {
-    let contents = fs::read_to_string(".env").expect("Failed to read env file");
-    let lines: Vec<_> = contents.split("\n").colect();
+pub fn load_env() -> Result<HashMap<String, String>, failure::Error> {
+    let contents = fs::read_to_string(".env")?;
+    let lines: Vec<_> = contents.split("\n").collect();
     let mut map = HashMap::new();
     for line in lines {
         let elm: Vec<_> = line.split("=").map(str::trim).collect();
@@ -113,20 +192,24 @@ pub fn load_env() -> HashMap<String, String> {
             map.insert(elm[0].to_string(), elm[1].to_string());
         }
     }
-    map
+    Ok(map)
 }
 
-/** 固定されたアドレス情報を返す */
-pub fn obtain_static_addresses(env: &HashMap<String, String>) -> Result<HashMap<String, Ipv4Addr>, AddrParseError> {
-    let network_addr: Ipv4Addr = env.get("NETWORK_ADDR").expect("Missing network_addr").parse()?;
+/** 固定されたアドレス情報を返す
+ * 設定の再読み込み時にも呼ばれるため、項目の欠落はパニックではなくエラーとして返す
+ */
+pub fn obtain_static_addresses(env: &HashMap<String, String>) -> Result<HashMap<String, Ipv4Addr>, failure::Error> {
+    let get = |key: &str| env.get(key).ok_or_else(|| failure::err_msg(format!("Missing {}", key.to_lowercase())));
+
+    let network_addr: Ipv4Addr = get("NETWORK_ADDR")?.parse()?;
 
-    let subnet_mask: Ipv4Addr = env.get("SUBNET_MASK").expect("Missing subnet_mask").parse()?;
+    let subnet_mask: Ipv4Addr = get("SUBNET_MASK")?.parse()?;
 
-    let dhcp_server_address = env.get("SERVER_IDENTIFIER").expect("Missing server_identifier").parse()?;
+    let dhcp_server_address = get("SERVER_IDENTIFIER")?.parse()?;
 
-    let default_gateway = env.get("DEFAULT_GATEWAY").expect("Missing default_gateway").parse()?;
+    let default_gateway = get("DEFAULT_GATEWAY")?.parse()?;
 
-    let dns_addr = env.get("DNS_SERVER").expect("Missing dns_server").parse()?;
+    let dns_addr = get("DNS_SERVER")?.parse()?;
 
     let mut map = HashMap::new();
     map.insert("network_addr".to_string(), network_addr);
@@ -137,6 +220,23 @@ pub fn obtain_static_addresses(env: &HashMap<String, String>) -> Result<HashMap<
     Ok(map)
 }
 
+/** .envの省略可能な項目を読む。なければデフォルト値を返す */
+pub fn get_env_or<T>(env: &HashMap<String, String>, key: &str, default: T) -> Result<T, failure::Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env.get(key) {
+        Some(value) => Ok(value.parse()?),
+        None => Ok(default),
+    }
+}
+
+/** 現在のUNIX時刻(秒) リースの期限に使う */
+pub fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
 /** u32をビッグエンディアンでバイト列ベクタに変換 */
 pub fn make_big_endian_vec_from_u32(i: u32) -> Result<Vec<u8>, io::Error> {
     let mut v = Vec::new();
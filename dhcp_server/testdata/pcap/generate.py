@@ -0,0 +1,198 @@
+#!/usr/bin/env python3
+"""testdata/pcap のキャプチャを生成する
+
+実機で採取したキャプチャが揃うまでの代わりに、各クライアントが送るオプションの並びと
+サーバーの応答を RFC2131 のやり取りどおりに組み立てる。出力は毎回同じバイト列になる。
+
+    python3 testdata/pcap/generate.py
+"""
+
+import os
+import struct
+
+DIR = os.path.dirname(os.path.abspath(__file__))
+
+BROADCAST_MAC = b"\xff" * 6
+SERVER_MAC = bytes.fromhex("525400000001")
+MAGIC_COOKIE = bytes([99, 130, 83, 99])
+
+DISCOVER, OFFER, REQUEST, ACK, RELEASE, INFORM = 1, 2, 3, 5, 7, 8
+
+
+def ip(text):
+    return bytes(int(part) for part in text.split("."))
+
+
+def checksum(data):
+    if len(data) % 2:
+        data += b"\0"
+    total = sum(struct.unpack("!%dH" % (len(data) // 2), data))
+    while total >> 16:
+        total = (total & 0xFFFF) + (total >> 16)
+    return ~total & 0xFFFF
+
+
+def options(*pairs):
+    out = bytearray(MAGIC_COOKIE)
+    for code, value in pairs:
+        out += bytes([code, len(value)]) + value
+    out.append(255)
+    return bytes(out)
+
+
+def dhcp(op, xid, chaddr, opts, ciaddr="0.0.0.0", yiaddr="0.0.0.0", flags=0):
+    body = struct.pack("!BBBBIHH", op, 1, 6, 0, xid, 0, flags)
+    body += ip(ciaddr) + ip(yiaddr) + ip("0.0.0.0") + ip("0.0.0.0")
+    body += chaddr + b"\0" * 10 + b"\0" * 64 + b"\0" * 128
+    body += opts
+    # BOOTPの最小長(300バイト)に満たない分は詰め物をする
+    return body + b"\0" * max(0, 300 - len(body))
+
+
+def frame(src_mac, dst_mac, src_ip, dst_ip, src_port, dst_port, payload):
+    udp = struct.pack("!HHHH", src_port, dst_port, 8 + len(payload), 0) + payload
+    header = struct.pack("!BBHHHBBH4s4s", 0x45, 0, 20 + len(udp), 0, 0, 64, 17, 0, ip(src_ip), ip(dst_ip))
+    header = header[:10] + struct.pack("!H", checksum(header)) + header[12:]
+    return dst_mac + src_mac + b"\x08\x00" + header + udp
+
+
+def write_pcap(name, frames):
+    with open(os.path.join(DIR, name), "wb") as f:
+        f.write(struct.pack("<IHHiIII", 0xA1B2C3D4, 2, 4, 0, 0, 65535, 1))
+        for i, data in enumerate(frames):
+            f.write(struct.pack("<IIII", 1700000000 + i, 0, len(data), len(data)))
+            f.write(data)
+
+
+def server_options(message_type, server, mask, router, dns, lease):
+    return options(
+        (53, bytes([message_type])),
+        (54, ip(server)),
+        (51, struct.pack("!I", lease)),
+        (58, struct.pack("!I", lease // 2)),
+        (59, struct.pack("!I", lease * 7 // 8)),
+        (1, ip(mask)),
+        (3, ip(router)),
+        (6, ip(dns)),
+    )
+
+
+def client(mac, src_ip, dst_ip, payload):
+    dst_mac = BROADCAST_MAC if dst_ip == "255.255.255.255" else SERVER_MAC
+    return frame(mac, dst_mac, src_ip, dst_ip, 68, 67, payload)
+
+
+def server(mac, dst_ip, payload, server_ip):
+    dst_mac = BROADCAST_MAC if dst_ip == "255.255.255.255" else mac
+    return frame(SERVER_MAC, dst_mac, server_ip, dst_ip, 67, 68, payload)
+
+
+def dhclient():
+    """DISCOVER -> OFFER -> REQUEST(SELECTING) -> ACK -> REQUEST(RENEWING) -> ACK"""
+    mac = bytes.fromhex("0800271a2b3c")
+    srv, addr = "192.168.10.1", "192.168.10.2"
+    reply = lambda t: server_options(t, srv, "255.255.255.0", srv, srv, 600)
+    prl = (55, bytes([1, 28, 2, 3, 15, 6, 119, 12, 44, 47, 26, 121, 42]))
+    hostname = (12, b"debian")
+    return [
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x3903F326, mac, options((53, bytes([DISCOVER])), hostname, prl))),
+        server(mac, "255.255.255.255", dhcp(2, 0x3903F326, mac, reply(OFFER), yiaddr=addr), srv),
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x3903F326, mac, options((53, bytes([REQUEST])), (54, ip(srv)), (50, ip(addr)), hostname, prl))),
+        server(mac, "255.255.255.255", dhcp(2, 0x3903F326, mac, reply(ACK), yiaddr=addr), srv),
+        # T1経過後の延長要求はciaddrを設定してサーバーへユニキャストで送る
+        client(mac, addr, srv, dhcp(1, 0x5A1C0E77, mac, options((53, bytes([REQUEST])), hostname, prl), ciaddr=addr)),
+        server(mac, addr, dhcp(2, 0x5A1C0E77, mac, reply(ACK), ciaddr=addr, yiaddr=addr), srv),
+    ]
+
+
+def windows():
+    """DISCOVER(要求アドレス付き) -> OFFER -> REQUEST -> ACK -> REQUEST(INIT-REBOOT) -> ACK -> INFORM"""
+    mac = bytes.fromhex("00155d4e5f60")
+    srv, addr = "192.168.1.1", "192.168.1.57"
+    reply = lambda t: server_options(t, srv, "255.255.255.0", srv, srv, 86400)
+    client_id = (61, b"\x01" + mac)
+    hostname = (12, b"DESKTOP-7Q2K")
+    vendor = (60, b"MSFT 5.0")
+    prl = (55, bytes([1, 3, 6, 15, 31, 33, 43, 44, 46, 47, 119, 121, 249, 252]))
+    return [
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x8A4F2B10, mac, options((53, bytes([DISCOVER])), client_id, (50, ip(addr)), hostname, vendor, prl), flags=0x8000)),
+        server(mac, "255.255.255.255", dhcp(2, 0x8A4F2B10, mac, reply(OFFER), yiaddr=addr, flags=0x8000), srv),
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x8A4F2B10, mac, options((53, bytes([REQUEST])), client_id, (50, ip(addr)), (54, ip(srv)), hostname, (81, b"\x00\x00\x00DESKTOP-7Q2K"), vendor, prl), flags=0x8000)),
+        server(mac, "255.255.255.255", dhcp(2, 0x8A4F2B10, mac, reply(ACK), yiaddr=addr, flags=0x8000), srv),
+        # 再起動後は記憶しているアドレスをserver_idなしで要求する
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x1E6D3C92, mac, options((53, bytes([REQUEST])), client_id, (50, ip(addr)), hostname, vendor, prl), flags=0x8000)),
+        server(mac, "255.255.255.255", dhcp(2, 0x1E6D3C92, mac, reply(ACK), yiaddr=addr, flags=0x8000), srv),
+        # DHCPINFORMにはこのサーバーは応答しない
+        client(mac, addr, "255.255.255.255", dhcp(1, 0x77E0A4C1, mac, options((53, bytes([INFORM])), client_id, hostname, vendor, prl), ciaddr=addr)),
+    ]
+
+
+def systemd_networkd():
+    """DISCOVER -> OFFER -> REQUEST -> ACK -> REQUEST(RENEWING) -> ACK -> RELEASE"""
+    mac = bytes.fromhex("525400123456")
+    srv, addr = "10.0.0.1", "10.0.0.2"
+    reply = lambda t: server_options(t, srv, "255.255.255.0", srv, srv, 3600)
+    # RFC4361のクライアント識別子(IAIDとDUID-EN)
+    client_id = (61, b"\xff" + bytes.fromhex("5b6c7d8e") + bytes.fromhex("0002 0000ab11 9c4e1fd27a3b5c60".replace(" ", "")))
+    max_size = (57, struct.pack("!H", 1472))
+    hostname = (12, b"nspawn-host")
+    prl = (55, bytes([1, 3, 6, 12, 15, 28, 42, 121]))
+    return [
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x6E2B9A41, mac, options((53, bytes([DISCOVER])), client_id, max_size, hostname, prl))),
+        server(mac, "255.255.255.255", dhcp(2, 0x6E2B9A41, mac, reply(OFFER), yiaddr=addr), srv),
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x6E2B9A41, mac, options((53, bytes([REQUEST])), client_id, max_size, (50, ip(addr)), (54, ip(srv)), hostname, prl))),
+        server(mac, "255.255.255.255", dhcp(2, 0x6E2B9A41, mac, reply(ACK), yiaddr=addr), srv),
+        client(mac, addr, srv, dhcp(1, 0x0C3F7D25, mac, options((53, bytes([REQUEST])), client_id, max_size, hostname, prl), ciaddr=addr)),
+        server(mac, addr, dhcp(2, 0x0C3F7D25, mac, reply(ACK), ciaddr=addr, yiaddr=addr), srv),
+        # 停止時にはリースを返却する。サーバーは応答しない
+        client(mac, addr, srv, dhcp(1, 0x2F8E61B0, mac, options((53, bytes([RELEASE])), (54, ip(srv)), client_id), ciaddr=addr)),
+    ]
+
+
+def android():
+    """DISCOVER -> OFFER -> REQUEST -> ACK -> 再接続時のREQUEST(INIT-REBOOT) -> ACK"""
+    mac = bytes.fromhex("3c286daabbcc")
+    srv, addr = "192.168.43.1", "192.168.43.2"
+    reply = lambda t: server_options(t, srv, "255.255.255.0", srv, srv, 3600)
+    client_id = (61, b"\x01" + mac)
+    max_size = (57, struct.pack("!H", 1500))
+    vendor = (60, b"android-dhcp-13")
+    hostname = (12, b"Pixel-7")
+    prl = (55, bytes([1, 3, 6, 15, 26, 28, 51, 58, 59, 43, 114, 108]))
+    return [
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0xB7C41E09, mac, options((53, bytes([DISCOVER])), client_id, max_size, vendor, hostname, prl))),
+        server(mac, "255.255.255.255", dhcp(2, 0xB7C41E09, mac, reply(OFFER), yiaddr=addr), srv),
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0xB7C41E09, mac, options((53, bytes([REQUEST])), client_id, max_size, (50, ip(addr)), (54, ip(srv)), vendor, hostname, prl))),
+        server(mac, "255.255.255.255", dhcp(2, 0xB7C41E09, mac, reply(ACK), yiaddr=addr), srv),
+        # 同じネットワークに再接続すると、前回のアドレスをserver_idなしで要求する
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x4D0A93F2, mac, options((53, bytes([REQUEST])), client_id, max_size, (50, ip(addr)), vendor, hostname, prl))),
+        server(mac, "255.255.255.255", dhcp(2, 0x4D0A93F2, mac, reply(ACK), yiaddr=addr), srv),
+    ]
+
+
+def pxe():
+    """UEFIのPXEブート: ブロードキャストフラグ付きのDISCOVER -> OFFER -> REQUEST -> ACK"""
+    mac = bytes.fromhex("001e67123456")
+    srv, addr = "172.16.0.1", "172.16.0.2"
+    reply = lambda t: server_options(t, srv, "255.255.255.0", srv, srv, 43200)
+    max_size = (57, struct.pack("!H", 1464))
+    prl = (55, bytes([1, 2, 3, 4, 5, 6, 11, 12, 13, 15, 16, 17, 18, 22, 23, 28, 40, 41, 42, 43, 50, 51, 54, 58, 59, 60, 66, 67, 97, 128, 129, 130, 131, 132, 133, 134, 135]))
+    uuid = (97, b"\x00" + bytes.fromhex("4c4c4544005a3110804bb4c04f565031"))
+    ndi = (94, bytes([1, 3, 16]))
+    arch = (93, struct.pack("!H", 7))
+    vendor = (60, b"PXEClient:Arch:00007:UNDI:003016")
+    pxe_options = (max_size, prl, uuid, ndi, arch, vendor)
+    return [
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x9E1D5C37, mac, options((53, bytes([DISCOVER])), *pxe_options), flags=0x8000)),
+        server(mac, "255.255.255.255", dhcp(2, 0x9E1D5C37, mac, reply(OFFER), yiaddr=addr, flags=0x8000), srv),
+        client(mac, "0.0.0.0", "255.255.255.255", dhcp(1, 0x9E1D5C37, mac, options((53, bytes([REQUEST])), (50, ip(addr)), (54, ip(srv)), *pxe_options), flags=0x8000)),
+        server(mac, "255.255.255.255", dhcp(2, 0x9E1D5C37, mac, reply(ACK), yiaddr=addr, flags=0x8000), srv),
+    ]
+
+
+if __name__ == "__main__":
+    write_pcap("dhclient.pcap", dhclient())
+    write_pcap("windows.pcap", windows())
+    write_pcap("systemd-networkd.pcap", systemd_networkd())
+    write_pcap("android.pcap", android())
+    write_pcap("pxe.pcap", pxe())
@@ -0,0 +1,633 @@
+//! アプリケーション層のデコーダ
+//! ポート番号とペイロードの先頭から判断し、DNS、DHCP、HTTP、TLSのClientHelloを表示用の行にする
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::dhcp_options::DhcpOptions;
+
+/** DHCPのオプションが始まる位置。dhcp_serverのOPTIONSと同じ */
+const DHCP_OPTIONS: usize = 236;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+
+const HTTP_METHODS: [&str; 9] = ["GET ", "POST ", "PUT ", "DELETE ", "HEAD ", "OPTIONS ", "PATCH ", "CONNECT ", "TRACE "];
+
+/** UDPのペイロードを解釈する。該当するプロトコルがなければNone */
+pub fn decode_udp(source_port: u16, destination_port: u16, payload: &[u8]) -> Option<Vec<String>> {
+    let ports = [source_port, destination_port];
+    if ports.contains(&53) || ports.contains(&5353) {
+        return decode_dns(payload);
+    }
+    if ports.contains(&67) || ports.contains(&68) || is_dhcp(payload) {
+        return decode_dhcp(payload);
+    }
+    None
+}
+
+/** TCPのペイロードを解釈する。セグメント単体で読める先頭部分だけを対象にする */
+pub fn decode_tcp(source_port: u16, destination_port: u16, payload: &[u8]) -> Option<Vec<String>> {
+    let ports = [source_port, destination_port];
+    // DNS over TCPは先頭に2バイトの長さがつく
+    if ports.contains(&53) && payload.len() > 2 {
+        return decode_dns(&payload[2..]);
+    }
+    if is_tls_client_hello(payload) {
+        return decode_client_hello(payload);
+    }
+    if is_http(payload) {
+        return decode_http(payload);
+    }
+    None
+}
+
+/** 受信したバイト列を表示用の文字列にする
+ * 端末を操作されたり表示を偽装されたりしないよう、制御文字と双方向テキストの制御文字はエスケープする
+ */
+fn printable(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for c in String::from_utf8_lossy(bytes).chars() {
+        if c.is_control() || matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}') {
+            text.extend(c.escape_default());
+        } else {
+            text.push(c);
+        }
+    }
+    text
+}
+
+/** 範囲外を読まないためのカーソル */
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, position: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.position..self.position.checked_add(len)?)?;
+        self.position += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3).map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+fn decode_dns(payload: &[u8]) -> Option<Vec<String>> {
+    let mut reader = Reader::new(payload);
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    let authorities = reader.u16()?;
+    let additionals = reader.u16()?;
+
+    let response = flags & 0x8000 != 0;
+    let mut lines = vec![if response {
+        format!("DNS response id 0x{:04x} {} ({} answers, {} authority, {} additional)", id, dns_rcode(flags & 0xf), answers, authorities, additionals)
+    } else {
+        format!("DNS query id 0x{:04x}", id)
+    }];
+
+    for _ in 0..questions {
+        let name = dns_name(payload, &mut reader)?;
+        let rtype = reader.u16()?;
+        let _class = reader.u16()?;
+        lines.push(format!("  question {} {}", dns_type(rtype), name));
+    }
+    for _ in 0..answers {
+        let name = dns_name(payload, &mut reader)?;
+        let rtype = reader.u16()?;
+        let _class = reader.u16()?;
+        let ttl = reader.u32()?;
+        let length = reader.u16()? as usize;
+        let start = reader.position;
+        let rdata = reader.bytes(length)?;
+        let value = match rtype {
+            1 if length == 4 => Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).to_string(),
+            28 if length == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                Ipv6Addr::from(octets).to_string()
+            }
+            // NS、CNAME、PTRは圧縮されたドメイン名
+            2 | 5 | 12 => dns_name(payload, &mut Reader { data: payload, position: start })?,
+            15 if length > 2 => format!("{} {}", u16::from_be_bytes([rdata[0], rdata[1]]), dns_name(payload, &mut Reader { data: payload, position: start + 2 })?),
+            16 => {
+                let mut texts = Vec::new();
+                let mut txt = Reader::new(rdata);
+                while let Some(len) = txt.u8() {
+                    texts.push(format!("\"{}\"", printable(txt.bytes(len as usize)?)));
+                }
+                texts.join(" ")
+            }
+            _ => format!("{} bytes", length),
+        };
+        lines.push(format!("  answer {} {} {} ttl {}", name, dns_type(rtype), value, ttl));
+    }
+    Some(lines)
+}
+
+/** 圧縮ポインタをたどってドメイン名を読む。ポインタの循環に備えて回数を制限する */
+fn dns_name(message: &[u8], reader: &mut Reader) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut position = reader.position;
+    let mut jumped = false;
+    for _ in 0..128 {
+        let len = *message.get(position)? as usize;
+        if len == 0 {
+            if !jumped {
+                reader.position = position + 1;
+            }
+            return Some(if labels.is_empty() { ".".to_string() } else { labels.join(".") });
+        }
+        if len & 0xc0 == 0xc0 {
+            let pointer = (len & 0x3f) << 8 | *message.get(position + 1)? as usize;
+            if !jumped {
+                reader.position = position + 2;
+            }
+            jumped = true;
+            position = pointer;
+            continue;
+        }
+        let label = message.get(position + 1..position + 1 + len)?;
+        labels.push(printable(label));
+        position += 1 + len;
+    }
+    None
+}
+
+fn dns_type(rtype: u16) -> String {
+    match rtype {
+        1 => "A".to_string(),
+        2 => "NS".to_string(),
+        5 => "CNAME".to_string(),
+        6 => "SOA".to_string(),
+        12 => "PTR".to_string(),
+        15 => "MX".to_string(),
+        16 => "TXT".to_string(),
+        28 => "AAAA".to_string(),
+        33 => "SRV".to_string(),
+        65 => "HTTPS".to_string(),
+        255 => "ANY".to_string(),
+        other => format!("TYPE{}", other),
+    }
+}
+
+fn dns_rcode(rcode: u16) -> &'static str {
+    match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => "RCODE?",
+    }
+}
+
+/** DHCPのメッセージタイプ(option 53)の値。DHCPでなければNone */
+pub fn dhcp_message_type_of(payload: &[u8]) -> Option<u8> {
+    if !is_dhcp(payload) {
+        return None;
+    }
+    // dhcp_serverと同じく、最初のoption 53の先頭バイトをメッセージタイプとする
+    DhcpOptions::new(&payload[DHCP_OPTIONS + 4..]).find(|(code, _)| *code == 53)?.1.first().cloned()
+}
+
+fn is_dhcp(payload: &[u8]) -> bool {
+    payload.get(DHCP_OPTIONS..DHCP_OPTIONS + 4) == Some(&DHCP_MAGIC_COOKIE[..])
+}
+
+/** dhcp_serverのDhcpPacketと同じ固定長部分のレイアウトで読む */
+fn decode_dhcp(payload: &[u8]) -> Option<Vec<String>> {
+    if !is_dhcp(payload) {
+        return None;
+    }
+    let address = |offset: usize| Ipv4Addr::new(payload[offset], payload[offset + 1], payload[offset + 2], payload[offset + 3]);
+    let xid = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+    let hlen = (payload[2] as usize).min(16);
+    let chaddr = payload[28..28 + hlen].iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":");
+
+    let mut message_type = "BOOTP".to_string();
+    let mut options = Vec::new();
+    let mut dhcp_options = DhcpOptions::new(&payload[DHCP_OPTIONS + 4..]);
+    for (code, value) in dhcp_options.by_ref() {
+        let len = value.len();
+        let ipv4_list = || value.chunks(4).filter(|c| c.len() == 4).map(|c| Ipv4Addr::new(c[0], c[1], c[2], c[3]).to_string()).collect::<Vec<_>>().join(", ");
+        let seconds = || if len == 4 { format!("{}s", u32::from_be_bytes([value[0], value[1], value[2], value[3]])) } else { format!("{} bytes", len) };
+        let described = match code {
+            53 if len == 1 => {
+                message_type = dhcp_message_type(value[0]).to_string();
+                continue;
+            }
+            1 => format!("subnet mask {}", ipv4_list()),
+            3 => format!("router {}", ipv4_list()),
+            6 => format!("dns {}", ipv4_list()),
+            12 => format!("hostname {}", printable(value)),
+            50 => format!("requested address {}", ipv4_list()),
+            51 => format!("lease time {}", seconds()),
+            54 => format!("server identifier {}", ipv4_list()),
+            55 => format!("parameter request list {:?}", value),
+            58 => format!("renewal time {}", seconds()),
+            59 => format!("rebinding time {}", seconds()),
+            61 => format!("client identifier {}", value.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")),
+            _ => format!("option {} ({} bytes)", code, len),
+        };
+        options.push(format!("  {}", described));
+    }
+    if dhcp_options.truncated() {
+        options.push("  truncated option".to_string());
+    }
+
+    let mut lines = vec![
+        format!("DHCP {} xid 0x{:08x} client {}", message_type, xid, chaddr),
+        format!("  ciaddr {} yiaddr {} siaddr {} giaddr {}", address(12), address(16), address(20), address(24)),
+    ];
+    lines.extend(options);
+    Some(lines)
+}
+
+fn dhcp_message_type(message_type: u8) -> &'static str {
+    match message_type {
+        1 => "DISCOVER",
+        2 => "OFFER",
+        3 => "REQUEST",
+        4 => "DECLINE",
+        5 => "ACK",
+        6 => "NAK",
+        7 => "RELEASE",
+        8 => "INFORM",
+        _ => "UNKNOWN",
+    }
+}
+
+fn is_http(payload: &[u8]) -> bool {
+    payload.starts_with(b"HTTP/1.") || HTTP_METHODS.iter().any(|method| payload.starts_with(method.as_bytes()))
+}
+
+/** リクエスト行またはステータス行とヘッダを表示する。ボディは表示しない */
+fn decode_http(payload: &[u8]) -> Option<Vec<String>> {
+    let head = match payload.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => &payload[..end],
+        None => payload,
+    };
+    let mut lines = head.split(|b| *b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    let first = lines.next()?;
+    let mut result = vec![format!("HTTP {}", printable(first))];
+    result.extend(lines.filter(|line| line.contains(&b':')).map(|line| format!("  {}", printable(line))));
+    Some(result)
+}
+
+fn is_tls_client_hello(payload: &[u8]) -> bool {
+    // ハンドシェイクのレコードで、最初のメッセージがClientHello
+    payload.len() > 5 && payload[0] == 0x16 && payload[1] == 0x03 && payload[5] == 0x01
+}
+
+fn decode_client_hello(payload: &[u8]) -> Option<Vec<String>> {
+    let mut reader = Reader::new(payload);
+    reader.bytes(5)?;
+    let _handshake_type = reader.u8()?;
+    let _length = reader.u24()?;
+    let mut version = reader.u16()?;
+    reader.bytes(32)?;
+    let session_id_length = reader.u8()? as usize;
+    reader.bytes(session_id_length)?;
+    let cipher_suites_length = reader.u16()? as usize;
+    let cipher_suites = reader.bytes(cipher_suites_length)?;
+    let compression_length = reader.u8()? as usize;
+    reader.bytes(compression_length)?;
+
+    let mut server_name = None;
+    let mut alpn = Vec::new();
+    // 拡張がないClientHelloもある
+    if let Some(extensions_length) = reader.u16() {
+        let mut extensions = Reader::new(reader.bytes(extensions_length as usize).unwrap_or(&payload[reader.position..]));
+        while let (Some(extension_type), Some(length)) = (extensions.u16(), extensions.u16()) {
+            let mut data = Reader::new(match extensions.bytes(length as usize) {
+                Some(data) => data,
+                None => break,
+            });
+            match extension_type {
+                0 => {
+                    data.u16();
+                    if data.u8() == Some(0) {
+                        let len = data.u16().unwrap_or(0) as usize;
+                        server_name = data.bytes(len).map(printable);
+                    }
+                }
+                16 => {
+                    data.u16();
+                    while let Some(len) = data.u8() {
+                        match data.bytes(len as usize) {
+                            Some(protocol) => alpn.push(printable(protocol)),
+                            None => break,
+                        }
+                    }
+                }
+                // supported_versionsがあれば、実際に提案している最も新しいバージョン
+                43 => {
+                    data.u8();
+                    while let Some(supported) = data.u16() {
+                        if supported & 0x0f0f != 0x0a0a && supported > version {
+                            version = supported;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let ciphers: Vec<String> = cipher_suites
+        .chunks(2)
+        .filter(|c| c.len() == 2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        // GREASEの値は除く
+        .filter(|suite| suite & 0x0f0f != 0x0a0a)
+        .map(cipher_suite_name)
+        .collect();
+
+    let mut lines = vec![format!("TLS ClientHello {}", tls_version(version))];
+    if let Some(name) = server_name {
+        lines.push(format!("  SNI {}", name));
+    }
+    if !alpn.is_empty() {
+        lines.push(format!("  ALPN {}", alpn.join(", ")));
+    }
+    lines.push(format!("  cipher suites {}", ciphers.join(", ")));
+    Some(lines)
+}
+
+fn tls_version(version: u16) -> String {
+    match version {
+        0x0300 => "SSL 3.0".to_string(),
+        0x0301 => "TLS 1.0".to_string(),
+        0x0302 => "TLS 1.1".to_string(),
+        0x0303 => "TLS 1.2".to_string(),
+        0x0304 => "TLS 1.3".to_string(),
+        other => format!("0x{:04x}", other),
+    }
+}
+
+fn cipher_suite_name(suite: u16) -> String {
+    match suite {
+        0x1301 => "TLS_AES_128_GCM_SHA256".to_string(),
+        0x1302 => "TLS_AES_256_GCM_SHA384".to_string(),
+        0x1303 => "TLS_CHACHA20_POLY1305_SHA256".to_string(),
+        0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string(),
+        0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384".to_string(),
+        0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string(),
+        0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".to_string(),
+        0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256".to_string(),
+        0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256".to_string(),
+        0xc013 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA".to_string(),
+        0xc014 => "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA".to_string(),
+        0x009c => "TLS_RSA_WITH_AES_128_GCM_SHA256".to_string(),
+        0x009d => "TLS_RSA_WITH_AES_256_GCM_SHA384".to_string(),
+        0x002f => "TLS_RSA_WITH_AES_128_CBC_SHA".to_string(),
+        0x0035 => "TLS_RSA_WITH_AES_256_CBC_SHA".to_string(),
+        0x00ff => "TLS_EMPTY_RENEGOTIATION_INFO_SCSV".to_string(),
+        other => format!("0x{:04x}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** dhcp_serverのdhcp.rsのテストと同じオプション列を使う */
+    fn dhcp(options: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0u8; DHCP_OPTIONS];
+        payload[0] = 1;
+        payload[2] = 6;
+        payload[4..8].copy_from_slice(&[0x39, 0x03, 0xf3, 0x26]);
+        payload[28..34].copy_from_slice(&[0x08, 0x00, 0x27, 0x1a, 0x2b, 0x3c]);
+        payload.extend_from_slice(&DHCP_MAGIC_COOKIE);
+        payload.extend_from_slice(options);
+        payload
+    }
+
+    fn codes(payload: &[u8]) -> Vec<u8> {
+        DhcpOptions::new(&payload[DHCP_OPTIONS + 4..]).map(|(code, _)| code).collect()
+    }
+
+    #[test]
+    fn dhcp_options_skip_padding_and_stop_at_end() {
+        let payload = dhcp(&[0, 53, 1, 3, 0, 0, 50, 4, 192, 168, 0, 10, 255, 54, 4, 192, 168, 0, 1]);
+        assert_eq!(codes(&payload), vec![53, 50]);
+        assert_eq!(dhcp_message_type_of(&payload), Some(3));
+        let lines = decode_dhcp(&payload).unwrap();
+        assert_eq!(lines[0], "DHCP REQUEST xid 0x3903f326 client 08:00:27:1a:2b:3c");
+        assert_eq!(&lines[2..], &["  requested address 192.168.0.10".to_string()]);
+    }
+
+    #[test]
+    fn dhcp_options_stop_at_an_option_longer_than_the_buffer() {
+        let payload = dhcp(&[53, 1, 1, 12, 200, b'h', b'o', b's', b't']);
+        assert_eq!(codes(&payload), vec![53]);
+        // 壊れたオプションの前までと固定長部分は表示する
+        let lines = decode_dhcp(&payload).unwrap();
+        assert_eq!(lines[0], "DHCP DISCOVER xid 0x3903f326 client 08:00:27:1a:2b:3c");
+        assert_eq!(&lines[2..], &["  truncated option".to_string()]);
+
+        // 長さのバイトがない
+        let payload = dhcp(&[53, 1, 1, 61]);
+        assert_eq!(codes(&payload), vec![53]);
+        assert_eq!(dhcp_message_type_of(&payload), Some(1));
+    }
+
+    #[test]
+    fn dhcp_options_read_to_the_end_without_an_end_option() {
+        let payload = dhcp(&[53, 1, 5, 51, 4, 0, 0, 14, 16, 12, 0]);
+        assert_eq!(codes(&payload), vec![53, 51, 12]);
+        assert_eq!(&decode_dhcp(&payload).unwrap()[2..], &["  lease time 3600s".to_string(), "  hostname ".to_string()]);
+    }
+
+    #[test]
+    fn dhcp_first_message_type_wins() {
+        assert_eq!(dhcp_message_type_of(&dhcp(&[53, 1, 1, 53, 1, 3, 255])), Some(1));
+        assert_eq!(dhcp_message_type_of(&dhcp(&[53, 2, 1, 1, 53, 1, 3, 255])), Some(1));
+        assert_eq!(dhcp_message_type_of(&dhcp(&[53, 0, 53, 1, 3, 255])), None);
+    }
+
+    #[test]
+    fn dhcp_hostname_is_escaped() {
+        let payload = dhcp(&[53, 1, 1, 12, 7, b'h', 0x1b, b'[', b'2', b'J', b'\n', b'x', 255]);
+        assert_eq!(&decode_dhcp(&payload).unwrap()[2..], &["  hostname h\\u{1b}[2J\\nx".to_string()]);
+    }
+
+    fn dns_header(id: u16, flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+        let mut message = Vec::new();
+        for value in [id, flags, questions, answers, 0, 0] {
+            message.extend_from_slice(&value.to_be_bytes());
+        }
+        message
+    }
+
+    #[test]
+    fn dns_query_and_compressed_answers() {
+        let mut message = dns_header(0x1234, 0x8180, 1, 2);
+        // 12バイト目からの質問の名前を答えで圧縮ポインタとして参照する
+        message.extend_from_slice(b"\x03www\x07example\x03com\x00");
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        message.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+        message.extend_from_slice(&[3, b'c', b'd', b'n', 0xc0, 16]);
+        message.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4, 93, 184, 216, 34]);
+
+        let lines = decode_udp(40000, 53, &message).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "DNS response id 0x1234 NOERROR (2 answers, 0 authority, 0 additional)".to_string(),
+                "  question A www.example.com".to_string(),
+                "  answer www.example.com CNAME cdn.example.com ttl 60".to_string(),
+                "  answer www.example.com A 93.184.216.34 ttl 256".to_string(),
+            ]
+        );
+        // DNS over TCPは先頭の長さを飛ばす
+        let mut tcp = (message.len() as u16).to_be_bytes().to_vec();
+        tcp.extend_from_slice(&message);
+        assert_eq!(decode_tcp(53, 40000, &tcp), Some(lines));
+    }
+
+    #[test]
+    fn dns_compression_loop_is_rejected() {
+        let mut message = dns_header(1, 0, 1, 0);
+        // 自分自身を指すポインタ
+        message.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert_eq!(decode_dns(&message), None);
+
+        // 2つのポインタが互いを指す
+        let mut message = dns_header(1, 0, 1, 0);
+        message.extend_from_slice(&[1, b'a', 0xc0, 18, 0, 1, 0xc0, 12]);
+        assert_eq!(decode_dns(&message), None);
+    }
+
+    #[test]
+    fn dns_labels_are_escaped() {
+        let mut message = dns_header(1, 0, 1, 0);
+        message.extend_from_slice(&[4, b'a', 0x1b, b'[', b'm', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1]);
+        assert_eq!(decode_dns(&message).unwrap()[1], "  question A a\\u{1b}[m.com");
+    }
+
+    #[test]
+    fn dns_truncated_message_is_rejected() {
+        let mut message = dns_header(1, 0, 1, 0);
+        message.extend_from_slice(&[3, b'w', b'w']);
+        assert_eq!(decode_dns(&message), None);
+        assert_eq!(decode_dns(&message[..5]), None);
+    }
+
+    #[test]
+    fn http_request_headers_without_body() {
+        let payload = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\nbody: not a header";
+        assert_eq!(
+            decode_tcp(40000, 80, payload).unwrap(),
+            vec!["HTTP GET /index.html HTTP/1.1".to_string(), "  Host: example.com".to_string(), "  Accept: */*".to_string()]
+        );
+        assert_eq!(decode_tcp(80, 40000, b"HTTP/1.1 404 Not Found\r\n").unwrap(), vec!["HTTP HTTP/1.1 404 Not Found".to_string()]);
+        assert_eq!(decode_tcp(40000, 80, b"GARBAGE / HTTP/1.1\r\n"), None);
+    }
+
+    #[test]
+    fn http_control_characters_are_escaped() {
+        let payload = b"GET /\x1b]0;title\x07 HTTP/1.1\r\nX-Evil: a\rb\xe2\x80\xaec\r\n\r\n";
+        assert_eq!(
+            decode_http(payload).unwrap(),
+            vec!["HTTP GET /\\u{1b}]0;title\\u{7} HTTP/1.1".to_string(), "  X-Evil: a\\rb\\u{202e}c".to_string()]
+        );
+    }
+
+    /** SNIとALPNとsupported_versionsを持つClientHello */
+    fn client_hello(server_name: &[u8], protocols: &[&[u8]]) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        let mut sni = ((server_name.len() + 3) as u16).to_be_bytes().to_vec();
+        sni.push(0);
+        sni.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(server_name);
+        let mut alpn_list = Vec::new();
+        for protocol in protocols {
+            alpn_list.push(protocol.len() as u8);
+            alpn_list.extend_from_slice(protocol);
+        }
+        let mut alpn = (alpn_list.len() as u16).to_be_bytes().to_vec();
+        alpn.extend_from_slice(&alpn_list);
+        // GREASEと1.3、1.2
+        let versions = [6, 0x0a, 0x0a, 0x03, 0x04, 0x03, 0x03];
+        for (extension_type, data) in [(0u16, &sni[..]), (16, &alpn[..]), (43, &versions[..])] {
+            extensions.extend_from_slice(&extension_type.to_be_bytes());
+            extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(data);
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.push(0);
+        hello.extend_from_slice(&[0, 6, 0x1a, 0x1a, 0x13, 0x01, 0xc0, 0x2f]);
+        hello.extend_from_slice(&[1, 0]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&((hello.len() + 4) as u16).to_be_bytes());
+        record.push(0x01);
+        record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&hello);
+        record
+    }
+
+    #[test]
+    fn tls_client_hello_sni_alpn_and_version() {
+        let lines = decode_tcp(40000, 443, &client_hello(b"example.com", &[b"h2", b"http/1.1"])).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "TLS ClientHello TLS 1.3".to_string(),
+                "  SNI example.com".to_string(),
+                "  ALPN h2, http/1.1".to_string(),
+                "  cipher suites TLS_AES_128_GCM_SHA256, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn tls_client_hello_names_are_escaped() {
+        let lines = decode_client_hello(&client_hello(b"evil\x1b[2J.com", &[b"h2\x08"])).unwrap();
+        assert_eq!(lines[1], "  SNI evil\\u{1b}[2J.com");
+        assert_eq!(lines[2], "  ALPN h2\\u{8}");
+    }
+
+    #[test]
+    fn tls_truncated_client_hello_is_rejected() {
+        let hello = client_hello(b"example.com", &[]);
+        assert_eq!(decode_client_hello(&hello[..40]), None);
+        // 拡張の途中で切れていても、それまでに読めた部分は表示する
+        assert_eq!(decode_client_hello(&hello[..hello.len() - 10]).unwrap()[0], "TLS ClientHello TLS 1.2");
+    }
+
+    #[test]
+    fn dhcp_requires_the_magic_cookie() {
+        let mut payload = dhcp(&[53, 1, 1, 255]);
+        payload[DHCP_OPTIONS] = 0;
+        assert_eq!(dhcp_message_type_of(&payload), None);
+        assert!(decode_dhcp(&payload).is_none());
+        assert!(decode_dhcp(&payload[..DHCP_OPTIONS + 2]).is_none());
+        assert!(decode_udp(1000, 2000, &payload).is_none());
+    }
+}
@@ -0,0 +1,566 @@
+//! フィルタ式をclassic BPFにコンパイルし、ソケットにSO_ATTACH_FILTERで取り付ける
+//! カーネルで不要なパケットを捨てることで、ユーザー空間へのコピーを減らす
+//! ポートの判定はtcpdumpと同様、IPv6では拡張ヘッダがない場合だけを対象にする
+//! VLANタグは2段まで外してから判定する。それより深いタグやMPLSはカーネルでは通し、ユーザー空間のフィルタに任せる
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+
+use crate::filter::{Direction, Expr, Proto};
+
+// 命令の種別 (linux/filter.h)
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MEM: u16 = 0x60;
+const BPF_ADD: u16 = 0x00;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JSET: u16 = 0x40;
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+const BPF_TAX: u16 = 0x00;
+
+/** 一致したパケットはスナップ長いっぱいまで受け取る */
+pub const ACCEPT_LEN: u32 = 262_144;
+
+const ETHERTYPE_OFFSET: u32 = 12;
+/** タグのないイーサネットヘッダの長さ。VLANタグ1つごとに4バイト増える */
+const ETHERNET_LENGTH: u32 = 14;
+const VLAN_TAG_LENGTH: u32 = 4;
+/** 外すVLANタグの段数 */
+const MAX_VLAN_TAGS: u32 = 2;
+const VLAN_ETHERTYPES: [u32; 3] = [0x8100, 0x88a8, 0x9100];
+const MPLS_ETHERTYPES: [u32; 2] = [0x8847, 0x8848];
+
+/** プロローグが判定の前に保存する値の、スクラッチメモリ上の位置 */
+const MEM_NETWORK_OFFSET: u32 = 0;
+const MEM_ETHERTYPE: u32 = 1;
+
+/** sock_filterと同じレイアウトの命令 */
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instruction {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{ 0x{:02x}, {}, {}, 0x{:08x} }}", self.code, self.jt, self.jf, self.k)
+    }
+}
+
+/** 構文木を分解した、1回の読み込みと比較で判定できる単位 */
+#[derive(Clone, Copy)]
+enum Atom {
+    /** VLANタグを外した内側のEtherType */
+    EtherType(u16),
+    /** IPv4のプロトコル番号 (EtherTypeは確認済み) */
+    Ipv4Proto(u8),
+    /** IPv6の次ヘッダ (EtherTypeは確認済み) */
+    Ipv6Next(u8),
+    /** フラグメントの先頭か(オフセットが0) */
+    Ipv4FirstFragment,
+    /** ネットワーク層の先頭からの位置、マスク、値 */
+    Word(u32, u32, u32),
+    Ipv4Port(u32, u16),
+    Ipv6Port(u32, u16),
+}
+
+/** 原子的な判定のみで構成された式 */
+enum Lowered {
+    And(Box<Lowered>, Box<Lowered>),
+    Or(Box<Lowered>, Box<Lowered>),
+    Not(Box<Lowered>),
+    Atom(Atom),
+}
+
+fn and(a: Lowered, b: Lowered) -> Lowered {
+    Lowered::And(Box::new(a), Box::new(b))
+}
+
+fn or(a: Lowered, b: Lowered) -> Lowered {
+    Lowered::Or(Box::new(a), Box::new(b))
+}
+
+fn atom(a: Atom) -> Lowered {
+    Lowered::Atom(a)
+}
+
+fn is_ipv4() -> Lowered {
+    atom(Atom::EtherType(0x0800))
+}
+
+fn is_ipv6() -> Lowered {
+    atom(Atom::EtherType(0x86dd))
+}
+
+fn by_direction<F: Fn(bool) -> Lowered>(direction: Direction, f: F) -> Lowered {
+    match direction {
+        Direction::Src => f(true),
+        Direction::Dst => f(false),
+        Direction::Any => or(f(true), f(false)),
+    }
+}
+
+/** IPv6アドレスをプレフィックス長に応じて4バイトずつ比較する */
+fn ipv6_prefix(offset: u32, addr: [u8; 16], prefix: u8) -> Lowered {
+    let mut result: Option<Lowered> = None;
+    for i in 0..4u32 {
+        let bits = (i32::from(prefix) - (i as i32) * 32).clamp(0, 32) as u32;
+        if bits == 0 {
+            break;
+        }
+        let mask = if bits == 32 { u32::MAX } else { !(u32::MAX >> bits) };
+        let start = (i * 4) as usize;
+        let value = u32::from_be_bytes([addr[start], addr[start + 1], addr[start + 2], addr[start + 3]]) & mask;
+        let word = atom(Atom::Word(offset + i * 4, mask, value));
+        result = Some(match result {
+            Some(prev) => and(prev, word),
+            None => word,
+        });
+    }
+    // プレフィックス長0はすべてのIPv6パケットに一致する
+    result.map_or_else(is_ipv6, |r| and(is_ipv6(), r))
+}
+
+fn address(direction: Direction, addr: IpAddr, prefix: u8) -> Lowered {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - u32::from(prefix)) };
+            let value = u32::from(addr) & mask;
+            and(is_ipv4(), by_direction(direction, |src| atom(Atom::Word(if src { 12 } else { 16 }, mask, value))))
+        }
+        IpAddr::V6(addr) => by_direction(direction, |src| ipv6_prefix(if src { 8 } else { 24 }, addr.octets(), prefix)),
+    }
+}
+
+fn tcp_or_udp_v4() -> Lowered {
+    or(atom(Atom::Ipv4Proto(6)), atom(Atom::Ipv4Proto(17)))
+}
+
+fn tcp_or_udp_v6() -> Lowered {
+    or(atom(Atom::Ipv6Next(6)), atom(Atom::Ipv6Next(17)))
+}
+
+fn lower(expr: &Expr) -> Lowered {
+    match expr {
+        Expr::And(a, b) => and(lower(a), lower(b)),
+        Expr::Or(a, b) => or(lower(a), lower(b)),
+        Expr::Not(a) => Lowered::Not(Box::new(lower(a))),
+        Expr::Proto(proto) => match proto {
+            Proto::Ip => is_ipv4(),
+            Proto::Ip6 => is_ipv6(),
+            Proto::Arp => atom(Atom::EtherType(0x0806)),
+            Proto::Tcp => or(and(is_ipv4(), atom(Atom::Ipv4Proto(6))), and(is_ipv6(), atom(Atom::Ipv6Next(6)))),
+            Proto::Udp => or(and(is_ipv4(), atom(Atom::Ipv4Proto(17))), and(is_ipv6(), atom(Atom::Ipv6Next(17)))),
+            Proto::Icmp => and(is_ipv4(), atom(Atom::Ipv4Proto(1))),
+            Proto::Icmp6 => and(is_ipv6(), atom(Atom::Ipv6Next(58))),
+        },
+        Expr::Host(direction, addr) => address(*direction, *addr, if addr.is_ipv4() { 32 } else { 128 }),
+        Expr::Net(direction, addr, prefix) => address(*direction, *addr, *prefix),
+        Expr::Port(direction, port) => {
+            let v4 = and(
+                and(is_ipv4(), tcp_or_udp_v4()),
+                and(atom(Atom::Ipv4FirstFragment), by_direction(*direction, |src| atom(Atom::Ipv4Port(if src { 0 } else { 2 }, *port)))),
+            );
+            let v6 = and(and(is_ipv6(), tcp_or_udp_v6()), by_direction(*direction, |src| atom(Atom::Ipv6Port(40 + if src { 0 } else { 2 }, *port))));
+            or(v4, v6)
+        }
+    }
+}
+
+/** ジャンプ先をラベルで持つ、配置前の命令 */
+enum Pending {
+    Stmt(u16, u32),
+    Jump(u16, u32, usize, usize),
+    Label(usize),
+}
+
+struct Compiler {
+    code: Vec<Pending>,
+    labels: usize,
+}
+
+impl Compiler {
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn stmt(&mut self, code: u16, k: u32) {
+        self.code.push(Pending::Stmt(code, k));
+    }
+
+    fn jump(&mut self, code: u16, k: u32, jt: usize, jf: usize) {
+        self.code.push(Pending::Jump(code, k, jt, jf));
+    }
+
+    fn place(&mut self, label: usize) {
+        self.code.push(Pending::Label(label));
+    }
+
+    /** 式が真ならtに、偽ならfに分岐する命令列を生成する */
+    fn compile(&mut self, expr: &Lowered, t: usize, f: usize) {
+        match expr {
+            Lowered::And(a, b) => {
+                let next = self.label();
+                self.compile(a, next, f);
+                self.place(next);
+                self.compile(b, t, f);
+            }
+            Lowered::Or(a, b) => {
+                let next = self.label();
+                self.compile(a, t, next);
+                self.place(next);
+                self.compile(b, t, f);
+            }
+            Lowered::Not(a) => self.compile(a, f, t),
+            Lowered::Atom(a) => self.compile_atom(*a, t, f),
+        }
+    }
+
+    /** VLANタグを外し、内側のEtherTypeとネットワーク層の位置をスクラッチメモリに保存する
+     * 判定の間はXレジスタがネットワーク層の位置を指す。外しきれないタグやMPLSはacceptに分岐する
+     */
+    fn prologue(&mut self, accept: usize) {
+        self.stmt(BPF_LDX | BPF_W | BPF_IMM, ETHERNET_LENGTH);
+        self.stmt(BPF_LD | BPF_H | BPF_ABS, ETHERTYPE_OFFSET);
+        let inner = self.label();
+        for depth in 1..=MAX_VLAN_TAGS {
+            let tagged = self.label();
+            self.jump_if_any(&VLAN_ETHERTYPES, tagged, inner);
+            self.place(tagged);
+            self.stmt(BPF_LDX | BPF_W | BPF_IMM, ETHERNET_LENGTH + depth * VLAN_TAG_LENGTH);
+            self.stmt(BPF_LD | BPF_H | BPF_ABS, ETHERTYPE_OFFSET + depth * VLAN_TAG_LENGTH);
+        }
+        self.jump_if_any(&VLAN_ETHERTYPES, accept, inner);
+        self.place(inner);
+        let body = self.label();
+        self.jump_if_any(&MPLS_ETHERTYPES, accept, body);
+        self.place(body);
+        self.stmt(BPF_ST, MEM_ETHERTYPE);
+        self.stmt(BPF_STX, MEM_NETWORK_OFFSET);
+    }
+
+    /** Aがvaluesのどれかに等しければtに、どれでもなければfに分岐する */
+    fn jump_if_any(&mut self, values: &[u32], t: usize, f: usize) {
+        for (i, value) in values.iter().enumerate() {
+            if i + 1 == values.len() {
+                self.jump(BPF_JMP | BPF_JEQ | BPF_K, *value, t, f);
+            } else {
+                let next = self.label();
+                self.jump(BPF_JMP | BPF_JEQ | BPF_K, *value, t, next);
+                self.place(next);
+            }
+        }
+    }
+
+    fn compile_atom(&mut self, a: Atom, t: usize, f: usize) {
+        match a {
+            Atom::EtherType(ethertype) => {
+                self.stmt(BPF_LD | BPF_MEM, MEM_ETHERTYPE);
+                self.jump(BPF_JMP | BPF_JEQ | BPF_K, u32::from(ethertype), t, f);
+            }
+            Atom::Ipv4Proto(proto) => {
+                self.stmt(BPF_LD | BPF_B | BPF_IND, 9);
+                self.jump(BPF_JMP | BPF_JEQ | BPF_K, u32::from(proto), t, f);
+            }
+            Atom::Ipv6Next(next) => {
+                self.stmt(BPF_LD | BPF_B | BPF_IND, 6);
+                self.jump(BPF_JMP | BPF_JEQ | BPF_K, u32::from(next), t, f);
+            }
+            Atom::Ipv4FirstFragment => {
+                self.stmt(BPF_LD | BPF_H | BPF_IND, 6);
+                self.jump(BPF_JMP | BPF_JSET | BPF_K, 0x1fff, f, t);
+            }
+            Atom::Word(offset, mask, value) => {
+                self.stmt(BPF_LD | BPF_W | BPF_IND, offset);
+                if mask != u32::MAX {
+                    self.stmt(BPF_ALU | BPF_AND | BPF_K, mask);
+                }
+                self.jump(BPF_JMP | BPF_JEQ | BPF_K, value, t, f);
+            }
+            Atom::Ipv4Port(offset, port) => {
+                // ldx 4*([k]&0xf) は絶対位置しか取れないので、ヘッダ長を計算してXに足す
+                self.stmt(BPF_LD | BPF_B | BPF_IND, 0);
+                self.stmt(BPF_ALU | BPF_AND | BPF_K, 0x0f);
+                self.stmt(BPF_ALU | BPF_LSH | BPF_K, 2);
+                self.stmt(BPF_ALU | BPF_ADD | BPF_X, 0);
+                self.stmt(BPF_MISC | BPF_TAX, 0);
+                self.stmt(BPF_LD | BPF_H | BPF_IND, offset);
+                // 次の判定のために、Xをネットワーク層の位置に戻す
+                self.stmt(BPF_LDX | BPF_MEM, MEM_NETWORK_OFFSET);
+                self.jump(BPF_JMP | BPF_JEQ | BPF_K, u32::from(port), t, f);
+            }
+            Atom::Ipv6Port(offset, port) => {
+                self.stmt(BPF_LD | BPF_H | BPF_IND, offset);
+                self.jump(BPF_JMP | BPF_JEQ | BPF_K, u32::from(port), t, f);
+            }
+        }
+    }
+
+    /** ラベルを解決して命令列にする。classic BPFのジャンプは前方に255命令までしか飛べない */
+    fn assemble(self) -> Result<Vec<Instruction>, failure::Error> {
+        let mut positions = vec![0usize; self.labels];
+        let mut position = 0;
+        for pending in self.code.iter() {
+            match pending {
+                Pending::Label(label) => positions[*label] = position,
+                _ => position += 1,
+            }
+        }
+
+        let mut program = Vec::with_capacity(position);
+        for pending in self.code.iter() {
+            match pending {
+                Pending::Stmt(code, k) => program.push(Instruction { code: *code, jt: 0, jf: 0, k: *k }),
+                Pending::Jump(code, k, jt, jf) => {
+                    let here = program.len() + 1;
+                    let offset = |label: usize| -> Result<u8, failure::Error> {
+                        let target = positions[label];
+                        if target < here || target - here > 255 {
+                            return Err(failure::err_msg("Filter is too complex to compile to BPF"));
+                        }
+                        Ok((target - here) as u8)
+                    };
+                    program.push(Instruction { code: *code, jt: offset(*jt)?, jf: offset(*jf)?, k: *k });
+                }
+                Pending::Label(_) => {}
+            }
+        }
+        Ok(program)
+    }
+}
+
+/** フィルタ式をclassic BPFのプログラムにコンパイルする */
+pub fn compile(expr: &Expr) -> Result<Vec<Instruction>, failure::Error> {
+    let mut compiler = Compiler { code: Vec::new(), labels: 0 };
+    let accept = compiler.label();
+    let reject = compiler.label();
+    compiler.prologue(accept);
+    compiler.compile(&lower(expr), accept, reject);
+    compiler.place(accept);
+    compiler.stmt(BPF_RET | BPF_K, ACCEPT_LEN);
+    compiler.place(reject);
+    compiler.stmt(BPF_RET | BPF_K, 0);
+    compiler.assemble()
+}
+
+/** SO_ATTACH_FILTERでソケットにプログラムを取り付ける */
+pub fn attach(fd: RawFd, program: &[Instruction]) -> io::Result<()> {
+    let prog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &prog as *const libc::sock_fprog as *const libc::c_void,
+            std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, PROTO_TCP, PROTO_UDP, TCP_SYN};
+
+    /** 生成する命令だけを対象にした、classic BPFのインタプリタ。戻り値はカーネルが渡すバイト数 */
+    fn run(program: &[Instruction], packet: &[u8]) -> u32 {
+        let load = |offset: u32, size: usize| packet.get(offset as usize..offset as usize + size).map(|bytes| bytes.iter().fold(0, |value, byte| value << 8 | u32::from(*byte)));
+        let (mut a, mut x, mut mem) = (0u32, 0u32, [0u32; 16]);
+        let mut pc = 0;
+        loop {
+            let instruction = program[pc];
+            let k = instruction.k;
+            pc += 1;
+            let size = match instruction.code & 0x18 {
+                BPF_W => 4,
+                BPF_H => 2,
+                _ => 1,
+            };
+            match instruction.code & 0x07 {
+                BPF_LD => {
+                    a = match instruction.code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_MEM => mem[k as usize],
+                        // 範囲外の読み込みはカーネルと同じくパケットを捨てる
+                        BPF_ABS => match load(k, size) {
+                            Some(value) => value,
+                            None => return 0,
+                        },
+                        BPF_IND => match load(x.wrapping_add(k), size) {
+                            Some(value) => value,
+                            None => return 0,
+                        },
+                        mode => panic!("unexpected ld mode 0x{:02x}", mode),
+                    }
+                }
+                BPF_LDX => {
+                    x = match instruction.code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_MEM => mem[k as usize],
+                        mode => panic!("unexpected ldx mode 0x{:02x}", mode),
+                    }
+                }
+                BPF_ST => mem[k as usize] = a,
+                BPF_STX => mem[k as usize] = x,
+                BPF_ALU => {
+                    let operand = if instruction.code & BPF_X != 0 { x } else { k };
+                    a = match instruction.code & 0xf0 {
+                        BPF_ADD => a.wrapping_add(operand),
+                        BPF_AND => a & operand,
+                        BPF_LSH => a << operand,
+                        op => panic!("unexpected alu op 0x{:02x}", op),
+                    }
+                }
+                BPF_JMP => {
+                    let taken = match instruction.code & 0xf0 {
+                        BPF_JEQ => a == k,
+                        BPF_JSET => a & k != 0,
+                        op => panic!("unexpected jmp op 0x{:02x}", op),
+                    };
+                    pc += usize::from(if taken { instruction.jt } else { instruction.jf });
+                }
+                BPF_RET => return k,
+                BPF_MISC => x = a,
+                class => panic!("unexpected class 0x{:02x}", class),
+            }
+        }
+    }
+
+    /** IPv4ヘッダに4バイトのオプションを足す(ヘッダ長24バイト) */
+    fn with_ip_options(frame: &[u8]) -> Vec<u8> {
+        let mut extended = frame[..34].to_vec();
+        extended[14] = 0x46;
+        let total_length = u16::from_be_bytes([extended[16], extended[17]]) + 4;
+        extended[16..18].copy_from_slice(&total_length.to_be_bytes());
+        extended.extend_from_slice(&[1, 1, 1, 0]);
+        extended.extend_from_slice(&frame[34..]);
+        extended
+    }
+
+    fn frames() -> Vec<(&'static str, Vec<u8>)> {
+        let tcp_v4 = testutil::tcp_frame("10.0.0.1", 40000, "192.168.1.5", 80, 1, TCP_SYN, b"");
+        let udp_v4 = testutil::udp_frame("10.0.0.1", 5353, "8.8.8.8", 53, b"query");
+        let tcp_v6 = testutil::tcp_frame("2001:db8::1", 40000, "2001:db8:1::2", 443, 1, TCP_SYN, b"");
+        let udp_v6 = testutil::udp_frame("2001:db8::1", 546, "ff02::1:2", 547, b"");
+        let icmp_v4 = testutil::ip_frame(1, "192.168.1.5", "10.0.0.1", &[8, 0, 0, 0, 0, 1, 0, 1]);
+        let icmp_v6 = testutil::ip_frame(58, "2001:db8::1", "2001:db8:1::2", &[128, 0, 0, 0, 0, 1, 0, 1]);
+        let arp = testutil::ethernet(0x0806, &[0, 1, 8, 0, 6, 4, 0, 1, 2, 0, 0, 0, 0, 1, 10, 0, 0, 1, 0, 0, 0, 0, 0, 0, 10, 0, 0, 2]);
+        let segment = testutil::tcp(40000, 80, 1, TCP_SYN, &[0; 16]);
+        let second_fragment = testutil::ethernet(0x0800, &testutil::ipv4_fragment(PROTO_TCP, "10.0.0.1", "192.168.1.5", 3, 16, false, &segment[16..]));
+        vec![
+            ("tcp v4", tcp_v4.clone()),
+            ("tcp v4 with ip options", with_ip_options(&tcp_v4)),
+            ("tcp v4 in vlan", testutil::vlan(100, &tcp_v4)),
+            ("tcp v4 in qinq", testutil::vlan(200, &testutil::vlan(100, &tcp_v4))),
+            ("tcp v4 with ip options in vlan", testutil::vlan(100, &with_ip_options(&tcp_v4))),
+            ("second fragment", second_fragment),
+            ("udp v4", udp_v4.clone()),
+            ("udp v4 in vlan", testutil::vlan(7, &udp_v4)),
+            ("tcp v6", tcp_v6.clone()),
+            ("tcp v6 in vlan", testutil::vlan(100, &tcp_v6)),
+            ("udp v6", udp_v6),
+            ("icmp v4", icmp_v4),
+            ("icmp v6 in vlan", testutil::vlan(5, &icmp_v6)),
+            ("arp", arp.clone()),
+            ("arp in vlan", testutil::vlan(5, &arp)),
+        ]
+    }
+
+    #[test]
+    fn compiled_program_agrees_with_userspace_filter() {
+        let filters = [
+            "ip",
+            "ip6",
+            "arp",
+            "tcp",
+            "udp",
+            "icmp",
+            "icmp6",
+            "port 80",
+            "tcp port 80",
+            "udp port 53",
+            "src port 40000",
+            "dst port 443",
+            "udp dst port 547",
+            "host 10.0.0.1",
+            "src host 10.0.0.1 and dst port 80",
+            "dst 8.8.8.8",
+            "net 192.168.0.0/16",
+            "src net 10.0.0.0/8 and not tcp",
+            "net 0.0.0.0/0",
+            "host 2001:db8::1",
+            "dst net 2001:db8:1::/48",
+            "net ::/0",
+            "not (tcp or udp)",
+            "icmp or arp or port 53",
+            "! ip and ! ip6",
+        ];
+        for filter in filters.iter() {
+            let expr = Expr::parse(filter).unwrap();
+            let program = compile(&expr).unwrap();
+            for (name, frame) in frames() {
+                let accepted = run(&program, &frame) != 0;
+                assert_eq!(accepted, expr.matches(&frame), "filter \"{}\" on {}", filter, name);
+            }
+        }
+    }
+
+    #[test]
+    fn deeper_vlan_stacks_and_mpls_are_left_to_userspace() {
+        let expr = Expr::parse("udp port 9999").unwrap();
+        let program = compile(&expr).unwrap();
+        let udp = testutil::udp_frame("10.0.0.1", 5353, "8.8.8.8", 53, b"");
+        let three_tags = testutil::vlan(3, &testutil::vlan(2, &testutil::vlan(1, &udp)));
+        let mut mpls = testutil::ethernet(0x8847, &[0x00, 0x01, 0x01, 0x40]);
+        mpls.extend_from_slice(&testutil::ipv4(PROTO_UDP, "10.0.0.1", "8.8.8.8", &testutil::udp(5353, 53, b"")));
+
+        for frame in [three_tags, mpls].iter() {
+            assert_eq!(run(&program, frame), ACCEPT_LEN);
+            assert!(!expr.matches(frame));
+        }
+        assert_eq!(run(&program, &testutil::vlan(2, &testutil::vlan(1, &udp))), 0);
+    }
+
+    #[test]
+    fn reading_past_the_end_drops_the_packet() {
+        // カーネルはパケットの外を読むとnotの中でも捨てる。ユーザー空間の判定とはここだけ異なる
+        let expr = Expr::parse("not tcp").unwrap();
+        let truncated = &testutil::tcp_frame("10.0.0.1", 40000, "192.168.1.5", 80, 1, TCP_SYN, b"")[..20];
+        assert_eq!(run(&compile(&expr).unwrap(), truncated), 0);
+        assert!(expr.matches(truncated));
+    }
+
+    #[test]
+    fn too_complex_filter_is_an_error() {
+        let filter = (0..40).map(|i| format!("host 2001:db8::{:x}", i)).collect::<Vec<_>>().join(" or ");
+        assert!(compile(&Expr::parse(&filter).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,438 @@
+//! スキャンや攻撃の兆候の検知 (--detect)
+//! port-scannerが送るSYN/FIN/Xmas/Nullスキャン、SYNフラッド、ARPスプーフィング、不正なDHCPサーバーを警告する
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use log::warn;
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::tcp::TcpFlags;
+use pnet::util::MacAddr;
+use serde_json::json;
+
+use crate::application;
+use crate::packet::DecodedPacket;
+
+const DHCP_OFFER: u8 = 2;
+const DHCP_ACK: u8 = 5;
+
+/** 警告の出し方 */
+#[derive(Clone, Copy, PartialEq)]
+pub enum AlertFormat {
+    Log,
+    Json,
+}
+
+impl AlertFormat {
+    pub fn parse(name: &str) -> Result<AlertFormat, failure::Error> {
+        match name {
+            "log" => Ok(AlertFormat::Log),
+            "json" => Ok(AlertFormat::Json),
+            _ => Err(failure::err_msg(format!("Unknown alert format {} (expected log or json)", name))),
+        }
+    }
+}
+
+/** --detectの設定 */
+#[derive(Clone)]
+pub struct DetectConfig {
+    pub format: AlertFormat,
+    /** スキャンとSYNフラッドを数える時間の幅 */
+    pub window: Duration,
+    /** 1つの送信元がwindowの間にこれだけの宛先ポートに触れたらスキャンとみなす */
+    pub scan_ports: usize,
+    /** 1つの宛先がwindowの間にこれだけのSYNを受けたらSYNフラッドとみなす */
+    pub syn_flood: usize,
+    /** 正規のDHCPサーバー。空なら最初に見たサーバーを正規とみなす */
+    pub dhcp_servers: Vec<Ipv4Addr>,
+}
+
+impl Default for DetectConfig {
+    fn default() -> DetectConfig {
+        DetectConfig {
+            format: AlertFormat::Log,
+            window: Duration::from_secs(10),
+            scan_ports: 20,
+            syn_flood: 200,
+            dhcp_servers: Vec::new(),
+        }
+    }
+}
+
+/** UDPの送信元と宛先のアドレスとポート */
+type UdpFlow = (IpAddr, u16, IpAddr, u16);
+
+/** 送信元ごとのスキャンの記録 */
+#[derive(Default)]
+struct ScanState {
+    /** 触れた宛先とその時刻 */
+    probes: VecDeque<(Duration, IpAddr, u16)>,
+    /** 前回警告した時刻。windowの間は同じ送信元について繰り返さない */
+    alerted_at: Option<Duration>,
+}
+
+pub struct Detector {
+    config: DetectConfig,
+    scans: HashMap<IpAddr, ScanState>,
+    /** 宛先ごとのSYNの時刻 */
+    syns: HashMap<(IpAddr, u16), (VecDeque<Duration>, Option<Duration>)>,
+    /** 送信元と種類ごとに、最後に警告した時刻 */
+    stealth: HashMap<(IpAddr, &'static str), Duration>,
+    /** 最近見たUDPの通信と最後に見た時刻。逆向きの通信への応答はスキャンとして数えない */
+    udp_flows: HashMap<UdpFlow, Duration>,
+    /** 最後に古い記録を取り除いた時刻 */
+    swept_at: Duration,
+    /** ARPで見たIPアドレスとMACアドレスの対応 */
+    arp_table: HashMap<Ipv4Addr, MacAddr>,
+    dhcp_servers: HashSet<Ipv4Addr>,
+    pub alerts: u64,
+}
+
+impl Detector {
+    pub fn new(config: DetectConfig) -> Detector {
+        let dhcp_servers = config.dhcp_servers.iter().cloned().collect();
+        Detector {
+            config,
+            scans: HashMap::new(),
+            syns: HashMap::new(),
+            stealth: HashMap::new(),
+            udp_flows: HashMap::new(),
+            swept_at: Duration::default(),
+            arp_table: HashMap::new(),
+            dhcp_servers,
+            alerts: 0,
+        }
+    }
+
+    /** 1フレームを調べ、兆候があれば警告する */
+    pub fn inspect(&mut self, frame: &[u8], timestamp: Duration) {
+        let packet = match DecodedPacket::decode(frame) {
+            Some(packet) => packet,
+            None => return,
+        };
+        if timestamp >= self.swept_at + self.config.window {
+            self.sweep(timestamp);
+        }
+        if packet.link.ethertype == EtherTypes::Arp {
+            self.inspect_arp(packet.payload, timestamp);
+            return;
+        }
+        let (ip, transport) = match (packet.ip.as_ref(), packet.transport.as_ref()) {
+            (Some(ip), Some(transport)) => (ip, transport),
+            _ => return,
+        };
+
+        match transport.tcp {
+            Some(tcp) => {
+                let flags = tcp.flags;
+                // ACKのないFIN、Xmas(FIN+PSH+URG)、Null(フラグなし)は通常の通信では送られない
+                let stealth = if flags == 0 {
+                    Some("null-scan")
+                } else if flags & (TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG) == TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG && flags & TcpFlags::ACK == 0 {
+                    Some("xmas-scan")
+                } else if flags & TcpFlags::FIN != 0 && flags & TcpFlags::ACK == 0 {
+                    Some("fin-scan")
+                } else {
+                    None
+                };
+                if let Some(kind) = stealth {
+                    let last = self.stealth.get(&(ip.source, kind)).cloned();
+                    if last.is_none_or(|last| timestamp >= last + self.config.window) {
+                        self.stealth.insert((ip.source, kind), timestamp);
+                        self.alert(timestamp, kind, ip.source, format!("TCP flags 0x{:03x} without ACK to {} port {}", flags, ip.destination, transport.destination_port));
+                    }
+                }
+
+                let is_syn = flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0;
+                if is_syn {
+                    self.count_syn(ip.destination, transport.destination_port, timestamp);
+                }
+                if is_syn || stealth.is_some() {
+                    self.count_probe(ip.source, ip.destination, transport.destination_port, timestamp);
+                }
+            }
+            None => {
+                // DNSやNTPのサーバーの応答のように、逆向きの通信に答えているものはスキャンではない
+                let flow = (ip.source, transport.source_port, ip.destination, transport.destination_port);
+                let reverse = (ip.destination, transport.destination_port, ip.source, transport.source_port);
+                let answered = self.udp_flows.get(&reverse).is_some_and(|at| timestamp < *at + self.config.window);
+                self.udp_flows.insert(flow, timestamp);
+                if !answered {
+                    self.count_probe(ip.source, ip.destination, transport.destination_port, timestamp);
+                }
+                if transport.source_port == 67 {
+                    if let IpAddr::V4(server) = ip.source {
+                        self.inspect_dhcp(server, packet.link.source, packet.payload, timestamp);
+                    }
+                }
+            }
+        }
+    }
+
+    /** windowより古い記録を取り除き、何もなくなった送信元や宛先を忘れる */
+    fn sweep(&mut self, now: Duration) {
+        let window = self.config.window;
+        let expired = |at: Duration| at + window < now;
+        self.scans.retain(|_, state| {
+            while state.probes.front().is_some_and(|(at, _, _)| expired(*at)) {
+                state.probes.pop_front();
+            }
+            !state.probes.is_empty() || state.alerted_at.is_some_and(|at| !expired(at))
+        });
+        self.syns.retain(|_, (syns, alerted_at)| {
+            while syns.front().is_some_and(|at| expired(*at)) {
+                syns.pop_front();
+            }
+            !syns.is_empty() || alerted_at.is_some_and(|at| !expired(at))
+        });
+        self.stealth.retain(|_, at| !expired(*at));
+        self.udp_flows.retain(|_, at| !expired(*at));
+        self.swept_at = now;
+    }
+
+    /** 送信元が触れた宛先ポートを数え、多ければポートスキャンとして警告する */
+    fn count_probe(&mut self, source: IpAddr, destination: IpAddr, port: u16, now: Duration) {
+        let window = self.config.window;
+        let state = self.scans.entry(source).or_default();
+        state.probes.push_back((now, destination, port));
+        while state.probes.front().is_some_and(|(at, _, _)| *at + window < now) {
+            state.probes.pop_front();
+        }
+        let ports: HashSet<(IpAddr, u16)> = state.probes.iter().map(|(_, destination, port)| (*destination, *port)).collect();
+        if ports.len() < self.config.scan_ports || state.alerted_at.is_some_and(|at| now < at + window) {
+            return;
+        }
+        state.alerted_at = Some(now);
+        let hosts: HashSet<IpAddr> = ports.iter().map(|(destination, _)| *destination).collect();
+        let message = format!("{} distinct ports on {} hosts in {}s", ports.len(), hosts.len(), window.as_secs());
+        self.alert(now, "port-scan", source, message);
+    }
+
+    /** 宛先ごとのSYNを数え、多ければSYNフラッドとして警告する */
+    fn count_syn(&mut self, destination: IpAddr, port: u16, now: Duration) {
+        let window = self.config.window;
+        let (syns, alerted_at) = self.syns.entry((destination, port)).or_insert_with(|| (VecDeque::new(), None));
+        syns.push_back(now);
+        while syns.front().is_some_and(|at| *at + window < now) {
+            syns.pop_front();
+        }
+        if syns.len() < self.config.syn_flood || alerted_at.is_some_and(|at| now < at + window) {
+            return;
+        }
+        *alerted_at = Some(now);
+        let message = format!("{} SYNs to port {} in {}s", syns.len(), port, window.as_secs());
+        self.alert(now, "syn-flood", destination, message);
+    }
+
+    /** 同じIPアドレスのMACアドレスが変わったらARPスプーフィングとして警告する */
+    fn inspect_arp(&mut self, payload: &[u8], now: Duration) {
+        let arp = match ArpPacket::new(payload) {
+            Some(arp) => arp,
+            None => return,
+        };
+        let address = arp.get_sender_proto_addr();
+        let mac = arp.get_sender_hw_addr();
+        if address.is_unspecified() {
+            return;
+        }
+        if let Some(previous) = self.arp_table.insert(address, mac) {
+            if previous != mac {
+                self.alert(now, "arp-spoofing", IpAddr::V4(address), format!("MAC address changed from {} to {}", previous, mac));
+            }
+        }
+    }
+
+    /** 正規でないサーバーからのOFFERとACKを警告する */
+    fn inspect_dhcp(&mut self, server: Ipv4Addr, mac: MacAddr, payload: &[u8], now: Duration) {
+        let message_type = match application::dhcp_message_type_of(payload) {
+            Some(message_type) if message_type == DHCP_OFFER || message_type == DHCP_ACK => message_type,
+            _ => return,
+        };
+        if self.dhcp_servers.is_empty() {
+            self.dhcp_servers.insert(server);
+            return;
+        }
+        if !self.dhcp_servers.contains(&server) {
+            let name = if message_type == DHCP_OFFER { "OFFER" } else { "ACK" };
+            self.alert(now, "rogue-dhcp", IpAddr::V4(server), format!("DHCP {} from unexpected server (MAC {})", name, mac));
+        }
+    }
+
+    fn alert(&mut self, timestamp: Duration, kind: &str, address: IpAddr, message: String) {
+        self.alerts += 1;
+        match self.config.format {
+            AlertFormat::Log => warn!("[{}] {}: {}", kind, address, message),
+            AlertFormat::Json => {
+                let event = json!({
+                    "timestamp": timestamp.as_secs_f64(),
+                    "alert": kind,
+                    "address": address.to_string(),
+                    "message": message,
+                });
+                println!("{}", event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, TCP_ACK, TCP_FIN, TCP_SYN};
+
+    const TCP_PSH: u8 = 0x08;
+    const TCP_URG: u8 = 0x20;
+
+    fn detector() -> Detector {
+        Detector::new(DetectConfig {
+            scan_ports: 5,
+            syn_flood: 10,
+            ..Default::default()
+        })
+    }
+
+    fn at(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn syn(source: &str, destination: &str, port: u16) -> Vec<u8> {
+        testutil::tcp_frame(source, 40000, destination, port, 1, TCP_SYN, b"")
+    }
+
+    fn arp_reply(mac: [u8; 6], address: [u8; 4]) -> Vec<u8> {
+        let mut arp = vec![0, 1, 8, 0, 6, 4, 0, 2];
+        arp.extend_from_slice(&mac);
+        arp.extend_from_slice(&address);
+        arp.extend_from_slice(&testutil::DESTINATION_MAC);
+        arp.extend_from_slice(&[192, 168, 0, 1]);
+        testutil::ethernet(0x0806, &arp)
+    }
+
+    fn dhcp_reply(server: &str, message_type: u8) -> Vec<u8> {
+        let mut payload = vec![0u8; 236];
+        payload[0] = 2;
+        payload.extend_from_slice(&[99, 130, 83, 99, 53, 1, message_type, 255]);
+        testutil::udp_frame(server, 67, "255.255.255.255", 68, &payload)
+    }
+
+    #[test]
+    fn port_scan_is_reported_at_the_threshold() {
+        let mut detector = detector();
+        for port in 1..5 {
+            detector.inspect(&syn("10.0.0.1", "10.0.0.2", port), at(port.into()));
+        }
+        assert_eq!(detector.alerts, 0);
+        detector.inspect(&syn("10.0.0.1", "10.0.0.2", 5), at(5));
+        assert_eq!(detector.alerts, 1);
+        // windowの間は同じ送信元について繰り返さない
+        detector.inspect(&syn("10.0.0.1", "10.0.0.2", 6), at(6));
+        assert_eq!(detector.alerts, 1);
+    }
+
+    #[test]
+    fn probes_outside_the_window_are_not_counted() {
+        let mut detector = detector();
+        for port in 1..=5 {
+            detector.inspect(&syn("10.0.0.1", "10.0.0.2", port), Duration::from_secs(u64::from(port) * 3));
+        }
+        assert_eq!(detector.alerts, 0);
+    }
+
+    #[test]
+    fn udp_server_replies_are_not_a_port_scan() {
+        let mut detector = detector();
+        for client in 1..=20u16 {
+            let address = format!("10.0.1.{}", client);
+            detector.inspect(&testutil::udp_frame(&address, 50000 + client, "10.0.0.53", 53, b"query"), at(client.into()));
+            detector.inspect(&testutil::udp_frame("10.0.0.53", 53, &address, 50000 + client, b"answer"), at(client.into()));
+        }
+        assert_eq!(detector.alerts, 0);
+    }
+
+    #[test]
+    fn unanswered_udp_probes_are_a_port_scan() {
+        let mut detector = detector();
+        for port in 1..=5 {
+            detector.inspect(&testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", port, b""), at(port.into()));
+        }
+        assert_eq!(detector.alerts, 1);
+    }
+
+    #[test]
+    fn established_tcp_traffic_is_not_a_probe() {
+        let mut detector = detector();
+        for port in 1..=20 {
+            detector.inspect(&testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", port, 1, TCP_ACK, b"data"), at(port.into()));
+        }
+        assert_eq!(detector.alerts, 0);
+    }
+
+    #[test]
+    fn stealth_scans_are_classified_by_flags() {
+        for (flags, kind) in [(0, "null-scan"), (TCP_FIN | TCP_PSH | TCP_URG, "xmas-scan"), (TCP_FIN, "fin-scan")] {
+            let mut detector = detector();
+            detector.inspect(&testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", 80, 1, flags, b""), at(0));
+            assert_eq!(detector.alerts, 1, "{}", kind);
+            assert!(detector.stealth.contains_key(&("10.0.0.1".parse().unwrap(), kind)), "{}", kind);
+        }
+        // ACKの付いたFINは通常の切断
+        let mut detector = detector();
+        detector.inspect(&testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", 80, 1, TCP_FIN | TCP_ACK, b""), at(0));
+        assert_eq!(detector.alerts, 0);
+    }
+
+    #[test]
+    fn syn_flood_is_reported_for_one_destination() {
+        let mut detector = Detector::new(DetectConfig {
+            syn_flood: 10,
+            ..Default::default()
+        });
+        for client in 1..10 {
+            detector.inspect(&syn(&format!("10.0.1.{}", client), "10.0.0.2", 80), at(client));
+        }
+        assert_eq!(detector.alerts, 0);
+        detector.inspect(&syn("10.0.1.10", "10.0.0.2", 80), at(10));
+        assert_eq!(detector.alerts, 1);
+    }
+
+    #[test]
+    fn arp_mac_change_is_reported() {
+        let mut detector = detector();
+        detector.inspect(&arp_reply([2, 0, 0, 0, 0, 0x10], [192, 168, 0, 10]), at(0));
+        detector.inspect(&arp_reply([2, 0, 0, 0, 0, 0x10], [192, 168, 0, 10]), at(1));
+        assert_eq!(detector.alerts, 0);
+        detector.inspect(&arp_reply([2, 0, 0, 0, 0, 0x66], [192, 168, 0, 10]), at(2));
+        assert_eq!(detector.alerts, 1);
+    }
+
+    #[test]
+    fn rogue_dhcp_server_is_reported() {
+        let mut detector = Detector::new(DetectConfig {
+            dhcp_servers: vec!["192.168.0.1".parse().unwrap()],
+            ..Default::default()
+        });
+        detector.inspect(&dhcp_reply("192.168.0.1", DHCP_OFFER), at(0));
+        assert_eq!(detector.alerts, 0);
+        detector.inspect(&dhcp_reply("192.168.0.66", DHCP_OFFER), at(1));
+        detector.inspect(&dhcp_reply("192.168.0.66", DHCP_ACK), at(2));
+        assert_eq!(detector.alerts, 2);
+    }
+
+    #[test]
+    fn idle_entries_are_evicted() {
+        let mut detector = detector();
+        detector.inspect(&syn("10.0.0.1", "10.0.0.2", 80), at(0));
+        detector.inspect(&testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", 80, 1, 0, b""), at(0));
+        detector.inspect(&testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b""), at(0));
+        assert!(!detector.scans.is_empty() && !detector.syns.is_empty() && !detector.stealth.is_empty() && !detector.udp_flows.is_empty());
+
+        // windowを過ぎてから別の通信を見ると、古い記録は取り除かれる
+        detector.inspect(&arp_reply([2, 0, 0, 0, 0, 0x10], [192, 168, 0, 10]), Duration::from_secs(30));
+        assert!(detector.scans.is_empty());
+        assert!(detector.syns.is_empty());
+        assert!(detector.stealth.is_empty());
+        assert!(detector.udp_flows.is_empty());
+    }
+}
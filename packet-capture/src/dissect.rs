@@ -0,0 +1,377 @@
+//! TCP/UDP以外のプロトコルのハンドラ
+//! ARP、ICMP、ICMPv6と、DecodedPacketが外した802.1Q/QinQのVLANタグ、MPLSのラベルを表示する
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use pnet::packet::arp::{ArpOperations, ArpPacket};
+use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
+use pnet::packet::icmpv6::{Icmpv6Packet, Icmpv6Types};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::Packet;
+
+use crate::packet::{Ip, Link, ETHERTYPE_8021AD};
+
+/** 表示する内容。送信元と宛先の1行と、それに続く詳細の行 */
+struct Description {
+    protocol: String,
+    source: String,
+    destination: String,
+    lines: Vec<String>,
+}
+
+impl Description {
+    fn print(&self) {
+        crate::print_summary(&self.protocol, &self.source, &self.destination);
+        for line in self.lines.iter() {
+            println!("{}", line);
+        }
+        println!();
+    }
+}
+
+/** ARPのリクエストとリプライ */
+pub fn arp_handler(payload: &[u8]) {
+    if let Some(description) = describe_arp(payload) {
+        description.print();
+    }
+}
+
+fn describe_arp(payload: &[u8]) -> Option<Description> {
+    let arp = ArpPacket::new(payload)?;
+    let operation = match arp.get_operation() {
+        ArpOperations::Request => "request",
+        ArpOperations::Reply => "reply",
+        _ => "unknown operation",
+    };
+    let mut lines = Vec::new();
+    if arp.get_operation() == ArpOperations::Request {
+        if arp.get_sender_proto_addr() == arp.get_target_proto_addr() {
+            lines.push(format!("Gratuitous ARP for {}", arp.get_sender_proto_addr()));
+        } else {
+            lines.push(format!("Who has {}? Tell {}", arp.get_target_proto_addr(), arp.get_sender_proto_addr()));
+        }
+    } else if arp.get_operation() == ArpOperations::Reply {
+        lines.push(format!("{} is at {}", arp.get_sender_proto_addr(), arp.get_sender_hw_addr()));
+    }
+    Some(Description {
+        protocol: format!("ARP {}", operation),
+        source: format!("{} | {}", arp.get_sender_proto_addr(), arp.get_sender_hw_addr()),
+        destination: format!("{} | {}", arp.get_target_proto_addr(), arp.get_target_hw_addr()),
+        lines,
+    })
+}
+
+/** ICMPのメッセージ。先頭4バイト以降はタイプごとに解釈する */
+pub fn icmp_handler(ip: &Ip, message: &[u8]) {
+    if let Some(description) = describe_icmp(ip, message) {
+        description.print();
+    }
+}
+
+fn describe_icmp(ip: &Ip, message: &[u8]) -> Option<Description> {
+    let icmp = IcmpPacket::new(message)?;
+    let rest = icmp.payload();
+    let code = icmp.get_icmp_code().0;
+    let line = match icmp.get_icmp_type() {
+        IcmpTypes::EchoRequest => format!("Echo request {}", echo_ids(rest)),
+        IcmpTypes::EchoReply => format!("Echo reply {}", echo_ids(rest)),
+        IcmpTypes::DestinationUnreachable => {
+            let reason = match code {
+                0 => "net unreachable",
+                1 => "host unreachable",
+                2 => "protocol unreachable",
+                3 => "port unreachable",
+                4 => "fragmentation needed",
+                9 | 10 | 13 => "administratively prohibited",
+                _ => "unreachable",
+            };
+            format!("Destination unreachable: {} (code {}){}", reason, code, original_ipv4(rest))
+        }
+        IcmpTypes::TimeExceeded => {
+            let reason = if code == 0 { "TTL exceeded in transit" } else { "fragment reassembly time exceeded" };
+            format!("Time exceeded: {}{}", reason, original_ipv4(rest))
+        }
+        IcmpTypes::RedirectMessage if rest.len() >= 4 => {
+            format!("Redirect to {}{}", Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3]), original_ipv4(rest))
+        }
+        other => format!("Type {} code {}", other.0, code),
+    };
+    Some(Description {
+        protocol: "ICMP".to_string(),
+        source: ip.source.to_string(),
+        destination: ip.destination.to_string(),
+        lines: vec![line],
+    })
+}
+
+/** ICMPv6のメッセージ。エラーとエコーに加えて近隣探索とルーター広告を解析する */
+pub fn icmpv6_handler(ip: &Ip, message: &[u8]) {
+    if let Some(description) = describe_icmpv6(ip, message) {
+        description.print();
+    }
+}
+
+fn describe_icmpv6(ip: &Ip, message: &[u8]) -> Option<Description> {
+    let icmp = Icmpv6Packet::new(message)?;
+    let rest = icmp.payload();
+    let code = icmp.get_icmpv6_code().0;
+    let line = match icmp.get_icmpv6_type() {
+        Icmpv6Types::EchoRequest => format!("Echo request {}", echo_ids(rest)),
+        Icmpv6Types::EchoReply => format!("Echo reply {}", echo_ids(rest)),
+        Icmpv6Types::DestinationUnreachable => {
+            let reason = match code {
+                0 => "no route to destination",
+                1 => "administratively prohibited",
+                3 => "address unreachable",
+                4 => "port unreachable",
+                _ => "unreachable",
+            };
+            format!("Destination unreachable: {} (code {}){}", reason, code, original_ipv6(rest))
+        }
+        Icmpv6Types::PacketTooBig if rest.len() >= 4 => {
+            format!("Packet too big: MTU {}{}", u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]), original_ipv6(rest))
+        }
+        Icmpv6Types::TimeExceeded => format!("Time exceeded (code {}){}", code, original_ipv6(rest)),
+        Icmpv6Types::RouterSolicit => format!("Router solicitation{}", ndp_options(rest.get(4..).unwrap_or(&[]))),
+        Icmpv6Types::RouterAdvert if rest.len() >= 12 => {
+            let flags = rest[1];
+            format!(
+                "Router advertisement: hop limit {}, lifetime {}s, managed {}, other {}, reachable {}ms, retrans {}ms{}",
+                rest[0],
+                u16::from_be_bytes([rest[2], rest[3]]),
+                flags & 0x80 != 0,
+                flags & 0x40 != 0,
+                u32::from_be_bytes([rest[4], rest[5], rest[6], rest[7]]),
+                u32::from_be_bytes([rest[8], rest[9], rest[10], rest[11]]),
+                ndp_options(&rest[12..])
+            )
+        }
+        Icmpv6Types::NeighborSolicit if rest.len() >= 20 => {
+            format!("Neighbor solicitation: who has {}{}", ipv6_at(rest, 4), ndp_options(&rest[20..]))
+        }
+        Icmpv6Types::NeighborAdvert if rest.len() >= 20 => {
+            let flags = rest[0];
+            format!(
+                "Neighbor advertisement: {} (router {}, solicited {}, override {}){}",
+                ipv6_at(rest, 4),
+                flags & 0x80 != 0,
+                flags & 0x40 != 0,
+                flags & 0x20 != 0,
+                ndp_options(&rest[20..])
+            )
+        }
+        Icmpv6Types::Redirect if rest.len() >= 36 => {
+            format!("Redirect {} via {}{}", ipv6_at(rest, 20), ipv6_at(rest, 4), ndp_options(&rest[36..]))
+        }
+        other => format!("Type {} code {}", other.0, code),
+    };
+    Some(Description {
+        protocol: "ICMPv6".to_string(),
+        source: ip.source.to_string(),
+        destination: ip.destination.to_string(),
+        lines: vec![line],
+    })
+}
+
+/** DecodedPacketが外した802.1Q/QinQのタグとMPLSのラベルを、外側から順に表示する */
+pub fn link_handler(link: &Link) {
+    for line in describe_link(link) {
+        println!("{}", line);
+    }
+}
+
+fn describe_link(link: &Link) -> Vec<String> {
+    let mut lines = Vec::new();
+    for tag in link.vlans.iter() {
+        let kind = if tag.tpid == ETHERTYPE_8021AD { "802.1ad" } else { "802.1Q" };
+        lines.push(format!("{} VLAN {} (priority {}, drop eligible {})", kind, tag.id, tag.priority, tag.drop_eligible));
+    }
+    for mpls in link.mpls_labels.iter() {
+        lines.push(format!("MPLS label {} (traffic class {}, ttl {}{})", mpls.label, mpls.traffic_class, mpls.ttl, if mpls.bottom { ", bottom of stack" } else { "" }));
+    }
+    lines
+}
+
+fn echo_ids(rest: &[u8]) -> String {
+    if rest.len() < 4 {
+        return String::new();
+    }
+    format!("id={} seq={}", u16::from_be_bytes([rest[0], rest[1]]), u16::from_be_bytes([rest[2], rest[3]]))
+}
+
+fn ipv6_at(bytes: &[u8], offset: usize) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&bytes[offset..offset + 16]);
+    Ipv6Addr::from(octets)
+}
+
+/** ICMPエラーに含まれる元のIPv4パケットの宛先 */
+fn original_ipv4(rest: &[u8]) -> String {
+    match rest.get(4..).and_then(Ipv4Packet::new) {
+        Some(original) => format!(", original {} -> {} ({})", original.get_source(), original.get_destination(), original.get_next_level_protocol()),
+        None => String::new(),
+    }
+}
+
+/** ICMPv6エラーに含まれる元のIPv6パケットの宛先 */
+fn original_ipv6(rest: &[u8]) -> String {
+    match rest.get(4..).and_then(Ipv6Packet::new) {
+        Some(original) => format!(", original {} -> {} ({})", original.get_source(), original.get_destination(), original.get_next_header()),
+        None => String::new(),
+    }
+}
+
+/** 近隣探索のオプションのうち、リンク層アドレス、プレフィックス、MTUを表示用の文字列にする */
+fn ndp_options(mut options: &[u8]) -> String {
+    let mut result = String::new();
+    while options.len() >= 2 {
+        // 長さは8バイト単位
+        let len = options[1] as usize * 8;
+        if len == 0 || options.len() < len {
+            break;
+        }
+        let body = &options[2..len];
+        match options[0] {
+            1 if body.len() >= 6 => result += &format!(", source {}", mac(body)),
+            2 if body.len() >= 6 => result += &format!(", target {}", mac(body)),
+            3 if body.len() >= 30 => result += &format!(", prefix {}/{} valid {}s", ipv6_at(body, 14), body[0], u32::from_be_bytes([body[2], body[3], body[4], body[5]])),
+            5 if body.len() >= 6 => result += &format!(", mtu {}", u32::from_be_bytes([body[2], body[3], body[4], body[5]])),
+            _ => {}
+        }
+        options = &options[len..];
+    }
+    result
+}
+
+fn mac(bytes: &[u8]) -> String {
+    bytes[..6].iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DecodedPacket;
+    use crate::testutil::{self, PROTO_UDP};
+
+    const PROTO_ICMP: u8 = 1;
+    const PROTO_ICMPV6: u8 = 58;
+
+    fn arp(operation: u16, sender: [u8; 4], target: [u8; 4]) -> Vec<u8> {
+        let mut arp = vec![0, 1, 8, 0, 6, 4];
+        arp.extend_from_slice(&operation.to_be_bytes());
+        arp.extend_from_slice(&testutil::SOURCE_MAC);
+        arp.extend_from_slice(&sender);
+        arp.extend_from_slice(&[0; 6]);
+        arp.extend_from_slice(&target);
+        arp
+    }
+
+    fn icmp(version: u8, message: &[u8]) -> Description {
+        let packet = if version == 4 {
+            testutil::ipv4(PROTO_ICMP, "10.0.0.1", "10.0.0.2", message)
+        } else {
+            testutil::ipv6(PROTO_ICMPV6, "fe80::1", "ff02::1", message)
+        };
+        let (ip, message) = if version == 4 { Ip::parse_v4(&packet) } else { Ip::parse_v6(&packet) }.unwrap();
+        if version == 4 { describe_icmp(&ip, message) } else { describe_icmpv6(&ip, message) }.unwrap()
+    }
+
+    /** 種類、コード、チェックサム(0)に続けて本体を置く */
+    fn message(icmp_type: u8, code: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![icmp_type, code, 0, 0];
+        message.extend_from_slice(body);
+        message
+    }
+
+    #[test]
+    fn arp_requests_and_replies() {
+        let request = describe_arp(&arp(1, [192, 168, 0, 10], [192, 168, 0, 1])).unwrap();
+        assert_eq!(request.protocol, "ARP request");
+        assert_eq!(request.source, "192.168.0.10 | 02:00:00:00:00:01");
+        assert_eq!(request.destination, "192.168.0.1 | 00:00:00:00:00:00");
+        assert_eq!(request.lines, vec!["Who has 192.168.0.1? Tell 192.168.0.10"]);
+
+        let gratuitous = describe_arp(&arp(1, [192, 168, 0, 10], [192, 168, 0, 10])).unwrap();
+        assert_eq!(gratuitous.lines, vec!["Gratuitous ARP for 192.168.0.10"]);
+
+        let reply = describe_arp(&arp(2, [192, 168, 0, 1], [192, 168, 0, 10])).unwrap();
+        assert_eq!(reply.protocol, "ARP reply");
+        assert_eq!(reply.lines, vec!["192.168.0.1 is at 02:00:00:00:00:01"]);
+
+        assert!(describe_arp(&arp(1, [0; 4], [0; 4])[..20]).is_none());
+    }
+
+    #[test]
+    fn icmp_echo_and_errors() {
+        let echo = icmp(4, &message(8, 0, &[0, 1, 0, 2]));
+        assert_eq!((echo.protocol.as_str(), echo.source.as_str(), echo.destination.as_str()), ("ICMP", "10.0.0.1", "10.0.0.2"));
+        assert_eq!(echo.lines, vec!["Echo request id=1 seq=2"]);
+        assert_eq!(icmp(4, &message(0, 0, &[0, 1, 0, 2])).lines, vec!["Echo reply id=1 seq=2"]);
+
+        // エラーには元のパケットのIPヘッダが含まれる
+        let mut body = vec![0; 4];
+        body.extend_from_slice(&testutil::ipv4(PROTO_UDP, "10.0.0.2", "10.0.0.9", &testutil::udp(40000, 53, b"")));
+        assert_eq!(icmp(4, &message(3, 3, &body)).lines, vec!["Destination unreachable: port unreachable (code 3), original 10.0.0.2 -> 10.0.0.9 (Udp)"]);
+        assert_eq!(icmp(4, &message(11, 0, &body)).lines, vec!["Time exceeded: TTL exceeded in transit, original 10.0.0.2 -> 10.0.0.9 (Udp)"]);
+        let mut redirect = vec![10, 0, 0, 254];
+        redirect.extend_from_slice(&body[4..]);
+        assert_eq!(icmp(4, &message(5, 1, &redirect)).lines, vec!["Redirect to 10.0.0.254, original 10.0.0.2 -> 10.0.0.9 (Udp)"]);
+        assert_eq!(icmp(4, &message(13, 0, &[])).lines, vec!["Type 13 code 0"]);
+    }
+
+    #[test]
+    fn neighbor_discovery() {
+        let target: Ipv6Addr = "fe80::2".parse().unwrap();
+        let mut solicit = vec![0; 4];
+        solicit.extend_from_slice(&target.octets());
+        // 送信元リンク層アドレスのオプション
+        solicit.extend_from_slice(&[1, 1, 2, 0, 0, 0, 0, 1]);
+        let description = icmp(6, &message(135, 0, &solicit));
+        assert_eq!(description.protocol, "ICMPv6");
+        assert_eq!(description.lines, vec!["Neighbor solicitation: who has fe80::2, source 02:00:00:00:00:01"]);
+
+        let mut advert = vec![0x60, 0, 0, 0];
+        advert.extend_from_slice(&target.octets());
+        advert.extend_from_slice(&[2, 1, 2, 0, 0, 0, 0, 2]);
+        assert_eq!(icmp(6, &message(136, 0, &advert)).lines, vec!["Neighbor advertisement: fe80::2 (router false, solicited true, override true), target 02:00:00:00:00:02"]);
+
+        let mut router = vec![64, 0x80, 0x07, 0x08, 0, 0, 0, 0, 0, 0, 0, 0];
+        // プレフィックス情報とMTUのオプション
+        router.extend_from_slice(&[3, 4, 64, 0xc0, 0, 0, 0x0e, 0x10, 0, 0, 0x07, 0x08, 0, 0, 0, 0]);
+        router.extend_from_slice(&"2001:db8::".parse::<Ipv6Addr>().unwrap().octets());
+        router.extend_from_slice(&[5, 1, 0, 0, 0, 0, 0x05, 0xdc]);
+        assert_eq!(
+            icmp(6, &message(134, 0, &router)).lines,
+            vec!["Router advertisement: hop limit 64, lifetime 1800s, managed true, other false, reachable 0ms, retrans 0ms, prefix 2001:db8::/64 valid 3600s, mtu 1500"]
+        );
+
+        assert_eq!(icmp(6, &message(133, 0, &[0, 0, 0, 0, 1, 1, 2, 0, 0, 0, 0, 1])).lines, vec!["Router solicitation, source 02:00:00:00:00:01"]);
+        // 長さが0のオプションで止まる
+        assert_eq!(icmp(6, &message(133, 0, &[0, 0, 0, 0, 1, 0, 2, 0, 0, 0, 0, 1])).lines, vec!["Router solicitation"]);
+        // 短すぎるルーター広告は種類だけを表示する
+        assert_eq!(icmp(6, &message(134, 0, &[64, 0])).lines, vec!["Type 134 code 0"]);
+        assert_eq!(icmp(6, &message(128, 0, &[0, 7, 0, 1])).lines, vec!["Echo request id=7 seq=1"]);
+    }
+
+    #[test]
+    fn vlan_tags_and_mpls_labels_from_the_decoded_packet() {
+        let inner = testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b"");
+        let mut frame = testutil::vlan(5 << 13 | 0x1000 | 100, &testutil::vlan(20, &inner));
+        frame[12..14].copy_from_slice(&0x88a8u16.to_be_bytes());
+        let packet = DecodedPacket::decode(&frame).unwrap();
+        assert_eq!(
+            describe_link(&packet.link),
+            vec!["802.1ad VLAN 100 (priority 5, drop eligible true)", "802.1Q VLAN 20 (priority 0, drop eligible false)"]
+        );
+
+        let mut mpls = Vec::new();
+        mpls.extend_from_slice(&(16u32 << 12 | 3 << 9 | 64).to_be_bytes());
+        mpls.extend_from_slice(&(17u32 << 12 | 0x100 | 63).to_be_bytes());
+        mpls.extend_from_slice(&inner[14..]);
+        let frame = testutil::ethernet(0x8847, &mpls);
+        let packet = DecodedPacket::decode(&frame).unwrap();
+        assert_eq!(describe_link(&packet.link), vec!["MPLS label 16 (traffic class 3, ttl 64)", "MPLS label 17 (traffic class 0, ttl 63, bottom of stack)"]);
+        assert!(packet.ip.is_some());
+    }
+}
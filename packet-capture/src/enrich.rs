@@ -0,0 +1,175 @@
+//! 送信元と宛先のアドレスに、逆引きしたホスト名と国、AS番号を添える
+//! 国とAS番号はローカルのMaxMind DBファイルから引き、逆引きはパケットの処理を待たせないように別スレッドで行う
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::info;
+use serde_json::{json, Value};
+
+use crate::mmdb::Database;
+
+/** --resolveと--geoipの設定 */
+#[derive(Clone, Default)]
+pub struct EnrichConfig {
+    /** ホスト名を逆引きするか */
+    pub resolve: bool,
+    /** 国やAS番号を引くデータベース。Country/City/ASNを組み合わせられる */
+    pub databases: Vec<PathBuf>,
+}
+
+/** 1つのアドレスについて分かったこと */
+#[derive(Default)]
+pub struct Endpoint {
+    pub hostname: Option<String>,
+    /** ISO 3166-1の国コード */
+    pub country: Option<String>,
+    pub asn: Option<u64>,
+    pub organization: Option<String>,
+}
+
+impl Endpoint {
+    pub fn is_empty(&self) -> bool {
+        self.hostname.is_none() && self.country.is_none() && self.asn.is_none()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "hostname": self.hostname,
+            "country": self.country,
+            "asn": self.asn,
+            "organization": self.organization,
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        parts.extend(self.hostname.clone());
+        parts.extend(self.country.clone());
+        if let Some(asn) = self.asn {
+            match self.organization {
+                Some(ref organization) => parts.push(format!("AS{} {}", asn, organization)),
+                None => parts.push(format!("AS{}", asn)),
+            }
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/** 逆引きの結果。問い合わせ中と名前がなかったアドレスはNone */
+type HostnameCache = Arc<Mutex<HashMap<IpAddr, Option<String>>>>;
+
+pub struct Enricher {
+    databases: Vec<Database>,
+    hostnames: HostnameCache,
+    /** 逆引きスレッドへの依頼。--resolveでなければNone */
+    resolver: Option<Sender<IpAddr>>,
+}
+
+impl Enricher {
+    pub fn new(config: &EnrichConfig) -> Result<Enricher, failure::Error> {
+        let mut databases = Vec::new();
+        for path in config.databases.iter() {
+            let database = Database::open(path)?;
+            info!("Loaded {} from {}", database.database_type, path.display());
+            databases.push(database);
+        }
+        let hostnames = HostnameCache::default();
+        let resolver = if config.resolve { Some(spawn_resolver(hostnames.clone())) } else { None };
+        Ok(Enricher { databases, hostnames, resolver })
+    }
+
+    /** アドレスについて分かっていることを返す
+     * ホスト名はまだ逆引きしていなければ依頼だけして、以降のパケットで表示する
+     */
+    pub fn describe(&self, addr: IpAddr) -> Endpoint {
+        let mut endpoint = Endpoint::default();
+        if let Some(resolver) = self.resolver.as_ref() {
+            let mut hostnames = self.hostnames.lock().unwrap();
+            match hostnames.get(&addr) {
+                Some(hostname) => endpoint.hostname = hostname.clone(),
+                None => {
+                    hostnames.insert(addr, None);
+                    // スレッドが終了していれば逆引きを諦める
+                    let _ = resolver.send(addr);
+                }
+            }
+        }
+
+        for database in self.databases.iter() {
+            let record = match database.lookup(addr) {
+                Some(record) => record,
+                None => continue,
+            };
+            // Cityにはcountry、登録国しか分からないネットワークにはregistered_countryだけがある
+            let country = record["country"]["iso_code"].as_str().or_else(|| record["registered_country"]["iso_code"].as_str());
+            if endpoint.country.is_none() {
+                endpoint.country = country.map(String::from);
+            }
+            if endpoint.asn.is_none() {
+                endpoint.asn = record["autonomous_system_number"].as_u64();
+                endpoint.organization = record["autonomous_system_organization"].as_str().map(String::from);
+            }
+        }
+        endpoint
+    }
+}
+
+/** 依頼されたアドレスを順に逆引きしてキャッシュに入れるスレッドを起動する */
+fn spawn_resolver(hostnames: HostnameCache) -> Sender<IpAddr> {
+    let (sender, requests) = mpsc::channel::<IpAddr>();
+    thread::spawn(move || {
+        for addr in requests {
+            if let Some(hostname) = reverse_lookup(addr) {
+                hostnames.lock().unwrap().insert(addr, Some(hostname));
+            }
+        }
+    });
+    sender
+}
+
+/** getnameinfoで逆引きする。/etc/hostsとシステムのリゾルバの設定に従う */
+fn reverse_lookup(addr: IpAddr) -> Option<String> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let length = match addr {
+        IpAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.octets());
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        IpAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_addr.s6_addr = addr.octets();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    // NI_NAMEREQDを付けると、名前がない時にアドレスの文字列ではなくエラーが返る
+    let ret = unsafe {
+        libc::getnameinfo(
+            &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+            length as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    let hostname = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+    Some(hostname.to_string_lossy().into_owned())
+}
@@ -0,0 +1,323 @@
+//! tcpdump風のキャプチャフィルタ
+//! 式を構文木にし、ユーザー空間での判定と、カーネルに渡すclassic BPFへのコンパイルの両方に使う
+//!
+//!   host 10.0.0.1 and (tcp port 80 or udp port 53)
+//!   src net 192.168.0.0/16 and not icmp
+
+use std::net::IpAddr;
+
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ip::IpNextHeaderProtocols;
+
+use crate::packet::DecodedPacket;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Direction {
+    Src,
+    Dst,
+    Any,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Proto {
+    Ip,
+    Ip6,
+    Arp,
+    Tcp,
+    Udp,
+    Icmp,
+    Icmp6,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Proto(Proto),
+    Host(Direction, IpAddr),
+    /** アドレスとプレフィックス長 */
+    Net(Direction, IpAddr, u8),
+    Port(Direction, u16),
+}
+
+/** フィルタの判定に使う、フレームから取り出した値 */
+struct Fields {
+    ethertype: u16,
+    ip_proto: Option<u8>,
+    src: Option<IpAddr>,
+    dst: Option<IpAddr>,
+    src_port: Option<u16>,
+    dst_port: Option<u16>,
+}
+
+impl Fields {
+    fn from_frame(frame: &[u8]) -> Option<Fields> {
+        let packet = DecodedPacket::decode(frame)?;
+        Some(Fields {
+            ethertype: packet.link.ethertype.0,
+            ip_proto: packet.ip.as_ref().map(|ip| ip.protocol.0),
+            src: packet.ip.as_ref().map(|ip| ip.source),
+            dst: packet.ip.as_ref().map(|ip| ip.destination),
+            src_port: packet.transport.as_ref().map(|transport| transport.source_port),
+            dst_port: packet.transport.as_ref().map(|transport| transport.destination_port),
+        })
+    }
+}
+
+impl Expr {
+    /** 式を構文解析する */
+    pub fn parse(expression: &str) -> Result<Expr, failure::Error> {
+        let tokens = tokenize(expression);
+        let mut parser = Parser { tokens, position: 0 };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(failure::err_msg(format!("Unexpected token in filter: {}", token)));
+        }
+        Ok(expr)
+    }
+
+    /** フレームがフィルタに一致するか */
+    pub fn matches(&self, frame: &[u8]) -> bool {
+        match Fields::from_frame(frame) {
+            Some(fields) => self.eval(&fields),
+            None => false,
+        }
+    }
+
+    fn eval(&self, fields: &Fields) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(fields) && b.eval(fields),
+            Expr::Or(a, b) => a.eval(fields) || b.eval(fields),
+            Expr::Not(a) => !a.eval(fields),
+            Expr::Proto(proto) => match proto {
+                Proto::Ip => fields.ethertype == EtherTypes::Ipv4.0,
+                Proto::Ip6 => fields.ethertype == EtherTypes::Ipv6.0,
+                Proto::Arp => fields.ethertype == EtherTypes::Arp.0,
+                Proto::Tcp => fields.ip_proto == Some(IpNextHeaderProtocols::Tcp.0),
+                Proto::Udp => fields.ip_proto == Some(IpNextHeaderProtocols::Udp.0),
+                Proto::Icmp => fields.ethertype == EtherTypes::Ipv4.0 && fields.ip_proto == Some(IpNextHeaderProtocols::Icmp.0),
+                Proto::Icmp6 => fields.ethertype == EtherTypes::Ipv6.0 && fields.ip_proto == Some(IpNextHeaderProtocols::Icmpv6.0),
+            },
+            Expr::Host(direction, addr) => match_direction(*direction, fields.src, fields.dst, |ip| ip == *addr),
+            Expr::Net(direction, addr, prefix) => match_direction(*direction, fields.src, fields.dst, |ip| in_network(ip, *addr, *prefix)),
+            Expr::Port(direction, port) => match_direction(*direction, fields.src_port, fields.dst_port, |p| p == *port),
+        }
+    }
+}
+
+fn match_direction<T: Copy, F: Fn(T) -> bool>(direction: Direction, src: Option<T>, dst: Option<T>, f: F) -> bool {
+    let src = src.is_some_and(&f);
+    let dst = dst.is_some_and(&f);
+    match direction {
+        Direction::Src => src,
+        Direction::Dst => dst,
+        Direction::Any => src || dst,
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - u32::from(prefix)) };
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - u32::from(prefix)) };
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+fn tokenize(expression: &str) -> Vec<String> {
+    expression
+        .replace('(', " ( ")
+        .replace(')', " ) ")
+        .replace('!', " ! ")
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<String, failure::Error> {
+        let token = self.tokens.get(self.position).cloned().ok_or_else(|| failure::err_msg("Unexpected end of filter"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, failure::Error> {
+        let mut expr = self.parse_and()?;
+        while let Some("or") | Some("||") = self.peek() {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, failure::Error> {
+        let mut expr = self.parse_not()?;
+        while let Some("and") | Some("&&") = self.peek() {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, failure::Error> {
+        match self.peek() {
+            Some("not") | Some("!") => {
+                self.position += 1;
+                Ok(Expr::Not(Box::new(self.parse_not()?)))
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, failure::Error> {
+        let token = self.next()?;
+        match token.as_str() {
+            "(" => {
+                let expr = self.parse_or()?;
+                match self.next()?.as_str() {
+                    ")" => Ok(expr),
+                    other => Err(failure::err_msg(format!("Expected ) but found {}", other))),
+                }
+            }
+            "proto" => {
+                let name = self.next()?;
+                parse_proto(&name).map(Expr::Proto).ok_or_else(|| failure::err_msg(format!("Unknown protocol {}", name)))
+            }
+            "src" => self.parse_qualified(Direction::Src),
+            "dst" => self.parse_qualified(Direction::Dst),
+            "host" | "net" | "port" => {
+                self.position -= 1;
+                self.parse_qualified(Direction::Any)
+            }
+            name => match parse_proto(name) {
+                // "tcp port 80" のようにプロトコルで限定されたポート
+                Some(proto) if (proto == Proto::Tcp || proto == Proto::Udp) && matches_port_qualifier(self.peek(), self.tokens.get(self.position + 1)) => {
+                    let port = self.parse_primary()?;
+                    Ok(Expr::And(Box::new(Expr::Proto(proto)), Box::new(port)))
+                }
+                Some(proto) => Ok(Expr::Proto(proto)),
+                None => Err(failure::err_msg(format!("Unknown filter primitive {}", name))),
+            },
+        }
+    }
+
+    /** src/dstの後に続く host/net/port、または省略されたhostのアドレス */
+    fn parse_qualified(&mut self, direction: Direction) -> Result<Expr, failure::Error> {
+        let token = self.next()?;
+        match token.as_str() {
+            "host" => Ok(Expr::Host(direction, self.next()?.parse()?)),
+            "net" => {
+                let value = self.next()?;
+                let mut parts = value.splitn(2, '/');
+                let addr: IpAddr = parts.next().unwrap().parse()?;
+                let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix = match parts.next() {
+                    Some(prefix) => prefix.parse()?,
+                    None => max_prefix,
+                };
+                if prefix > max_prefix {
+                    return Err(failure::err_msg(format!("Invalid prefix length in {}", value)));
+                }
+                Ok(Expr::Net(direction, addr, prefix))
+            }
+            "port" => Ok(Expr::Port(direction, self.next()?.parse()?)),
+            addr => Ok(Expr::Host(direction, addr.parse().map_err(|_| failure::err_msg(format!("Expected host, net or port but found {}", addr)))?)),
+        }
+    }
+}
+
+fn matches_port_qualifier(next: Option<&str>, after: Option<&String>) -> bool {
+    match next {
+        Some("port") => true,
+        Some("src") | Some("dst") => after.is_some_and(|token| token == "port"),
+        _ => false,
+    }
+}
+
+fn parse_proto(name: &str) -> Option<Proto> {
+    match name {
+        "ip" => Some(Proto::Ip),
+        "ip6" => Some(Proto::Ip6),
+        "arp" => Some(Proto::Arp),
+        "tcp" => Some(Proto::Tcp),
+        "udp" => Some(Proto::Udp),
+        "icmp" => Some(Proto::Icmp),
+        "icmp6" => Some(Proto::Icmp6),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn host(direction: Direction, addr: &str) -> Box<Expr> {
+        Box::new(Expr::Host(direction, addr.parse().unwrap()))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let expr = Expr::parse("host 10.0.0.1 and tcp port 80 or udp").unwrap();
+        let tcp_port = Box::new(Expr::And(Box::new(Expr::Proto(Proto::Tcp)), Box::new(Expr::Port(Direction::Any, 80))));
+        assert_eq!(expr, Expr::Or(Box::new(Expr::And(host(Direction::Any, "10.0.0.1"), tcp_port)), Box::new(Expr::Proto(Proto::Udp))));
+    }
+
+    #[test]
+    fn parses_negation_parentheses_and_qualifiers() {
+        assert_eq!(Expr::parse("!(src 10.0.0.1 || dst host ::1)").unwrap(), Expr::Not(Box::new(Expr::Or(host(Direction::Src, "10.0.0.1"), host(Direction::Dst, "::1")))));
+        assert_eq!(Expr::parse("not not arp").unwrap(), Expr::Not(Box::new(Expr::Not(Box::new(Expr::Proto(Proto::Arp))))));
+        assert_eq!(Expr::parse("proto icmp6 && src net 10.0.0.0/8").unwrap(), Expr::And(Box::new(Expr::Proto(Proto::Icmp6)), Box::new(Expr::Net(Direction::Src, "10.0.0.0".parse().unwrap(), 8))));
+        assert_eq!(Expr::parse("net 2001:db8::").unwrap(), Expr::Net(Direction::Any, "2001:db8::".parse().unwrap(), 128));
+        assert_eq!(
+            Expr::parse("udp dst port 53").unwrap(),
+            Expr::And(Box::new(Expr::Proto(Proto::Udp)), Box::new(Expr::Port(Direction::Dst, 53)))
+        );
+        // tcpとsrcの組み合わせでもportが続かなければ、別々のプリミティブとして扱う
+        assert!(Expr::parse("tcp src 10.0.0.1").is_err());
+        assert_eq!(Expr::parse("tcp and src 10.0.0.1").unwrap(), Expr::And(Box::new(Expr::Proto(Proto::Tcp)), host(Direction::Src, "10.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for expression in ["", "host", "(tcp", "tcp)", "tcp udp", "port http", "port 65536", "net 10.0.0.0/33", "net ::/129", "src foo", "proto sctp", "vlan 10", "tcp and"].iter() {
+            assert!(Expr::parse(expression).is_err(), "{} should be rejected", expression);
+        }
+    }
+
+    #[test]
+    fn matches_inside_vlan_tags() {
+        let frame = testutil::vlan(10, &testutil::tcp_frame("192.168.1.10", 40000, "10.1.2.3", 443, 1, testutil::TCP_SYN, b""));
+        for expression in ["tcp", "ip", "dst port 443", "src net 192.168.0.0/16", "dst host 10.1.2.3", "not udp"].iter() {
+            assert!(Expr::parse(expression).unwrap().matches(&frame), "{}", expression);
+        }
+        for expression in ["udp", "ip6", "src port 443", "dst net 192.168.0.0/16", "src 10.1.2.3"].iter() {
+            assert!(!Expr::parse(expression).unwrap().matches(&frame), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn network_prefixes_cover_both_families() {
+        assert!(in_network("10.20.30.40".parse().unwrap(), "10.20.0.0".parse().unwrap(), 16));
+        assert!(!in_network("10.21.30.40".parse().unwrap(), "10.20.0.0".parse().unwrap(), 16));
+        assert!(in_network("1.2.3.4".parse().unwrap(), "0.0.0.0".parse().unwrap(), 0));
+        assert!(in_network("2001:db8:1::5".parse().unwrap(), "2001:db8::".parse().unwrap(), 32));
+        assert!(!in_network("2001:db9::5".parse().unwrap(), "2001:db8::".parse().unwrap(), 32));
+        assert!(!in_network("10.0.0.1".parse().unwrap(), "::".parse().unwrap(), 0));
+    }
+}
@@ -0,0 +1,485 @@
+//! 双方向の5タプルでパケットを集計するフローテーブル
+//! --statsの時はパケットごとの表示の代わりに、通信量の多いフローを定期的に表示する
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use std::net::IpAddr;
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::tcp::TcpFlags;
+
+use crate::packet::DecodedPacket;
+
+/** 通信のないフローをテーブルから外すまでの時間 */
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/** 閉じたフローを残しておく時間。最後のACKや再送もそのフローに数える */
+const CLOSED_TIMEOUT: Duration = Duration::from_secs(10);
+/** 期限切れのフローを探す間隔 */
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/** アドレスとポート。TCPとUDP以外はポートを持たない */
+type Endpoint = (IpAddr, Option<u16>);
+
+/** プロトコルと、順序を正規化した両端 */
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct FlowKey {
+    protocol: IpNextHeaderProtocol,
+    low: Endpoint,
+    high: Endpoint,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum TcpState {
+    /** SYNを見ていない途中からのフロー */
+    Unknown,
+    SynSent,
+    SynReceived,
+    Established,
+    Closing,
+    Closed,
+    Reset,
+}
+
+#[derive(Default)]
+struct Direction {
+    packets: u64,
+    bytes: u64,
+}
+
+struct Flow {
+    /** 最初に見たパケットの送信元。SYNを見た場合はSYNの送信元 */
+    initiator: Endpoint,
+    responder: Endpoint,
+    forward: Direction,
+    backward: Direction,
+    first_seen: Duration,
+    last_seen: Duration,
+    state: TcpState,
+    syn_at: Option<Duration>,
+    /** 3ウェイハンドシェイクのSYNから最後のACKまで */
+    rtt: Option<Duration>,
+    /** ハンドシェイクを見て確立したか。途中から見たフローでは閉じかけていてもfalse */
+    established: bool,
+    /** 方向ごとにFINを見たか。再送されたFINで閉じたとみなさないよう、両方の方向で揃ったら閉じる */
+    fin_from_initiator: bool,
+    fin_from_responder: bool,
+}
+
+impl Flow {
+    fn bytes(&self) -> u64 {
+        self.forward.bytes + self.backward.bytes
+    }
+
+    /** 確立した後のSYNは再送なので、状態も開始側も変えない。閉じた後のSYNは同じポートでの新しい接続 */
+    fn accepts_syn(&self) -> bool {
+        !self.established || matches!(self.state, TcpState::Closed | TcpState::Reset)
+    }
+
+    /** テーブルから外してよいか */
+    fn is_expired(&self, now: Duration) -> bool {
+        let timeout = match self.state {
+            TcpState::Closed | TcpState::Reset => CLOSED_TIMEOUT,
+            _ => IDLE_TIMEOUT,
+        };
+        now.checked_sub(self.last_seen).is_some_and(|idle| idle >= timeout)
+    }
+
+    /** TCPのフラグで状態を進め、ハンドシェイクからRTTを見積もる */
+    fn update_tcp(&mut self, from_initiator: bool, flags: u16, now: Duration) {
+        if flags & TcpFlags::RST != 0 {
+            self.state = TcpState::Reset;
+            return;
+        }
+        if flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0 {
+            if self.accepts_syn() {
+                // 閉じた後のSYNは新しい接続なので、前の接続のFINを忘れる
+                if matches!(self.state, TcpState::Closed | TcpState::Reset) {
+                    self.fin_from_initiator = false;
+                    self.fin_from_responder = false;
+                }
+                self.syn_at = Some(now);
+                self.state = TcpState::SynSent;
+                self.established = false;
+            }
+        } else if flags & TcpFlags::SYN != 0 && !from_initiator {
+            self.state = TcpState::SynReceived;
+        } else if flags & TcpFlags::ACK != 0 && from_initiator && self.state == TcpState::SynReceived {
+            self.rtt = self.syn_at.and_then(|syn_at| now.checked_sub(syn_at));
+            self.state = TcpState::Established;
+            self.established = true;
+        }
+        if flags & TcpFlags::FIN != 0 {
+            if from_initiator {
+                self.fin_from_initiator = true;
+            } else {
+                self.fin_from_responder = true;
+            }
+            self.state = if self.fin_from_initiator && self.fin_from_responder { TcpState::Closed } else { TcpState::Closing };
+        }
+    }
+}
+
+pub struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    /** 表示するフローの数 */
+    top: usize,
+    interval: Duration,
+    last_printed: Option<Duration>,
+    packets: u64,
+    /** IP以外やヘッダが壊れていて集計できなかったパケット */
+    unrecognized: u64,
+    last_expired: Option<Duration>,
+    /** テーブルから外したフローの数と合計。終了時の集計に含める */
+    expired_flows: u64,
+    expired: Direction,
+}
+
+impl FlowTable {
+    pub fn new(top: usize, interval: Duration) -> FlowTable {
+        FlowTable {
+            flows: HashMap::new(),
+            top,
+            interval,
+            last_printed: None,
+            packets: 0,
+            unrecognized: 0,
+            last_expired: None,
+            expired_flows: 0,
+            expired: Direction::default(),
+        }
+    }
+
+    /** フレームを集計し、前回の表示から間隔が空いていれば表を表示し直す */
+    pub fn record(&mut self, frame: &[u8], timestamp: Duration) {
+        self.packets += 1;
+        if !self.add(frame, timestamp) {
+            self.unrecognized += 1;
+        }
+        self.refresh(timestamp);
+    }
+
+    /** 前回の表示から間隔が空いていれば表を表示し直す。パケットが来ない間もライブキャプチャのtickから呼ばれる */
+    pub fn refresh(&mut self, now: Duration) {
+        self.expire(now);
+        let due = self.last_printed.is_none_or(|printed| now.checked_sub(printed).is_some_and(|elapsed| elapsed >= self.interval));
+        if due {
+            // 画面を消して先頭から書き直す
+            print!("\x1b[2J\x1b[H");
+            self.print_table(self.top);
+            self.last_printed = Some(now);
+        }
+    }
+
+    /** 終了時の集計。テーブルに残っているすべてのフローを表示する */
+    pub fn print_summary(&self) {
+        println!(
+            "Captured {} packets in {} flows ({} not in any flow)",
+            self.packets,
+            self.flows.len() as u64 + self.expired_flows,
+            self.unrecognized
+        );
+        if self.expired_flows > 0 {
+            println!("{} closed or idle flows not shown: {} packets, {} bytes", self.expired_flows, self.expired.packets, self.expired.bytes);
+        }
+        self.print_table(self.flows.len());
+    }
+
+    /** 閉じてから時間の経ったフローと、通信のないフローをテーブルから外し、合計だけ残す */
+    fn expire(&mut self, now: Duration) {
+        let due = self.last_expired.is_none_or(|expired| now.checked_sub(expired).is_some_and(|elapsed| elapsed >= EXPIRE_INTERVAL));
+        if !due {
+            return;
+        }
+        self.last_expired = Some(now);
+        let expired = &mut self.expired;
+        let expired_flows = &mut self.expired_flows;
+        self.flows.retain(|_, flow| {
+            if !flow.is_expired(now) {
+                return true;
+            }
+            *expired_flows += 1;
+            expired.packets += flow.forward.packets + flow.backward.packets;
+            expired.bytes += flow.bytes();
+            false
+        });
+    }
+
+    fn add(&mut self, frame: &[u8], now: Duration) -> bool {
+        let packet = match DecodedPacket::decode(frame) {
+            Some(packet) => packet,
+            None => return false,
+        };
+        let ip = match packet.ip {
+            Some(ref ip) => ip,
+            None => return false,
+        };
+        let protocol = ip.protocol;
+        let transport = packet.transport.as_ref();
+        let source = (ip.source, transport.map(|transport| transport.source_port));
+        let destination = (ip.destination, transport.map(|transport| transport.destination_port));
+        let tcp_flags = transport.and_then(|transport| transport.tcp).map(|tcp| tcp.flags);
+        let length = frame.len();
+
+        let key = if source <= destination {
+            FlowKey { protocol, low: source, high: destination }
+        } else {
+            FlowKey { protocol, low: destination, high: source }
+        };
+        let is_syn = tcp_flags.is_some_and(|flags| flags & TcpFlags::SYN != 0 && flags & TcpFlags::ACK == 0);
+        let flow = self.flows.entry(key).or_insert_with(|| Flow {
+            initiator: source,
+            responder: destination,
+            forward: Direction::default(),
+            backward: Direction::default(),
+            first_seen: now,
+            last_seen: now,
+            state: TcpState::Unknown,
+            syn_at: None,
+            rtt: None,
+            established: false,
+            fin_from_initiator: false,
+            fin_from_responder: false,
+        });
+        // 途中から見たフローでも、SYNを見たらその送信元を開始側とする
+        if is_syn && flow.initiator != source && flow.accepts_syn() {
+            std::mem::swap(&mut flow.initiator, &mut flow.responder);
+            std::mem::swap(&mut flow.forward, &mut flow.backward);
+            std::mem::swap(&mut flow.fin_from_initiator, &mut flow.fin_from_responder);
+        }
+
+        let from_initiator = flow.initiator == source;
+        let direction = if from_initiator { &mut flow.forward } else { &mut flow.backward };
+        direction.packets += 1;
+        direction.bytes += length as u64;
+        flow.last_seen = now;
+        if let Some(flags) = tcp_flags {
+            flow.update_tcp(from_initiator, flags, now);
+        }
+        true
+    }
+
+    fn print_table(&self, limit: usize) {
+        let mut flows: Vec<(&FlowKey, &Flow)> = self.flows.iter().collect();
+        flows.sort_by_key(|flow| std::cmp::Reverse(flow.1.bytes()));
+
+        println!(
+            "{:<6} {:<45} {:<45} {:>8} {:>10} {:>8} {:>10} {:<12} {:>10} {:>10}",
+            "proto", "initiator", "responder", "pkts>", "bytes>", "<pkts", "<bytes", "state", "duration", "rtt"
+        );
+        for (key, flow) in flows.iter().take(limit) {
+            let state = if key.protocol == IpNextHeaderProtocols::Tcp { format!("{:?}", flow.state) } else { "-".to_string() };
+            let rtt = flow.rtt.map_or_else(|| "-".to_string(), |rtt| format!("{:.3}ms", rtt.as_secs_f64() * 1000.0));
+            let duration = flow.last_seen.checked_sub(flow.first_seen).unwrap_or_default();
+            println!(
+                "{:<6} {:<45} {:<45} {:>8} {:>10} {:>8} {:>10} {:<12} {:>9.3}s {:>10}",
+                protocol_name(key.protocol),
+                endpoint(&flow.initiator),
+                endpoint(&flow.responder),
+                flow.forward.packets,
+                flow.forward.bytes,
+                flow.backward.packets,
+                flow.backward.bytes,
+                state,
+                duration.as_secs_f64(),
+                rtt
+            );
+        }
+        println!();
+    }
+}
+
+fn protocol_name(protocol: IpNextHeaderProtocol) -> String {
+    match protocol {
+        IpNextHeaderProtocols::Tcp => "TCP".to_string(),
+        IpNextHeaderProtocols::Udp => "UDP".to_string(),
+        IpNextHeaderProtocols::Icmp => "ICMP".to_string(),
+        IpNextHeaderProtocols::Icmpv6 => "ICMPv6".to_string(),
+        other => other.0.to_string(),
+    }
+}
+
+fn endpoint((addr, port): &Endpoint) -> String {
+    match (addr, port) {
+        (_, None) => addr.to_string(),
+        (IpAddr::V6(addr), Some(port)) => format!("[{}]:{}", addr, port),
+        (IpAddr::V4(addr), Some(port)) => format!("{}:{}", addr, port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+
+    const CLIENT: &str = "10.0.0.1";
+    const SERVER: &str = "10.0.0.2";
+
+    fn from_client(table: &mut FlowTable, flags: u8, millis: u64) {
+        let frame = testutil::tcp_frame(CLIENT, 40000, SERVER, 80, 1, flags, b"");
+        table.record(&frame, Duration::from_millis(millis));
+    }
+
+    fn from_server(table: &mut FlowTable, flags: u8, millis: u64) {
+        let frame = testutil::tcp_frame(SERVER, 80, CLIENT, 40000, 1, flags, b"");
+        table.record(&frame, Duration::from_millis(millis));
+    }
+
+    fn only_flow(table: &FlowTable) -> &Flow {
+        assert_eq!(table.flows.len(), 1);
+        table.flows.values().next().unwrap()
+    }
+
+    fn table() -> FlowTable {
+        // 表示し直さないよう、間隔を長くする
+        FlowTable::new(10, Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn handshake_establishes_and_measures_rtt() {
+        let mut table = table();
+        from_client(&mut table, TCP_SYN, 1000);
+        from_server(&mut table, TCP_SYN | TCP_ACK, 1015);
+        from_client(&mut table, TCP_ACK, 1020);
+        let flow = only_flow(&table);
+        assert_eq!(flow.state, TcpState::Established);
+        assert_eq!(flow.rtt, Some(Duration::from_millis(20)));
+        assert_eq!((flow.forward.packets, flow.backward.packets), (2, 1));
+    }
+
+    #[test]
+    fn retransmitted_fin_does_not_close_the_flow() {
+        let mut table = table();
+        from_client(&mut table, TCP_SYN, 0);
+        from_server(&mut table, TCP_SYN | TCP_ACK, 1);
+        from_client(&mut table, TCP_ACK, 2);
+        from_client(&mut table, TCP_FIN | TCP_ACK, 3);
+        from_client(&mut table, TCP_FIN | TCP_ACK, 300);
+        assert_eq!(only_flow(&table).state, TcpState::Closing);
+
+        from_server(&mut table, TCP_FIN | TCP_ACK, 301);
+        assert_eq!(only_flow(&table).state, TcpState::Closed);
+    }
+
+    #[test]
+    fn fin_seen_before_the_syn_keeps_its_direction() {
+        let mut table = table();
+        // 途中から見たフローでは、最初のパケットの送信元を開始側とする
+        from_server(&mut table, TCP_FIN | TCP_ACK, 0);
+        assert!(only_flow(&table).fin_from_initiator);
+
+        // SYNを見て開始側が入れ替わっても、FINを送ったのはサーバー側のまま
+        from_client(&mut table, TCP_SYN, 1);
+        let flow = only_flow(&table);
+        assert_eq!(flow.initiator, (CLIENT.parse().unwrap(), Some(40000)));
+        assert!(!flow.fin_from_initiator && flow.fin_from_responder);
+        assert_eq!(flow.backward.packets, 1);
+
+        from_server(&mut table, TCP_FIN | TCP_ACK, 2);
+        assert_eq!(only_flow(&table).state, TcpState::Closing);
+        from_client(&mut table, TCP_FIN | TCP_ACK, 3);
+        assert_eq!(only_flow(&table).state, TcpState::Closed);
+    }
+
+    #[test]
+    fn both_directions_share_one_flow_and_non_ip_is_unrecognized() {
+        let mut table = table();
+        table.record(&testutil::udp_frame(CLIENT, 5353, SERVER, 53, b"query"), Duration::from_secs(1));
+        table.record(&testutil::udp_frame(SERVER, 53, CLIENT, 5353, b"answer!"), Duration::from_secs(2));
+        table.record(&testutil::ethernet(0x0806, &[0; 28]), Duration::from_secs(3));
+        let flow = only_flow(&table);
+        assert_eq!(flow.state, TcpState::Unknown);
+        assert_eq!((flow.forward.bytes, flow.backward.bytes), (47, 49));
+        assert_eq!((table.packets, table.unrecognized), (3, 1));
+    }
+
+    #[test]
+    fn refresh_redraws_after_the_interval_without_packets() {
+        let mut table = FlowTable::new(10, Duration::from_secs(2));
+        table.record(&testutil::udp_frame(CLIENT, 5353, SERVER, 53, b""), Duration::from_secs(10));
+        assert_eq!(table.last_printed, Some(Duration::from_secs(10)));
+        table.refresh(Duration::from_secs(11));
+        assert_eq!(table.last_printed, Some(Duration::from_secs(10)));
+        table.refresh(Duration::from_secs(12));
+        assert_eq!(table.last_printed, Some(Duration::from_secs(12)));
+    }
+
+    fn handshake(table: &mut FlowTable, millis: u64) {
+        from_client(table, TCP_SYN, millis);
+        from_server(table, TCP_SYN | TCP_ACK, millis + 10);
+        from_client(table, TCP_ACK, millis + 20);
+    }
+
+    #[test]
+    fn retransmitted_syn_does_not_reset_an_established_flow() {
+        let mut table = table();
+        handshake(&mut table, 0);
+        from_client(&mut table, TCP_SYN, 1000);
+        let flow = only_flow(&table);
+        assert_eq!(flow.state, TcpState::Established);
+        assert_eq!(flow.syn_at, Some(Duration::from_millis(0)));
+        assert_eq!(flow.rtt, Some(Duration::from_millis(20)));
+
+        // 確立した後に反対側からSYNが来ても開始側は入れ替えない
+        from_server(&mut table, TCP_SYN, 1001);
+        let flow = only_flow(&table);
+        assert_eq!(flow.initiator, (CLIENT.parse().unwrap(), Some(40000)));
+        assert_eq!((flow.forward.packets, flow.backward.packets), (3, 2));
+    }
+
+    #[test]
+    fn syn_after_close_starts_a_new_connection() {
+        let mut table = table();
+        handshake(&mut table, 0);
+        from_client(&mut table, TCP_FIN | TCP_ACK, 100);
+        from_server(&mut table, TCP_FIN | TCP_ACK, 101);
+        assert_eq!(only_flow(&table).state, TcpState::Closed);
+
+        from_client(&mut table, TCP_SYN, 200);
+        let flow = only_flow(&table);
+        assert_eq!(flow.state, TcpState::SynSent);
+        assert_eq!(flow.syn_at, Some(Duration::from_millis(200)));
+        assert!(!flow.fin_from_initiator && !flow.fin_from_responder);
+    }
+
+    #[test]
+    fn closed_and_idle_flows_are_expired_but_counted() {
+        let mut table = table();
+        handshake(&mut table, 0);
+        from_client(&mut table, TCP_RST, 100);
+        let open = testutil::udp_frame(CLIENT, 5353, SERVER, 53, b"query");
+        table.record(&open, Duration::from_millis(100));
+        assert_eq!(table.flows.len(), 2);
+
+        // リセットされたフローは少し待ってから外す
+        table.refresh(Duration::from_millis(100) + CLOSED_TIMEOUT - EXPIRE_INTERVAL);
+        assert_eq!(table.flows.len(), 2);
+        table.refresh(Duration::from_millis(100) + CLOSED_TIMEOUT);
+        assert_eq!(table.flows.len(), 1);
+        assert_eq!(table.expired_flows, 1);
+        assert_eq!(table.expired.packets, 4);
+        assert_eq!(table.expired.bytes, 4 * 54);
+
+        // 通信のないフローはもっと長く待つ
+        table.refresh(Duration::from_millis(100) + IDLE_TIMEOUT - Duration::from_secs(1));
+        assert_eq!(table.flows.len(), 1);
+        table.refresh(Duration::from_millis(100) + IDLE_TIMEOUT);
+        assert!(table.flows.is_empty());
+        assert_eq!((table.expired_flows, table.expired.packets), (2, 5));
+        assert_eq!(table.expired.bytes, 4 * 54 + open.len() as u64);
+    }
+
+    #[test]
+    fn expiry_runs_at_most_once_per_interval() {
+        let mut table = table();
+        handshake(&mut table, 0);
+        from_client(&mut table, TCP_RST, 30);
+        table.refresh(CLOSED_TIMEOUT);
+        assert_eq!(table.flows.len(), 1);
+        // 前回探してから間隔が空いていないので、期限が過ぎていても残る
+        table.refresh(CLOSED_TIMEOUT + Duration::from_millis(30));
+        assert_eq!(table.flows.len(), 1);
+        table.refresh(CLOSED_TIMEOUT + EXPIRE_INTERVAL);
+        assert!(table.flows.is_empty());
+    }
+}
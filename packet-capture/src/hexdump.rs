@@ -0,0 +1,229 @@
+//! tcpdump -Xやxxdと同じ形式の16進ダンプ
+//! オフセット、16進、表示可能なASCIIを1行に並べ、層の境目を色で示せる
+
+use std::ops::Range;
+
+use crate::packet::DecodedPacket;
+
+pub const DEFAULT_WIDTH: usize = 16;
+
+const RESET: &str = "\x1b[0m";
+/** リンク層、ネットワーク層、トランスポート層、ペイロードの色 */
+const COLORS: [&str; 4] = ["\x1b[34m", "\x1b[32m", "\x1b[33m", ""];
+
+/** どの層からダンプするか。その層に含まれる上位の層もダンプする */
+#[derive(Clone, Copy, PartialEq)]
+pub enum DumpLayer {
+    None,
+    Frame,
+    Network,
+    Transport,
+    Payload,
+}
+
+impl DumpLayer {
+    pub fn parse(name: &str) -> Result<DumpLayer, failure::Error> {
+        match name {
+            "none" => Ok(DumpLayer::None),
+            "frame" => Ok(DumpLayer::Frame),
+            "network" => Ok(DumpLayer::Network),
+            "transport" => Ok(DumpLayer::Transport),
+            "payload" => Ok(DumpLayer::Payload),
+            _ => Err(failure::err_msg(format!("Unknown layer {} (expected none, frame, network, transport or payload)", name))),
+        }
+    }
+}
+
+/** --dump-*の設定 */
+#[derive(Clone, Copy)]
+pub struct HexdumpConfig {
+    pub layer: DumpLayer,
+    /** 1行のバイト数 */
+    pub width: usize,
+    /** ダンプする最大のバイト数 */
+    pub length: Option<usize>,
+    /** 層の境目を色分けするか */
+    pub color: bool,
+}
+
+impl Default for HexdumpConfig {
+    fn default() -> HexdumpConfig {
+        HexdumpConfig {
+            layer: DumpLayer::Payload,
+            width: DEFAULT_WIDTH,
+            length: None,
+            color: false,
+        }
+    }
+}
+
+/** フレーム中の各層の先頭。ない層は次の層と同じ位置になる */
+struct Boundaries {
+    network: usize,
+    transport: usize,
+    payload: usize,
+    /** IPの全長で決まるパケットの終わり。イーサネットのパディングを含まない */
+    end: usize,
+}
+
+impl Boundaries {
+    fn of(frame: &[u8]) -> Option<Boundaries> {
+        let packet = DecodedPacket::decode(frame)?;
+        let offset = |bytes: &[u8]| bytes.as_ptr() as usize - frame.as_ptr() as usize;
+        let network = offset(packet.network);
+        let payload = offset(packet.payload);
+        let transport = packet.ip.as_ref().map_or(payload, |ip| (network + ip.header_length).min(payload));
+        Some(Boundaries {
+            network,
+            transport,
+            payload,
+            end: payload + packet.payload.len(),
+        })
+    }
+
+    /** バイトがどの層に属するか。COLORSの添字 */
+    fn layer_of(&self, index: usize) -> usize {
+        if index < self.network {
+            0
+        } else if index < self.transport {
+            1
+        } else if index < self.payload {
+            2
+        } else {
+            3
+        }
+    }
+}
+
+/** 設定に従ってフレームの一部をダンプする */
+pub fn dump(frame: &[u8], config: &HexdumpConfig) {
+    for line in lines(frame, config) {
+        println!("{}", line);
+    }
+}
+
+/** ダンプを行ごとの文字列にする */
+pub fn lines(frame: &[u8], config: &HexdumpConfig) -> Vec<String> {
+    if config.layer == DumpLayer::None {
+        return Vec::new();
+    }
+    let boundaries = match Boundaries::of(frame) {
+        Some(boundaries) => boundaries,
+        // 短すぎるフレームやVLANタグの途中で切れたフレームは層を分けられないので、全体をダンプする
+        None => return render(frame, 0..frame.len(), config, None),
+    };
+    let range = match config.layer {
+        DumpLayer::None => return Vec::new(),
+        DumpLayer::Frame => 0..frame.len(),
+        DumpLayer::Network => boundaries.network..boundaries.end,
+        DumpLayer::Transport => boundaries.transport..boundaries.end,
+        DumpLayer::Payload => boundaries.payload..boundaries.end,
+    };
+    render(frame, range, config, if config.color { Some(&boundaries) } else { None })
+}
+
+fn render(frame: &[u8], range: Range<usize>, config: &HexdumpConfig, boundaries: Option<&Boundaries>) -> Vec<String> {
+    let width = config.width.max(1);
+    let start = range.start;
+    let end = config.length.map_or(range.end, |length| range.end.min(start + length));
+    let colored = |index: usize, text: String| match boundaries {
+        Some(boundaries) if !COLORS[boundaries.layer_of(index)].is_empty() => format!("{}{}{}", COLORS[boundaries.layer_of(index)], text, RESET),
+        _ => text,
+    };
+
+    let mut lines = Vec::new();
+    for line_start in (start..end).step_by(width) {
+        let line_end = (line_start + width).min(end);
+        let mut hex = String::new();
+        let mut ascii = String::new();
+        for i in line_start..line_start + width {
+            // 2バイトごとに区切る
+            if i > line_start && (i - line_start) % 2 == 0 {
+                hex.push(' ');
+            }
+            if let Some(&c) = frame[..line_end].get(i) {
+                hex.push_str(&colored(i, format!("{:02x}", c)));
+                let printable = if c.is_ascii_graphic() || c == b' ' { c as char } else { '.' };
+                ascii.push_str(&colored(i, printable.to_string()));
+            } else {
+                hex.push_str("  ");
+            }
+        }
+        lines.push(format!("0x{:04x}:  {}  {}", line_start - start, hex, ascii));
+    }
+    if end < range.end {
+        lines.push(format!("({} more bytes)", range.end - end));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn config(layer: DumpLayer, width: usize, length: Option<usize>) -> HexdumpConfig {
+        HexdumpConfig { layer, width, length, color: false }
+    }
+
+    /** 各行の先頭のオフセット */
+    fn offsets(lines: &[String]) -> Vec<&str> {
+        lines.iter().map(|line| &line[..6]).collect()
+    }
+
+    #[test]
+    fn layers_select_where_the_dump_starts() {
+        let frame = testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b"hello");
+        let first_bytes = |layer| lines(&frame, &config(layer, 16, None))[0][9..18].to_string();
+        assert_eq!(first_bytes(DumpLayer::Frame), "0200 0000");
+        assert_eq!(first_bytes(DumpLayer::Network), "4500 0021");
+        assert_eq!(first_bytes(DumpLayer::Transport), "9c40 0035");
+        assert_eq!(first_bytes(DumpLayer::Payload), "6865 6c6c");
+        assert!(lines(&frame, &config(DumpLayer::None, 16, None)).is_empty());
+        assert_eq!(lines(&frame, &config(DumpLayer::Network, 16, None)).len(), 3);
+    }
+
+    #[test]
+    fn ethernet_padding_is_not_dumped_after_the_packet() {
+        let mut frame = testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b"hi");
+        frame.resize(60, 0);
+        assert_eq!(lines(&frame, &config(DumpLayer::Payload, 16, None)), vec!["0x0000:  6869                                     hi"]);
+        assert_eq!(lines(&frame, &config(DumpLayer::Frame, 16, None)).len(), 4);
+    }
+
+    #[test]
+    fn width_and_length() {
+        let frame = testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b"hello");
+        assert_eq!(lines(&frame, &config(DumpLayer::Payload, 4, None)), vec!["0x0000:  6865 6c6c  hell", "0x0004:  6f         o"]);
+        assert_eq!(offsets(&lines(&frame, &config(DumpLayer::Frame, 8, None))), vec!["0x0000", "0x0008", "0x0010", "0x0018", "0x0020", "0x0028"]);
+        assert_eq!(lines(&frame, &config(DumpLayer::Payload, 16, Some(2))), vec!["0x0000:  6865                                     he", "(3 more bytes)"]);
+        // 表示できない文字は.にする
+        let frame = testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b"a\x1b\n");
+        assert!(lines(&frame, &config(DumpLayer::Payload, 16, None))[0].ends_with("  a.."));
+    }
+
+    #[test]
+    fn undecodable_frames_are_dumped_whole() {
+        let short = [0xffu8; 10];
+        for layer in [DumpLayer::Frame, DumpLayer::Network, DumpLayer::Payload] {
+            assert_eq!(lines(&short, &config(layer, 16, None)), vec!["0x0000:  ffff ffff ffff ffff ffff                 .........."]);
+        }
+        // VLANタグの途中で切れたフレーム
+        let tagged = testutil::vlan(10, &testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b""));
+        let truncated = &tagged[..16];
+        assert_eq!(offsets(&lines(truncated, &config(DumpLayer::Payload, 8, None))), vec!["0x0000", "0x0008"]);
+        assert!(lines(truncated, &config(DumpLayer::None, 8, None)).is_empty());
+    }
+
+    #[test]
+    fn colors_mark_the_layers() {
+        let frame = testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b"x");
+        let colored = HexdumpConfig { color: true, ..config(DumpLayer::Frame, 64, None) };
+        let line = &lines(&frame, &colored)[0];
+        for color in &COLORS[..3] {
+            assert!(line.contains(color));
+        }
+        assert!(line.contains(RESET));
+        assert!(!lines(&frame, &config(DumpLayer::Frame, 64, None))[0].contains('\x1b'));
+    }
+}
@@ -0,0 +1,71 @@
+//! キャプチャするインターフェースの一覧と選択
+//! 名前の誤りはパニックせず、使えるインターフェースの名前を添えたエラーにする
+
+use pnet::datalink::{self, NetworkInterface};
+
+/** すべての起動中のインターフェースを表す名前 */
+pub const ANY: &str = "any";
+
+/** --list-interfaces。名前、MACアドレス、アドレス、フラグを表示する */
+pub fn list() {
+    for interface in datalink::interfaces() {
+        let mac = interface.mac.map_or_else(|| "-".to_string(), |mac| mac.to_string());
+        println!("{:>3} {:<16} {:<17} <{}>", interface.index, interface.name, mac, flags(&interface).join(","));
+        for ip in interface.ips.iter() {
+            println!("    {}", ip);
+        }
+    }
+}
+
+/** 名前からインターフェースを探す。anyは起動中のすべてのインターフェースになる */
+pub fn resolve(names: &[String]) -> Result<Vec<NetworkInterface>, failure::Error> {
+    let interfaces = datalink::interfaces();
+    if names.iter().any(|name| name == ANY) {
+        let up: Vec<NetworkInterface> = interfaces.into_iter().filter(|interface| interface.is_up()).collect();
+        if up.is_empty() {
+            return Err(failure::err_msg("No interface is up"));
+        }
+        return Ok(up);
+    }
+
+    let mut resolved: Vec<NetworkInterface> = Vec::new();
+    for name in names {
+        match interfaces.iter().find(|interface| interface.name == *name) {
+            Some(interface) => {
+                if !resolved.iter().any(|other| other.index == interface.index) {
+                    resolved.push(interface.clone());
+                }
+            }
+            None => {
+                let available: Vec<&str> = interfaces.iter().map(|interface| interface.name.as_str()).collect();
+                return Err(failure::err_msg(format!(
+                    "Unknown interface {} (available: {}, or {} for all of them; see --list-interfaces)",
+                    name,
+                    available.join(", "),
+                    ANY
+                )));
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+fn flags(interface: &NetworkInterface) -> Vec<&'static str> {
+    let mut flags = Vec::new();
+    if interface.is_up() {
+        flags.push("UP");
+    }
+    if interface.is_broadcast() {
+        flags.push("BROADCAST");
+    }
+    if interface.is_loopback() {
+        flags.push("LOOPBACK");
+    }
+    if interface.is_point_to_point() {
+        flags.push("POINTOPOINT");
+    }
+    if interface.is_multicast() {
+        flags.push("MULTICAST");
+    }
+    flags
+}
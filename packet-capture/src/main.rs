@@ -1,127 +1,755 @@
-use log::{error, info};
-use pnet::{datalink::{self, EtherType}, packet::{ip::IpNextHeaderProtocols, ipv4, ipv6, Packet}};
+mod application;
+mod bpf;
+mod detect;
+#[path = "../../dhcp_server/src/options.rs"]
+mod dhcp_options;
+mod dissect;
+mod enrich;
+mod filter;
+mod flow;
+mod hexdump;
+mod interfaces;
+mod mmdb;
+mod options;
+mod packet;
+mod pcap;
+mod raw;
+mod reassembly;
+mod record;
+mod replay;
+mod ring;
+mod signal;
+mod trigger;
+mod tui;
+mod validate;
+#[cfg(test)]
+mod testutil;
+
+use std::env;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use pnet::{datalink, packet::ip::IpNextHeaderProtocols};
+use pnet::datalink::{Channel, DataLinkReceiver};
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+use pnet::util::MacAddr;
+use serde_json::json;
+
+use detect::Detector;
+use enrich::{Endpoint, Enricher};
+use filter::Expr;
+use flow::FlowTable;
+use hexdump::HexdumpConfig;
+use options::{Options, Source};
+use packet::{DecodedPacket, Ip, Transport};
+use pcap::{CaptureReader, CaptureWriter, InterfaceInfo, RecordedFrame};
+use raw::{RawSocket, Received};
+use reassembly::{ReassemblyConfig, Reassembler};
+use record::{OutputFormat, TimeFormat, Timing};
+use ring::{Ring, Statistics};
+use trigger::{Limits, Trigger};
+use tui::Tui;
+use validate::Counters;
 
 fn main() {
     env::set_var("RUST_LOG", "debug");
     env_logger::init();
-    let args: Vec<String> = env::args.collect();
-    if args.len() != 2 {
-        error!("Please specify target interface name");
+    let args: Vec<String> = env::args().collect();
+    let options = Options::parse(&args).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+
+    if options.list_interfaces {
+        interfaces::list();
+        return;
+    }
+    if options.dump_bpf {
+        dump_bpf(&options);
+        return;
+    }
+    signal::install();
+
+    if let (Some(config), Source::Interfaces(interface_names)) = (&options.replay, &options.source) {
+        let result = interfaces::resolve(interface_names).and_then(|interfaces| replay::run(&interfaces[0], config, options.filter.as_ref()));
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let result = match options.source {
+        Source::Interfaces(ref interface_names) => capture_live(interface_names, &options),
+        Source::File(ref path) => capture_offline(path, &options),
+    };
+    if let Err(e) = result {
+        error!("{}", e);
         std::process::exit(1);
     }
+}
+
+/** フィルタをコンパイルしたBPFを表示する */
+fn dump_bpf(options: &Options) {
+    let filter = options.filter.as_ref().expect("--dump-bpf requires a filter");
+    match bpf::compile(filter) {
+        Ok(program) => {
+            for (i, instruction) in program.iter().enumerate() {
+                println!("({:03}) {}", i, instruction);
+            }
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    let interface_name = &args[1];
+/** 受信スレッドから処理側へ渡すキューに溜められるバッチの数
+ * 処理が追いつかない時は、メモリを使い続けずに新しいフレームを捨てる
+ */
+const QUEUE_BATCHES: usize = 256;
+/** ユーザー空間での取りこぼしを報告する間隔 */
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
 
-    let interfaces = datalink::interfaces();
-    let interface = interfaces.into_iter().find(|iface| iface.name == *interface_name).expect("Failed to get interface");
+/** インターフェースごとの受信口 */
+enum Receiver {
+    Channel(Box<dyn DataLinkReceiver>),
+    /** --kernel-filterと--kernel-timestampsの時はカーネルの受信時刻を受け取れるソケットで受信する */
+    Raw(RawSocket),
+}
 
-    /** データリンクのチャンネルを取得 */
-    let (_tx, mut rx) = match  datalink::channel(&interface, Default::default()) {
-        Ok(Ethernet(tx, tc)) => (tx, rx),
-        Ok(_) => panic!("Unhandled channel type"),
-        Err(e) => panic!("Failed to create datalink channel {}", e),
+impl Receiver {
+    /** 1フレーム受信する。pnetのチャンネルではカーネルの受信時刻を得られない */
+    fn next(&mut self) -> io::Result<Received<'_>> {
+        match self {
+            Receiver::Channel(rx) => rx.next().map(|data| Received {
+                original_length: data.len(),
+                data,
+                timestamp: None,
+            }),
+            Receiver::Raw(socket) => socket.next(),
+        }
+    }
+}
+
+/** インターフェースからライブキャプチャする
+ * インターフェースごとにスレッドで受信し、1つのパイプラインにまとめて流す
+ */
+fn capture_live(interface_names: &[String], options: &Options) -> Result<(), failure::Error> {
+    let interfaces = interfaces::resolve(interface_names)?;
+    let program = if options.kernel_filter {
+        let filter = options.filter.as_ref().expect("--kernel-filter requires a filter");
+        Some(bpf::compile(filter)?)
+    } else {
+        None
     };
 
-    loop {
-        match rx.next() {
-            Ok(frame) => {
-                let frame = EthernetPacket::new(frame).unwrap();
-                match frame.get_ethertype() {
-                    EtherTypes::Ipv4 => {
-                        ipv4_handler(&frame);
-                    }
-                    EtherTypes::Ipv6 => {
-                        ipv6_handler(&frame);
-                    }
-                    _ => {
-                        info!("Not an Ipv4 or Ipv6 packet");
-                    }
+    // 受信口はここで開き、開けなかった時はスレッドを起動する前にエラーにする
+    let mut receivers = Vec::new();
+    let mut rings = Vec::new();
+    for (interface_id, interface) in interfaces.iter().enumerate() {
+        if let Some(ref config) = options.ring {
+            // ファンアウトのグループはプロセスとインターフェースごとに分ける
+            let group = if config.fanout > 1 { Some((std::process::id() as u16).wrapping_add(interface_id as u16)) } else { None };
+            for _ in 0..config.fanout {
+                rings.push((interface_id, Ring::open(interface.index, config, program.as_deref(), group)?));
+            }
+            if program.is_some() {
+                info!("Attached a BPF program to {} ring sockets on {}", config.fanout, interface.name);
+            }
+            continue;
+        }
+        let receiver = match program {
+            Some(ref program) => {
+                let socket = RawSocket::open(interface.index, Some(program))?;
+                info!("Attached a {} instruction BPF program to {}", program.len(), interface.name);
+                Receiver::Raw(socket)
+            }
+            None if options.kernel_timestamps => Receiver::Raw(RawSocket::open(interface.index, None)?),
+            None => {
+                let config = datalink::Config {
+                    read_timeout: Some(Duration::from_millis(500)),
+                    ..Default::default()
+                };
+                match datalink::channel(interface, config) {
+                    Ok(Channel::Ethernet(_tx, rx)) => Receiver::Channel(rx),
+                    Ok(_) => return Err(failure::err_msg(format!("Unhandled channel type on {}", interface.name))),
+                    Err(e) => return Err(failure::err_msg(format!("Failed to create datalink channel on {}: {}", interface.name, e))),
                 }
             }
-            Err(e) => {
-                error!("Failed to read: {}", e);
+        };
+        receivers.push((interface_id, receiver));
+    }
+
+    // 受信スレッドは表示を待たずに次を受信し、キューが一杯ならフレームを捨てて数える
+    let (sender, frames) = mpsc::sync_channel(QUEUE_BATCHES);
+    let dropped = Arc::new(AtomicU64::new(0));
+    for (interface_id, receiver) in receivers {
+        let queue = Queue { sender: sender.clone(), dropped: dropped.clone() };
+        let name = interfaces[interface_id].name.clone();
+        thread::spawn(move || receive(receiver, interface_id as u32, &name, queue));
+    }
+    let mut ring_threads = Vec::new();
+    for (interface_id, ring) in rings {
+        let queue = Queue { sender: sender.clone(), dropped: dropped.clone() };
+        let name = interfaces[interface_id].name.clone();
+        let interval = options.ring.as_ref().map_or(Duration::from_secs(10), |config| config.stats_interval);
+        ring_threads.push(thread::spawn(move || receive_ring(ring, interface_id as u32, &name, queue, interval)));
+    }
+    drop(sender);
+
+    let infos = interfaces
+        .iter()
+        .map(|interface| InterfaceInfo {
+            name: interface.name.clone(),
+            mac: interface.mac,
+        })
+        .collect();
+    // --kernel-filterでも、カーネルが判定せずに通したフレーム(深いVLANタグやMPLS)があるのでユーザー空間でも判定する
+    let mut pipeline = Pipeline::new(options, infos)?;
+
+    let mut reported_drops = 0;
+    let mut last_report = Instant::now();
+    while !signal::interrupted() {
+        match frames.recv_timeout(Duration::from_millis(100)) {
+            Ok(batch) => {
+                for frame in batch {
+                    pipeline.handle_frame(&frame);
+                }
             }
+            Err(RecvTimeoutError::Timeout) => pipeline.tick(),
+            // すべての受信スレッドが終了した
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if last_report.elapsed() >= DROP_REPORT_INTERVAL {
+            last_report = Instant::now();
+            let total = dropped.load(Ordering::Relaxed);
+            if total > reported_drops {
+                warn!("Dropped {} frames because processing fell behind", total - reported_drops);
+                reported_drops = total;
+            }
+        }
+    }
+    pipeline.finish()?;
+
+    // リングの受信スレッドはタイムアウトごとに終了を確認するので、待って取りこぼしの合計を出す
+    let mut total = Statistics::default();
+    for thread in ring_threads {
+        if let Ok(statistics) = thread.join() {
+            total.add(statistics);
         }
     }
+    if options.ring.is_some() {
+        info!("Kernel received {} packets, dropped {} ({} queue freezes)", total.packets, total.drops, total.freezes);
+    }
+    let dropped = dropped.load(Ordering::Relaxed);
+    if dropped > 0 {
+        warn!("Dropped {} frames in total because processing fell behind", dropped);
+    }
+    Ok(())
 }
 
-/** Ipv4パケットを構築次のレイヤーのハンドラを呼び出す */
+/** 受信スレッドから処理側へのキュー */
+struct Queue {
+    sender: SyncSender<Vec<RecordedFrame>>,
+    /** キューが一杯で捨てたフレームの数。すべての受信スレッドで共有する */
+    dropped: Arc<AtomicU64>,
+}
 
-fn ipv4_handler(ethernet: &EthernetPacket) {
-    if let Some(packet) = Ipv4Packet::new(ethernet.payload()) {
-        match packet.get_next_level_protocol() {
-            IpNextHeaderProtocols::Tcp => {
-                tcp_handler(&packet);
+impl Queue {
+    /** 処理を待たずに送る。キューが一杯なら捨てて数える。処理側が終了していればfalseを返す */
+    fn send(&self, batch: Vec<RecordedFrame>) -> bool {
+        match self.sender.try_send(batch) {
+            Ok(()) => true,
+            Err(TrySendError::Full(batch)) => {
+                self.dropped.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                true
             }
-            IpNextHeaderProtocols::Udp => {
-                udp_handler(&packet);
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/** 受信スレッド。受信時刻を付けてフレームを送る。カーネルの時刻がなければ受け取った時刻を使う */
+fn receive(mut receiver: Receiver, interface_id: u32, name: &str, queue: Queue) {
+    while !signal::interrupted() {
+        match receiver.next() {
+            Ok(received) => {
+                let captured = RecordedFrame {
+                    timestamp: received.timestamp.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()),
+                    interface_id,
+                    data: received.data.to_vec(),
+                    original_length: received.original_length,
+                };
+                if !queue.send(vec![captured]) {
+                    return;
+                }
             }
-            _ => {
-                info!("Not a TCP or UDP packet");
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                error!("Failed to read from {}: {}", name, e);
+                return;
             }
         }
     }
 }
 
-fn ipv6_handler(ethernet: &EthernetPacket) {
-    if let Some(packet) = Ipv6Packet::new(ethernet.payload()) {
-        match packet.get_next_header() {
-            IpNextHeaderProtocols::Tcp => {
-                tcp_handler(&packet);;
+/** リングの受信スレッド。ブロック単位でまとめて送り、カーネルでの取りこぼしを定期的に報告する */
+fn receive_ring(mut ring: Ring, interface_id: u32, name: &str, queue: Queue, interval: Duration) -> Statistics {
+    let mut total = Statistics::default();
+    let mut last_report = Instant::now();
+    while !signal::interrupted() {
+        let mut batch = Vec::new();
+        let received = ring.next_block(Duration::from_millis(500), |frame, timestamp, original_length| {
+            batch.push(RecordedFrame {
+                timestamp,
+                interface_id,
+                data: frame.to_vec(),
+                original_length,
+            });
+        });
+        match received {
+            Ok(true) => {
+                if !queue.send(batch) {
+                    break;
+                }
             }
-            IpNextHeaderProtocols::Udp => {
-                udp_handler(&packet);
+            Ok(false) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                error!("Failed to read from {}: {}", name, e);
+                break;
             }
-            _ => {
-                info!("Not a TCP or UDP packet");
+        }
+
+        if last_report.elapsed() >= interval {
+            last_report = Instant::now();
+            match ring.statistics() {
+                Ok(statistics) => {
+                    total.add(statistics);
+                    if statistics.drops > 0 {
+                        warn!("{}: kernel dropped {} of {} packets ({} queue freezes)", name, statistics.drops, statistics.packets, statistics.freezes);
+                    } else {
+                        info!("{}: kernel received {} packets, no drops", name, statistics.packets);
+                    }
+                }
+                Err(e) => error!("Failed to get statistics of {}: {}", name, e),
             }
         }
     }
+    if let Ok(statistics) = ring.statistics() {
+        total.add(statistics);
+    }
+    total
 }
 
-/// TCPパケット構築
-/// @param packet
-fn tcp_handler(packet: &GettableEndPoints) {
-    let tcp = TcpPacket::new(packet.get_payload());
-    if let Some(tcp) = tcp {
-        print_packet_info(packet, &tcp, "TCP");
+/** -r で指定されたキャプチャファイルを、ライブキャプチャと同じ処理に流す */
+fn capture_offline(path: &Path, options: &Options) -> Result<(), failure::Error> {
+    let mut reader = CaptureReader::open(path)?;
+
+    let info = InterfaceInfo {
+        name: path.display().to_string(),
+        mac: None,
+    };
+    let mut pipeline = Pipeline::new(options, vec![info])?;
+
+    while !signal::interrupted() {
+        let frame = match reader.next_frame()? {
+            Some(frame) => frame,
+            None => break,
+        };
+        pipeline.handle_frame(&frame);
     }
+    pipeline.finish()
 }
 
-/// UDPパケット構築
-/// @param packet
-fn udp_handler(packet: &GettableEndPoints) {
-    let udp = UdpPacket::new(packet.get_payload());
-    if let Some(udp) = udp {
-        print_packet_info(packet, &udp, "UDP");
-    }
+/** フレームごとの処理に必要な状態 */
+struct Pipeline<'a> {
+    /** -w が指定されていれば書き出すキャプチャファイル */
+    writer: Option<CaptureWriter>,
+    print: bool,
+    filter: Option<&'a Expr>,
+    reassembler: Reassembler,
+    /** --statsの時はパケットごとに表示せず、フローを集計する */
+    flows: Option<FlowTable>,
+    /** --tuiの時は一覧と詳細を対話的に表示する */
+    tui: Option<Tui>,
+    /** --detectの時はパケットごとに表示せず、スキャンや攻撃の兆候を警告する */
+    detector: Option<Detector>,
+    /** --triggerの時は一致するまでフレームを保持する */
+    trigger: Option<Trigger<'a>>,
+    limits: Limits,
+    /** 最初のパケットを受信した時刻、またはライブキャプチャを始めた時刻 */
+    started: Option<Duration>,
+    /** これまでに受け取ったフレームの最も新しい時刻 */
+    latest: Option<Duration>,
+    /** -r の時は経過時間を現在時刻ではなくフレームの時刻で測る */
+    offline: bool,
+    /** フィルタとトリガーを通ったパケットの数とバイト数 */
+    captured: u64,
+    captured_bytes: u64,
+    format: OutputFormat,
+    /** --format jsonでペイロードをbase64で含めるか */
+    include_payload: bool,
+    time_format: TimeFormat,
+    /** --resolveと--geoipの時は送信元と宛先の情報を添える */
+    enricher: Option<Enricher>,
+    /** 最初に処理したパケットと直前に処理したパケットの時刻 */
+    first_timestamp: Option<Duration>,
+    previous_timestamp: Option<Duration>,
+    /** インターフェースIDごとの名前 */
+    interface_names: Vec<String>,
+    hexdump: HexdumpConfig,
+    /** インターフェースIDごとのMACアドレス。送信したパケットのチェックサムオフロードの判定に使う */
+    local_macs: Vec<Option<MacAddr>>,
+    /** 壊れたパケットや途中で切れたパケットの数 */
+    counters: Counters,
 }
 
-fn print_packet_info(l3: &GettableEndPoints, l4: &GettableEndPoints, proto: &str) {
-    println!("Captured a {} packet from {} | {} to {} | {} \n", proto, l3.get_source(), l4.get_source(), l3.get_destination(), l4.get_destination());
+impl<'a> Pipeline<'a> {
+    fn new(options: &'a Options, interfaces: Vec<InterfaceInfo>) -> Result<Pipeline<'a>, failure::Error> {
+        let interface_names: Vec<String> = interfaces.iter().map(|info| info.name.clone()).collect();
+        let local_macs = interfaces.iter().map(|info| info.mac).collect();
+        let writer = match options.output {
+            Some(ref output) => Some(CaptureWriter::create(output.clone(), interfaces)?),
+            None => None,
+        };
+        // ファイルに書き出す場合は、--printが指定された時だけ表示する
+        let print = writer.is_none() || options.print;
+        if let Some(ref dir) = options.stream_dir {
+            std::fs::create_dir_all(dir)?;
+        }
+        let reassembler = Reassembler::new(ReassemblyConfig {
+            follow_stream: options.follow_stream,
+            stream_dir: options.stream_dir.clone(),
+        });
+        let flows = if options.stats { Some(FlowTable::new(options.top, options.stats_interval)) } else { None };
+        let tui = if options.tui { Some(Tui::new(interface_names.clone())?) } else { None };
+        if print && flows.is_none() && tui.is_none() && options.format == OutputFormat::Csv {
+            println!("{}", record::CSV_HEADER);
+        }
+        Ok(Pipeline {
+            writer,
+            print,
+            filter: options.filter.as_ref(),
+            reassembler,
+            flows,
+            tui,
+            detector: options.detect.clone().map(Detector::new),
+            trigger: options.trigger.as_ref().map(Trigger::new),
+            limits: options.limits,
+            started: None,
+            latest: None,
+            offline: matches!(options.source, Source::File(_)),
+            captured: 0,
+            captured_bytes: 0,
+            format: options.format,
+            include_payload: options.include_payload,
+            time_format: options.time_format,
+            enricher: match options.enrich {
+                Some(ref config) => Some(Enricher::new(config)?),
+                None => None,
+            },
+            first_timestamp: None,
+            previous_timestamp: None,
+            interface_names,
+            hexdump: options.hexdump,
+            local_macs,
+            counters: Counters::default(),
+        })
+    }
+
+    /** フィルタとトリガーを通ったフレームを処理し、終了条件に達したら受信ループを終わらせる */
+    fn handle_frame(&mut self, frame: &RecordedFrame) {
+        // --durationはフィルタに一致しないフレームも含め、最初のフレームの時刻から測る
+        self.started.get_or_insert(frame.timestamp);
+        self.latest = Some(self.latest.map_or(frame.timestamp, |latest| latest.max(frame.timestamp)));
+        if self.duration_elapsed(frame.timestamp) {
+            signal::request_stop();
+            return;
+        }
+        if let Some(filter) = self.filter {
+            if !filter.matches(&frame.data) {
+                return;
+            }
+        }
+        if let Some(trigger) = self.trigger.as_mut() {
+            for held in trigger.offer(frame) {
+                if self.limit_reached() {
+                    break;
+                }
+                self.process_frame(&held);
+            }
+        } else if !self.limit_reached() {
+            self.process_frame(frame);
+        }
+        if self.limit_reached() {
+            signal::request_stop();
+        }
+    }
+
+    /** --resolveと--geoipの時、IPパケットの送信元と宛先について分かったこと */
+    fn describe_endpoints(&self, frame: &[u8]) -> Option<[(IpAddr, Endpoint); 2]> {
+        let enricher = self.enricher.as_ref()?;
+        let ip = DecodedPacket::decode(frame)?.ip?;
+        Some([(ip.source, enricher.describe(ip.source)), (ip.destination, enricher.describe(ip.destination))])
+    }
+
+    /** -cと--max-bytesに達したか */
+    fn limit_reached(&self) -> bool {
+        self.limits.count.is_some_and(|count| self.captured >= count) || self.limits.max_bytes.is_some_and(|max| self.captured_bytes >= max)
+    }
 
-    let payload = l4.get_payload();
-    let len = payload.len();
+    /** --durationが過ぎたか */
+    fn duration_elapsed(&self, now: Duration) -> bool {
+        match (self.limits.duration, self.started) {
+            (Some(duration), Some(started)) => now.checked_sub(started).is_some_and(|elapsed| elapsed >= duration),
+            _ => false,
+        }
+    }
 
-    for i in 0..len {
-        print!("{:<02X}", payload[i]);
+    /** 1フレームを書き出し、各レイヤーのハンドラに渡す */
+    fn process_frame(&mut self, recorded: &RecordedFrame) {
+        self.captured += 1;
+        self.captured_bytes += recorded.data.len() as u64;
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(e) = writer.write_frame(recorded) {
+                error!("Failed to write a frame: {}", e);
+            }
+        }
+        let (frame, timestamp, interface_id) = (recorded.data.as_slice(), recorded.timestamp, recorded.interface_id);
+        // 時刻が戻った場合(複数のインターフェースやファイルの並び)は経過時間を0にする
+        let first = *self.first_timestamp.get_or_insert(timestamp);
+        let timing = Timing {
+            absolute: timestamp,
+            relative: timestamp.checked_sub(first).unwrap_or_default(),
+            delta: self.previous_timestamp.and_then(|previous| timestamp.checked_sub(previous)).unwrap_or_default(),
+        };
+        self.previous_timestamp = Some(timestamp);
+        let local_mac = self.local_macs.get(interface_id as usize).cloned().unwrap_or(None);
+        let problems = validate::check(frame, local_mac);
+        self.counters.record(&problems);
+        // --stream-dirは-wやjson、csv、--statsと組み合わせても書き出すので、出力形式で分ける前に渡す
+        let reassembled = self.reassembler.feed(frame, timestamp);
+        if let Some(detector) = self.detector.as_mut() {
+            detector.inspect(frame, timestamp);
+            return;
+        }
+        if let Some(flows) = self.flows.as_mut() {
+            flows.record(frame, timestamp);
+            return;
+        }
+        if let Some(tui) = self.tui.as_mut() {
+            tui.push(frame, timestamp, interface_id);
+            tui.tick();
+            return;
+        }
+        if !self.print {
+            return;
+        }
 
-        if i % WIDTH == WIDTH - 1 || i == len - 1 {
-            for _j in 0..WIDTH - 1 - (i % (WIDTH)) {
-                print!(" ");
+        let interface = self.interface_names.get(interface_id as usize).map_or("", String::as_str);
+        let endpoints = self.describe_endpoints(frame);
+        match self.format {
+            OutputFormat::Json => {
+                let enrichment = endpoints.map(|[(_, source), (_, destination)]| json!({ "source": source.to_json(), "destination": destination.to_json() }));
+                println!("{}", record::to_json(recorded, &timing, interface, self.include_payload, &problems, enrichment));
+                return;
+            }
+            OutputFormat::Csv => {
+                println!("{}", record::to_csv(recorded, &timing, interface));
+                return;
             }
-            print!("| ");
+            OutputFormat::Text => {}
+        }
 
-            for j in i - i % WIDTH..=i {
-                if payload[j].is_ascii_alphabetic() {
-                    print!("{}", payload[j] as char);
-                } else {
-                    print!(".");
-                }
+        println!("{} {} length {} captured {}", timing.format(self.time_format), interface, recorded.original_length, frame.len());
+        for (label, (addr, endpoint)) in ["Source", "Destination"].iter().zip(endpoints.iter().flatten()) {
+            if !endpoint.is_empty() {
+                println!("{} {}: {}", label, addr, endpoint);
             }
-            print!();
+        }
+
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+            println!("Malformed packet: {}", problems.join(", "));
+        }
+
+        let packet = match DecodedPacket::decode(frame) {
+            Some(packet) => packet,
+            None => return,
+        };
+        let follow_stream = self.reassembler.follows_streams();
+        dissect::link_handler(&packet.link);
+        ethertype_handler(packet.link.ethertype, packet.network, follow_stream);
+        // このフレームでフラグメントが揃った場合は、再構築したパケットを続けて表示する
+        if let Some(whole) = reassembled {
+            ipv4_handler(&whole, follow_stream);
+        }
+
+        // --follow-streamではセグメントごとの表示をしないので、ダンプもしない
+        if !follow_stream {
+            hexdump::dump(frame, &self.hexdump);
+            println!("{}", "=".repeat(self.hexdump.width * 3));
+            println!();
+        }
+    }
+
+    /** パケットが来ない間もキー入力を受け付け、表示を更新する
+     * ライブキャプチャではパケットが来なくても--durationが過ぎたら終わる
+     */
+    fn tick(&mut self) {
+        if let Some(tui) = self.tui.as_mut() {
+            tui.tick();
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        if let Some(flows) = self.flows.as_mut() {
+            flows.refresh(now);
+        }
+        self.started.get_or_insert(now);
+        if self.duration_elapsed(now) {
+            signal::request_stop();
         }
     }
-    println!("{}", "=".repeat(WIDTH * 3));
-    println!();
-}
\ No newline at end of file
+
+    /** 書き出し途中のファイルとストリームを閉じ、フローの集計を表示する */
+    fn finish(&mut self) -> Result<(), failure::Error> {
+        // 集計を表示する前に端末を元に戻す
+        if let Some(mut tui) = self.tui.take() {
+            tui.wait();
+        }
+        self.reassembler.finish();
+        if let Some(flows) = self.flows.as_ref() {
+            flows.print_summary();
+        }
+        self.counters.print_summary();
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        let end = if self.offline { self.latest } else { SystemTime::now().duration_since(UNIX_EPOCH).ok() };
+        let elapsed = self.started.zip(end).and_then(|(started, end)| end.checked_sub(started)).unwrap_or_default();
+        info!("Captured {} packets ({} bytes) in {:.3}s", self.captured, self.captured_bytes, elapsed.as_secs_f64());
+        if let Some(trigger) = self.trigger.as_ref() {
+            info!("Trigger fired {} times", trigger.fired);
+        }
+        if let Some(detector) = self.detector.as_ref() {
+            info!("Raised {} alerts", detector.alerts);
+        }
+        Ok(())
+    }
+}
+
+/** タグやラベルを外した後のEtherTypeに応じたハンドラを呼び出す */
+fn ethertype_handler(ethertype: EtherType, payload: &[u8], follow_stream: bool) {
+    match ethertype {
+        EtherTypes::Ipv4 => {
+            ipv4_handler(payload, follow_stream);
+        }
+        EtherTypes::Ipv6 => {
+            ipv6_handler(payload, follow_stream);
+        }
+        EtherTypes::Arp => {
+            dissect::arp_handler(payload);
+        }
+        _ => {
+            info!("Not an Ipv4 or Ipv6 packet");
+        }
+    }
+}
+
+/** Ipv4パケットを構築次のレイヤーのハンドラを呼び出す */
+fn ipv4_handler(payload: &[u8], follow_stream: bool) {
+    if let Some((ip, l4)) = Ip::parse_v4(payload) {
+        // フラグメントはReassemblerが揃うまで保持し、再構築したパケットをPipelineが改めて表示する
+        if ip.fragment.is_some() {
+            return;
+        }
+        ip_handler(&ip, l4, follow_stream);
+    }
+}
+
+fn ipv6_handler(payload: &[u8], follow_stream: bool) {
+    if let Some((ip, l4)) = Ip::parse_v6(payload) {
+        for extension in ip.extensions.iter() {
+            println!("IPv6 extension {} ({} bytes)", extension.header, extension.length);
+        }
+        if let Some(fragment) = ip.fragment {
+            println!("IPv6 fragment id {} offset {} more fragments {}", fragment.identification, fragment.offset, fragment.more_fragments);
+        }
+        if !ip.has_transport_header() {
+            info!("Not the first fragment of {} packet", ip.protocol);
+            return;
+        }
+        ip_handler(&ip, l4, follow_stream);
+    }
+}
+
+/** 上位層のプロトコルに応じたハンドラを呼び出す */
+fn ip_handler(ip: &Ip, payload: &[u8], follow_stream: bool) {
+    match ip.protocol {
+        IpNextHeaderProtocols::Tcp => {
+            tcp_handler(ip, payload, follow_stream);
+        }
+        IpNextHeaderProtocols::Udp => {
+            udp_handler(ip, payload);
+        }
+        IpNextHeaderProtocols::Icmp => {
+            dissect::icmp_handler(ip, payload);
+        }
+        IpNextHeaderProtocols::Icmpv6 => {
+            dissect::icmpv6_handler(ip, payload);
+        }
+        _ => {
+            info!("Not a TCP or UDP packet");
+        }
+    }
+}
+
+/// TCPパケット構築
+/// @param ip
+/// @param segment
+fn tcp_handler(ip: &Ip, segment: &[u8], follow_stream: bool) {
+    if let Some((tcp, payload)) = Transport::parse(ip, segment) {
+        // --follow-streamではセグメントごとではなく再構築したデータを表示する
+        if !follow_stream {
+            let details = application::decode_tcp(tcp.source_port, tcp.destination_port, payload);
+            print_packet_info(ip, &tcp, details);
+        }
+    }
+}
+
+/// UDPパケット構築
+/// @param ip
+/// @param datagram
+fn udp_handler(ip: &Ip, datagram: &[u8]) {
+    if let Some((udp, payload)) = Transport::parse(ip, datagram) {
+        let details = application::decode_udp(udp.source_port, udp.destination_port, payload);
+        print_packet_info(ip, &udp, details);
+    }
+}
+
+/** どのプロトコルでも共通の、送信元と宛先の1行 */
+fn print_summary(proto: &str, source: &str, destination: &str) {
+    println!("Captured a {} packet from {} to {} \n", proto, source, destination);
+}
+
+/** 送信元と宛先、アプリケーション層のデコード結果を表示する。ダンプはPipelineがフレーム全体から行う */
+fn print_packet_info(ip: &Ip, transport: &Transport, details: Option<Vec<String>>) {
+    let source = packet::endpoint(ip.source, Some(transport.source_port));
+    let destination = packet::endpoint(ip.destination, Some(transport.destination_port));
+    print_summary(transport.name(), &source, &destination);
+    if let Some(details) = details {
+        for line in details {
+            println!("{}", line);
+        }
+        println!();
+    }
+}
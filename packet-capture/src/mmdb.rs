@@ -0,0 +1,523 @@
+//! MaxMind DB形式(.mmdb)のデータベースの読み込み
+//! GeoLite2-Country/City/ASNなどのファイルを、ネットワークに問い合わせずにIPアドレスで検索する
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+/** メタデータの直前に置かれる目印 */
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/** 検索木とデータ部の間の区切り */
+const DATA_SECTION_SEPARATOR: usize = 16;
+/** 壊れたファイルのポインタの循環で止まらないように、入れ子をこの深さまでに制限する */
+const MAX_DEPTH: usize = 32;
+
+/** データ部の型 */
+const TYPE_EXTENDED: u8 = 0;
+const TYPE_POINTER: u8 = 1;
+const TYPE_STRING: u8 = 2;
+const TYPE_DOUBLE: u8 = 3;
+const TYPE_BYTES: u8 = 4;
+const TYPE_UINT16: u8 = 5;
+const TYPE_UINT32: u8 = 6;
+const TYPE_MAP: u8 = 7;
+const TYPE_INT32: u8 = 8;
+const TYPE_UINT64: u8 = 9;
+const TYPE_UINT128: u8 = 10;
+const TYPE_ARRAY: u8 = 11;
+const TYPE_BOOLEAN: u8 = 14;
+const TYPE_FLOAT: u8 = 15;
+
+pub struct Database {
+    data: Vec<u8>,
+    node_count: usize,
+    /** 1つのレコードのビット数。24、28、32のいずれか */
+    record_size: usize,
+    ip_version: u16,
+    /** データ部の先頭。ポインタはここからのオフセット */
+    data_section: usize,
+    /** データ部の終わり(メタデータの目印の位置)。壊れた値でメタデータを読まないようにする */
+    data_end: usize,
+    /** IPv6のデータベースでIPv4アドレス(::/96)を検索し始めるノード */
+    ipv4_start: usize,
+    pub database_type: String,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Database, failure::Error> {
+        let data = fs::read(path)?;
+        let invalid = || failure::err_msg(format!("{} is not a MaxMind DB file", path.display()));
+        let marker = data.windows(METADATA_MARKER.len()).rposition(|window| window == METADATA_MARKER).ok_or_else(invalid)?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder { data: &data[metadata_start..] }.decode(0).ok_or_else(invalid)?;
+
+        let node_count = metadata["node_count"].as_u64().ok_or_else(invalid)? as usize;
+        let record_size = metadata["record_size"].as_u64().ok_or_else(invalid)? as usize;
+        let ip_version = metadata["ip_version"].as_u64().ok_or_else(invalid)? as u16;
+        if record_size != 24 && record_size != 28 && record_size != 32 {
+            return Err(failure::err_msg(format!("{}: unsupported record size {}", path.display(), record_size)));
+        }
+        let tree_size = node_count * record_size * 2 / 8;
+        if tree_size + DATA_SECTION_SEPARATOR > marker {
+            return Err(invalid());
+        }
+
+        let mut database = Database {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            data_section: tree_size + DATA_SECTION_SEPARATOR,
+            data_end: marker,
+            ipv4_start: 0,
+            database_type: metadata["database_type"].as_str().unwrap_or("unknown").to_string(),
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = database.read_record(node, 0);
+            }
+            database.ipv4_start = node;
+        }
+        Ok(database)
+    }
+
+    /** アドレスを含むネットワークのデータを返す。見つからなければNone */
+    pub fn lookup(&self, addr: IpAddr) -> Option<Value> {
+        let (bytes, mut node) = match addr {
+            IpAddr::V4(addr) => (addr.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(addr) => (addr.octets().to_vec(), 0),
+        };
+        for i in 0..bytes.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bytes[i / 8] >> (7 - i % 8)) & 1;
+            node = self.read_record(node, bit);
+        }
+        // node_countと等しければ該当なし、それより大きければデータ部を指す
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = (node - self.node_count).checked_sub(DATA_SECTION_SEPARATOR)?;
+        let (value, _) = Decoder { data: self.data.get(self.data_section..self.data_end)? }.decode(offset)?;
+        Some(value)
+    }
+
+    /** ノードの左(bit=0)または右(bit=1)のレコード */
+    fn read_record(&self, node: usize, bit: u8) -> usize {
+        let base = node * self.record_size * 2 / 8;
+        let byte = |offset: usize| usize::from(self.data[base + offset]);
+        match (self.record_size, bit) {
+            (24, 0) => byte(0) << 16 | byte(1) << 8 | byte(2),
+            (24, _) => byte(3) << 16 | byte(4) << 8 | byte(5),
+            // 28ビットの場合は中央のバイトの上位4ビットが左、下位4ビットが右のレコードの最上位になる
+            (28, 0) => (byte(3) & 0xf0) << 20 | byte(0) << 16 | byte(1) << 8 | byte(2),
+            (28, _) => (byte(3) & 0x0f) << 24 | byte(4) << 16 | byte(5) << 8 | byte(6),
+            (_, 0) => byte(0) << 24 | byte(1) << 16 | byte(2) << 8 | byte(3),
+            (_, _) => byte(4) << 24 | byte(5) << 16 | byte(6) << 8 | byte(7),
+        }
+    }
+}
+
+/** データ部の値をJSONの値に変換する */
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /** offsetの値を読み、値と次の値の位置を返す */
+    fn decode(&self, offset: usize) -> Option<(Value, usize)> {
+        self.decode_nested(offset, 0)
+    }
+
+    fn decode_nested(&self, offset: usize, depth: usize) -> Option<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let control = *self.data.get(offset)?;
+        let mut position = offset + 1;
+        let mut kind = control >> 5;
+        if kind == TYPE_POINTER {
+            let (target, next) = self.pointer(control, position)?;
+            // ポインタの先の値を読んだ後は、ポインタの直後から続ける
+            let (value, _) = self.decode_nested(target, depth + 1)?;
+            return Some((value, next));
+        }
+        if kind == TYPE_EXTENDED {
+            kind = 7 + *self.data.get(position)?;
+            position += 1;
+        }
+        let (size, position) = self.size(control, position)?;
+
+        match kind {
+            TYPE_STRING => {
+                let bytes = self.data.get(position..position + size)?;
+                Some((Value::from(String::from_utf8_lossy(bytes).into_owned()), position + size))
+            }
+            TYPE_DOUBLE => {
+                let bytes = self.data.get(position..position + 8)?;
+                Some((Value::from(f64::from_bits(self.unsigned(bytes))), position + 8))
+            }
+            TYPE_FLOAT => {
+                let bytes = self.data.get(position..position + 4)?;
+                Some((Value::from(f64::from(f32::from_bits(self.unsigned(bytes) as u32))), position + 4))
+            }
+            TYPE_BYTES => {
+                let bytes = self.data.get(position..position + size)?;
+                Some((Value::from(bytes.to_vec()), position + size))
+            }
+            TYPE_UINT16 | TYPE_UINT32 | TYPE_UINT64 => {
+                let bytes = self.data.get(position..position + size)?;
+                Some((Value::from(self.unsigned(bytes)), position + size))
+            }
+            TYPE_INT32 => {
+                let bytes = self.data.get(position..position + size)?;
+                // 4バイトより短い場合は上位が省略されている
+                Some((Value::from(self.unsigned(bytes) as u32 as i32), position + size))
+            }
+            TYPE_UINT128 => {
+                let bytes = self.data.get(position..position + size)?;
+                let value = bytes.iter().fold(0u128, |value, byte| value << 8 | u128::from(*byte));
+                Some((Value::from(value.to_string()), position + size))
+            }
+            TYPE_BOOLEAN => Some((Value::from(size != 0), position)),
+            TYPE_MAP => {
+                let mut map = Map::new();
+                let mut position = position;
+                for _ in 0..size {
+                    let (key, next) = self.decode_nested(position, depth + 1)?;
+                    let (value, next) = self.decode_nested(next, depth + 1)?;
+                    map.insert(key.as_str()?.to_string(), value);
+                    position = next;
+                }
+                Some((Value::Object(map), position))
+            }
+            TYPE_ARRAY => {
+                let mut values = Vec::new();
+                let mut position = position;
+                for _ in 0..size {
+                    let (value, next) = self.decode_nested(position, depth + 1)?;
+                    values.push(value);
+                    position = next;
+                }
+                Some((Value::from(values), position))
+            }
+            _ => None,
+        }
+    }
+
+    /** ポインタの指すオフセットと、ポインタの次の位置 */
+    fn pointer(&self, control: u8, position: usize) -> Option<(usize, usize)> {
+        let size = usize::from((control >> 3) & 0x3) + 1;
+        let bytes = self.data.get(position..position + size)?;
+        let value = self.unsigned(bytes) as usize;
+        let low = usize::from(control & 0x7);
+        let target = match size {
+            1 => low << 8 | value,
+            2 => (low << 16 | value) + 2048,
+            3 => (low << 24 | value) + 526_336,
+            _ => value,
+        };
+        Some((target, position + size))
+    }
+
+    /** 制御バイトの下位5ビットが29以上の時は続くバイトに長さがある */
+    fn size(&self, control: u8, position: usize) -> Option<(usize, usize)> {
+        let size = usize::from(control & 0x1f);
+        match size {
+            29 => Some((29 + usize::from(*self.data.get(position)?), position + 1)),
+            30 => Some((285 + self.unsigned(self.data.get(position..position + 2)?) as usize, position + 2)),
+            31 => Some((65_821 + self.unsigned(self.data.get(position..position + 3)?) as usize, position + 3)),
+            _ => Some((size, position)),
+        }
+    }
+
+    /** ビッグエンディアンの符号なし整数 */
+    fn unsigned(&self, bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0, |value, byte| value << 8 | u64::from(*byte))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::net::Ipv6Addr;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("packet-capture-{}-{}", std::process::id(), name))
+    }
+
+    /** 型と長さの制御バイト。拡張型は2バイト目に型を置く */
+    fn control(kind: u8, size: usize) -> Vec<u8> {
+        let (low, extra) = match size {
+            0..=28 => (size as u8, Vec::new()),
+            29..=284 => (29, vec![(size - 29) as u8]),
+            _ => (30, ((size - 285) as u16).to_be_bytes().to_vec()),
+        };
+        let mut bytes = if kind > 7 { vec![low, kind - 7] } else { vec![kind << 5 | low] };
+        bytes.extend(extra);
+        bytes
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = control(TYPE_STRING, value.len());
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    fn unsigned(kind: u8, value: u64) -> Vec<u8> {
+        let significant: Vec<u8> = value.to_be_bytes().iter().cloned().skip_while(|byte| *byte == 0).collect();
+        let mut bytes = control(kind, significant.len());
+        bytes.extend(significant);
+        bytes
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = control(TYPE_MAP, entries.len());
+        for (key, value) in entries.iter() {
+            bytes.extend(string(key));
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    fn array(values: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = control(TYPE_ARRAY, values.len());
+        for value in values.iter() {
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    /** 2048未満は1バイト、それ以上は2バイトのポインタ */
+    fn pointer(target: usize) -> Vec<u8> {
+        if target < 2048 {
+            vec![TYPE_POINTER << 5 | (target >> 8) as u8, target as u8]
+        } else {
+            let value = target - 2048;
+            vec![TYPE_POINTER << 5 | 1 << 3 | (value >> 16) as u8, (value >> 8) as u8, value as u8]
+        }
+    }
+
+    fn metadata(node_count: usize, record_size: usize, ip_version: u16) -> Vec<u8> {
+        map(&[
+            ("node_count", unsigned(TYPE_UINT32, node_count as u64)),
+            ("record_size", unsigned(TYPE_UINT16, record_size as u64)),
+            ("ip_version", unsigned(TYPE_UINT16, u64::from(ip_version))),
+            ("database_type", string("Test-Country")),
+        ])
+    }
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    /** ネットワークごとのデータから検索木とデータ部を組み立てる */
+    struct Fixture {
+        nodes: Vec<[Record; 2]>,
+        data: Vec<u8>,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            Fixture { nodes: vec![[Record::Empty; 2]], data: Vec::new() }
+        }
+
+        /** 値をデータ部に追加し、そのオフセットを返す */
+        fn value(&mut self, bytes: &[u8]) -> usize {
+            self.data.extend_from_slice(bytes);
+            self.data.len() - bytes.len()
+        }
+
+        fn insert(&mut self, network: &[u8], prefix_length: usize, offset: usize) {
+            let mut node = 0;
+            for i in 0..prefix_length - 1 {
+                let bit = usize::from((network[i / 8] >> (7 - i % 8)) & 1);
+                node = match self.nodes[node][bit] {
+                    Record::Node(next) => next,
+                    _ => {
+                        self.nodes.push([Record::Empty; 2]);
+                        self.nodes[node][bit] = Record::Node(self.nodes.len() - 1);
+                        self.nodes.len() - 1
+                    }
+                };
+            }
+            let last = prefix_length - 1;
+            let bit = usize::from((network[last / 8] >> (7 - last % 8)) & 1);
+            self.nodes[node][bit] = Record::Data(offset);
+        }
+
+        /** メタデータの前までのファイルの内容 */
+        fn tree_and_data(&self, record_size: usize) -> Vec<u8> {
+            let node_count = self.nodes.len();
+            let value = |record: Record| match record {
+                Record::Empty => node_count,
+                Record::Node(node) => node,
+                Record::Data(offset) => node_count + DATA_SECTION_SEPARATOR + offset,
+            } as u32;
+            let mut file = Vec::new();
+            for [left, right] in self.nodes.iter() {
+                let (left, right) = (value(*left), value(*right));
+                match record_size {
+                    24 => {
+                        file.extend_from_slice(&left.to_be_bytes()[1..]);
+                        file.extend_from_slice(&right.to_be_bytes()[1..]);
+                    }
+                    28 => {
+                        file.extend_from_slice(&left.to_be_bytes()[1..]);
+                        file.push(((left >> 24) as u8) << 4 | (right >> 24) as u8);
+                        file.extend_from_slice(&right.to_be_bytes()[1..]);
+                    }
+                    _ => {
+                        file.extend_from_slice(&left.to_be_bytes());
+                        file.extend_from_slice(&right.to_be_bytes());
+                    }
+                }
+            }
+            file.extend_from_slice(&[0; DATA_SECTION_SEPARATOR]);
+            file.extend_from_slice(&self.data);
+            file
+        }
+
+        fn write(&self, name: &str, record_size: usize, ip_version: u16) -> PathBuf {
+            let mut file = self.tree_and_data(record_size);
+            file.extend_from_slice(METADATA_MARKER);
+            file.extend(metadata(self.nodes.len(), record_size, ip_version));
+            let path = temp_path(name);
+            fs::write(&path, file).unwrap();
+            path
+        }
+    }
+
+    /** 192.0.2.0/24と198.51.100.0/25を持つIPv4のデータベース */
+    fn ipv4_fixture() -> Fixture {
+        let mut fixture = Fixture::new();
+        let near = fixture.value(&string("JP"));
+        fixture.value(&[0; 2048]);
+        let far = fixture.value(&string("Tokyo"));
+        let documentation = fixture.value(&map(&[
+            ("iso_code", pointer(near)),
+            ("city", pointer(far)),
+            ("asn", unsigned(TYPE_UINT32, 64500)),
+            ("offset", vec![0x01, TYPE_INT32 - 7, 0xff]),
+            ("anycast", control(TYPE_BOOLEAN, 1)),
+            ("ranks", array(&[unsigned(TYPE_UINT16, 1), unsigned(TYPE_UINT64, 1 << 40)])),
+            ("note", string(&"x".repeat(300))),
+        ]));
+        let other = fixture.value(&map(&[("iso_code", string("US"))]));
+        fixture.insert(&[192, 0, 2, 0], 24, documentation);
+        fixture.insert(&[198, 51, 100, 0], 25, other);
+        fixture
+    }
+
+    #[test]
+    fn finds_the_network_containing_an_ipv4_address() {
+        let fixture = ipv4_fixture();
+        for record_size in [24, 28, 32].iter() {
+            let path = fixture.write(&format!("ipv4-{}.mmdb", record_size), *record_size, 4);
+            let database = Database::open(&path).unwrap();
+            assert_eq!(database.database_type, "Test-Country");
+
+            assert_eq!(database.lookup("192.0.2.77".parse().unwrap()), Some(json!({
+                "iso_code": "JP",
+                "city": "Tokyo",
+                "asn": 64500,
+                "offset": 255,
+                "anycast": true,
+                "ranks": [1, 1u64 << 40],
+                "note": "x".repeat(300),
+            })));
+            assert_eq!(database.lookup("198.51.100.127".parse().unwrap()), Some(json!({ "iso_code": "US" })));
+            assert_eq!(database.lookup("198.51.100.128".parse().unwrap()), None);
+            assert_eq!(database.lookup("203.0.113.1".parse().unwrap()), None);
+            assert_eq!(database.lookup("2001:db8::1".parse().unwrap()), None);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn looks_up_ipv4_addresses_below_the_ipv6_root() {
+        let mut fixture = Fixture::new();
+        let v6 = fixture.value(&map(&[("iso_code", string("DE"))]));
+        let v4 = fixture.value(&map(&[("iso_code", string("FR"))]));
+        fixture.insert(&"2001:db8::".parse::<Ipv6Addr>().unwrap().octets(), 32, v6);
+        fixture.insert(&"::192.0.2.0".parse::<Ipv6Addr>().unwrap().octets(), 120, v4);
+        let path = fixture.write("ipv6.mmdb", 28, 6);
+        let database = Database::open(&path).unwrap();
+
+        assert_eq!(database.lookup("2001:db8:1::1".parse().unwrap()), Some(json!({ "iso_code": "DE" })));
+        assert_eq!(database.lookup("192.0.2.200".parse().unwrap()), Some(json!({ "iso_code": "FR" })));
+        assert_eq!(database.lookup("::192.0.2.200".parse().unwrap()), Some(json!({ "iso_code": "FR" })));
+        assert_eq!(database.lookup("198.51.100.1".parse().unwrap()), None);
+        assert_eq!(database.lookup("2001:db9::1".parse().unwrap()), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_the_high_bits_of_28_bit_records_from_the_middle_byte() {
+        let database = Database {
+            data: vec![0x12, 0x34, 0x56, 0xab, 0xcd, 0xef, 0x01],
+            node_count: 1,
+            record_size: 28,
+            ip_version: 4,
+            data_section: 0,
+            data_end: 0,
+            ipv4_start: 0,
+            database_type: String::new(),
+        };
+        assert_eq!(database.read_record(0, 0), 0x0a12_3456);
+        assert_eq!(database.read_record(0, 1), 0x0bcd_ef01);
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_databases() {
+        let fixture = ipv4_fixture();
+        let path = temp_path("invalid.mmdb");
+        let with_metadata = |metadata: Vec<u8>| {
+            let mut file = fixture.tree_and_data(24);
+            file.extend_from_slice(METADATA_MARKER);
+            file.extend(metadata);
+            fs::write(&path, file).unwrap();
+            Database::open(&path)
+        };
+
+        assert!(with_metadata(metadata(fixture.nodes.len(), 24, 4)).is_ok());
+        assert!(with_metadata(metadata(fixture.nodes.len(), 20, 4)).is_err());
+        // 検索木がメタデータに重なる
+        assert!(with_metadata(metadata(fixture.nodes.len() + 1000, 24, 4)).is_err());
+        assert!(with_metadata(map(&[("record_size", unsigned(TYPE_UINT16, 24))])).is_err());
+
+        fs::write(&path, b"GIF89a").unwrap();
+        assert!(Database::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stops_at_broken_data() {
+        let mut fixture = Fixture::new();
+        let looping = fixture.value(&pointer(0));
+        let truncated = fixture.value(&[TYPE_STRING << 5 | 10, b'a']);
+        fixture.insert(&[10, 0, 0, 0], 8, looping);
+        fixture.insert(&[192, 168, 0, 0], 16, truncated);
+        fixture.insert(&[172, 16, 0, 0], 12, 1000);
+        let path = fixture.write("broken.mmdb", 24, 4);
+        let database = Database::open(&path).unwrap();
+
+        assert_eq!(database.lookup("10.1.2.3".parse().unwrap()), None);
+        assert_eq!(database.lookup("192.168.1.1".parse().unwrap()), None);
+        assert_eq!(database.lookup("172.16.0.1".parse().unwrap()), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::detect::{AlertFormat, DetectConfig};
+use crate::enrich::EnrichConfig;
+use crate::filter::Expr;
+use crate::hexdump::{DumpLayer, HexdumpConfig};
+use crate::pcap::{Format, OutputConfig};
+use crate::record::{OutputFormat, TimeFormat};
+use crate::replay::{Pacing, ReplayConfig, Rewrite};
+use crate::ring::RingConfig;
+use crate::trigger::{Limits, TriggerConfig};
+
+const USAGE: &str = "Usage: packet-capture <interface> | -i interface[,interface...] | -i any | -r file | --list-interfaces [-w file.pcap|file.pcapng] [-C megabytes] [-G seconds] [-W count] [--print] [-c count] [--duration seconds] [--max-bytes n] [--trigger expression [--pre-trigger seconds] [--post-trigger seconds]] [--kernel-filter] [--dump-bpf] [--follow-stream] [--stream-dir dir] [--stats [--top n] [--interval seconds]] [--tui] [--detect [--alerts log|json] [--scan-ports n] [--syn-flood n] [--window seconds] [--dhcp-server addr]] [--format text|json|csv] [--payload] [--time absolute|relative|delta] [--kernel-timestamps] [--resolve] [--geoip file.mmdb]... [--dump-layer none|frame|network|transport|payload] [--dump-width n] [--dump-length n] [--color] [--ring [--block-size KiB] [--blocks n] [--fanout n] [--drop-interval seconds]] [filter expression]\n       packet-capture <interface> --replay file [--speed n|top | --pps n] [--loop n] [--src-mac mac] [--dst-mac mac] [--map-ip old=new]...";
+
+/** パケットの読み込み元 */
+pub enum Source {
+    /** ライブキャプチャするインターフェース名。anyはすべてのインターフェース */
+    Interfaces(Vec<String>),
+    /** -r で指定されたキャプチャファイル */
+    File(PathBuf),
+}
+
+/** コマンドライン引数 */
+pub struct Options {
+    pub source: Source,
+    /** -w で指定された書き出し先とローテーションの設定 */
+    pub output: Option<OutputConfig>,
+    /** ファイルに書き出しながら標準出力にも表示するか */
+    pub print: bool,
+    /** インターフェース名(または-r)の後に続く引数から作ったフィルタ */
+    pub filter: Option<Expr>,
+    /** フィルタをBPFにコンパイルしてカーネルで適用するか */
+    pub kernel_filter: bool,
+    /** コンパイルしたBPFを表示して終了するか */
+    pub dump_bpf: bool,
+    /** 再構築したTCPストリームを表示するか */
+    pub follow_stream: bool,
+    /** 再構築したTCPストリームを書き出すディレクトリ */
+    pub stream_dir: Option<PathBuf>,
+    /** パケットを表示せず、フローの統計を表示するか */
+    pub stats: bool,
+    /** --statsで表示するフローの数 */
+    pub top: usize,
+    /** --statsの表を更新する間隔 */
+    pub stats_interval: Duration,
+    /** パケットごとの表示形式 */
+    pub format: OutputFormat,
+    /** --format jsonでペイロードを含めるか */
+    pub include_payload: bool,
+    /** テキスト表示でのパケットの時刻の表し方 */
+    pub time_format: TimeFormat,
+    /** カーネルが付けた受信時刻を使うか */
+    pub kernel_timestamps: bool,
+    /** --resolveと--geoipの時は送信元と宛先の情報を添える */
+    pub enrich: Option<EnrichConfig>,
+    /** インターフェースの一覧を表示して終了するか */
+    pub list_interfaces: bool,
+    /** --ringの時はTPACKET_V3のリングバッファで受信する */
+    pub ring: Option<RingConfig>,
+    /** 対話的な端末表示にするか */
+    pub tui: bool,
+    /** --detectの時はスキャンや攻撃の兆候を警告する */
+    pub detect: Option<DetectConfig>,
+    /** -c、--duration、--max-bytes */
+    pub limits: Limits,
+    /** --triggerに一致するまでは記録しない */
+    pub trigger: Option<TriggerConfig>,
+    /** テキスト表示でのダンプの形式 */
+    pub hexdump: HexdumpConfig,
+    /** --replayの時はキャプチャせず、ファイルのフレームを送信する */
+    pub replay: Option<ReplayConfig>,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, failure::Error> {
+        let mut interface_names: Vec<String> = Vec::new();
+        let mut read_path = None;
+        let mut output_path: Option<PathBuf> = None;
+        let mut rotate_size = None;
+        let mut rotate_interval = None;
+        let mut file_count = None;
+        let mut print = false;
+        let mut kernel_filter = false;
+        let mut dump_bpf = false;
+        let mut follow_stream = false;
+        let mut stream_dir = None;
+        let mut stats = false;
+        let mut top = 20;
+        let mut stats_interval = Duration::from_secs(2);
+        let mut format = OutputFormat::Text;
+        let mut include_payload = false;
+        let mut time_format = TimeFormat::Absolute;
+        let mut kernel_timestamps = false;
+        let mut enrich: Option<EnrichConfig> = None;
+        let mut list_interfaces = false;
+        let mut ring: Option<RingConfig> = None;
+        let mut replay_path = None;
+        let mut hexdump = HexdumpConfig::default();
+        let mut tui = false;
+        let mut limits = Limits::default();
+        let mut detect: Option<DetectConfig> = None;
+        let mut trigger = None;
+        let mut pre_trigger = Duration::from_secs(5);
+        let mut post_trigger = Duration::from_secs(5);
+        let mut pacing = Pacing::Speed(1.0);
+        let mut loops = 1;
+        let mut rewrite = Rewrite::default();
+        let mut positionals = Vec::new();
+
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-i" => interface_names.extend(next_value(&mut iter, arg)?.split(',').filter(|name| !name.is_empty()).map(String::from)),
+                "-r" => read_path = Some(PathBuf::from(next_value(&mut iter, arg)?)),
+                "-w" => output_path = Some(PathBuf::from(next_value(&mut iter, arg)?)),
+                "-C" => rotate_size = Some(next_value(&mut iter, arg)?.parse::<u64>()? * 1_000_000),
+                "-G" => rotate_interval = Some(Duration::from_secs(next_value(&mut iter, arg)?.parse()?)),
+                "-W" => file_count = Some(next_value(&mut iter, arg)?.parse()?),
+                "--print" => print = true,
+                "-c" => limits.count = Some(next_value(&mut iter, arg)?.parse()?),
+                "--duration" => limits.duration = Some(Duration::from_secs(next_value(&mut iter, arg)?.parse()?)),
+                "--max-bytes" => limits.max_bytes = Some(next_value(&mut iter, arg)?.parse()?),
+                "--trigger" => trigger = Some(Expr::parse(next_value(&mut iter, arg)?)?),
+                "--pre-trigger" => pre_trigger = Duration::from_secs(next_value(&mut iter, arg)?.parse()?),
+                "--post-trigger" => post_trigger = Duration::from_secs(next_value(&mut iter, arg)?.parse()?),
+                "--kernel-filter" => kernel_filter = true,
+                "--dump-bpf" => dump_bpf = true,
+                "--follow-stream" => follow_stream = true,
+                "--stream-dir" => stream_dir = Some(PathBuf::from(next_value(&mut iter, arg)?)),
+                "--stats" => stats = true,
+                "--top" => top = next_value(&mut iter, arg)?.parse()?,
+                "--interval" => stats_interval = Duration::from_secs(next_value(&mut iter, arg)?.parse()?),
+                "--format" => format = OutputFormat::parse(next_value(&mut iter, arg)?)?,
+                "--payload" => include_payload = true,
+                "--time" => time_format = TimeFormat::parse(next_value(&mut iter, arg)?)?,
+                "--kernel-timestamps" => kernel_timestamps = true,
+                "--resolve" => enrich.get_or_insert_with(EnrichConfig::default).resolve = true,
+                "--geoip" => enrich.get_or_insert_with(EnrichConfig::default).databases.push(PathBuf::from(next_value(&mut iter, arg)?)),
+                "--list-interfaces" => list_interfaces = true,
+                "--ring" => {
+                    ring.get_or_insert_with(RingConfig::default);
+                }
+                "--block-size" => ring.get_or_insert_with(RingConfig::default).block_size = next_value(&mut iter, arg)?.parse::<usize>()? * 1024,
+                "--blocks" => ring.get_or_insert_with(RingConfig::default).block_count = next_value(&mut iter, arg)?.parse()?,
+                "--fanout" => ring.get_or_insert_with(RingConfig::default).fanout = next_value(&mut iter, arg)?.parse()?,
+                "--tui" => tui = true,
+                "--detect" => {
+                    detect.get_or_insert_with(DetectConfig::default);
+                }
+                "--alerts" => detect.get_or_insert_with(DetectConfig::default).format = AlertFormat::parse(next_value(&mut iter, arg)?)?,
+                "--scan-ports" => detect.get_or_insert_with(DetectConfig::default).scan_ports = next_value(&mut iter, arg)?.parse()?,
+                "--syn-flood" => detect.get_or_insert_with(DetectConfig::default).syn_flood = next_value(&mut iter, arg)?.parse()?,
+                "--window" => detect.get_or_insert_with(DetectConfig::default).window = Duration::from_secs(next_value(&mut iter, arg)?.parse()?),
+                "--dhcp-server" => detect.get_or_insert_with(DetectConfig::default).dhcp_servers.push(next_value(&mut iter, arg)?.parse()?),
+                "--dump-layer" => hexdump.layer = DumpLayer::parse(next_value(&mut iter, arg)?)?,
+                "--dump-width" => hexdump.width = next_value(&mut iter, arg)?.parse()?,
+                "--dump-length" => hexdump.length = Some(next_value(&mut iter, arg)?.parse()?),
+                "--color" => hexdump.color = true,
+                "--replay" => replay_path = Some(PathBuf::from(next_value(&mut iter, arg)?)),
+                "--speed" => pacing = Pacing::parse_speed(next_value(&mut iter, arg)?)?,
+                "--pps" => pacing = Pacing::parse_pps(next_value(&mut iter, arg)?)?,
+                "--loop" => loops = next_value(&mut iter, arg)?.parse()?,
+                "--src-mac" => rewrite.source_mac = Some(next_value(&mut iter, arg)?.parse().map_err(|_| failure::err_msg(format!("Invalid MAC address for {}", arg)))?),
+                "--dst-mac" => rewrite.destination_mac = Some(next_value(&mut iter, arg)?.parse().map_err(|_| failure::err_msg(format!("Invalid MAC address for {}", arg)))?),
+                "--map-ip" => rewrite.add_ip_mapping(next_value(&mut iter, arg)?)?,
+                "--drop-interval" => ring.get_or_insert_with(RingConfig::default).stats_interval = Duration::from_secs(next_value(&mut iter, arg)?.parse()?),
+                _ if arg.starts_with('-') => return Err(failure::err_msg(format!("Unknown option {}\n{}", arg, USAGE))),
+                _ => positionals.push(arg.clone()),
+            }
+        }
+
+        // -iも-rもなければ最初の引数はインターフェース名、残りはフィルタ式
+        // --dump-bpfと--list-interfacesはキャプチャしないので、すべてをフィルタ式とみなす
+        let mut positionals = positionals.into_iter();
+        if interface_names.is_empty() && read_path.is_none() && !dump_bpf && !list_interfaces {
+            interface_names.extend(positionals.next());
+        }
+        if !interface_names.is_empty() && read_path.is_some() {
+            return Err(failure::err_msg("-i cannot be used with -r"));
+        }
+        let expression = positionals.collect::<Vec<_>>().join(" ");
+        let filter = if expression.is_empty() { None } else { Some(Expr::parse(&expression)?) };
+        if (kernel_filter || dump_bpf) && filter.is_none() {
+            return Err(failure::err_msg("--kernel-filter and --dump-bpf require a filter expression"));
+        }
+        if kernel_filter && read_path.is_some() {
+            return Err(failure::err_msg("--kernel-filter cannot be used with -r"));
+        }
+
+        // リングバッファはブロックごとにカーネルの時刻が付くので指定するまでもない
+        if kernel_timestamps && (read_path.is_some() || ring.is_some()) {
+            return Err(failure::err_msg("--kernel-timestamps cannot be used with -r or --ring"));
+        }
+
+        if let Some(ref config) = ring {
+            if read_path.is_some() {
+                return Err(failure::err_msg("--ring cannot be used with -r"));
+            }
+            // ブロックはページサイズの倍数でなければならない
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            if config.block_size == 0 || config.block_size % page_size != 0 {
+                return Err(failure::err_msg(format!("--block-size must be a multiple of the page size ({} KiB)", page_size / 1024)));
+            }
+            if config.block_count == 0 || config.fanout == 0 {
+                return Err(failure::err_msg("--blocks and --fanout must be at least 1"));
+            }
+        }
+
+        let trigger = trigger.map(|filter| TriggerConfig {
+            filter,
+            before: pre_trigger,
+            after: post_trigger,
+        });
+
+        if tui && (stats || format != OutputFormat::Text) {
+            return Err(failure::err_msg("--tui cannot be used with --stats or --format"));
+        }
+        if detect.is_some() && (tui || stats) {
+            return Err(failure::err_msg("--detect cannot be used with --tui or --stats"));
+        }
+        if hexdump.width == 0 {
+            return Err(failure::err_msg("--dump-width must be at least 1"));
+        }
+        let replay = replay_path.map(|path| ReplayConfig { path, pacing, loops, rewrite });
+        if replay.is_some() && (read_path.is_some() || interface_names.len() > 1 || interface_names.iter().any(|name| name == crate::interfaces::ANY)) {
+            return Err(failure::err_msg("--replay sends on exactly one interface and cannot be used with -r"));
+        }
+
+        let output = match output_path {
+            Some(path) => Some(OutputConfig {
+                format: Format::from_path(&path),
+                path,
+                rotate_size,
+                rotate_interval,
+                file_count,
+            }),
+            None if rotate_size.is_some() || rotate_interval.is_some() || file_count.is_some() => {
+                return Err(failure::err_msg("-C, -G and -W require -w"));
+            }
+            None => None,
+        };
+
+        let source = match read_path {
+            Some(path) => Source::File(path),
+            None if !interface_names.is_empty() || dump_bpf || list_interfaces => Source::Interfaces(interface_names),
+            None => return Err(failure::err_msg(format!("Please specify target interface name or -r file\n{}", USAGE))),
+        };
+
+        Ok(Options {
+            source,
+            output,
+            print,
+            filter,
+            kernel_filter,
+            dump_bpf,
+            follow_stream,
+            stream_dir,
+            stats,
+            top,
+            stats_interval,
+            format,
+            include_payload,
+            time_format,
+            kernel_timestamps,
+            enrich,
+            list_interfaces,
+            ring,
+            tui,
+            detect,
+            limits,
+            trigger,
+            hexdump,
+            replay,
+        })
+    }
+}
+
+fn next_value<'a, I: Iterator<Item = &'a String>>(iter: &mut I, option: &str) -> Result<&'a String, failure::Error> {
+    iter.next().ok_or_else(|| failure::err_msg(format!("{} requires a value", option)))
+}
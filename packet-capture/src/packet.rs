@@ -1,62 +1,507 @@
-pub trait GettableEndPoints {
-    fn get_source(&self) -> String;
-    fn get_destination(&self) -> String;
-    fn get_payload(&self) -> &[u8];
+//! レイヤーごとに型付けしたパケットのモデル
+//! ハンドラ、フィルタ、フロー集計、出力形式は文字列ではなくここで解析した値を使う
+
+use std::net::IpAddr;
+
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet};
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::tcp::{self, TcpPacket};
+use pnet::packet::udp::{self, UdpPacket};
+use pnet::packet::vlan::VlanPacket;
+use pnet::util::MacAddr;
+
+/** 802.1ad(QinQの外側のタグ) */
+pub const ETHERTYPE_8021AD: EtherType = EtherType(0x88a8);
+pub const ETHERTYPE_MPLS_UNICAST: EtherType = EtherType(0x8847);
+pub const ETHERTYPE_MPLS_MULTICAST: EtherType = EtherType(0x8848);
+
+/** イーサネットヘッダと、その内側のVLANタグ、MPLSラベル */
+pub struct Link {
+    pub source: MacAddr,
+    pub destination: MacAddr,
+    /** タグやラベルを除いた後のEtherType */
+    pub ethertype: EtherType,
+    /** 外側から順のVLANタグ */
+    pub vlans: Vec<VlanTag>,
+    /** 外側から順のMPLSラベル */
+    pub mpls_labels: Vec<MplsLabel>,
+}
+
+/** 802.1Q/802.1adのタグ1つ分 */
+#[derive(Clone, Copy)]
+pub struct VlanTag {
+    /** タグの種類を示すEtherType(TPID) */
+    pub tpid: EtherType,
+    pub id: u16,
+    pub priority: u8,
+    pub drop_eligible: bool,
+}
+
+/** MPLSのラベルスタックのエントリ1つ分 */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MplsLabel {
+    /** 20ビットのラベル値 */
+    pub label: u32,
+    pub traffic_class: u8,
+    /** スタックの底(S)ビット */
+    pub bottom: bool,
+    pub ttl: u8,
 }
 
-impl<'a> GettableEndPoints for Ipv4Packet<'a> {
-    fn get_source(&self) -> String {
-        self.get_source().to_string()
+impl MplsLabel {
+    /** 32ビットのエントリ(ラベル20ビット、TC3ビット、S1ビット、TTL8ビット)を分解する */
+    fn from_entry(entry: u32) -> MplsLabel {
+        MplsLabel {
+            label: entry >> 12,
+            traffic_class: ((entry >> 9) & 0x7) as u8,
+            bottom: entry & 0x100 != 0,
+            ttl: (entry & 0xff) as u8,
+        }
     }
+}
+
+/** IPv6の拡張ヘッダ1つ分 */
+pub struct Ipv6Extension {
+    pub header: IpNextHeaderProtocol,
+    /** ヘッダ全体のバイト数 */
+    pub length: usize,
+}
 
-    fn get_destination(&self) -> String {
-        self.get_destination().to_string()
+/** IPv4のフラグメント、またはIPv6のFragmentヘッダ */
+#[derive(Clone, Copy)]
+pub struct Fragment {
+    pub identification: u32,
+    /** ペイロード中のバイト単位のオフセット */
+    pub offset: usize,
+    pub more_fragments: bool,
+}
+
+/** IPv4とIPv6に共通のネットワーク層 */
+pub struct Ip {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    /** 上位層のプロトコル。IPv6では拡張ヘッダをたどった後のもの */
+    pub protocol: IpNextHeaderProtocol,
+    /** IPv6では拡張ヘッダを含む */
+    pub header_length: usize,
+    /** TTLまたはホップリミット */
+    pub ttl: u8,
+    pub fragment: Option<Fragment>,
+    pub extensions: Vec<Ipv6Extension>,
+    /** IPv4ヘッダのチェックサム。IPv6にはない */
+    pub checksum_valid: Option<bool>,
+    /** ヘッダの示す全長よりキャプチャしたバイト数が少ない */
+    pub truncated: bool,
+}
+
+impl Ip {
+    pub fn version(&self) -> u8 {
+        if self.source.is_ipv4() {
+            4
+        } else {
+            6
+        }
     }
 
-    fn get_payload(&self) -> &[u8] {
-        self.payload()
+    /** 先頭以外のフラグメントには上位層のヘッダがない */
+    pub fn has_transport_header(&self) -> bool {
+        self.fragment.is_none_or(|fragment| fragment.offset == 0)
     }
-}
 
-impl<'a> GettableEndPoints for Ipv6Packet<'a> {
-    fn get_source(&self) -> String {
-        self.get_source().to_string()
+    /** IPv4パケットを解析し、ヘッダとペイロード(全長で切り詰めたもの)を返す */
+    pub fn parse_v4(bytes: &[u8]) -> Option<(Ip, &[u8])> {
+        let packet = Ipv4Packet::new(bytes)?;
+        let header_length = packet.get_header_length() as usize * 4;
+        if header_length < 20 || header_length > bytes.len() {
+            return None;
+        }
+        let total_length = (packet.get_total_length() as usize).max(header_length);
+        let end = total_length.min(bytes.len());
+
+        let more_fragments = packet.get_flags() & Ipv4Flags::MoreFragments != 0;
+        let offset = packet.get_fragment_offset() as usize * 8;
+        let fragment = if more_fragments || offset != 0 {
+            Some(Fragment {
+                identification: u32::from(packet.get_identification()),
+                offset,
+                more_fragments,
+            })
+        } else {
+            None
+        };
+
+        let ip = Ip {
+            source: IpAddr::V4(packet.get_source()),
+            destination: IpAddr::V4(packet.get_destination()),
+            protocol: packet.get_next_level_protocol(),
+            header_length,
+            ttl: packet.get_ttl(),
+            fragment,
+            extensions: Vec::new(),
+            checksum_valid: Some(ipv4::checksum(&packet) == packet.get_checksum()),
+            truncated: total_length > bytes.len(),
+        };
+        Some((ip, &bytes[header_length..end]))
     }
 
-    fn get_destination(&self) -> String {
-        self.get_destination().to_string()
+    /** IPv6パケットを解析し、Hop-by-Hop、Routing、Fragment、Destination Options、AHをたどる */
+    pub fn parse_v6(bytes: &[u8]) -> Option<(Ip, &[u8])> {
+        let packet = Ipv6Packet::new(bytes)?;
+        let total_length = 40 + packet.get_payload_length() as usize;
+        let end = total_length.min(bytes.len());
+        let mut ip = Ip {
+            source: IpAddr::V6(packet.get_source()),
+            destination: IpAddr::V6(packet.get_destination()),
+            protocol: packet.get_next_header(),
+            header_length: 40,
+            ttl: packet.get_hop_limit(),
+            fragment: None,
+            extensions: Vec::new(),
+            checksum_valid: None,
+            truncated: total_length > bytes.len(),
+        };
+
+        loop {
+            let payload = &bytes[ip.header_length..end];
+            let length = match ip.protocol {
+                IpNextHeaderProtocols::Hopopt | IpNextHeaderProtocols::Ipv6Route | IpNextHeaderProtocols::Ipv6Opts if payload.len() >= 2 => (payload[1] as usize + 1) * 8,
+                IpNextHeaderProtocols::Ipv6Frag => 8,
+                // AHの長さは4バイト単位で、先頭の2単位を含まない
+                IpNextHeaderProtocols::Ah if payload.len() >= 2 => (payload[1] as usize + 2) * 4,
+                _ => break,
+            };
+            if payload.len() < length {
+                break;
+            }
+            if ip.protocol == IpNextHeaderProtocols::Ipv6Frag {
+                let offset_flags = u16::from_be_bytes([payload[2], payload[3]]);
+                ip.fragment = Some(Fragment {
+                    identification: u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+                    offset: (offset_flags >> 3) as usize * 8,
+                    more_fragments: offset_flags & 0x1 != 0,
+                });
+            }
+            ip.extensions.push(Ipv6Extension { header: ip.protocol, length });
+            ip.protocol = IpNextHeaderProtocol::new(payload[0]);
+            ip.header_length += length;
+            if !ip.has_transport_header() {
+                break;
+            }
+        }
+        let header_length = ip.header_length;
+        Some((ip, &bytes[header_length..end]))
     }
+}
 
-    fn get_payload(&self) -> &[u8] {
-        self.payload()
+/** TCPの場合だけのヘッダの値 */
+#[derive(Clone, Copy)]
+pub struct TcpHeader {
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    pub flags: u16,
+    pub window: u16,
+}
+
+/** TCPとUDPに共通のトランスポート層 */
+pub struct Transport {
+    pub protocol: IpNextHeaderProtocol,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub header_length: usize,
+    pub tcp: Option<TcpHeader>,
+    /** 疑似ヘッダを含めたチェックサム。UDPでチェックサムが0(省略)の場合や、フラグメントや途中で切れたパケットの一部しかない場合はNone */
+    pub checksum_valid: Option<bool>,
+}
+
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        if self.protocol == IpNextHeaderProtocols::Tcp {
+            "TCP"
+        } else {
+            "UDP"
+        }
+    }
+
+    /** TCPまたはUDPのヘッダを解析し、ヘッダとペイロードを返す */
+    pub fn parse<'a>(ip: &Ip, bytes: &'a [u8]) -> Option<(Transport, &'a [u8])> {
+        match ip.protocol {
+            IpNextHeaderProtocols::Tcp => {
+                let packet = TcpPacket::new(bytes)?;
+                let header_length = packet.get_data_offset() as usize * 4;
+                if header_length < 20 || header_length > bytes.len() {
+                    return None;
+                }
+                let checksum = match (ip.source, ip.destination) {
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => tcp::ipv4_checksum(&packet, &source, &destination),
+                    (IpAddr::V6(source), IpAddr::V6(destination)) => tcp::ipv6_checksum(&packet, &source, &destination),
+                    _ => return None,
+                };
+                let transport = Transport {
+                    protocol: ip.protocol,
+                    source_port: packet.get_source(),
+                    destination_port: packet.get_destination(),
+                    header_length,
+                    tcp: Some(TcpHeader {
+                        sequence: packet.get_sequence(),
+                        acknowledgement: packet.get_acknowledgement(),
+                        flags: packet.get_flags(),
+                        window: packet.get_window(),
+                    }),
+                    checksum_valid: if ip.fragment.is_some() || ip.truncated { None } else { Some(checksum == packet.get_checksum()) },
+                };
+                Some((transport, &bytes[header_length..]))
+            }
+            IpNextHeaderProtocols::Udp => {
+                let packet = UdpPacket::new(bytes)?;
+                let checksum = match (ip.source, ip.destination) {
+                    // IPv4のUDPではチェックサムを省略できる
+                    (IpAddr::V4(_), IpAddr::V4(_)) if packet.get_checksum() == 0 => None,
+                    _ if ip.fragment.is_some() || ip.truncated => None,
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => Some(udp::ipv4_checksum(&packet, &source, &destination)),
+                    (IpAddr::V6(source), IpAddr::V6(destination)) => Some(udp::ipv6_checksum(&packet, &source, &destination)),
+                    _ => return None,
+                };
+                let end = (packet.get_length() as usize).max(8).min(bytes.len());
+                let transport = Transport {
+                    protocol: ip.protocol,
+                    source_port: packet.get_source(),
+                    destination_port: packet.get_destination(),
+                    header_length: 8,
+                    tcp: None,
+                    checksum_valid: checksum.map(|checksum| checksum == packet.get_checksum()),
+                };
+                Some((transport, &bytes[8..end]))
+            }
+            _ => None,
+        }
     }
 }
 
-impl<'a> GettableEndPoints for TcpPacket<'a> {
-    fn get_source(&self) -> String {
-        self.get_source().to_string()
+/** フレームを解析できたところまでの各層と、最も内側のペイロード */
+pub struct DecodedPacket<'a> {
+    pub link: Link,
+    /** タグやラベルを除いた、ネットワーク層の先頭からのバイト列 */
+    pub network: &'a [u8],
+    pub ip: Option<Ip>,
+    pub transport: Option<Transport>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> DecodedPacket<'a> {
+    /** イーサネットフレームを解析する。IPより上は先頭のフラグメントだけを対象にする */
+    pub fn decode(frame: &'a [u8]) -> Option<DecodedPacket<'a>> {
+        let ethernet = EthernetPacket::new(frame)?;
+        let mut link = Link {
+            source: ethernet.get_source(),
+            destination: ethernet.get_destination(),
+            ethertype: ethernet.get_ethertype(),
+            vlans: Vec::new(),
+            mpls_labels: Vec::new(),
+        };
+        let mut payload = &frame[14..];
+
+        // VLANタグとMPLSラベルを外す
+        loop {
+            match link.ethertype {
+                EtherTypes::Vlan | EtherTypes::QinQ | ETHERTYPE_8021AD => {
+                    let vlan = VlanPacket::new(payload)?;
+                    link.vlans.push(VlanTag {
+                        tpid: link.ethertype,
+                        id: vlan.get_vlan_identifier(),
+                        priority: vlan.get_priority_code_point().0,
+                        drop_eligible: vlan.get_drop_eligible_indicator() != 0,
+                    });
+                    link.ethertype = vlan.get_ethertype();
+                    payload = &payload[4..];
+                }
+                ETHERTYPE_MPLS_UNICAST | ETHERTYPE_MPLS_MULTICAST => {
+                    let (labels, inner) = mpls_labels(payload);
+                    link.mpls_labels.extend(labels);
+                    payload = inner;
+                    // 底のラベルの次は先頭のニブルでIPv4/IPv6と判断する
+                    link.ethertype = match payload.first().map(|b| b >> 4) {
+                        Some(4) => EtherTypes::Ipv4,
+                        Some(6) => EtherTypes::Ipv6,
+                        _ => EtherType(0),
+                    };
+                }
+                _ => break,
+            }
+        }
+
+        let mut packet = DecodedPacket {
+            link,
+            network: payload,
+            ip: None,
+            transport: None,
+            payload,
+        };
+        let parsed = match packet.link.ethertype {
+            EtherTypes::Ipv4 => Ip::parse_v4(payload),
+            EtherTypes::Ipv6 => Ip::parse_v6(payload),
+            _ => None,
+        };
+        if let Some((ip, l4)) = parsed {
+            packet.payload = l4;
+            if ip.has_transport_header() {
+                if let Some((transport, data)) = Transport::parse(&ip, l4) {
+                    packet.transport = Some(transport);
+                    packet.payload = data;
+                }
+            }
+            packet.ip = Some(ip);
+        }
+        Some(packet)
     }
+}
 
-    fn get_destination(&self) -> String {
-        self.get_destination().to_string()
+/** MPLSのラベルスタックを読み、ラベルと内側のペイロードを返す */
+fn mpls_labels(payload: &[u8]) -> (Vec<MplsLabel>, &[u8]) {
+    let mut labels = Vec::new();
+    let mut offset = 0;
+    while payload.len() >= offset + 4 {
+        let label = MplsLabel::from_entry(u32::from_be_bytes([payload[offset], payload[offset + 1], payload[offset + 2], payload[offset + 3]]));
+        labels.push(label);
+        offset += 4;
+        if label.bottom {
+            break;
+        }
     }
+    (labels, &payload[offset..])
+}
 
-    fn get_payload(&self) -> &[u8] {
-        self.payload()
+/** 表示用に「アドレス | ポート」の形にする */
+pub fn endpoint(addr: IpAddr, port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("{} | {}", addr, port),
+        None => addr.to_string(),
     }
 }
 
-impl<'a> GettableEndPoints for UdpPacket<'a> {
-    fn get_source(&self) -> String {
-        self.get_source().to_string()
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn mpls_label_stack_entries_are_split_into_fields() {
+        let inner = testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b"");
+        let mut stack = Vec::new();
+        stack.extend_from_slice(&(0xfffffu32 << 12 | 5 << 9 | 255).to_be_bytes());
+        stack.extend_from_slice(&(16u32 << 12 | 0x100 | 1).to_be_bytes());
+        stack.extend_from_slice(&inner[14..]);
+        let frame = testutil::ethernet(0x8847, &stack);
+
+        let packet = DecodedPacket::decode(&frame).unwrap();
+        assert_eq!(
+            packet.link.mpls_labels,
+            vec![
+                MplsLabel { label: 0xfffff, traffic_class: 5, bottom: false, ttl: 255 },
+                MplsLabel { label: 16, traffic_class: 0, bottom: true, ttl: 1 },
+            ]
+        );
+        assert_eq!(packet.link.ethertype, EtherTypes::Ipv4);
+        assert_eq!(packet.transport.map(|transport| transport.destination_port), Some(53));
     }
 
-    fn get_destination(&self) -> String {
-        self.get_destination().to_string()
+    #[test]
+    fn mpls_stack_without_bottom_of_stack_is_not_ip() {
+        let frame = testutil::ethernet(0x8847, &(16u32 << 12 | 64).to_be_bytes());
+        let packet = DecodedPacket::decode(&frame).unwrap();
+        assert_eq!(packet.link.mpls_labels.len(), 1);
+        assert!(!packet.link.mpls_labels[0].bottom);
+        assert_eq!(packet.link.ethertype, EtherType(0));
+        assert!(packet.ip.is_none());
     }
 
-    fn get_payload(&self) -> &[u8] {
-        self.payload()
+    /** Hop-by-Hop、Routing、Destination Options形式の拡張ヘッダ(8バイト単位の長さを持つ) */
+    fn extension(next_header: u8, units: u8) -> Vec<u8> {
+        let mut header = vec![0; (units as usize + 1) * 8];
+        header[0] = next_header;
+        header[1] = units;
+        header
+    }
+
+    fn fragment_header(next_header: u8, offset: usize, more_fragments: bool, identification: u32) -> Vec<u8> {
+        let mut header = vec![next_header, 0];
+        // オフセットは8バイト単位で上位13ビットに入るので、バイト数のまま置ける
+        header.extend_from_slice(&(offset as u16 | u16::from(more_fragments)).to_be_bytes());
+        header.extend_from_slice(&identification.to_be_bytes());
+        header
+    }
+
+    fn headers(ip: &Ip) -> Vec<(IpNextHeaderProtocol, usize)> {
+        ip.extensions.iter().map(|extension| (extension.header, extension.length)).collect()
     }
-}
 
+    #[test]
+    fn ipv6_extension_chain_is_followed_to_the_upper_layer() {
+        let mut payload = extension(43, 0);
+        payload.extend(extension(44, 1));
+        payload.extend(fragment_header(testutil::PROTO_UDP, 0, true, 0xdeadbeef));
+        payload.extend(testutil::udp(5353, 53, b"query"));
+        let bytes = testutil::ipv6(0, "fe80::1", "fe80::2", &payload);
+
+        let (ip, rest) = Ip::parse_v6(&bytes).unwrap();
+        assert_eq!(headers(&ip), vec![(IpNextHeaderProtocols::Hopopt, 8), (IpNextHeaderProtocols::Ipv6Route, 16), (IpNextHeaderProtocols::Ipv6Frag, 8)]);
+        assert_eq!(ip.protocol, IpNextHeaderProtocols::Udp);
+        assert_eq!(ip.header_length, 40 + 32);
+        let fragment = ip.fragment.unwrap();
+        assert_eq!((fragment.identification, fragment.offset, fragment.more_fragments), (0xdeadbeef, 0, true));
+        assert!(!ip.truncated);
+        assert_eq!(rest.len(), 8 + 5);
+
+        let frame = testutil::ethernet(0x86dd, &bytes);
+        let packet = DecodedPacket::decode(&frame).unwrap();
+        let transport = packet.transport.unwrap();
+        assert_eq!((transport.source_port, transport.destination_port), (5353, 53));
+        assert_eq!(packet.payload, b"query");
+    }
+
+    #[test]
+    fn ipv6_later_fragments_have_no_upper_layer_header() {
+        let mut payload = fragment_header(testutil::PROTO_TCP, 1232, false, 7);
+        payload.extend_from_slice(b"rest of the segment");
+        let frame = testutil::ethernet(0x86dd, &testutil::ipv6(44, "2001:db8::1", "2001:db8::2", &payload));
+
+        let packet = DecodedPacket::decode(&frame).unwrap();
+        let ip = packet.ip.unwrap();
+        assert_eq!(ip.protocol, IpNextHeaderProtocols::Tcp);
+        assert_eq!(ip.fragment.map(|fragment| (fragment.offset, fragment.more_fragments)), Some((1232, false)));
+        assert!(packet.transport.is_none());
+        assert_eq!(packet.payload, b"rest of the segment");
+    }
+
+    #[test]
+    fn ipv6_truncated_extension_header_stops_the_chain() {
+        // Destination Optionsは24バイトあると言っているが、16バイトしかない
+        let mut payload = extension(60, 0);
+        payload.extend(&extension(testutil::PROTO_TCP, 2)[..16]);
+        let bytes = testutil::ipv6(0, "2001:db8::1", "2001:db8::2", &payload);
+
+        let (ip, rest) = Ip::parse_v6(&bytes).unwrap();
+        assert_eq!(headers(&ip), vec![(IpNextHeaderProtocols::Hopopt, 8)]);
+        assert_eq!(ip.protocol, IpNextHeaderProtocols::Ipv6Opts);
+        assert_eq!(ip.header_length, 48);
+        assert_eq!(rest.len(), 16);
+        assert!(DecodedPacket::decode(&testutil::ethernet(0x86dd, &bytes)).unwrap().transport.is_none());
+
+        // キャプチャがペイロード長より短い場合は途中で切れたことを示す
+        let (ip, _) = Ip::parse_v6(&bytes[..50]).unwrap();
+        assert!(ip.truncated);
+        assert_eq!(ip.protocol, IpNextHeaderProtocols::Ipv6Opts);
+    }
+
+    #[test]
+    fn ipv6_without_extensions_reports_the_next_header() {
+        let frame = testutil::tcp_frame("2001:db8::1", 40000, "2001:db8::2", 443, 1, testutil::TCP_SYN, b"");
+        let packet = DecodedPacket::decode(&frame).unwrap();
+        let ip = packet.ip.unwrap();
+        assert!(ip.extensions.is_empty());
+        assert_eq!((ip.protocol, ip.header_length, ip.ttl), (IpNextHeaderProtocols::Tcp, 40, 64));
+        assert_eq!(packet.transport.map(|transport| transport.destination_port), Some(443));
+    }
+}
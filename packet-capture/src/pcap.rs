@@ -0,0 +1,772 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use pnet::util::MacAddr;
+
+/** LINKTYPE_ETHERNET */
+const LINKTYPE_ETHERNET: u16 = 1;
+const SNAPLEN: u32 = 65535;
+/** 読み込むフレームの上限。壊れたファイルの長さで巨大なバッファを確保しないようにする(Wiresharkと同じ値) */
+const MAX_CAPLEN: u32 = 262_144;
+/** pcapngのブロックの上限。フレームの上限にオプションの分を加える */
+const MAX_BLOCK_LEN: usize = MAX_CAPLEN as usize + 65_536;
+
+/** マイクロ秒精度、ナノ秒精度のpcapのマジックナンバー */
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x0000_0003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_MACADDR: u16 = 6;
+const OPT_IF_TSRESOL: u16 = 9;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Pcap,
+    Pcapng,
+}
+
+impl Format {
+    /** 拡張子が.pcapngならpcapng、それ以外はpcap */
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("pcapng") => Format::Pcapng,
+            _ => Format::Pcap,
+        }
+    }
+}
+
+/** 書き出し先とローテーションの設定 */
+#[derive(Clone)]
+pub struct OutputConfig {
+    pub path: PathBuf,
+    pub format: Format,
+    /** このバイト数を超えたら次のファイルに切り替える */
+    pub rotate_size: Option<u64>,
+    /** この時間が経過したら次のファイルに切り替える */
+    pub rotate_interval: Option<Duration>,
+    /** 残しておくファイル数。超えたら古いものから削除する(リングバッファ) */
+    pub file_count: Option<usize>,
+}
+
+/** pcapngのInterface Description Blockに書き込むインターフェースの情報 */
+#[derive(Clone)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub mac: Option<MacAddr>,
+}
+
+/** 1つのキャプチャファイル */
+struct CaptureFile {
+    out: BufWriter<File>,
+    format: Format,
+    written: u64,
+}
+
+impl CaptureFile {
+    fn create(path: &Path, format: Format, interfaces: &[InterfaceInfo]) -> io::Result<CaptureFile> {
+        let mut file = CaptureFile {
+            out: BufWriter::new(File::create(path)?),
+            format,
+            written: 0,
+        };
+        match format {
+            Format::Pcap => file.write_pcap_header()?,
+            Format::Pcapng => {
+                file.write_section_header()?;
+                for interface in interfaces {
+                    file.write_interface_description(interface)?;
+                }
+            }
+        }
+        Ok(file)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.out.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn write_pcap_header(&mut self) -> io::Result<()> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC_NANOS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&u32::from(LINKTYPE_ETHERNET).to_le_bytes());
+        self.write(&header)
+    }
+
+    fn write_section_header(&mut self) -> io::Result<()> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // セクション長は不明(-1)
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        self.write_block(PCAPNG_SECTION_HEADER, &body)
+    }
+
+    fn write_interface_description(&mut self, interface: &InterfaceInfo) -> io::Result<()> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&SNAPLEN.to_le_bytes());
+        push_option(&mut body, OPT_IF_NAME, interface.name.as_bytes());
+        if let Some(mac) = interface.mac {
+            push_option(&mut body, OPT_IF_MACADDR, &[mac.0, mac.1, mac.2, mac.3, mac.4, mac.5]);
+        }
+        // タイムスタンプの分解能は10^-9秒
+        push_option(&mut body, OPT_IF_TSRESOL, &[9]);
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        self.write_block(PCAPNG_INTERFACE_DESCRIPTION, &body)
+    }
+
+    /** タイムスタンプはUNIXエポックからの経過時間
+     * ヘッダで宣言したsnaplenより長いフレームは切り詰め、元の長さはoriginal_lengthに残す
+     */
+    fn write_frame(&mut self, interface_id: u32, timestamp: Duration, data: &[u8], original_length: usize) -> io::Result<()> {
+        let original_length = original_length.max(data.len());
+        let data = &data[..data.len().min(SNAPLEN as usize)];
+        match self.format {
+            Format::Pcap => {
+                let mut header = Vec::with_capacity(16);
+                header.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+                header.extend_from_slice(&timestamp.subsec_nanos().to_le_bytes());
+                header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                header.extend_from_slice(&(original_length as u32).to_le_bytes());
+                self.write(&header)?;
+                self.write(data)
+            }
+            Format::Pcapng => {
+                let nanos = timestamp.as_secs() * 1_000_000_000 + u64::from(timestamp.subsec_nanos());
+                let mut body = Vec::with_capacity(20 + data.len() + 3);
+                body.extend_from_slice(&interface_id.to_le_bytes());
+                body.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+                body.extend_from_slice(&(nanos as u32).to_le_bytes());
+                body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                body.extend_from_slice(&(original_length as u32).to_le_bytes());
+                body.extend_from_slice(data);
+                pad_to_4(&mut body);
+                self.write_block(PCAPNG_ENHANCED_PACKET, &body)
+            }
+        }
+    }
+
+    /** ブロック種別、全長、本体、全長の順に書き込む */
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let total_len = (body.len() + 12) as u32;
+        let mut block = Vec::with_capacity(total_len as usize);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&total_len.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&total_len.to_le_bytes());
+        self.write(&block)
+    }
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad_to_4(body);
+}
+
+fn pad_to_4(body: &mut Vec<u8>) {
+    while !body.len().is_multiple_of(4) {
+        body.push(0);
+    }
+}
+
+/** キャプチャしたフレームをpcap/pcapngに書き出す。サイズや時間によるファイルのローテーションを行う */
+pub struct CaptureWriter {
+    config: OutputConfig,
+    interfaces: Vec<InterfaceInfo>,
+    current: CaptureFile,
+    opened_at: Instant,
+    last_flush: Instant,
+    /** ローテーションで作られたファイル。古い順 */
+    files: VecDeque<PathBuf>,
+    next_index: u32,
+}
+
+impl CaptureWriter {
+    pub fn create(config: OutputConfig, interfaces: Vec<InterfaceInfo>) -> io::Result<CaptureWriter> {
+        let path = file_path(&config, 1);
+        let current = CaptureFile::create(&path, config.format, &interfaces)?;
+        info!("writing {:?} to {}", config.format, path.display());
+
+        let mut files = VecDeque::new();
+        files.push_back(path);
+        Ok(CaptureWriter {
+            config,
+            interfaces,
+            current,
+            opened_at: Instant::now(),
+            last_flush: Instant::now(),
+            files,
+            next_index: 2,
+        })
+    }
+
+    /** interface_idはcreateで渡したインターフェースの添字 */
+    pub fn write_frame(&mut self, frame: &RecordedFrame) -> io::Result<()> {
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+        self.current.write_frame(frame.interface_id, frame.timestamp, &frame.data, frame.original_length)?;
+
+        // バッファに溜めたままにしないよう、1秒ごとにフラッシュする
+        if self.last_flush.elapsed() >= Duration::from_secs(1) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.current.out.flush()
+    }
+
+    fn should_rotate(&self) -> bool {
+        let by_size = self.config.rotate_size.is_some_and(|size| self.current.written >= size);
+        let by_time = self.config.rotate_interval.is_some_and(|interval| self.opened_at.elapsed() >= interval);
+        by_size || by_time
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.flush()?;
+        let path = file_path(&self.config, self.next_index);
+        self.next_index += 1;
+        self.current = CaptureFile::create(&path, self.config.format, &self.interfaces)?;
+        self.opened_at = Instant::now();
+        info!("rotated to {}", path.display());
+        self.files.push_back(path);
+
+        if let Some(count) = self.config.file_count {
+            while self.files.len() > count {
+                let oldest = self.files.pop_front().unwrap();
+                if let Err(e) = fs::remove_file(&oldest) {
+                    warn!("Failed to remove {}: {}", oldest.display(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/** ローテーションする場合は capture.pcap -> capture_00001.pcap のように連番を付ける */
+fn file_path(config: &OutputConfig, index: u32) -> PathBuf {
+    if config.rotate_size.is_none() && config.rotate_interval.is_none() {
+        return config.path.clone();
+    }
+    let stem = config.path.file_stem().and_then(|s| s.to_str()).unwrap_or("capture");
+    let name = match config.path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{}_{:05}.{}", stem, index, ext),
+        None => format!("{}_{:05}", stem, index),
+    };
+    config.path.with_file_name(name)
+}
+
+/** キャプチャファイルから読み込んだ、またはインターフェースで受信した1フレーム */
+#[derive(Clone)]
+pub struct RecordedFrame {
+    /** UNIXエポックからの経過時間 */
+    pub timestamp: Duration,
+    pub interface_id: u32,
+    pub data: Vec<u8>,
+    /** 回線上の長さ。snaplenで切り詰められた場合はdataより長い */
+    pub original_length: usize,
+}
+
+/** pcap/pcapngを読み込む。形式はファイル先頭のマジックナンバーで判別する */
+pub struct CaptureReader {
+    input: BufReader<File>,
+    format: Format,
+    big_endian: bool,
+    /** pcapの場合の1秒あたりのタイムスタンプ単位数 */
+    pcap_units_per_sec: u64,
+    /** pcapのグローバルヘッダのsnaplen。これより長いフレームは壊れているとみなす */
+    pcap_snaplen: u32,
+    /** pcapngのインターフェースごとの1秒あたりのタイムスタンプ単位数 */
+    interfaces: Vec<InterfaceResolution>,
+}
+
+struct InterfaceResolution {
+    linktype: u16,
+    units_per_sec: u64,
+}
+
+impl CaptureReader {
+    pub fn open(path: &Path) -> Result<CaptureReader, failure::Error> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+
+        let mut reader = CaptureReader {
+            input,
+            format: Format::Pcap,
+            big_endian: false,
+            pcap_units_per_sec: 1_000_000,
+            pcap_snaplen: MAX_CAPLEN,
+            interfaces: Vec::new(),
+        };
+
+        if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+            reader.format = Format::Pcapng;
+            reader.read_section_header()?;
+            return Ok(reader);
+        }
+
+        let (big_endian, units_per_sec) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (PCAP_MAGIC_MICROS, _) => (false, 1_000_000),
+            (PCAP_MAGIC_NANOS, _) => (false, 1_000_000_000),
+            (_, PCAP_MAGIC_MICROS) => (true, 1_000_000),
+            (_, PCAP_MAGIC_NANOS) => (true, 1_000_000_000),
+            _ => return Err(failure::err_msg(format!("{} is neither pcap nor pcapng", path.display()))),
+        };
+        reader.big_endian = big_endian;
+        reader.pcap_units_per_sec = units_per_sec;
+
+        let mut header = [0u8; 20];
+        reader.input.read_exact(&mut header)?;
+        let linktype = reader.u32(&header[16..20]);
+        if linktype != u32::from(LINKTYPE_ETHERNET) {
+            return Err(failure::err_msg(format!("Unsupported link type {}", linktype)));
+        }
+        // snaplenが0や上限より大きいファイルもあるので、その場合は上限で打ち切る
+        let snaplen = reader.u32(&header[12..16]);
+        reader.pcap_snaplen = if snaplen == 0 { MAX_CAPLEN } else { snaplen.min(MAX_CAPLEN) };
+        Ok(reader)
+    }
+
+    /** 次のフレームを返す。ファイルの終端ではNone */
+    pub fn next_frame(&mut self) -> Result<Option<RecordedFrame>, failure::Error> {
+        match self.format {
+            Format::Pcap => self.next_pcap_frame(),
+            Format::Pcapng => self.next_pcapng_frame(),
+        }
+    }
+
+    fn next_pcap_frame(&mut self) -> Result<Option<RecordedFrame>, failure::Error> {
+        let mut header = [0u8; 16];
+        if !self.read_or_eof(&mut header)? {
+            return Ok(None);
+        }
+        let seconds = u64::from(self.u32(&header[0..4]));
+        let fraction = u64::from(self.u32(&header[4..8]));
+        let caplen = self.u32(&header[8..12]);
+        let original_length = self.u32(&header[12..16]) as usize;
+        if caplen > self.pcap_snaplen {
+            return Err(failure::err_msg(format!("Invalid pcap record length {} (snaplen {})", caplen, self.pcap_snaplen)));
+        }
+
+        let mut data = vec![0u8; caplen as usize];
+        self.input.read_exact(&mut data)?;
+        Ok(Some(RecordedFrame {
+            timestamp: Duration::from_secs(seconds) + units_to_duration(fraction, self.pcap_units_per_sec),
+            interface_id: 0,
+            data,
+            original_length,
+        }))
+    }
+
+    fn next_pcapng_frame(&mut self) -> Result<Option<RecordedFrame>, failure::Error> {
+        loop {
+            let mut header = [0u8; 8];
+            if !self.read_or_eof(&mut header)? {
+                return Ok(None);
+            }
+            let block_type = self.u32(&header[0..4]);
+            let total_len = self.u32(&header[4..8]) as usize;
+            if !(12..=MAX_BLOCK_LEN).contains(&total_len) {
+                return Err(failure::err_msg(format!("Invalid pcapng block length {}", total_len)));
+            }
+            let mut body = vec![0u8; total_len - 12];
+            self.input.read_exact(&mut body)?;
+            let mut trailer = [0u8; 4];
+            self.input.read_exact(&mut trailer)?;
+
+            // 固定長部分より短いブロックは壊れている
+            let min_len = match block_type {
+                PCAPNG_SECTION_HEADER => 16,
+                PCAPNG_INTERFACE_DESCRIPTION => 8,
+                PCAPNG_ENHANCED_PACKET => 20,
+                PCAPNG_SIMPLE_PACKET => 4,
+                _ => 0,
+            };
+            if body.len() < min_len {
+                return Err(failure::err_msg(format!("Truncated pcapng block 0x{:08x} ({} bytes)", block_type, total_len)));
+            }
+
+            match block_type {
+                PCAPNG_SECTION_HEADER => {
+                    // 新しいセクション。インターフェースの番号は振り直される
+                    self.big_endian = body[0..4] != PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes();
+                    self.interfaces.clear();
+                }
+                PCAPNG_INTERFACE_DESCRIPTION => {
+                    let linktype = self.u16(&body[0..2]);
+                    let units_per_sec = self.interface_resolution(&body[8..])?;
+                    self.interfaces.push(InterfaceResolution { linktype, units_per_sec });
+                }
+                PCAPNG_ENHANCED_PACKET => {
+                    let interface_id = self.u32(&body[0..4]);
+                    let ts = (u64::from(self.u32(&body[4..8])) << 32) | u64::from(self.u32(&body[8..12]));
+                    let caplen = self.u32(&body[12..16]) as usize;
+                    let original_length = self.u32(&body[16..20]) as usize;
+                    let interface = self.interfaces.get(interface_id as usize).ok_or_else(|| failure::err_msg(format!("Unknown interface id {}", interface_id)))?;
+                    if interface.linktype != LINKTYPE_ETHERNET {
+                        continue;
+                    }
+                    let data = body.get(20..20 + caplen).ok_or_else(|| failure::err_msg("Truncated enhanced packet block"))?.to_vec();
+                    return Ok(Some(RecordedFrame {
+                        timestamp: units_to_duration(ts, interface.units_per_sec),
+                        interface_id,
+                        data,
+                        original_length,
+                    }));
+                }
+                PCAPNG_SIMPLE_PACKET => {
+                    // タイムスタンプを持たないブロック
+                    let original_length = self.u32(&body[0..4]) as usize;
+                    let caplen = original_length.min(body.len() - 4);
+                    return Ok(Some(RecordedFrame {
+                        timestamp: Duration::from_secs(0),
+                        interface_id: 0,
+                        data: body[4..4 + caplen].to_vec(),
+                        original_length,
+                    }));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn read_section_header(&mut self) -> Result<(), failure::Error> {
+        let mut len = [0u8; 4];
+        self.input.read_exact(&mut len)?;
+        let mut magic = [0u8; 4];
+        self.input.read_exact(&mut magic)?;
+        self.big_endian = magic != PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes();
+
+        // 残り(バージョン、セクション長、オプション、全長)を読み飛ばす
+        let total_len = self.u32(&len) as usize;
+        if !(28..=MAX_BLOCK_LEN).contains(&total_len) {
+            return Err(failure::err_msg(format!("Invalid pcapng section header length {}", total_len)));
+        }
+        let mut rest = vec![0u8; total_len - 12];
+        self.input.read_exact(&mut rest)?;
+        Ok(())
+    }
+
+    /** IDBのif_tsresolオプションから分解能を求める。省略時はマイクロ秒
+     * 1秒あたりの単位数がu64に収まらない分解能はエラーにする
+     */
+    fn interface_resolution(&self, mut options: &[u8]) -> Result<u64, failure::Error> {
+        while options.len() >= 4 {
+            let code = self.u16(&options[0..2]);
+            let len = self.u16(&options[2..4]) as usize;
+            if code == OPT_ENDOFOPT || options.len() < 4 + len {
+                break;
+            }
+            if code == OPT_IF_TSRESOL && len >= 1 {
+                let resolution = options[4];
+                let units_per_sec = if resolution & 0x80 != 0 { 1u64.checked_shl(u32::from(resolution & 0x7f)) } else { 10u64.checked_pow(u32::from(resolution)) };
+                return units_per_sec.ok_or_else(|| failure::err_msg(format!("Unsupported timestamp resolution 0x{:02x}", resolution)));
+            }
+            // 最後のオプションはパディングが省かれていることがある
+            options = options.get((4 + len).div_ceil(4) * 4..).unwrap_or(&[]);
+        }
+        Ok(1_000_000)
+    }
+
+    /** バッファを埋める。ファイルの終端に達していればfalse */
+    fn read_or_eof(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        match self.input.read_exact(buf) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn u16(&self, buf: &[u8]) -> u16 {
+        let bytes = [buf[0], buf[1]];
+        if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+    }
+
+    fn u32(&self, buf: &[u8]) -> u32 {
+        let bytes = [buf[0], buf[1], buf[2], buf[3]];
+        if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+    }
+}
+
+fn units_to_duration(units: u64, units_per_sec: u64) -> Duration {
+    let nanos = (u128::from(units % units_per_sec) * 1_000_000_000 / u128::from(units_per_sec)) as u32;
+    Duration::new(units / units_per_sec, nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** テストごとに別の一時ファイルを使う */
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("packet-capture-{}-{}", std::process::id(), name))
+    }
+
+    fn read_all(path: &Path) -> Result<Vec<RecordedFrame>, failure::Error> {
+        let mut reader = CaptureReader::open(path)?;
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.next_frame()? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    fn sample_frames() -> Vec<RecordedFrame> {
+        vec![
+            RecordedFrame {
+                timestamp: Duration::new(1_700_000_000, 123_456_789),
+                interface_id: 0,
+                data: (0u8..60).collect(),
+                original_length: 60,
+            },
+            RecordedFrame {
+                timestamp: Duration::new(1_700_000_001, 5),
+                interface_id: 1,
+                // snaplenで切り詰められたフレーム。pcapngのパディングも確認する
+                data: vec![0xab; 37],
+                original_length: 1514,
+            },
+        ]
+    }
+
+    fn round_trip(name: &str, format: Format) -> Vec<RecordedFrame> {
+        round_trip_frames(name, format, &sample_frames())
+    }
+
+    fn round_trip_frames(name: &str, format: Format, written: &[RecordedFrame]) -> Vec<RecordedFrame> {
+        let path = temp_path(name);
+        let config = OutputConfig {
+            path: path.clone(),
+            format,
+            rotate_size: None,
+            rotate_interval: None,
+            file_count: None,
+        };
+        let interfaces = vec![
+            InterfaceInfo { name: "eth0".to_string(), mac: Some(MacAddr::new(2, 0, 0, 0, 0, 1)) },
+            InterfaceInfo { name: "eth1".to_string(), mac: None },
+        ];
+        let mut writer = CaptureWriter::create(config, interfaces).unwrap();
+        for frame in written {
+            writer.write_frame(frame).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let frames = read_all(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        frames
+    }
+
+    #[test]
+    fn pcap_round_trip() {
+        let frames = round_trip("round-trip.pcap", Format::Pcap);
+        let expected = sample_frames();
+        assert_eq!(frames.len(), expected.len());
+        for (frame, expected) in frames.iter().zip(expected.iter()) {
+            assert_eq!(frame.timestamp, expected.timestamp);
+            assert_eq!(frame.data, expected.data);
+            assert_eq!(frame.original_length, expected.original_length);
+            // pcapはインターフェースを区別しない
+            assert_eq!(frame.interface_id, 0);
+        }
+    }
+
+    #[test]
+    fn pcapng_round_trip() {
+        let frames = round_trip("round-trip.pcapng", Format::Pcapng);
+        let expected = sample_frames();
+        assert_eq!(frames.len(), expected.len());
+        for (frame, expected) in frames.iter().zip(expected.iter()) {
+            assert_eq!(frame.timestamp, expected.timestamp);
+            assert_eq!(frame.interface_id, expected.interface_id);
+            assert_eq!(frame.data, expected.data);
+            assert_eq!(frame.original_length, expected.original_length);
+        }
+    }
+
+    #[test]
+    fn frames_longer_than_snaplen_are_truncated() {
+        // ジャンボフレームやGROでまとめられたフレームはsnaplenを超える
+        let long = vec![RecordedFrame {
+            timestamp: Duration::new(1_700_000_002, 0),
+            interface_id: 0,
+            data: (0..70_000).map(|i| i as u8).collect(),
+            original_length: 70_000,
+        }];
+        for (name, format) in [("snaplen.pcap", Format::Pcap), ("snaplen.pcapng", Format::Pcapng)] {
+            let frames = round_trip_frames(name, format, &long);
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].data.len(), SNAPLEN as usize);
+            assert_eq!(frames[0].data[..], long[0].data[..SNAPLEN as usize]);
+            assert_eq!(frames[0].original_length, 70_000);
+        }
+    }
+
+    #[test]
+    fn reads_big_endian_microsecond_pcap() {
+        let mut file = Vec::new();
+        file.extend_from_slice(&PCAP_MAGIC_MICROS.to_be_bytes());
+        file.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        file.extend_from_slice(&65535u32.to_be_bytes());
+        file.extend_from_slice(&1u32.to_be_bytes());
+        file.extend_from_slice(&10u32.to_be_bytes());
+        file.extend_from_slice(&250_000u32.to_be_bytes());
+        file.extend_from_slice(&3u32.to_be_bytes());
+        file.extend_from_slice(&3u32.to_be_bytes());
+        file.extend_from_slice(&[1, 2, 3]);
+
+        let path = temp_path("big-endian.pcap");
+        fs::write(&path, &file).unwrap();
+        let frames = read_all(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].timestamp, Duration::from_millis(10_250));
+        assert_eq!(frames[0].data, vec![1, 2, 3]);
+    }
+
+    /** pcapのグローバルヘッダ(リトルエンディアン、マイクロ秒) */
+    fn pcap_header() -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend_from_slice(&PCAP_MAGIC_MICROS.to_le_bytes());
+        file.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        file.extend_from_slice(&SNAPLEN.to_le_bytes());
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let total_len = (body.len() + 12) as u32;
+        let mut block = Vec::new();
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&total_len.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&total_len.to_le_bytes());
+        block
+    }
+
+    fn section_header() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&[1, 0, 0, 0]);
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        block(PCAPNG_SECTION_HEADER, &body)
+    }
+
+    fn interface_description(options: &[u8]) -> Vec<u8> {
+        let mut body = vec![1, 0, 0, 0, 0xff, 0xff, 0, 0];
+        body.extend_from_slice(options);
+        block(PCAPNG_INTERFACE_DESCRIPTION, &body)
+    }
+
+    /** 読み込みがパニックせずにエラーを返すことを確認する */
+    fn assert_rejected(name: &str, file: &[u8]) {
+        let path = temp_path(name);
+        fs::write(&path, file).unwrap();
+        let result = read_all(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err(), "{} was accepted", name);
+    }
+
+    #[test]
+    fn rejects_oversized_pcap_record() {
+        let mut file = pcap_header();
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_rejected("oversized.pcap", &file);
+    }
+
+    #[test]
+    fn rejects_truncated_pcapng_blocks() {
+        for (name, block_type) in [
+            ("short-shb.pcapng", PCAPNG_SECTION_HEADER),
+            ("short-idb.pcapng", PCAPNG_INTERFACE_DESCRIPTION),
+            ("short-epb.pcapng", PCAPNG_ENHANCED_PACKET),
+            ("short-spb.pcapng", PCAPNG_SIMPLE_PACKET),
+        ] {
+            let mut file = section_header();
+            file.extend(interface_description(&[]));
+            file.extend(block(block_type, &[]));
+            assert_rejected(name, &file);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_pcapng_block_length() {
+        let mut file = section_header();
+        file.extend_from_slice(&PCAPNG_ENHANCED_PACKET.to_le_bytes());
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_rejected("huge-block.pcapng", &file);
+
+        let mut file = section_header();
+        file.extend_from_slice(&PCAPNG_ENHANCED_PACKET.to_le_bytes());
+        file.extend_from_slice(&8u32.to_le_bytes());
+        assert_rejected("tiny-block.pcapng", &file);
+    }
+
+    #[test]
+    fn rejects_unrepresentable_timestamp_resolution() {
+        // 10^100と2^127はu64に収まらない
+        for resolution in [100u8, 0xff] {
+            let mut file = section_header();
+            file.extend(interface_description(&[9, 0, 1, 0, resolution, 0, 0, 0]));
+            assert_rejected("tsresol.pcapng", &file);
+        }
+    }
+
+    #[test]
+    fn reads_simple_packet_and_binary_resolution() {
+        let mut file = section_header();
+        // 2^-10秒単位
+        file.extend(interface_description(&[9, 0, 1, 0, 0x8a, 0, 0, 0, 0, 0, 0, 0]));
+        let mut epb = Vec::new();
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&2048u32.to_le_bytes());
+        epb.extend_from_slice(&2u32.to_le_bytes());
+        epb.extend_from_slice(&2u32.to_le_bytes());
+        epb.extend_from_slice(&[7, 8, 0, 0]);
+        file.extend(block(PCAPNG_ENHANCED_PACKET, &epb));
+        // 本体より長いoriginal_lengthは本体の長さに切り詰める
+        file.extend(block(PCAPNG_SIMPLE_PACKET, &[100, 0, 0, 0, 1, 2, 3, 4]));
+
+        let path = temp_path("simple.pcapng");
+        fs::write(&path, &file).unwrap();
+        let frames = read_all(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].timestamp, Duration::from_secs(2));
+        assert_eq!(frames[0].data, vec![7, 8]);
+        assert_eq!(frames[1].data, vec![1, 2, 3, 4]);
+        assert_eq!(frames[1].original_length, 100);
+    }
+}
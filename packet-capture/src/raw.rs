@@ -0,0 +1,117 @@
+//! BPFを取り付けるためのAF_PACKETソケット
+//! pnetのチャンネルはソケットを公開しないため、--kernel-filterと--kernel-timestampsの時はこちらで受信する
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use crate::bpf::{self, Instruction};
+
+pub struct RawSocket {
+    fd: RawFd,
+    buffer: Vec<u8>,
+    /** 補助データ(SCM_TIMESTAMPNS)を受け取る領域 */
+    control: Vec<u8>,
+}
+
+/** 受信した1フレームと、カーネルが付けた受信時刻、回線上の長さ */
+pub struct Received<'a> {
+    pub data: &'a [u8],
+    pub timestamp: Option<Duration>,
+    pub original_length: usize,
+}
+
+impl RawSocket {
+    /** インターフェースに束縛したソケットを開き、プログラムがあれば取り付ける */
+    pub fn open(ifindex: u32, program: Option<&[Instruction]>) -> Result<RawSocket, failure::Error> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be() as libc::c_int;
+        // プロトコル0で作ったソケットはbindするまで何も受信しない
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let socket = RawSocket {
+            fd,
+            buffer: vec![0; bpf::ACCEPT_LEN as usize],
+            control: vec![0; 64],
+        };
+
+        // bindより前に取り付け、フィルタを通っていないパケットがキューに入らないようにする
+        if let Some(program) = program {
+            bpf::attach(fd, program)?;
+        }
+
+        // 受信時刻はユーザー空間で受け取った時刻ではなく、カーネルが受信した時刻を使う
+        let enable: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_TIMESTAMPNS,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = protocol as u16;
+        addr.sll_ifindex = ifindex as libc::c_int;
+        let ret = unsafe { libc::bind(fd, &addr as *const libc::sockaddr_ll as *const libc::sockaddr, mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(socket)
+    }
+
+    /** 1フレーム受信する
+     * MSG_TRUNCを付けると、バッファに収まらなかった場合も回線上の長さが返る
+     */
+    pub fn next(&mut self) -> io::Result<Received<'_>> {
+        let mut iov = libc::iovec {
+            iov_base: self.buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: self.buffer.len(),
+        };
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = self.control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = self.control.len() as _;
+
+        let size = unsafe { libc::recvmsg(self.fd, &mut message, libc::MSG_TRUNC) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut timestamp = None;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&message);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS {
+                    let spec = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                    timestamp = Some(Duration::new(spec.tv_sec as u64, spec.tv_nsec as u32));
+                }
+                cmsg = libc::CMSG_NXTHDR(&message, cmsg);
+            }
+        }
+
+        let original_length = size as usize;
+        Ok(Received {
+            data: &self.buffer[..original_length.min(self.buffer.len())],
+            timestamp,
+            original_length,
+        })
+    }
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,600 @@
+//! IPv4フラグメントの再構築とTCPストリームの再構築
+//! フラグメントは揃った時点で1つのIPv4パケットに戻して通常のハンドラに流す
+//! TCPはシーケンス番号で並べ替え、再送や重複を取り除いた方向ごとのバイト列を表示またはファイルに書き出す
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::tcp::TcpFlags;
+use pnet::packet::Packet;
+
+use crate::packet::{DecodedPacket, Ip, Transport};
+
+/** 揃わないフラグメントを保持する時間 (RFC 791の推奨値の上限に合わせる) */
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+/** 通信のないTCPストリームを閉じるまでの時間 */
+const STREAM_TIMEOUT: Duration = Duration::from_secs(300);
+/** 欠けたセグメントを待つ間に溜める最大バイト数。超えたら欠落として先に進む */
+const MAX_PENDING_BYTES: usize = 1024 * 1024;
+/** IPv4パケットの最大長 */
+const MAX_DATAGRAM: usize = 65535;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    identification: u16,
+    protocol: u8,
+}
+
+struct FragmentBuffer {
+    /** オフセットが0のフラグメントのIPヘッダ */
+    header: Option<Vec<u8>>,
+    /** ペイロード中のオフセットとデータ。同じオフセットは先に届いたものを使う */
+    pieces: BTreeMap<usize, Vec<u8>>,
+    /** 最後のフラグメントが届いたら分かるペイロード全体の長さ */
+    total_length: Option<usize>,
+    last_seen: Duration,
+}
+
+impl FragmentBuffer {
+    /** 隙間なく揃っていれば重複を除いて連結する */
+    fn assemble(&self) -> Option<Vec<u8>> {
+        let total_length = self.total_length?;
+        let mut data = Vec::with_capacity(total_length);
+        for (offset, piece) in self.pieces.iter() {
+            if *offset > data.len() {
+                return None;
+            }
+            let end = (offset + piece.len()).min(total_length);
+            if end > data.len() {
+                let start = data.len() - offset;
+                data.extend_from_slice(&piece[start..end - offset]);
+            }
+        }
+        if data.len() == total_length {
+            Some(data)
+        } else {
+            None
+        }
+    }
+}
+
+/** TCPの片方向を表す送信元と宛先 */
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct StreamKey {
+    source: (IpAddr, u16),
+    destination: (IpAddr, u16),
+}
+
+struct Stream {
+    /** 最初のデータバイトのシーケンス番号 */
+    base: u32,
+    /** 次に期待する、baseからの相対オフセット */
+    next: u32,
+    /** 先に届いたセグメント。相対オフセットをキーにする */
+    pending: BTreeMap<u32, Vec<u8>>,
+    pending_bytes: usize,
+    delivered: u64,
+    finished: bool,
+    file: Option<BufWriter<File>>,
+    last_seen: Duration,
+}
+
+/** 再構築の設定 */
+pub struct ReassemblyConfig {
+    /** 再構築したTCPストリームを表示する */
+    pub follow_stream: bool,
+    /** 再構築したTCPストリームを方向ごとのファイルに書き出すディレクトリ */
+    pub stream_dir: Option<PathBuf>,
+}
+
+pub struct Reassembler {
+    config: ReassemblyConfig,
+    fragments: HashMap<FragmentKey, FragmentBuffer>,
+    streams: HashMap<StreamKey, Stream>,
+    /** 閉じたストリームと閉じた時刻。遅れて届いたACKや再送でファイルを作り直さないよう、しばらく覚えておく */
+    closed: HashMap<StreamKey, Duration>,
+    /** 処理中のフレームのタイムスタンプ。ファイルの読み込みでもキャプチャ時刻で期限を判断する */
+    now: Duration,
+}
+
+impl Reassembler {
+    pub fn new(config: ReassemblyConfig) -> Reassembler {
+        Reassembler {
+            config,
+            fragments: HashMap::new(),
+            streams: HashMap::new(),
+            closed: HashMap::new(),
+            now: Duration::from_secs(0),
+        }
+    }
+
+    /** フレームのタイムスタンプを進め、期限切れのフラグメントとストリームを捨てる */
+    fn advance(&mut self, now: Duration) {
+        self.now = now;
+        let fragments_before = self.fragments.len();
+        self.fragments.retain(|_, buffer| now.checked_sub(buffer.last_seen).is_none_or(|idle| idle < FRAGMENT_TIMEOUT));
+        if self.fragments.len() < fragments_before {
+            info!("Dropped {} incomplete fragmented packets", fragments_before - self.fragments.len());
+        }
+
+        let expired: Vec<StreamKey> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| now.checked_sub(stream.last_seen).is_some_and(|idle| idle >= STREAM_TIMEOUT))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            self.close_stream(&key, "idle");
+        }
+        self.closed.retain(|_, closed_at| now.checked_sub(*closed_at).is_none_or(|elapsed| elapsed < STREAM_TIMEOUT));
+    }
+
+    /** TCPストリームを追跡するか */
+    fn tracks_streams(&self) -> bool {
+        self.config.follow_stream || self.config.stream_dir.is_some()
+    }
+
+    pub fn follows_streams(&self) -> bool {
+        self.config.follow_stream
+    }
+
+    /** 出力形式に関係なくすべてのフレームを渡す。フラグメントを保持し、TCPセグメントをストリームに加える
+     * フラグメントが揃った時は、表示に使えるよう再構築したIPv4パケットを返す
+     */
+    pub fn feed(&mut self, frame: &[u8], now: Duration) -> Option<Vec<u8>> {
+        self.advance(now);
+        let packet = DecodedPacket::decode(frame)?;
+        let ip = packet.ip.as_ref()?;
+        if ip.fragment.is_some() {
+            if ip.version() != 4 {
+                return None;
+            }
+            let whole = Ipv4Packet::new(packet.network).and_then(|fragment| self.defragment(&fragment))?;
+            if self.tracks_streams() {
+                if let Some((ip, l4)) = Ip::parse_v4(&whole) {
+                    if let Some((transport, payload)) = Transport::parse(&ip, l4) {
+                        self.tcp_segment(&ip, &transport, payload);
+                    }
+                }
+            }
+            return Some(whole);
+        }
+        if self.tracks_streams() {
+            if let Some(transport) = packet.transport.as_ref() {
+                self.tcp_segment(ip, transport, packet.payload);
+            }
+        }
+        None
+    }
+
+    /** フラグメントを保持し、すべて揃ったら再構築したIPv4パケットを返す */
+    fn defragment(&mut self, packet: &Ipv4Packet) -> Option<Vec<u8>> {
+        let key = FragmentKey {
+            source: packet.get_source(),
+            destination: packet.get_destination(),
+            identification: packet.get_identification(),
+            protocol: packet.get_next_level_protocol().0,
+        };
+        let offset = packet.get_fragment_offset() as usize * 8;
+        let more_fragments = packet.get_flags() & Ipv4Flags::MoreFragments != 0;
+        let payload = packet.payload();
+        if offset + payload.len() > MAX_DATAGRAM {
+            warn!("Fragment of {} exceeds the maximum datagram size", key.source);
+            return None;
+        }
+
+        let now = self.now;
+        let buffer = self.fragments.entry(key).or_insert_with(|| FragmentBuffer {
+            header: None,
+            pieces: BTreeMap::new(),
+            total_length: None,
+            last_seen: now,
+        });
+        buffer.last_seen = now;
+        if offset == 0 && buffer.header.is_none() {
+            let header_length = packet.get_header_length() as usize * 4;
+            buffer.header = Some(packet.packet()[..header_length].to_vec());
+        }
+        if !more_fragments {
+            buffer.total_length = Some(offset + payload.len());
+        }
+        buffer.pieces.entry(offset).or_insert_with(|| payload.to_vec());
+
+        let data = match (buffer.header.as_ref(), buffer.assemble()) {
+            (Some(_), Some(data)) => data,
+            _ => return None,
+        };
+        let buffer = self.fragments.remove(&key)?;
+        let mut whole = buffer.header?;
+        let header_length = whole.len();
+        whole.extend_from_slice(&data);
+        if whole.len() > MAX_DATAGRAM {
+            return None;
+        }
+
+        // 1つのパケットとして扱えるよう、長さとフラグメントの情報を書き換える
+        let mut ipv4 = MutableIpv4Packet::new(&mut whole)?;
+        ipv4.set_total_length((header_length + data.len()) as u16);
+        ipv4.set_flags(ipv4.get_flags() & !Ipv4Flags::MoreFragments);
+        ipv4.set_fragment_offset(0);
+        let checksum = pnet::packet::ipv4::checksum(&ipv4.to_immutable());
+        ipv4.set_checksum(checksum);
+        info!("Reassembled {} bytes from {} fragments", data.len(), buffer.pieces.len());
+        Some(whole)
+    }
+
+    /** TCPセグメントを方向ごとのストリームに加え、新しく連続したデータを表示・書き出しする */
+    fn tcp_segment(&mut self, ip: &Ip, transport: &Transport, payload: &[u8]) {
+        let header = match transport.tcp {
+            Some(header) => header,
+            None => return,
+        };
+        let key = StreamKey {
+            source: (ip.source, transport.source_port),
+            destination: (ip.destination, transport.destination_port),
+        };
+        let flags = header.flags;
+        let sequence = header.sequence;
+
+        if !self.streams.contains_key(&key) {
+            let syn = flags & TcpFlags::SYN != 0;
+            // 閉じた後の最後のACKや再送では開き直さない。同じポートの組で新しく接続した時はSYNから始まる
+            if !syn && (payload.is_empty() || self.closed.contains_key(&key)) {
+                return;
+            }
+            // 閉じたストリームを開き直す時は、書き出し済みの内容を残して後ろに追記する
+            let reopened = self.closed.remove(&key).is_some();
+            // SYNを見ていなければ、最初に見たセグメントから始める
+            let base = if syn { sequence.wrapping_add(1) } else { sequence };
+            let file = self.open_stream_file(&key, reopened);
+            self.streams.insert(
+                key,
+                Stream {
+                    base,
+                    next: 0,
+                    pending: BTreeMap::new(),
+                    pending_bytes: 0,
+                    delivered: 0,
+                    finished: false,
+                    file,
+                    last_seen: self.now,
+                },
+            );
+        }
+
+        let data = {
+            let stream = self.streams.get_mut(&key).unwrap();
+            stream.last_seen = self.now;
+            // SYN自体はシーケンス番号を1つ消費するので、データはその次から始まる
+            let start = if flags & TcpFlags::SYN != 0 { sequence.wrapping_add(1) } else { sequence };
+            stream.accept(start.wrapping_sub(stream.base), payload)
+        };
+        if !data.is_empty() {
+            self.deliver(&key, &data);
+        }
+
+        if flags & TcpFlags::RST != 0 {
+            self.close_stream(&key, "reset");
+            let reverse = StreamKey { source: key.destination, destination: key.source };
+            self.close_stream(&reverse, "reset");
+        } else if flags & TcpFlags::FIN != 0 {
+            if let Some(stream) = self.streams.get_mut(&key) {
+                stream.finished = true;
+            }
+            let reverse = StreamKey { source: key.destination, destination: key.source };
+            if self.streams.get(&reverse).is_some_and(|stream| stream.finished) {
+                self.close_stream(&key, "finished");
+                self.close_stream(&reverse, "finished");
+            }
+        }
+    }
+
+    /** 残っているストリームをすべて閉じる */
+    pub fn finish(&mut self) {
+        let keys: Vec<StreamKey> = self.streams.keys().cloned().collect();
+        for key in keys {
+            self.close_stream(&key, "end of capture");
+        }
+    }
+
+    fn deliver(&mut self, key: &StreamKey, data: &[u8]) {
+        if self.config.follow_stream {
+            println!("Stream {} -> {} ({} bytes)", endpoint(key.source), endpoint(key.destination), data.len());
+            println!("{}", String::from_utf8_lossy(data));
+            println!("{}", "=".repeat(48));
+        }
+        if let Some(stream) = self.streams.get_mut(key) {
+            stream.delivered += data.len() as u64;
+            if let Some(file) = stream.file.as_mut() {
+                if let Err(e) = file.write_all(data) {
+                    error!("Failed to write stream {} -> {}: {}", endpoint(key.source), endpoint(key.destination), e);
+                    stream.file = None;
+                }
+            }
+        }
+    }
+
+    fn close_stream(&mut self, key: &StreamKey, reason: &str) {
+        let mut stream = match self.streams.remove(key) {
+            Some(stream) => stream,
+            None => return,
+        };
+        if !stream.pending.is_empty() {
+            warn!("Stream {} -> {} closed with {} bytes after a gap", endpoint(key.source), endpoint(key.destination), stream.pending_bytes);
+        }
+        if let Some(mut file) = stream.file.take() {
+            if let Err(e) = file.flush() {
+                error!("Failed to write stream {} -> {}: {}", endpoint(key.source), endpoint(key.destination), e);
+            }
+        }
+        if self.config.follow_stream {
+            println!("Stream {} -> {} closed ({}, {} bytes)\n", endpoint(key.source), endpoint(key.destination), reason, stream.delivered);
+        }
+        self.closed.insert(*key, self.now);
+    }
+
+    /** tcpflowと同じく「送信元.ポート-宛先.ポート」という名前で書き出す。appendの時は既存の内容に追記する */
+    fn open_stream_file(&self, key: &StreamKey, append: bool) -> Option<BufWriter<File>> {
+        let dir = self.config.stream_dir.as_ref()?;
+        let name = format!("{}.{}-{}.{}", key.source.0, key.source.1, key.destination.0, key.destination.1);
+        let path = dir.join(name);
+        let opened = if append { OpenOptions::new().append(true).create(true).open(&path) } else { File::create(&path) };
+        match opened {
+            Ok(file) => Some(BufWriter::new(file)),
+            Err(e) => {
+                error!("Failed to create {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+impl Stream {
+    /** セグメントを加え、次に期待するオフセットから連続したデータを返す
+     * 受け取り済みの範囲(再送)は捨て、一部が重なるセグメントは先に受け取ったデータを優先する
+     */
+    fn accept(&mut self, offset: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        if payload.is_empty() {
+            return data;
+        }
+        let end = offset.wrapping_add(payload.len() as u32);
+
+        // シーケンス番号の折り返しを考慮して、nextより前かを符号付きの差で判断する
+        if (end.wrapping_sub(self.next) as i32) <= 0 {
+            return data;
+        }
+        if (offset.wrapping_sub(self.next) as i32) > 0 {
+            let replace = self.pending.get(&offset).is_none_or(|held| held.len() < payload.len());
+            if replace {
+                let previous = self.pending.insert(offset, payload.to_vec()).map_or(0, |held| held.len());
+                self.pending_bytes = self.pending_bytes + payload.len() - previous;
+            }
+            if self.pending_bytes > MAX_PENDING_BYTES {
+                // 欠けたセグメントはもう届かないとみなし、保持している先頭まで進める
+                let first = *self.pending.keys().min_by_key(|key| key.wrapping_sub(self.next)).unwrap();
+                warn!("Skipping {} missing bytes in a TCP stream", first.wrapping_sub(self.next));
+                self.next = first;
+            } else {
+                return data;
+            }
+        } else {
+            let skip = self.next.wrapping_sub(offset) as usize;
+            data.extend_from_slice(&payload[skip..]);
+            self.next = end;
+        }
+
+        // 保持していたセグメントのうち、つながったものを取り出す
+        loop {
+            let next = self.next;
+            let key = match self.pending.keys().find(|key| (key.wrapping_sub(next) as i32) <= 0) {
+                Some(key) => *key,
+                None => break,
+            };
+            let segment = self.pending.remove(&key).unwrap();
+            self.pending_bytes -= segment.len();
+            let segment_end = key.wrapping_add(segment.len() as u32);
+            if (segment_end.wrapping_sub(next) as i32) > 0 {
+                let skip = next.wrapping_sub(key) as usize;
+                data.extend_from_slice(&segment[skip..]);
+                self.next = segment_end;
+            }
+        }
+        data
+    }
+}
+
+fn endpoint((addr, port): (IpAddr, u16)) -> String {
+    match addr {
+        IpAddr::V4(addr) => format!("{}:{}", addr, port),
+        IpAddr::V6(addr) => format!("[{}]:{}", addr, port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, PROTO_TCP, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+
+    fn stream() -> Stream {
+        Stream {
+            base: 0,
+            next: 0,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            delivered: 0,
+            finished: false,
+            file: None,
+            last_seen: Duration::from_secs(0),
+        }
+    }
+
+    fn stream_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("packet-capture-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn reassembler(stream_dir: Option<PathBuf>) -> Reassembler {
+        Reassembler::new(ReassemblyConfig { follow_stream: false, stream_dir })
+    }
+
+    #[test]
+    fn out_of_order_segments_are_delivered_in_sequence() {
+        let mut stream = stream();
+        assert!(stream.accept(10, b"world").is_empty());
+        assert!(stream.accept(5, b", ").is_empty());
+        assert_eq!(stream.pending_bytes, 7);
+        // 5から7まではつながるが、10からのセグメントは隙間が埋まるまで保持したまま
+        assert_eq!(stream.accept(0, b"hello"), b"hello, ");
+        assert_eq!(stream.pending_bytes, 5);
+        assert_eq!(stream.accept(7, b"..."), b"...world");
+        assert!(stream.pending.is_empty());
+        assert_eq!(stream.pending_bytes, 0);
+        assert_eq!(stream.next, 15);
+    }
+
+    #[test]
+    fn retransmitted_and_overlapping_segments_keep_the_first_data() {
+        let mut stream = stream();
+        assert_eq!(stream.accept(0, b"hello"), b"hello");
+        assert!(stream.accept(0, b"hello").is_empty());
+        assert!(stream.accept(2, b"LL").is_empty());
+        // 受け取り済みの範囲と重なる部分は捨て、新しい部分だけを返す
+        assert_eq!(stream.accept(3, b"LO world"), b" world");
+
+        // 保持中のセグメントに重なる、より長いセグメントは置き換える
+        assert!(stream.accept(13, b"ab").is_empty());
+        assert!(stream.accept(13, b"abcd").is_empty());
+        assert_eq!(stream.pending_bytes, 4);
+        assert_eq!(stream.accept(11, b"!!XX"), b"!!XXcd");
+        assert_eq!(stream.next, 17);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut stream = stream();
+        stream.next = u32::MAX - 1;
+        // 折り返した後のオフセットは、折り返す前のnextより後ろとして保持する
+        assert!(stream.accept(1, b"de").is_empty());
+        assert_eq!(stream.accept(u32::MAX - 1, b"abc"), b"abcde");
+        assert_eq!(stream.next, 3);
+    }
+
+    #[test]
+    fn feed_writes_streams_without_printing() {
+        let dir = stream_dir("stream-dir");
+        let mut reassembler = reassembler(Some(dir.clone()));
+        let segments: Vec<(u32, u8, &[u8])> = vec![
+            (100, TCP_SYN, b""),
+            (107, TCP_ACK, b"world"),
+            (101, TCP_ACK, b"hello "),
+            (101, TCP_ACK, b"hello "),
+            (112, TCP_ACK | TCP_FIN, b""),
+        ];
+        for (i, (sequence, flags, payload)) in segments.into_iter().enumerate() {
+            let frame = testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", 80, sequence, flags, payload);
+            // VLANタグの内側のセグメントも同じストリームとして扱う
+            let frame = if i % 2 == 0 { frame } else { testutil::vlan(10, &frame) };
+            assert!(reassembler.feed(&frame, Duration::from_secs(i as u64)).is_none());
+        }
+        reassembler.finish();
+        let written = std::fs::read(dir.join("10.0.0.1.40000-10.0.0.2.80")).unwrap();
+        assert_eq!(written, b"hello world");
+    }
+
+    /** クライアントから送ったバイト列のファイル */
+    fn client_stream(dir: &std::path::Path) -> Vec<u8> {
+        std::fs::read(dir.join("10.0.0.1.40000-10.0.0.2.80")).unwrap()
+    }
+
+    #[test]
+    fn closing_handshake_keeps_the_written_stream() {
+        let dir = stream_dir("close");
+        let mut reassembler = reassembler(Some(dir.clone()));
+        let client = |sequence, flags, payload: &[u8]| testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", 80, sequence, flags, payload);
+        let server = |sequence, flags, payload: &[u8]| testutil::tcp_frame("10.0.0.2", 80, "10.0.0.1", 40000, sequence, flags, payload);
+        let frames = [
+            client(100, TCP_SYN, b""),
+            server(500, TCP_SYN | TCP_ACK, b""),
+            client(101, TCP_ACK, b""),
+            client(101, TCP_ACK, b"GET /"),
+            server(501, TCP_ACK, b"200"),
+            client(106, TCP_ACK | TCP_FIN, b""),
+            server(504, TCP_ACK | TCP_FIN, b""),
+            // 両方のFINで閉じた後の最後のACKと、遅れて届いた再送
+            client(107, TCP_ACK, b""),
+            client(101, TCP_ACK, b"GET /"),
+        ];
+        for (i, frame) in frames.iter().enumerate() {
+            reassembler.feed(frame, Duration::from_secs(i as u64));
+        }
+        assert!(reassembler.streams.is_empty());
+        reassembler.finish();
+        assert_eq!(client_stream(&dir), b"GET /");
+        assert_eq!(std::fs::read(dir.join("10.0.0.2.80-10.0.0.1.40000")).unwrap(), b"200");
+
+        // 同じポートの組での新しい接続は、前の接続の後ろに追記する
+        reassembler.feed(&client(9000, TCP_SYN, b""), Duration::from_secs(20));
+        reassembler.feed(&client(9001, TCP_ACK, b"GET /next"), Duration::from_secs(21));
+        reassembler.finish();
+        assert_eq!(client_stream(&dir), b"GET /GET /next");
+    }
+
+    #[test]
+    fn closed_streams_are_forgotten_after_the_timeout() {
+        let dir = stream_dir("tombstone");
+        let mut reassembler = reassembler(Some(dir.clone()));
+        let client = |sequence, flags, payload: &[u8]| testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", 80, sequence, flags, payload);
+        reassembler.feed(&client(1, TCP_ACK, b"abc"), Duration::from_secs(0));
+        reassembler.feed(&client(4, TCP_RST, b""), Duration::from_secs(1));
+        assert!(reassembler.streams.is_empty());
+        assert_eq!(reassembler.closed.len(), 1);
+        // 閉じた後の再送では開き直さない
+        reassembler.feed(&client(1, TCP_ACK, b"abc"), Duration::from_secs(2));
+        assert!(reassembler.streams.is_empty());
+
+        reassembler.feed(&testutil::udp_frame("10.0.0.1", 53, "10.0.0.2", 53, b""), Duration::from_secs(1) + STREAM_TIMEOUT);
+        assert!(reassembler.closed.is_empty());
+        assert_eq!(client_stream(&dir), b"abc");
+    }
+
+    #[test]
+    fn feed_defragments_ipv4_and_tracks_the_stream_inside() {
+        let dir = stream_dir("fragments");
+        let mut reassembler = reassembler(Some(dir.clone()));
+        let segment = testutil::tcp(40000, 80, 1000, TCP_ACK, b"0123456789abcdef");
+        let whole = testutil::ipv4(PROTO_TCP, "10.0.0.1", "10.0.0.2", &segment);
+        // チェックサムは分割前のパケットで計算済みなので、ペイロードをそのまま分ける
+        let payload = &whole[20..];
+        let first = testutil::ipv4_fragment(PROTO_TCP, "10.0.0.1", "10.0.0.2", 7, 0, true, &payload[..24]);
+        let second = testutil::ipv4_fragment(PROTO_TCP, "10.0.0.1", "10.0.0.2", 7, 24, false, &payload[24..]);
+
+        assert!(reassembler.feed(&testutil::ethernet(0x0800, &second), Duration::from_secs(1)).is_none());
+        let reassembled = reassembler.feed(&testutil::ethernet(0x0800, &first), Duration::from_secs(2)).unwrap();
+        assert_eq!(&reassembled[20..], payload);
+        assert!(reassembler.fragments.is_empty());
+        reassembler.finish();
+        let written = std::fs::read(dir.join("10.0.0.1.40000-10.0.0.2.80")).unwrap();
+        assert_eq!(written, b"0123456789abcdef");
+    }
+
+    #[test]
+    fn incomplete_fragments_expire() {
+        let mut reassembler = reassembler(None);
+        let fragment = testutil::ipv4_fragment(PROTO_TCP, "10.0.0.1", "10.0.0.2", 9, 0, true, &[0; 24]);
+        assert!(reassembler.feed(&testutil::ethernet(0x0800, &fragment), Duration::from_secs(1)).is_none());
+        assert_eq!(reassembler.fragments.len(), 1);
+        reassembler.feed(&testutil::udp_frame("10.0.0.1", 53, "10.0.0.2", 53, b""), Duration::from_secs(1) + FRAGMENT_TIMEOUT);
+        assert!(reassembler.fragments.is_empty());
+    }
+}
@@ -0,0 +1,526 @@
+//! スクリプトで処理するための出力形式
+//! --format jsonは1パケット1行のJSON、--format csvはパケットごとの要約を1行ずつ出力する
+
+use std::time::Duration;
+
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::icmpv6::Icmpv6Packet;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use serde_json::{json, Map, Value};
+
+use crate::application;
+use crate::packet::{self, DecodedPacket};
+use crate::pcap::RecordedFrame;
+use crate::validate::Problem;
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub const CSV_HEADER: &str = "timestamp,interface,protocol,source,source_port,destination,destination_port,length,info,original_length,relative,delta";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Result<OutputFormat, failure::Error> {
+        match name {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(failure::err_msg(format!("Unknown format {} (expected text, json or csv)", name))),
+        }
+    }
+}
+
+/** テキスト出力でのパケットの時刻の表し方 */
+#[derive(Clone, Copy, PartialEq)]
+pub enum TimeFormat {
+    /** UTCの日時 */
+    Absolute,
+    /** 最初のパケットからの経過秒数 */
+    Relative,
+    /** 直前のパケットからの経過秒数 */
+    Delta,
+}
+
+impl TimeFormat {
+    pub fn parse(name: &str) -> Result<TimeFormat, failure::Error> {
+        match name {
+            "absolute" => Ok(TimeFormat::Absolute),
+            "relative" => Ok(TimeFormat::Relative),
+            "delta" => Ok(TimeFormat::Delta),
+            _ => Err(failure::err_msg(format!("Unknown time format {} (expected absolute, relative or delta)", name))),
+        }
+    }
+}
+
+/** 1パケットの受信時刻と、最初のパケットおよび直前のパケットからの経過時間 */
+#[derive(Clone, Copy)]
+pub struct Timing {
+    pub absolute: Duration,
+    pub relative: Duration,
+    pub delta: Duration,
+}
+
+impl Timing {
+    pub fn format(&self, format: TimeFormat) -> String {
+        match format {
+            TimeFormat::Absolute => format_utc(self.absolute),
+            TimeFormat::Relative => seconds(self.relative),
+            TimeFormat::Delta => format!("+{}", seconds(self.delta)),
+        }
+    }
+}
+
+/** UNIXエポックからの経過時間を"YYYY-MM-DD HH:MM:SS.nnnnnnnnn"にする */
+fn format_utc(timestamp: Duration) -> String {
+    let secs = timestamp.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:09}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        timestamp.subsec_nanos()
+    )
+}
+
+/** 1970-01-01からの日数をグレゴリオ暦の年月日にする */
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/** 秒数をナノ秒まで表示する */
+fn seconds(duration: Duration) -> String {
+    format!("{}.{:09}", duration.as_secs(), duration.subsec_nanos())
+}
+
+/** 1パケットを層ごとに分解した結果 */
+struct Record<'a> {
+    packet: Option<DecodedPacket<'a>>,
+    layers: Vec<Value>,
+    /** 最も上位で解析したプロトコル */
+    protocol: String,
+    /** アプリケーション層のデコード結果の1行目 */
+    info: String,
+}
+
+impl<'a> Record<'a> {
+    fn decode(frame: &'a [u8]) -> Record<'a> {
+        let mut record = Record {
+            packet: None,
+            layers: Vec::new(),
+            protocol: "unknown".to_string(),
+            info: String::new(),
+        };
+        let packet = match DecodedPacket::decode(frame) {
+            Some(packet) => packet,
+            None => return record,
+        };
+
+        let link = &packet.link;
+        record.push("ethernet", json!({
+            "source": link.source.to_string(),
+            "destination": link.destination.to_string(),
+            "ethertype": link.ethertype.0,
+        }));
+        for vlan in link.vlans.iter() {
+            record.push("vlan", json!({ "id": vlan.id }));
+        }
+        for mpls in link.mpls_labels.iter() {
+            record.push("mpls", json!({ "label": mpls.label, "ttl": mpls.ttl }));
+        }
+        if link.ethertype == EtherTypes::Arp {
+            record.arp(packet.payload);
+        }
+
+        if let Some(ref ip) = packet.ip {
+            let extensions: Vec<u8> = ip.extensions.iter().map(|extension| extension.header.0).collect();
+            record.push(if ip.version() == 4 { "ipv4" } else { "ipv6" }, json!({
+                "source": ip.source.to_string(),
+                "destination": ip.destination.to_string(),
+                "ttl": ip.ttl,
+                "protocol": ip.protocol.0,
+                "header_length": ip.header_length,
+                "extensions": extensions,
+                "fragment": ip.fragment.map(|fragment| json!({
+                    "identification": fragment.identification,
+                    "offset": fragment.offset,
+                    "more_fragments": fragment.more_fragments,
+                })),
+                "checksum_valid": ip.checksum_valid,
+            }));
+            match (ip.protocol, packet.transport.as_ref()) {
+                (_, Some(transport)) => {
+                    let mut fields = json!({
+                        "source_port": transport.source_port,
+                        "destination_port": transport.destination_port,
+                        "header_length": transport.header_length,
+                        "checksum_valid": transport.checksum_valid,
+                    });
+                    if let Some(tcp) = transport.tcp {
+                        fields["sequence"] = Value::from(tcp.sequence);
+                        fields["acknowledgement"] = Value::from(tcp.acknowledgement);
+                        fields["flags"] = Value::from(tcp.flags);
+                        fields["window"] = Value::from(tcp.window);
+                    }
+                    record.push(&transport.name().to_lowercase(), fields);
+                    let details = if transport.tcp.is_some() {
+                        application::decode_tcp(transport.source_port, transport.destination_port, packet.payload)
+                    } else {
+                        application::decode_udp(transport.source_port, transport.destination_port, packet.payload)
+                    };
+                    record.application(details);
+                }
+                (IpNextHeaderProtocols::Icmp, None) if ip.has_transport_header() => {
+                    if let Some(icmp) = IcmpPacket::new(packet.payload) {
+                        record.push("icmp", json!({ "type": icmp.get_icmp_type().0, "code": icmp.get_icmp_code().0 }));
+                    }
+                }
+                (IpNextHeaderProtocols::Icmpv6, None) if ip.has_transport_header() => {
+                    if let Some(icmp) = Icmpv6Packet::new(packet.payload) {
+                        record.push("icmpv6", json!({ "type": icmp.get_icmpv6_type().0, "code": icmp.get_icmpv6_code().0 }));
+                    }
+                }
+                _ => {}
+            }
+        }
+        record.packet = Some(packet);
+        record
+    }
+
+    fn push(&mut self, layer: &str, fields: Value) {
+        let mut object = Map::new();
+        object.insert("layer".to_string(), Value::from(layer));
+        if let Value::Object(fields) = fields {
+            object.extend(fields);
+        }
+        self.layers.push(Value::Object(object));
+        self.protocol = layer.to_string();
+    }
+
+    fn arp(&mut self, payload: &[u8]) {
+        if let Some(arp) = ArpPacket::new(payload) {
+            self.push("arp", json!({
+                "operation": arp.get_operation().0,
+                "sender_mac": arp.get_sender_hw_addr().to_string(),
+                "sender_ip": arp.get_sender_proto_addr().to_string(),
+                "target_mac": arp.get_target_hw_addr().to_string(),
+                "target_ip": arp.get_target_proto_addr().to_string(),
+            }));
+        }
+    }
+
+    /** デコーダの1行目がプロトコル名で始まるので、それを層の名前にする */
+    fn application(&mut self, details: Option<Vec<String>>) {
+        let lines = match details {
+            Some(lines) if !lines.is_empty() => lines,
+            _ => return,
+        };
+        let name = lines[0].split_whitespace().next().unwrap_or("application").to_lowercase();
+        self.info = lines[0].clone();
+        let details: Vec<String> = lines.iter().skip(1).map(|line| line.trim().to_string()).collect();
+        self.push(&name, json!({ "summary": lines[0], "details": details }));
+    }
+
+    /** 最も内側のペイロード */
+    fn payload(&self) -> &'a [u8] {
+        self.packet.as_ref().map_or(&[][..], |packet| packet.payload)
+    }
+}
+
+/** 1パケットを1行のJSONにする。enrichmentは送信元と宛先について分かったこと */
+pub fn to_json(frame: &RecordedFrame, timing: &Timing, interface: &str, include_payload: bool, problems: &[Problem], enrichment: Option<Value>) -> String {
+    let record = Record::decode(&frame.data);
+    let mut object = json!({
+        "timestamp": timing.absolute.as_secs_f64(),
+        "relative": timing.relative.as_secs_f64(),
+        "delta": timing.delta.as_secs_f64(),
+        "interface": interface,
+        "length": frame.data.len(),
+        "original_length": frame.original_length,
+        "protocol": record.protocol,
+        "layers": record.layers,
+    });
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+        object["problems"] = Value::from(problems);
+    }
+    if let Some(enrichment) = enrichment {
+        object["enrichment"] = enrichment;
+    }
+    if include_payload {
+        object["payload"] = Value::from(base64(record.payload()));
+    }
+    object.to_string()
+}
+
+/** 1パケットの要約をCSVの1行にする */
+pub fn to_csv(frame: &RecordedFrame, timing: &Timing, interface: &str) -> String {
+    let record = Record::decode(&frame.data);
+    let ip = record.packet.as_ref().and_then(|packet| packet.ip.as_ref());
+    let transport = record.packet.as_ref().and_then(|packet| packet.transport.as_ref());
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    [
+        seconds(timing.absolute),
+        csv_field(interface),
+        record.protocol.clone(),
+        optional(ip.map(|ip| ip.source.to_string())),
+        optional(transport.map(|transport| transport.source_port.to_string())),
+        optional(ip.map(|ip| ip.destination.to_string())),
+        optional(transport.map(|transport| transport.destination_port.to_string())),
+        frame.data.len().to_string(),
+        csv_field(&record.info),
+        frame.original_length.to_string(),
+        seconds(timing.relative),
+        seconds(timing.delta),
+    ]
+    .join(",")
+}
+
+/** 一覧に表示する1パケットの要約 */
+pub struct Summary {
+    pub protocol: String,
+    pub source: String,
+    pub destination: String,
+    pub info: String,
+}
+
+/** 1パケットを一覧の1行分に要約する。IP以外はMACアドレスを表示する */
+pub fn summarize(frame: &[u8]) -> Summary {
+    let record = Record::decode(frame);
+    let (source, destination) = match record.packet {
+        Some(DecodedPacket { ip: Some(ref ip), ref transport, .. }) => (
+            packet::endpoint(ip.source, transport.as_ref().map(|transport| transport.source_port)),
+            packet::endpoint(ip.destination, transport.as_ref().map(|transport| transport.destination_port)),
+        ),
+        Some(ref packet) => (packet.link.source.to_string(), packet.link.destination.to_string()),
+        None => (String::new(), String::new()),
+    };
+    Summary {
+        protocol: record.protocol.clone(),
+        source,
+        destination,
+        info: record.info.clone(),
+    }
+}
+
+/** 層ごとのフィールドを、層の名前と字下げしたフィールドの行にする */
+pub fn layer_lines(frame: &[u8]) -> Vec<String> {
+    let record = Record::decode(frame);
+    let mut lines = Vec::new();
+    for layer in record.layers.iter() {
+        let fields = match layer.as_object() {
+            Some(fields) => fields,
+            None => continue,
+        };
+        lines.push(fields.get("layer").and_then(Value::as_str).unwrap_or("").to_uppercase());
+        for (key, value) in fields.iter().filter(|(key, _)| *key != "layer") {
+            let value = match value {
+                Value::Null => continue,
+                Value::String(value) => value.clone(),
+                Value::Array(values) if values.iter().all(Value::is_string) => {
+                    values.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(", ")
+                }
+                other => other.to_string(),
+            };
+            lines.push(format!("  {}: {}", key, value));
+        }
+    }
+    lines
+}
+
+/** カンマや引用符を含むフィールドは引用符で囲む (RFC 4180) */
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(bits >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn recorded(data: Vec<u8>) -> RecordedFrame {
+        RecordedFrame {
+            timestamp: Duration::from_secs(1_600_000_000),
+            interface_id: 0,
+            original_length: data.len(),
+            data,
+        }
+    }
+
+    fn timing() -> Timing {
+        Timing {
+            absolute: Duration::new(1_600_000_000, 5),
+            relative: Duration::from_millis(1500),
+            delta: Duration::from_millis(250),
+        }
+    }
+
+    /** RFC 4180の1行をフィールドに分ける */
+    fn split_csv(line: &str) -> Vec<String> {
+        let mut fields = vec![String::new()];
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    fields.last_mut().unwrap().push('"');
+                }
+                ('"', _) => quoted = !quoted,
+                (',', false) => fields.push(String::new()),
+                (c, _) => fields.last_mut().unwrap().push(c),
+            }
+        }
+        assert!(!quoted, "unterminated quote in {:?}", line);
+        fields
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("eth0"), "eth0");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("carriage\rreturn"), "\"carriage\rreturn\"");
+    }
+
+    #[test]
+    fn csv_rows_line_up_with_the_header() {
+        let http = b"GET /a,\"b\" HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let frame = recorded(tcp_frame("192.0.2.1", 40000, "192.0.2.2", 80, 1, TCP_ACK, http));
+        let line = to_csv(&frame, &timing(), "my \"eth\",0");
+        let header: Vec<&str> = CSV_HEADER.split(',').collect();
+        let fields = split_csv(&line);
+        assert_eq!(fields.len(), header.len(), "{}", line);
+
+        let column = |name: &str| fields[header.iter().position(|column| *column == name).unwrap()].as_str();
+        assert_eq!(column("timestamp"), "1600000000.000000005");
+        assert_eq!(column("interface"), "my \"eth\",0");
+        assert_eq!(column("protocol"), "http");
+        assert_eq!(column("source"), "192.0.2.1");
+        assert_eq!(column("source_port"), "40000");
+        assert_eq!(column("destination"), "192.0.2.2");
+        assert_eq!(column("destination_port"), "80");
+        assert_eq!(column("info"), "HTTP GET /a,\"b\" HTTP/1.1");
+        assert_eq!(column("length"), frame.data.len().to_string());
+        assert_eq!(column("relative"), "1.500000000");
+        assert_eq!(column("delta"), "0.250000000");
+    }
+
+    #[test]
+    fn csv_leaves_ip_columns_empty_for_other_frames() {
+        let frame = recorded(ethernet(0x88b5, &[0; 46]));
+        let fields = split_csv(&to_csv(&frame, &timing(), "eth0"));
+        assert_eq!(fields.len(), CSV_HEADER.split(',').count());
+        assert_eq!(fields[2], "ethernet");
+        assert!(fields[3..7].iter().all(String::is_empty));
+    }
+
+    #[test]
+    fn json_escapes_strings_and_parses_back() {
+        let interface = "tap \"0\" \\ \u{1}\n\t";
+        let http = b"GET /\"q\"\\\x7f HTTP/1.1\r\n\r\n";
+        let frame = recorded(tcp_frame("2001:db8::1", 40000, "2001:db8::2", 80, 1, TCP_ACK, http));
+        let line = to_json(&frame, &timing(), interface, false, &[], None);
+        assert!(!line.contains('\n'), "{}", line);
+
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["interface"], interface);
+        assert_eq!(value["protocol"], "http");
+        let layers = value["layers"].as_array().unwrap();
+        let names: Vec<&str> = layers.iter().filter_map(|layer| layer["layer"].as_str()).collect();
+        assert_eq!(names, ["ethernet", "ipv6", "tcp", "http"]);
+        // 制御文字はアプリケーション層のデコーダでエスケープ済み
+        assert_eq!(layers[3]["summary"], "HTTP GET /\"q\"\\\\u{7f} HTTP/1.1");
+        assert_eq!(value["delta"], 0.25);
+        assert!(value.get("payload").is_none());
+        assert!(value.get("problems").is_none());
+        assert!(value.get("enrichment").is_none());
+    }
+
+    #[test]
+    fn json_replaces_invalid_utf8_in_decoded_text() {
+        let http = b"GET /\xff\xfe HTTP/1.1\r\n\r\n";
+        let frame = recorded(tcp_frame("192.0.2.1", 40000, "192.0.2.2", 80, 1, TCP_ACK, http));
+        let value: Value = serde_json::from_str(&to_json(&frame, &timing(), "eth0", false, &[], None)).unwrap();
+        assert_eq!(value["layers"][3]["summary"], "HTTP GET /\u{fffd}\u{fffd} HTTP/1.1");
+    }
+
+    #[test]
+    fn json_includes_payload_problems_and_enrichment() {
+        let frame = recorded(udp_frame("192.0.2.1", 5000, "192.0.2.2", 6000, b"foobar"));
+        let problems = [Problem::BadChecksum("UDP"), Problem::Truncated("IPv4")];
+        let enrichment = json!({ "source": { "country": "JP" } });
+        let line = to_json(&frame, &timing(), "eth0", true, &problems, Some(enrichment));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["payload"], "Zm9vYmFy");
+        assert_eq!(value["problems"], json!(["bad UDP checksum", "truncated IPv4"]));
+        assert_eq!(value["enrichment"]["source"]["country"], "JP");
+        assert_eq!(value["length"], frame.data.len());
+    }
+
+    #[test]
+    fn base64_pads_partial_groups() {
+        let cases: [(&[u8], &str); 7] = [
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"fooba", "Zm9vYmE="),
+            (&[0xfb, 0xff, 0xbf], "+/+/"),
+        ];
+        for (data, expected) in cases.iter() {
+            assert_eq!(base64(data), *expected);
+        }
+    }
+
+    #[test]
+    fn utc_timestamps_use_the_gregorian_calendar() {
+        assert_eq!(format_utc(Duration::ZERO), "1970-01-01 00:00:00.000000000");
+        assert_eq!(format_utc(Duration::new(951_782_400, 1)), "2000-02-29 00:00:00.000000001");
+        assert_eq!(format_utc(Duration::new(1_709_251_199, 999_999_999)), "2024-02-29 23:59:59.999999999");
+        assert_eq!(format_utc(Duration::new(4_102_444_800, 0)), "2100-01-01 00:00:00.000000000");
+    }
+}
@@ -0,0 +1,369 @@
+//! キャプチャファイルのフレームをインターフェースから送信し直す
+//! 元の間隔、倍速、最高速、一定のppsで送信でき、MACアドレスとIPアドレスを書き換えられる
+//! IPアドレスは元のアドレスから新しいアドレスへの対応で書き換えるので、双方向の通信は双方向のまま送られる
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+use pnet::datalink::{self, Channel, DataLinkSender, NetworkInterface};
+use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::icmpv6::{self, MutableIcmpv6Packet};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{self, MutableIpv4Packet};
+use pnet::packet::ipv6::MutableIpv6Packet;
+use pnet::packet::tcp::{self, MutableTcpPacket};
+use pnet::packet::udp::{self, MutableUdpPacket};
+use pnet::util::MacAddr;
+
+use crate::filter::Expr;
+use crate::pcap::CaptureReader;
+use crate::signal;
+
+/** 送信の間隔 */
+#[derive(Clone, Copy)]
+pub enum Pacing {
+    /** 記録された間隔を倍率で割って送る。1.0なら元の間隔 */
+    Speed(f64),
+    /** 待たずに送る */
+    TopSpeed,
+    /** 1秒あたりのパケット数を一定にする */
+    PacketsPerSecond(f64),
+}
+
+impl Pacing {
+    /** --speedの値。topは最高速 */
+    pub fn parse_speed(value: &str) -> Result<Pacing, failure::Error> {
+        if value == "top" {
+            return Ok(Pacing::TopSpeed);
+        }
+        let speed: f64 = value.trim_end_matches('x').parse()?;
+        if !speed.is_finite() || speed <= 0.0 {
+            return Err(failure::err_msg("--speed must be a positive number"));
+        }
+        Ok(Pacing::Speed(speed))
+    }
+
+    /** --ppsの値 */
+    pub fn parse_pps(value: &str) -> Result<Pacing, failure::Error> {
+        let pps: f64 = value.parse()?;
+        if !pps.is_finite() || pps <= 0.0 {
+            return Err(failure::err_msg("--pps must be a positive number"));
+        }
+        Ok(Pacing::PacketsPerSecond(pps))
+    }
+
+    /** 送信を始めてからindex番目のフレームを送るまでの時間。待たない時はNone
+     * 極端に遅い指定ではDurationで表せないので、パニックせずにエラーにする
+     */
+    fn offset(&self, index: u32, elapsed: Duration) -> Result<Option<Duration>, failure::Error> {
+        let seconds = match *self {
+            Pacing::Speed(speed) => elapsed.as_secs_f64() / speed,
+            Pacing::PacketsPerSecond(pps) => f64::from(index) / pps,
+            Pacing::TopSpeed => return Ok(None),
+        };
+        Duration::try_from_secs_f64(seconds).map(Some).map_err(|_| failure::err_msg(format!("Interval of {}s before frame {} is too long", seconds, index)))
+    }
+}
+
+/** 送信前に書き換えるアドレス */
+#[derive(Default)]
+pub struct Rewrite {
+    pub source_mac: Option<MacAddr>,
+    pub destination_mac: Option<MacAddr>,
+    /** 元のIPアドレスと書き換え後のIPアドレス。送信元と宛先のどちらにあっても書き換える */
+    pub ip_map: HashMap<IpAddr, IpAddr>,
+}
+
+impl Rewrite {
+    /** --map-ipの値 old=new を加える。IPv4とIPv6を混ぜることはできない */
+    pub fn add_ip_mapping(&mut self, value: &str) -> Result<(), failure::Error> {
+        let invalid = || failure::err_msg(format!("Invalid --map-ip {} (expected old=new)", value));
+        let (old, new) = value.split_once('=').ok_or_else(invalid)?;
+        let (old, new): (IpAddr, IpAddr) = (old.parse().map_err(|_| invalid())?, new.parse().map_err(|_| invalid())?);
+        if old.is_ipv4() != new.is_ipv4() {
+            return Err(failure::err_msg(format!("--map-ip {} mixes IPv4 and IPv6", value)));
+        }
+        self.ip_map.insert(old, new);
+        Ok(())
+    }
+
+    fn ip(&self, address: IpAddr) -> IpAddr {
+        self.ip_map.get(&address).cloned().unwrap_or(address)
+    }
+}
+
+/** --replayの設定 */
+pub struct ReplayConfig {
+    pub path: PathBuf,
+    pub pacing: Pacing,
+    /** ファイルを繰り返し送る回数。0なら中断されるまで繰り返す */
+    pub loops: u32,
+    pub rewrite: Rewrite,
+}
+
+/** ファイルのフレームをインターフェースから送信する。フィルタがあれば一致したフレームだけを送る */
+pub fn run(interface: &NetworkInterface, config: &ReplayConfig, filter: Option<&Expr>) -> Result<(), failure::Error> {
+    let mut tx: Box<dyn DataLinkSender> = match datalink::channel(interface, Default::default()) {
+        Ok(Channel::Ethernet(tx, _rx)) => tx,
+        Ok(_) => return Err(failure::err_msg(format!("Unhandled channel type on {}", interface.name))),
+        Err(e) => return Err(failure::err_msg(format!("Failed to create datalink channel on {}: {}", interface.name, e))),
+    };
+
+    let started = Instant::now();
+    let mut sent = 0u64;
+    let mut bytes = 0u64;
+    let mut iteration = 0;
+    while !signal::interrupted() && (config.loops == 0 || iteration < config.loops) {
+        iteration += 1;
+        let mut reader = CaptureReader::open(&config.path)?;
+        // 送信の予定時刻は繰り返しごとに、その回の開始時刻から数える
+        let loop_started = Instant::now();
+        let mut first_timestamp = None;
+        let mut index = 0u32;
+
+        while !signal::interrupted() {
+            let mut frame = match reader.next_frame()? {
+                Some(frame) => frame,
+                None => break,
+            };
+            if filter.is_some_and(|filter| !filter.matches(&frame.data)) {
+                continue;
+            }
+            let first = *first_timestamp.get_or_insert(frame.timestamp);
+            if let Some(offset) = config.pacing.offset(index, frame.timestamp.checked_sub(first).unwrap_or_default())? {
+                if let Some(wait) = offset.checked_sub(loop_started.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+
+            rewrite_frame(&mut frame.data, &config.rewrite);
+            match tx.send_to(&frame.data, None) {
+                Some(Ok(())) => {
+                    sent += 1;
+                    bytes += frame.data.len() as u64;
+                }
+                Some(Err(e)) => return Err(failure::err_msg(format!("Failed to send a frame on {}: {}", interface.name, e))),
+                None => return Err(failure::err_msg(format!("Failed to send a frame on {}", interface.name))),
+            }
+            index += 1;
+        }
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    info!(
+        "Sent {} frames ({} bytes) on {} in {:.3}s ({:.0} pps)",
+        sent,
+        bytes,
+        interface.name,
+        elapsed,
+        if elapsed > 0.0 { sent as f64 / elapsed } else { 0.0 }
+    );
+    Ok(())
+}
+
+/** アドレスを書き換え、IPv4ヘッダとTCP/UDP/ICMPv6のチェックサムを計算し直す
+ * フラグメントはトランスポート層のチェックサムを計算し直せないので書き換えない
+ */
+fn rewrite_frame(frame: &mut [u8], rewrite: &Rewrite) {
+    let mut ethernet = match MutableEthernetPacket::new(frame) {
+        Some(ethernet) => ethernet,
+        None => return,
+    };
+    if let Some(mac) = rewrite.source_mac {
+        ethernet.set_source(mac);
+    }
+    if let Some(mac) = rewrite.destination_mac {
+        ethernet.set_destination(mac);
+    }
+    if rewrite.ip_map.is_empty() {
+        return;
+    }
+
+    let ethertype = ethernet.get_ethertype();
+    let payload = &mut frame[14..];
+    match ethertype {
+        EtherTypes::Ipv4 => {
+            let mut packet = match MutableIpv4Packet::new(payload) {
+                Some(packet) => packet,
+                None => return,
+            };
+            if packet.get_fragment_offset() != 0 || packet.get_flags() & ipv4::Ipv4Flags::MoreFragments != 0 {
+                return;
+            }
+            if let IpAddr::V4(source) = rewrite.ip(IpAddr::V4(packet.get_source())) {
+                packet.set_source(source);
+            }
+            if let IpAddr::V4(destination) = rewrite.ip(IpAddr::V4(packet.get_destination())) {
+                packet.set_destination(destination);
+            }
+            let checksum = ipv4::checksum(&packet.to_immutable());
+            packet.set_checksum(checksum);
+
+            let (source, destination) = (IpAddr::V4(packet.get_source()), IpAddr::V4(packet.get_destination()));
+            let protocol = packet.get_next_level_protocol();
+            let header_length = packet.get_header_length() as usize * 4;
+            let end = (packet.get_total_length() as usize).min(payload.len());
+            if header_length <= end {
+                update_checksum(protocol, source, destination, &mut payload[header_length..end]);
+            }
+        }
+        EtherTypes::Ipv6 => {
+            let mut packet = match MutableIpv6Packet::new(payload) {
+                Some(packet) => packet,
+                None => return,
+            };
+            if let IpAddr::V6(source) = rewrite.ip(IpAddr::V6(packet.get_source())) {
+                packet.set_source(source);
+            }
+            if let IpAddr::V6(destination) = rewrite.ip(IpAddr::V6(packet.get_destination())) {
+                packet.set_destination(destination);
+            }
+            let (source, destination) = (IpAddr::V6(packet.get_source()), IpAddr::V6(packet.get_destination()));
+            // 拡張ヘッダがある場合はチェックサムを計算し直さない
+            let protocol = packet.get_next_header();
+            let end = (40 + packet.get_payload_length() as usize).min(payload.len());
+            update_checksum(protocol, source, destination, &mut payload[40..end]);
+        }
+        _ => {}
+    }
+}
+
+/** 疑似ヘッダを含むチェックサムを計算し直す */
+fn update_checksum(protocol: IpNextHeaderProtocol, source: IpAddr, destination: IpAddr, segment: &mut [u8]) {
+    match (protocol, source, destination) {
+        (IpNextHeaderProtocols::Tcp, IpAddr::V4(source), IpAddr::V4(destination)) => {
+            if let Some(mut packet) = MutableTcpPacket::new(segment) {
+                let checksum = tcp::ipv4_checksum(&packet.to_immutable(), &source, &destination);
+                packet.set_checksum(checksum);
+            }
+        }
+        (IpNextHeaderProtocols::Tcp, IpAddr::V6(source), IpAddr::V6(destination)) => {
+            if let Some(mut packet) = MutableTcpPacket::new(segment) {
+                let checksum = tcp::ipv6_checksum(&packet.to_immutable(), &source, &destination);
+                packet.set_checksum(checksum);
+            }
+        }
+        (IpNextHeaderProtocols::Udp, IpAddr::V4(source), IpAddr::V4(destination)) => {
+            if let Some(mut packet) = MutableUdpPacket::new(segment) {
+                // チェックサムを省略したデータグラムはそのままにする
+                if packet.get_checksum() != 0 {
+                    let checksum = udp::ipv4_checksum(&packet.to_immutable(), &source, &destination);
+                    packet.set_checksum(checksum);
+                }
+            }
+        }
+        (IpNextHeaderProtocols::Udp, IpAddr::V6(source), IpAddr::V6(destination)) => {
+            if let Some(mut packet) = MutableUdpPacket::new(segment) {
+                let checksum = udp::ipv6_checksum(&packet.to_immutable(), &source, &destination);
+                packet.set_checksum(checksum);
+            }
+        }
+        (IpNextHeaderProtocols::Icmpv6, IpAddr::V6(source), IpAddr::V6(destination)) => {
+            if let Some(mut packet) = MutableIcmpv6Packet::new(segment) {
+                let checksum = icmpv6::checksum(&packet.to_immutable(), &source, &destination);
+                packet.set_checksum(checksum);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, TCP_ACK};
+
+    fn mapping(pairs: &[&str]) -> Rewrite {
+        let mut rewrite = Rewrite::default();
+        for pair in pairs {
+            rewrite.add_ip_mapping(pair).unwrap();
+        }
+        rewrite
+    }
+
+    #[test]
+    fn both_directions_of_an_ipv4_flow_are_rewritten() {
+        let rewrite = mapping(&["10.0.0.1=192.168.1.1", "10.0.0.2=192.168.1.2"]);
+        let mut request = testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", 80, 1, TCP_ACK, b"request");
+        let mut response = testutil::udp_frame("10.0.0.2", 53, "10.0.0.1", 40000, b"response");
+        rewrite_frame(&mut request, &rewrite);
+        rewrite_frame(&mut response, &rewrite);
+        // 組み立て直したフレームと一致すれば、IPv4ヘッダとTCP/UDPのチェックサムも正しい
+        assert_eq!(request, testutil::tcp_frame("192.168.1.1", 40000, "192.168.1.2", 80, 1, TCP_ACK, b"request"));
+        assert_eq!(response, testutil::udp_frame("192.168.1.2", 53, "192.168.1.1", 40000, b"response"));
+    }
+
+    #[test]
+    fn addresses_without_a_mapping_are_kept() {
+        let rewrite = mapping(&["10.0.0.1=192.168.1.1"]);
+        let mut frame = testutil::tcp_frame("10.0.0.9", 40000, "10.0.0.1", 80, 1, TCP_ACK, b"");
+        rewrite_frame(&mut frame, &rewrite);
+        assert_eq!(frame, testutil::tcp_frame("10.0.0.9", 40000, "192.168.1.1", 80, 1, TCP_ACK, b""));
+    }
+
+    #[test]
+    fn ipv6_checksums_are_recalculated() {
+        let rewrite = mapping(&["2001:db8::1=fd00::1"]);
+        let mut tcp = testutil::tcp_frame("2001:db8::1", 40000, "2001:db8::2", 443, 7, TCP_ACK, b"data");
+        let mut udp = testutil::udp_frame("2001:db8::2", 53, "2001:db8::1", 40000, b"answer");
+        rewrite_frame(&mut tcp, &rewrite);
+        rewrite_frame(&mut udp, &rewrite);
+        assert_eq!(tcp, testutil::tcp_frame("fd00::1", 40000, "2001:db8::2", 443, 7, TCP_ACK, b"data"));
+        assert_eq!(udp, testutil::udp_frame("2001:db8::2", 53, "fd00::1", 40000, b"answer"));
+    }
+
+    #[test]
+    fn udp_without_checksum_and_fragments_keep_their_checksums() {
+        let rewrite = mapping(&["10.0.0.1=192.168.1.1"]);
+        let datagram = testutil::udp(40000, 53, b"query");
+        let mut frame = testutil::ethernet(0x0800, &testutil::ipv4_fragment(testutil::PROTO_UDP, "10.0.0.1", "10.0.0.2", 1, 8, false, &datagram));
+        let original = frame.clone();
+        rewrite_frame(&mut frame, &rewrite);
+        assert_eq!(frame, original);
+
+        // チェックサムが0のデータグラムは0のまま送る
+        let mut frame = testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b"query");
+        frame[40..42].copy_from_slice(&[0, 0]);
+        rewrite_frame(&mut frame, &rewrite);
+        let mut expected = testutil::udp_frame("192.168.1.1", 40000, "10.0.0.2", 53, b"query");
+        expected[40..42].copy_from_slice(&[0, 0]);
+        assert_eq!(frame, expected);
+    }
+
+    #[test]
+    fn invalid_mappings_are_rejected() {
+        let mut rewrite = Rewrite::default();
+        assert!(rewrite.add_ip_mapping("10.0.0.1").is_err());
+        assert!(rewrite.add_ip_mapping("10.0.0.1=fd00::1").is_err());
+        assert!(rewrite.add_ip_mapping("10.0.0.1=host").is_err());
+        assert!(rewrite.ip_map.is_empty());
+    }
+
+    #[test]
+    fn pacing_must_be_a_positive_number() {
+        for value in ["nan", "inf", "-1", "0", "fast"] {
+            assert!(Pacing::parse_speed(value).is_err(), "{}", value);
+            assert!(Pacing::parse_pps(value).is_err(), "{}", value);
+        }
+        assert!(matches!(Pacing::parse_speed("2x"), Ok(Pacing::Speed(speed)) if speed == 2.0));
+        assert!(matches!(Pacing::parse_speed("top"), Ok(Pacing::TopSpeed)));
+    }
+
+    #[test]
+    fn pacing_offsets() {
+        let speed = Pacing::parse_speed("2").unwrap();
+        assert_eq!(speed.offset(5, Duration::from_secs(3)).unwrap(), Some(Duration::from_millis(1500)));
+        let pps = Pacing::parse_pps("100").unwrap();
+        assert_eq!(pps.offset(5, Duration::from_secs(3)).unwrap(), Some(Duration::from_millis(50)));
+        assert_eq!(Pacing::TopSpeed.offset(5, Duration::from_secs(3)).unwrap(), None);
+
+        // Durationで表せないほど遅い指定はパニックせずにエラーになる
+        assert!(Pacing::parse_pps("1e-300").unwrap().offset(1, Duration::ZERO).is_err());
+        assert!(Pacing::parse_speed("1e-300").unwrap().offset(1, Duration::from_secs(1)).is_err());
+        assert_eq!(Pacing::parse_pps("1e-300").unwrap().offset(0, Duration::ZERO).unwrap(), Some(Duration::ZERO));
+    }
+}
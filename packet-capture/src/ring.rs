@@ -0,0 +1,303 @@
+//! PACKET_MMAPのTPACKET_V3によるリングバッファでの受信
+//! カーネルがブロック単位でまとめて書き込んだフレームを、コピーやシステムコールなしで読み出す
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::time::Duration;
+
+use crate::bpf::{self, Instruction};
+
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_STATISTICS: libc::c_int = 6;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_FANOUT: libc::c_int = 18;
+const TPACKET_V3: libc::c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+/** 5タプルのハッシュでソケットに振り分ける */
+const PACKET_FANOUT_HASH: u32 = 0;
+/** 振り分ける前にIPのフラグメントを再構築し、同じパケットのフラグメントを同じソケットに送る */
+const PACKET_FANOUT_FLAG_DEFRAG: u32 = 0x8000;
+/** ブロックが埋まらなくてもユーザー空間に渡すまでのミリ秒 */
+const BLOCK_TIMEOUT_MS: u32 = 100;
+
+/** --ringの設定 */
+#[derive(Clone)]
+pub struct RingConfig {
+    /** 1ブロックのバイト数。ページサイズの倍数 */
+    pub block_size: usize,
+    pub block_count: usize,
+    /** PACKET_FANOUTで負荷を分けるソケット(とスレッド)の数。1なら使わない */
+    pub fanout: usize,
+    /** カーネルでの取りこぼしを報告する間隔 */
+    pub stats_interval: Duration,
+}
+
+impl Default for RingConfig {
+    fn default() -> RingConfig {
+        RingConfig {
+            block_size: 1 << 20,
+            block_count: 64,
+            fanout: 1,
+            stats_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/** struct tpacket_req3 */
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: libc::c_uint,
+    tp_block_nr: libc::c_uint,
+    tp_frame_size: libc::c_uint,
+    tp_frame_nr: libc::c_uint,
+    tp_retire_blk_tov: libc::c_uint,
+    tp_sizeof_priv: libc::c_uint,
+    tp_feature_req_word: libc::c_uint,
+}
+
+/** struct tpacket_block_descのうち、使う部分 */
+#[repr(C)]
+#[allow(dead_code)]
+struct BlockDescriptor {
+    version: u32,
+    offset_to_priv: u32,
+    block_status: u32,
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+    blk_len: u32,
+}
+
+/** struct tpacket3_hdrのうち、使う部分 */
+#[repr(C)]
+#[allow(dead_code)]
+struct PacketHeader {
+    tp_next_offset: u32,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_snaplen: u32,
+    tp_len: u32,
+    tp_status: u32,
+    tp_mac: u16,
+    tp_net: u16,
+}
+
+/** struct tpacket_stats_v3 */
+#[repr(C)]
+#[derive(Default)]
+struct TpacketStatsV3 {
+    tp_packets: libc::c_uint,
+    tp_drops: libc::c_uint,
+    tp_freeze_q_cnt: libc::c_uint,
+}
+
+/** PACKET_STATISTICSの値。読み出すたびにカーネル側ではリセットされる */
+#[derive(Default, Clone, Copy)]
+pub struct Statistics {
+    pub packets: u64,
+    pub drops: u64,
+    /** リングが埋まってキューが止まった回数 */
+    pub freezes: u64,
+}
+
+impl Statistics {
+    pub fn add(&mut self, other: Statistics) {
+        self.packets += other.packets;
+        self.drops += other.drops;
+        self.freezes += other.freezes;
+    }
+}
+
+pub struct Ring {
+    fd: RawFd,
+    map: *mut u8,
+    block_size: usize,
+    block_count: usize,
+    /** 次に読むブロック */
+    current: usize,
+}
+
+// マップした領域はこのソケットだけが使うので、スレッドに移してよい
+unsafe impl Send for Ring {}
+
+impl Ring {
+    /** インターフェースに束縛したリングを開く
+     * fanout_groupを指定すると、同じグループのソケットにパケットが振り分けられる
+     */
+    pub fn open(ifindex: u32, config: &RingConfig, program: Option<&[Instruction]>, fanout_group: Option<u16>) -> Result<Ring, failure::Error> {
+        // プロトコル0で作ったソケットはbindするまで何も受信しない
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut ring = Ring {
+            fd,
+            map: ptr::null_mut(),
+            block_size: config.block_size,
+            block_count: config.block_count,
+            current: 0,
+        };
+
+        setsockopt(fd, PACKET_VERSION, &TPACKET_V3)?;
+        setsockopt(fd, PACKET_RX_RING, &ring_request(config))?;
+
+        let length = config.block_size * config.block_count;
+        let map = unsafe { libc::mmap(ptr::null_mut(), length, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0) };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        ring.map = map as *mut u8;
+
+        if let Some(program) = program {
+            bpf::attach(fd, program)?;
+        }
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        addr.sll_ifindex = ifindex as libc::c_int;
+        let ret = unsafe { libc::bind(fd, &addr as *const libc::sockaddr_ll as *const libc::sockaddr, mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // PACKET_FANOUTはbindした後でないと設定できない
+        if let Some(group) = fanout_group {
+            let fanout: u32 = (PACKET_FANOUT_HASH | PACKET_FANOUT_FLAG_DEFRAG) << 16 | group as u32;
+            setsockopt(fd, PACKET_FANOUT, &fanout)?;
+        }
+        Ok(ring)
+    }
+
+    /** 次のブロックをtimeoutまで待ち、含まれるフレームを受信時刻、回線上の長さと共に渡してからカーネルに返す
+     * ブロックが来なければfalseを返す
+     */
+    pub fn next_block<F: FnMut(&[u8], Duration, usize)>(&mut self, timeout: Duration, mut handle: F) -> io::Result<bool> {
+        let block = unsafe { self.map.add(self.current * self.block_size) };
+        let descriptor = block as *mut BlockDescriptor;
+
+        if unsafe { ptr::read_volatile(&(*descriptor).block_status) } & TP_STATUS_USER == 0 {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN | libc::POLLERR,
+                revents: 0,
+            };
+            let ret = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if unsafe { ptr::read_volatile(&(*descriptor).block_status) } & TP_STATUS_USER == 0 {
+                return Ok(false);
+            }
+        }
+
+        let count = unsafe { (*descriptor).num_pkts };
+        let mut offset = unsafe { (*descriptor).offset_to_first_pkt } as usize;
+        for _ in 0..count {
+            let header = unsafe { &*(block.add(offset) as *const PacketHeader) };
+            let frame = unsafe { std::slice::from_raw_parts(block.add(offset + header.tp_mac as usize), header.tp_snaplen as usize) };
+            handle(frame, Duration::new(u64::from(header.tp_sec), header.tp_nsec), header.tp_len as usize);
+            offset += header.tp_next_offset as usize;
+        }
+
+        // 読み終えたブロックをカーネルに返す
+        unsafe { ptr::write_volatile(&mut (*descriptor).block_status, TP_STATUS_KERNEL) };
+        self.current = (self.current + 1) % self.block_count;
+        Ok(true)
+    }
+
+    /** 前回読み出してからの受信数と取りこぼし数 */
+    pub fn statistics(&self) -> io::Result<Statistics> {
+        let mut stats = TpacketStatsV3::default();
+        let mut length = mem::size_of::<TpacketStatsV3>() as libc::socklen_t;
+        let ret = unsafe { libc::getsockopt(self.fd, libc::SOL_PACKET, PACKET_STATISTICS, &mut stats as *mut TpacketStatsV3 as *mut libc::c_void, &mut length) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Statistics {
+            packets: u64::from(stats.tp_packets),
+            drops: u64::from(stats.tp_drops),
+            freezes: u64::from(stats.tp_freeze_q_cnt),
+        })
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.map.is_null() {
+                libc::munmap(self.map as *mut libc::c_void, self.block_size * self.block_count);
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+/** PACKET_RX_RINGに渡す大きさ
+ * V3ではフレームは可変長なので、frame_sizeは1フレームの上限としてしか使われない
+ */
+fn ring_request(config: &RingConfig) -> TpacketReq3 {
+    let frame_size = (bpf::ACCEPT_LEN as usize).min(config.block_size);
+    TpacketReq3 {
+        tp_block_size: config.block_size as libc::c_uint,
+        tp_block_nr: config.block_count as libc::c_uint,
+        tp_frame_size: frame_size as libc::c_uint,
+        tp_frame_nr: (config.block_size / frame_size * config.block_count) as libc::c_uint,
+        tp_retire_blk_tov: BLOCK_TIMEOUT_MS,
+        tp_sizeof_priv: 0,
+        tp_feature_req_word: 0,
+    }
+}
+
+fn setsockopt<T>(fd: RawFd, option: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe { libc::setsockopt(fd, libc::SOL_PACKET, option, value as *const T as *const libc::c_void, mem::size_of::<T>() as libc::socklen_t) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(block_size: usize, block_count: usize) -> RingConfig {
+        RingConfig {
+            block_size,
+            block_count,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn frames_are_limited_to_the_accepted_length() {
+        // 1MiBのブロックには262144バイトのフレームが4つ入る
+        let request = ring_request(&config(1 << 20, 64));
+        assert_eq!(request.tp_block_size, 1 << 20);
+        assert_eq!(request.tp_block_nr, 64);
+        assert_eq!(request.tp_frame_size, bpf::ACCEPT_LEN);
+        assert_eq!(request.tp_frame_nr, 4 * 64);
+        assert_eq!(request.tp_retire_blk_tov, BLOCK_TIMEOUT_MS);
+    }
+
+    #[test]
+    fn small_blocks_hold_one_frame_each() {
+        // ブロックがフレームの上限より小さければ、フレームの上限はブロックの大きさになる
+        let request = ring_request(&config(4096, 8));
+        assert_eq!(request.tp_frame_size, 4096);
+        assert_eq!(request.tp_frame_nr, 8);
+    }
+
+    #[test]
+    fn statistics_are_accumulated() {
+        let mut total = Statistics::default();
+        total.add(Statistics { packets: 10, drops: 2, freezes: 1 });
+        total.add(Statistics { packets: 5, drops: 0, freezes: 0 });
+        total.add(Statistics::default());
+        assert_eq!(total.packets, 15);
+        assert_eq!(total.drops, 2);
+        assert_eq!(total.freezes, 1);
+    }
+}
@@ -0,0 +1,34 @@
+//! SIGINTで受信ループを抜け、集計や書き出し途中のファイルを片付けてから終了する
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    // 片付けが終わらない時のために、2回目は待たずに終了する
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/** SIGINTのハンドラを登録する
+ * SA_RESTARTを付けないので、受信待ちのシステムコールはEINTRで戻り、ループで終了を確認できる
+ */
+pub fn install() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+/** SIGINTを受け取ったか */
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/** SIGINTを受け取った時と同じように受信ループを終わらせる */
+pub fn request_stop() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
@@ -0,0 +1,129 @@
+//! テストで使うフレームを組み立てる
+//! チェックサムは正しく計算するので、validateで問題として扱われない
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::util::{checksum, ipv4_checksum, ipv6_checksum};
+
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+pub const SOURCE_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+pub const DESTINATION_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_ACK: u8 = 0x10;
+
+pub fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = DESTINATION_MAC.to_vec();
+    frame.extend_from_slice(&SOURCE_MAC);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/** 送信元MACアドレスの後に802.1Qタグを挟む */
+pub fn vlan(id: u16, frame: &[u8]) -> Vec<u8> {
+    let mut tagged = frame[..12].to_vec();
+    tagged.extend_from_slice(&0x8100u16.to_be_bytes());
+    tagged.extend_from_slice(&id.to_be_bytes());
+    tagged.extend_from_slice(&frame[12..]);
+    tagged
+}
+
+pub fn tcp(source_port: u16, destination_port: u16, sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend_from_slice(&source_port.to_be_bytes());
+    segment.extend_from_slice(&destination_port.to_be_bytes());
+    segment.extend_from_slice(&sequence.to_be_bytes());
+    segment.extend_from_slice(&0u32.to_be_bytes());
+    segment.extend_from_slice(&[5 << 4, flags]);
+    segment.extend_from_slice(&65535u16.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(payload);
+    segment
+}
+
+pub fn udp(source_port: u16, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(8 + payload.len());
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&destination_port.to_be_bytes());
+    datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/** TCPとUDPのチェックサムの位置 (16ビット単位) */
+fn checksum_word(protocol: u8) -> Option<usize> {
+    match protocol {
+        PROTO_TCP => Some(8),
+        PROTO_UDP => Some(3),
+        _ => None,
+    }
+}
+
+/** IPv4ヘッダを付けたパケット。フラグメントの時はoffset(バイト)とmore_fragmentsを指定する */
+pub fn ipv4_fragment(protocol: u8, source: &str, destination: &str, identification: u16, offset: usize, more_fragments: bool, payload: &[u8]) -> Vec<u8> {
+    let source: Ipv4Addr = source.parse().unwrap();
+    let destination: Ipv4Addr = destination.parse().unwrap();
+    let mut payload = payload.to_vec();
+    if let Some(word) = checksum_word(protocol).filter(|_| offset == 0 && !more_fragments) {
+        let sum = ipv4_checksum(&payload, word, &[], &source, &destination, IpNextHeaderProtocol(protocol));
+        payload[word * 2..word * 2 + 2].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    let flags_offset = (if more_fragments { 0x2000 } else { 0 }) | (offset / 8) as u16;
+    let mut packet = vec![0x45, 0];
+    packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&identification.to_be_bytes());
+    packet.extend_from_slice(&flags_offset.to_be_bytes());
+    packet.extend_from_slice(&[64, protocol, 0, 0]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    let sum = checksum(&packet, 5);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(&payload);
+    packet
+}
+
+pub fn ipv4(protocol: u8, source: &str, destination: &str, payload: &[u8]) -> Vec<u8> {
+    ipv4_fragment(protocol, source, destination, 1, 0, false, payload)
+}
+
+pub fn ipv6(next_header: u8, source: &str, destination: &str, payload: &[u8]) -> Vec<u8> {
+    let source: Ipv6Addr = source.parse().unwrap();
+    let destination: Ipv6Addr = destination.parse().unwrap();
+    let mut payload = payload.to_vec();
+    if let Some(word) = checksum_word(next_header) {
+        let sum = ipv6_checksum(&payload, word, &[], &source, &destination, IpNextHeaderProtocol(next_header));
+        payload[word * 2..word * 2 + 2].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    let mut packet = vec![0x60, 0, 0, 0];
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[next_header, 64]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    packet.extend_from_slice(&payload);
+    packet
+}
+
+/** アドレスの種類に合わせてIPv4またはIPv6のイーサネットフレームにする */
+pub fn ip_frame(protocol: u8, source: &str, destination: &str, payload: &[u8]) -> Vec<u8> {
+    match source.parse::<IpAddr>().unwrap() {
+        IpAddr::V4(_) => ethernet(0x0800, &ipv4(protocol, source, destination, payload)),
+        IpAddr::V6(_) => ethernet(0x86dd, &ipv6(protocol, source, destination, payload)),
+    }
+}
+
+pub fn tcp_frame(source: &str, source_port: u16, destination: &str, destination_port: u16, sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    ip_frame(PROTO_TCP, source, destination, &tcp(source_port, destination_port, sequence, flags, payload))
+}
+
+pub fn udp_frame(source: &str, source_port: u16, destination: &str, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+    ip_frame(PROTO_UDP, source, destination, &udp(source_port, destination_port, payload))
+}
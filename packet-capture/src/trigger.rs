@@ -0,0 +1,142 @@
+//! キャプチャの終了条件とトリガー
+//! -c、--duration、--max-bytesに達したら受信ループを終わらせる
+//! --triggerに一致するパケットを見るまでは直前の数秒分だけを保持し、一致したら前後の数秒分を記録する
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::filter::Expr;
+use crate::pcap::RecordedFrame;
+
+/** 受信を終える条件。どれかに達したら終わる */
+#[derive(Default, Clone, Copy)]
+pub struct Limits {
+    pub count: Option<u64>,
+    pub duration: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+/** --triggerの設定 */
+pub struct TriggerConfig {
+    pub filter: Expr,
+    /** 一致する前の何秒分を記録するか */
+    pub before: Duration,
+    /** 最後に一致してから何秒分を記録するか */
+    pub after: Duration,
+}
+
+pub struct Trigger<'a> {
+    config: &'a TriggerConfig,
+    /** トリガー前のフレーム。beforeより古いものは捨てる */
+    pending: VecDeque<RecordedFrame>,
+    /** この時刻までのフレームは記録する */
+    recording_until: Option<Duration>,
+    /** トリガーが記録を始めた回数 */
+    pub fired: u64,
+}
+
+impl<'a> Trigger<'a> {
+    pub fn new(config: &'a TriggerConfig) -> Trigger<'a> {
+        Trigger {
+            config,
+            pending: VecDeque::new(),
+            recording_until: None,
+            fired: 0,
+        }
+    }
+
+    /** フレームを受け取り、記録するフレームを返す
+     * 記録中でなければ保持して何も返さず、トリガーに一致したら保持していたものと一緒に返す
+     */
+    pub fn offer(&mut self, frame: &RecordedFrame) -> Vec<RecordedFrame> {
+        let timestamp = frame.timestamp;
+        let held = frame.clone();
+        let recording = self.recording_until.is_some_and(|until| timestamp <= until);
+        // 一致したフレームの時刻から見てbeforeより古いものは、一緒に返さない
+        while self.pending.front().is_some_and(|oldest| oldest.timestamp + self.config.before < timestamp) {
+            self.pending.pop_front();
+        }
+        if self.config.filter.matches(&frame.data) {
+            // 記録中に一致した場合は記録する期間を延ばす
+            if !recording {
+                self.fired += 1;
+            }
+            self.recording_until = Some(timestamp + self.config.after);
+            let mut frames: Vec<RecordedFrame> = self.pending.drain(..).collect();
+            frames.push(held);
+            return frames;
+        }
+        if recording {
+            return vec![held];
+        }
+
+        self.pending.push_back(held);
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn config() -> TriggerConfig {
+        TriggerConfig {
+            filter: Expr::parse("tcp port 80").unwrap(),
+            before: Duration::from_secs(2),
+            after: Duration::from_secs(3),
+        }
+    }
+
+    fn frame(secs: u64, matches: bool) -> RecordedFrame {
+        let data = if matches {
+            testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", 80, 1, testutil::TCP_SYN, b"")
+        } else {
+            testutil::udp_frame("10.0.0.1", 40000, "10.0.0.2", 53, b"")
+        };
+        RecordedFrame {
+            timestamp: Duration::from_secs(secs),
+            interface_id: 0,
+            original_length: data.len(),
+            data,
+        }
+    }
+
+    fn timestamps(frames: &[RecordedFrame]) -> Vec<u64> {
+        frames.iter().map(|frame| frame.timestamp.as_secs()).collect()
+    }
+
+    #[test]
+    fn holds_only_the_frames_within_the_pre_window() {
+        let config = config();
+        let mut trigger = Trigger::new(&config);
+        for secs in 0..=10 {
+            assert!(trigger.offer(&frame(secs, false)).is_empty());
+        }
+        assert_eq!(trigger.pending.iter().map(|frame| frame.timestamp.as_secs()).collect::<Vec<_>>(), vec![8, 9, 10]);
+        assert_eq!(trigger.fired, 0);
+
+        assert_eq!(timestamps(&trigger.offer(&frame(11, true))), vec![9, 10, 11]);
+        assert_eq!(trigger.fired, 1);
+        assert!(trigger.pending.is_empty());
+    }
+
+    #[test]
+    fn records_the_post_window_and_extends_it_on_another_match() {
+        let config = config();
+        let mut trigger = Trigger::new(&config);
+        assert_eq!(timestamps(&trigger.offer(&frame(0, true))), vec![0]);
+        assert_eq!(timestamps(&trigger.offer(&frame(2, false))), vec![2]);
+
+        // 記録中の一致は回数に数えず、記録する期間を延ばす
+        assert_eq!(timestamps(&trigger.offer(&frame(3, true))), vec![3]);
+        assert_eq!(trigger.fired, 1);
+        assert_eq!(timestamps(&trigger.offer(&frame(6, false))), vec![6]);
+
+        // 期間を過ぎたら再び保持するだけになり、次の一致で改めて記録を始める
+        assert!(trigger.offer(&frame(7, false)).is_empty());
+        assert!(trigger.offer(&frame(8, false)).is_empty());
+        assert_eq!(timestamps(&trigger.offer(&frame(9, true))), vec![7, 8, 9]);
+        assert_eq!(trigger.fired, 2);
+    }
+}
@@ -0,0 +1,572 @@
+//! 対話的な端末表示 (--tui)
+//! パケットの一覧、選択したパケットの層ごとのフィールドとダンプ、統計を1画面に表示する
+//! 表示のためのクレートは使わず、termiosとANSIエスケープシーケンスで描画する
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::filter::Expr;
+use crate::hexdump::{self, DumpLayer, HexdumpConfig};
+use crate::record::{self, Summary};
+use crate::signal;
+
+/** 保持するパケットの数。古いものから捨てる */
+const MAX_PACKETS: usize = 10_000;
+const SIDEBAR_WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const HELP: &str = "q quit  / filter  space pause  up/down/PgUp/PgDn select  End follow";
+
+/** 端末を非カノニカルモードと代替画面に切り替え、終了時に元に戻す */
+struct Terminal {
+    original: libc::termios,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Terminal> {
+        let mut original: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // ISIGは残すので、Ctrl-Cはこれまで通りSIGINTになる
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush()?;
+        Ok(Terminal { original })
+    }
+
+    /** 端末の行数と桁数 */
+    fn size(&self) -> (usize, usize) {
+        let mut size: libc::winsize = unsafe { mem::zeroed() };
+        let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+        if ret < 0 || size.ws_row == 0 || size.ws_col == 0 {
+            return (24, 80);
+        }
+        (size.ws_row as usize, size.ws_col as usize)
+    }
+
+    /** 押されたキーを待たずに読む */
+    fn read_keys(&self) -> Vec<Key> {
+        let mut buffer = [0u8; 64];
+        let size = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if size <= 0 {
+            return Vec::new();
+        }
+        Key::parse(&buffer[..size as usize])
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+    }
+}
+
+#[derive(PartialEq, Debug)]
+enum Key {
+    Char(char),
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Escape,
+    Backspace,
+}
+
+impl Key {
+    fn parse(bytes: &[u8]) -> Vec<Key> {
+        let mut keys = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let (key, length) = match &bytes[i..] {
+                [0x1b, b'[', b'A', ..] => (Key::Up, 3),
+                [0x1b, b'[', b'B', ..] => (Key::Down, 3),
+                [0x1b, b'[', b'H', ..] => (Key::Home, 3),
+                [0x1b, b'[', b'F', ..] => (Key::End, 3),
+                [0x1b, b'[', b'5', b'~', ..] => (Key::PageUp, 4),
+                [0x1b, b'[', b'6', b'~', ..] => (Key::PageDown, 4),
+                [0x1b, b'[', b'1', b'~', ..] => (Key::Home, 4),
+                [0x1b, b'[', b'4', b'~', ..] => (Key::End, 4),
+                [0x1b, ..] => (Key::Escape, 1),
+                [b'\r', ..] | [b'\n', ..] => (Key::Enter, 1),
+                [0x7f, ..] | [0x08, ..] => (Key::Backspace, 1),
+                [b, ..] if b.is_ascii() && !b.is_ascii_control() => (Key::Char(*b as char), 1),
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            keys.push(key);
+            i += length;
+        }
+        keys
+    }
+}
+
+/** 一覧の1行分 */
+struct Entry {
+    number: u64,
+    timestamp: Duration,
+    interface_id: u32,
+    data: Vec<u8>,
+    summary: Summary,
+}
+
+pub struct Tui {
+    terminal: Terminal,
+    entries: VecDeque<Entry>,
+    next_number: u64,
+    /** 選択しているパケットの番号。Noneなら最新のパケットを追いかける */
+    selected: Option<u64>,
+    /** 一覧の先頭に表示している位置 */
+    top: usize,
+    paused: bool,
+    /** 一時停止中に一覧に加えなかったパケットの数 */
+    missed: u64,
+    /** 表示フィルタ。キャプチャのフィルタとは別に、保持しているパケットから選ぶ */
+    filter: Option<Expr>,
+    filter_text: String,
+    /** フィルタを編集中なら、入力中の文字列 */
+    editing: Option<String>,
+    message: Option<String>,
+    interface_names: Vec<String>,
+    first_timestamp: Option<Duration>,
+    packets: u64,
+    bytes: u64,
+    /** 直近1秒のパケットの時刻と長さ */
+    recent: VecDeque<(Duration, usize)>,
+    protocols: HashMap<String, u64>,
+    last_draw: Option<Instant>,
+}
+
+impl Tui {
+    pub fn new(interface_names: Vec<String>) -> io::Result<Tui> {
+        Ok(Tui {
+            terminal: Terminal::enter()?,
+            entries: VecDeque::new(),
+            next_number: 1,
+            selected: None,
+            top: 0,
+            paused: false,
+            missed: 0,
+            filter: None,
+            filter_text: String::new(),
+            editing: None,
+            message: None,
+            interface_names,
+            first_timestamp: None,
+            packets: 0,
+            bytes: 0,
+            recent: VecDeque::new(),
+            protocols: HashMap::new(),
+            last_draw: None,
+        })
+    }
+
+    /** パケットを統計に加え、一時停止中でなければ一覧に加える */
+    pub fn push(&mut self, frame: &[u8], timestamp: Duration, interface_id: u32) {
+        let summary = record::summarize(frame);
+        self.packets += 1;
+        self.bytes += frame.len() as u64;
+        *self.protocols.entry(summary.protocol.clone()).or_insert(0) += 1;
+        self.recent.push_back((timestamp, frame.len()));
+        while self.recent.front().is_some_and(|(oldest, _)| *oldest + Duration::from_secs(1) < timestamp) {
+            self.recent.pop_front();
+        }
+        self.first_timestamp.get_or_insert(timestamp);
+
+        let number = self.next_number;
+        self.next_number += 1;
+        if self.paused {
+            self.missed += 1;
+            return;
+        }
+        self.entries.push_back(Entry {
+            number,
+            timestamp,
+            interface_id,
+            data: frame.to_vec(),
+            summary,
+        });
+        if self.entries.len() > MAX_PACKETS {
+            if let Some(evicted) = self.entries.pop_front() {
+                self.selected = after_eviction(self.selected, evicted.number, self.entries.front().map(|entry| entry.number));
+            }
+            self.top = self.top.saturating_sub(1);
+        }
+    }
+
+    /** キー入力を処理し、前回から間隔が空いていれば描画し直す */
+    pub fn tick(&mut self) {
+        for key in self.terminal.read_keys() {
+            self.handle_key(key);
+        }
+        if self.last_draw.is_none_or(|drawn| drawn.elapsed() >= REDRAW_INTERVAL) {
+            if let Err(e) = self.draw() {
+                self.message = Some(format!("Failed to draw: {}", e));
+            }
+            self.last_draw = Some(Instant::now());
+        }
+    }
+
+    /** ファイルを読み終えた後も、終了するまで操作を受け付ける */
+    pub fn wait(&mut self) {
+        self.last_draw = None;
+        while !signal::interrupted() {
+            self.tick();
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn handle_key(&mut self, key: Key) {
+        // 編集中の入力はフィルタの文字列になる
+        if let Some(mut text) = self.editing.take() {
+            match key {
+                Key::Enter => self.apply_filter(text),
+                Key::Escape => {}
+                Key::Backspace => {
+                    text.pop();
+                    self.editing = Some(text);
+                }
+                Key::Char(c) => {
+                    text.push(c);
+                    self.editing = Some(text);
+                }
+                _ => self.editing = Some(text),
+            }
+            self.last_draw = None;
+            return;
+        }
+
+        match key {
+            Key::Char('q') => signal::request_stop(),
+            Key::Char('/') => self.editing = Some(self.filter_text.clone()),
+            Key::Char(' ') => {
+                self.paused = !self.paused;
+                if !self.paused {
+                    self.missed = 0;
+                }
+            }
+            _ => {
+                let numbers: Vec<u64> = self.visible().into_iter().map(|i| self.entries[i].number).collect();
+                if let Some(selected) = navigate(&key, &numbers, self.selected, self.list_height()) {
+                    self.selected = selected;
+                }
+            }
+        }
+        self.last_draw = None;
+    }
+
+    fn apply_filter(&mut self, text: String) {
+        let text = text.trim().to_string();
+        if text.is_empty() {
+            self.filter = None;
+            self.filter_text = text;
+            self.message = None;
+            return;
+        }
+        match Expr::parse(&text) {
+            Ok(filter) => {
+                self.filter = Some(filter);
+                self.filter_text = text;
+                self.message = None;
+                self.top = 0;
+            }
+            Err(e) => self.message = Some(format!("Invalid filter: {}", e)),
+        }
+    }
+
+    /** 表示フィルタに一致するパケットの添字 */
+    fn visible(&self) -> Vec<usize> {
+        (0..self.entries.len())
+            .filter(|&i| self.filter.as_ref().is_none_or(|filter| filter.matches(&self.entries[i].data)))
+            .collect()
+    }
+
+    fn selected_position(&self, visible: &[usize]) -> Option<usize> {
+        match self.selected {
+            Some(number) => visible.iter().position(|&i| self.entries[i].number == number),
+            None => None,
+        }
+    }
+
+    fn list_height(&self) -> usize {
+        let (rows, _) = self.terminal.size();
+        rows.saturating_sub(2) / 2
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let (rows, columns) = self.terminal.size();
+        let sidebar = columns >= 80;
+        let width = if sidebar { columns - SIDEBAR_WIDTH - 1 } else { columns };
+        let list_height = self.list_height();
+        let visible = self.visible();
+
+        // 選択したパケットが見えるように一覧をずらす
+        let position = self.selected_position(&visible).or_else(|| visible.len().checked_sub(1));
+        if let Some(position) = position {
+            if position < self.top {
+                self.top = position;
+            } else if position >= self.top + list_height {
+                self.top = position + 1 - list_height;
+            }
+        }
+        self.top = self.top.min(visible.len().saturating_sub(1));
+
+        let mut screen = String::from("\x1b[H");
+        let mut left: Vec<String> = Vec::with_capacity(rows);
+        left.push(format!("\x1b[7m{}\x1b[0m", fit(&format!("{:>7} {:>11} {:<8} {:<28} {:<28} {}", "No.", "Time", "Proto", "Source", "Destination", "Info"), width)));
+        for row in 0..list_height {
+            let line = match visible.get(self.top + row) {
+                Some(&i) => {
+                    let entry = &self.entries[i];
+                    let time = entry.timestamp.checked_sub(self.first_timestamp.unwrap_or_default()).unwrap_or_default();
+                    let mut info = entry.summary.info.clone();
+                    if self.interface_names.len() > 1 {
+                        let interface = self.interface_names.get(entry.interface_id as usize).map_or("", String::as_str);
+                        info = format!("[{}] {}", interface, info);
+                    }
+                    let text = fit(
+                        &format!("{:>7} {:>11.6} {:<8} {:<28} {:<28} {}", entry.number, time.as_secs_f64(), entry.summary.protocol, entry.summary.source, entry.summary.destination, info),
+                        width,
+                    );
+                    if Some(self.top + row) == position {
+                        format!("\x1b[7m{}\x1b[0m", text)
+                    } else {
+                        text
+                    }
+                }
+                None => String::new(),
+            };
+            left.push(line);
+        }
+
+        // 選択したパケットの層ごとのフィールドとダンプ
+        let detail_height = rows.saturating_sub(2 + list_height);
+        let (tree, dump) = match position.and_then(|position| visible.get(position)) {
+            Some(&i) => {
+                let config = HexdumpConfig {
+                    layer: DumpLayer::Frame,
+                    ..HexdumpConfig::default()
+                };
+                (record::layer_lines(&self.entries[i].data), hexdump::lines(&self.entries[i].data, &config))
+            }
+            None => (Vec::new(), Vec::new()),
+        };
+        let dump_width = 9 + hexdump::DEFAULT_WIDTH / 2 * 5 + 1 + hexdump::DEFAULT_WIDTH;
+        if width >= dump_width + 30 {
+            let tree_width = width - dump_width - 1;
+            for row in 0..detail_height {
+                let tree_line = tree.get(row).map_or("", String::as_str);
+                let dump_line = dump.get(row).map_or("", String::as_str);
+                left.push(format!("{:<tree_width$} {}", fit(tree_line, tree_width), fit(dump_line, dump_width), tree_width = tree_width));
+            }
+        } else {
+            let tree_height = detail_height / 2;
+            for row in 0..tree_height {
+                left.push(fit(tree.get(row).map_or("", String::as_str), width));
+            }
+            for row in 0..detail_height - tree_height {
+                left.push(fit(dump.get(row).map_or("", String::as_str), width));
+            }
+        }
+
+        let right = if sidebar { self.sidebar() } else { Vec::new() };
+        for row in 0..rows.saturating_sub(1) {
+            let line = left.get(row).map_or("", String::as_str);
+            screen.push_str(line);
+            screen.push_str("\x1b[K");
+            if let Some(side) = right.get(row) {
+                screen.push_str(&format!("\x1b[{};{}H\u{2502}{}", row + 1, width + 1, fit(side, SIDEBAR_WIDTH)));
+            } else if sidebar {
+                screen.push_str(&format!("\x1b[{};{}H\u{2502}", row + 1, width + 1));
+            }
+            screen.push_str("\r\n");
+        }
+
+        // 最下行は入力中のフィルタ、メッセージ、または操作方法
+        let status = match (&self.editing, &self.message) {
+            (Some(text), _) => format!("Filter: {}_", text),
+            (None, Some(message)) => message.clone(),
+            (None, None) => {
+                let mut status = String::new();
+                if self.paused {
+                    status.push_str(&format!("[PAUSED, {} new] ", self.missed));
+                }
+                if !self.filter_text.is_empty() {
+                    status.push_str(&format!("[{}] ", self.filter_text));
+                }
+                status.push_str(HELP);
+                status
+            }
+        };
+        screen.push_str(&format!("\x1b[7m{:<columns$}\x1b[0m", fit(&status, columns), columns = columns));
+
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        out.write_all(screen.as_bytes())?;
+        out.flush()
+    }
+
+    /** 統計の欄。直近1秒のレートとプロトコルごとの内訳 */
+    fn sidebar(&self) -> Vec<String> {
+        let bits: usize = self.recent.iter().map(|(_, length)| length * 8).sum();
+        let mut lines = vec![
+            " Statistics".to_string(),
+            format!(" packets {:>12}", self.packets),
+            format!(" bytes   {:>12}", self.bytes),
+            format!(" pps     {:>12}", self.recent.len()),
+            format!(" bps     {:>12}", human(bits as f64)),
+            format!(" shown   {:>12}", self.entries.len()),
+            String::new(),
+            " Protocols".to_string(),
+        ];
+        let mut protocols: Vec<(&String, &u64)> = self.protocols.iter().collect();
+        protocols.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (protocol, count) in protocols {
+            let share = *count as f64 * 100.0 / self.packets.max(1) as f64;
+            lines.push(format!(" {:<10} {:>8} {:>5.1}%", protocol, count, share));
+        }
+        lines
+    }
+}
+
+/** 一覧で選択を動かすキーなら、動かした後の選択を返す
+ * numbersは表示しているパケットの番号で、選択がNoneなら最新のパケットを追いかける。最後の行まで進んだら追いかけるのに戻る
+ */
+fn navigate(key: &Key, numbers: &[u64], selected: Option<u64>, page: usize) -> Option<Option<u64>> {
+    let position = selected.and_then(|selected| numbers.iter().position(|&number| number == selected));
+    let moved = |delta: isize| -> Option<u64> {
+        let last = numbers.len().checked_sub(1)?;
+        let target = (position.unwrap_or(last) as isize + delta).max(0) as usize;
+        if target >= last {
+            None
+        } else {
+            Some(numbers[target])
+        }
+    };
+    let page = page.max(1) as isize;
+    let selection = match key {
+        Key::Up | Key::Char('k') => moved(-1).or_else(|| numbers.last().copied()),
+        Key::Down | Key::Char('j') => moved(1),
+        Key::PageUp => moved(-page).or_else(|| numbers.last().copied()),
+        Key::PageDown => moved(page),
+        Key::Home | Key::Char('g') => numbers.first().copied(),
+        Key::End | Key::Char('G') => None,
+        _ => return None,
+    };
+    Some(selection)
+}
+
+/** 古いパケットを捨てた後の選択。選択していたパケットを捨てたら、残っている最も古いパケットを選ぶ */
+fn after_eviction(selected: Option<u64>, evicted: u64, oldest: Option<u64>) -> Option<u64> {
+    if selected == Some(evicted) {
+        oldest
+    } else {
+        selected
+    }
+}
+
+/** 幅に収まるように切り詰める。足りない分は空白で埋めない */
+fn fit(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+/** 1000単位の接頭辞を付ける */
+fn human(value: f64) -> String {
+    if value >= 1e9 {
+        format!("{:.1}G", value / 1e9)
+    } else if value >= 1e6 {
+        format!("{:.1}M", value / 1e6)
+    } else if value >= 1e3 {
+        format!("{:.1}k", value / 1e3)
+    } else {
+        format!("{:.0}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_escape_sequences_and_characters() {
+        assert_eq!(
+            Key::parse(b"\x1b[A\x1b[B\x1b[5~\x1b[6~\x1b[H\x1b[4~q/\r\x7f"),
+            vec![Key::Up, Key::Down, Key::PageUp, Key::PageDown, Key::Home, Key::End, Key::Char('q'), Key::Char('/'), Key::Enter, Key::Backspace]
+        );
+        // 単独のESCと、知らない制御文字やASCII以外のバイト
+        assert_eq!(Key::parse(b"\x1b"), vec![Key::Escape]);
+        assert_eq!(Key::parse(b"\x01a\xffb"), vec![Key::Char('a'), Key::Char('b')]);
+        assert_eq!(Key::parse(b"\x1b[Zx"), vec![Key::Escape, Key::Char('['), Key::Char('Z'), Key::Char('x')]);
+    }
+
+    #[test]
+    fn fit_truncates_by_characters() {
+        assert_eq!(fit("abcdef", 3), "abc");
+        assert_eq!(fit("ab", 5), "ab");
+        assert_eq!(fit("\u{2502}\u{2502}\u{2502}", 2), "\u{2502}\u{2502}");
+        assert_eq!(fit("abc", 0), "");
+    }
+
+    #[test]
+    fn human_uses_thousands() {
+        assert_eq!(human(999.0), "999");
+        assert_eq!(human(1500.0), "1.5k");
+        assert_eq!(human(2_000_000.0), "2.0M");
+        assert_eq!(human(3.25e9), "3.2G");
+    }
+
+    #[test]
+    fn navigation_moves_the_selection_and_returns_to_following() {
+        let numbers = [10, 11, 12, 13, 14];
+        // 最新を追いかけている時、上で最後から2番目、下ではそのまま
+        assert_eq!(navigate(&Key::Up, &numbers, None, 3), Some(Some(13)));
+        assert_eq!(navigate(&Key::Down, &numbers, None, 3), Some(None));
+        assert_eq!(navigate(&Key::Char('k'), &numbers, Some(11), 3), Some(Some(10)));
+        assert_eq!(navigate(&Key::Up, &numbers, Some(10), 3), Some(Some(10)));
+        // 最後の行まで進んだら追いかけるのに戻る
+        assert_eq!(navigate(&Key::Char('j'), &numbers, Some(12), 3), Some(Some(13)));
+        assert_eq!(navigate(&Key::Down, &numbers, Some(13), 3), Some(None));
+        assert_eq!(navigate(&Key::PageUp, &numbers, None, 3), Some(Some(11)));
+        assert_eq!(navigate(&Key::PageUp, &numbers, Some(11), 3), Some(Some(10)));
+        assert_eq!(navigate(&Key::PageDown, &numbers, Some(10), 3), Some(Some(13)));
+        assert_eq!(navigate(&Key::PageDown, &numbers, Some(11), 3), Some(None));
+        assert_eq!(navigate(&Key::Home, &numbers, None, 3), Some(Some(10)));
+        assert_eq!(navigate(&Key::Char('G'), &numbers, Some(11), 3), Some(None));
+        assert_eq!(navigate(&Key::Char('x'), &numbers, Some(11), 3), None);
+    }
+
+    #[test]
+    fn navigation_handles_empty_and_hidden_selections() {
+        assert_eq!(navigate(&Key::Up, &[], None, 3), Some(None));
+        assert_eq!(navigate(&Key::Home, &[], Some(4), 3), Some(None));
+        // 1つしかなければ上で選ぶ
+        assert_eq!(navigate(&Key::Up, &[7], None, 3), Some(Some(7)));
+        // 選択がフィルタで隠れた場合は最新から動かす
+        assert_eq!(navigate(&Key::Up, &[1, 2, 3], Some(9), 0), Some(Some(2)));
+    }
+
+    #[test]
+    fn evicting_the_selected_packet_selects_the_oldest_remaining() {
+        assert_eq!(after_eviction(Some(1), 1, Some(2)), Some(2));
+        assert_eq!(after_eviction(Some(5), 1, Some(2)), Some(5));
+        assert_eq!(after_eviction(None, 1, Some(2)), None);
+        assert_eq!(after_eviction(Some(1), 1, None), None);
+    }
+}
@@ -0,0 +1,357 @@
+//! ヘッダの長さとチェックサムの検証
+//! 途中で切れたパケット、長さやヘッダ長の不正、チェックサムの誤りを検出して数える
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use log::info;
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::icmp::{self, IcmpPacket};
+use pnet::packet::icmpv6::{self, Icmpv6Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::util::MacAddr;
+
+use crate::packet::{DecodedPacket, Ip, Transport};
+
+/** 1パケットの中で見つかった問題。層の名前を持つ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum Problem {
+    /** キャプチャしたバイト数がヘッダや全長より少ない */
+    Truncated(&'static str),
+    /** 全長やUDPの長さがヘッダ長より短い */
+    BadLength(&'static str),
+    /** IHLやTCPのデータオフセットが最小値より小さい */
+    BadHeaderLength(&'static str),
+    BadChecksum(&'static str),
+    /** 自ホストが送信したパケットのチェックサムの誤り
+     * チェックサムをNICで計算する(オフロード)場合、キャプチャした時点では未計算のため誤りとしない
+     */
+    Offloaded(&'static str),
+}
+
+impl Problem {
+    /** 壊れたパケットとして数えるか */
+    pub fn is_malformed(self) -> bool {
+        !matches!(self, Problem::Offloaded(_))
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Truncated(layer) => write!(f, "truncated {}", layer),
+            Problem::BadLength(layer) => write!(f, "bad {} length", layer),
+            Problem::BadHeaderLength(layer) => write!(f, "bad {} header length", layer),
+            Problem::BadChecksum(layer) => write!(f, "bad {} checksum", layer),
+            Problem::Offloaded(layer) => write!(f, "unverified {} checksum (offloaded)", layer),
+        }
+    }
+}
+
+/** フレームを検証する。local_macはキャプチャしているインターフェースのMACアドレス */
+pub fn check(frame: &[u8], local_mac: Option<MacAddr>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if frame.len() < 14 {
+        problems.push(Problem::Truncated("Ethernet"));
+        return problems;
+    }
+    let packet = match DecodedPacket::decode(frame) {
+        Some(packet) => packet,
+        None => {
+            problems.push(Problem::Truncated("VLAN"));
+            return problems;
+        }
+    };
+
+    let ip = match packet.link.ethertype {
+        EtherTypes::Ipv4 => check_ipv4(packet.network, &mut problems),
+        EtherTypes::Ipv6 => check_ipv6(packet.network, &mut problems),
+        _ => None,
+    };
+    if let Some((ip, l4)) = ip {
+        if ip.has_transport_header() {
+            check_transport(&ip, l4, &mut problems);
+        }
+    }
+
+    // 送信したパケットのチェックサムの誤りはオフロードによるものとみなす
+    if local_mac == Some(packet.link.source) {
+        for problem in problems.iter_mut() {
+            if let Problem::BadChecksum(layer) = *problem {
+                *problem = Problem::Offloaded(layer);
+            }
+        }
+    }
+    problems
+}
+
+fn check_ipv4<'a>(bytes: &'a [u8], problems: &mut Vec<Problem>) -> Option<(Ip, &'a [u8])> {
+    if bytes.len() < 20 {
+        problems.push(Problem::Truncated("IPv4"));
+        return None;
+    }
+    let header_length = (bytes[0] & 0x0f) as usize * 4;
+    let total_length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+    if header_length < 20 {
+        problems.push(Problem::BadHeaderLength("IPv4"));
+        return None;
+    }
+    if header_length > bytes.len() {
+        problems.push(Problem::Truncated("IPv4"));
+        return None;
+    }
+    if total_length < header_length {
+        problems.push(Problem::BadLength("IPv4"));
+    }
+
+    let (ip, l4) = Ip::parse_v4(bytes)?;
+    if ip.truncated {
+        problems.push(Problem::Truncated("IPv4"));
+    }
+    if ip.checksum_valid == Some(false) {
+        problems.push(Problem::BadChecksum("IPv4"));
+    }
+    Some((ip, l4))
+}
+
+fn check_ipv6<'a>(bytes: &'a [u8], problems: &mut Vec<Problem>) -> Option<(Ip, &'a [u8])> {
+    if bytes.len() < 40 {
+        problems.push(Problem::Truncated("IPv6"));
+        return None;
+    }
+    let (ip, l4) = Ip::parse_v6(bytes)?;
+    if ip.truncated {
+        problems.push(Problem::Truncated("IPv6"));
+    }
+    Some((ip, l4))
+}
+
+fn check_transport(ip: &Ip, bytes: &[u8], problems: &mut Vec<Problem>) {
+    match ip.protocol {
+        IpNextHeaderProtocols::Tcp => {
+            if bytes.len() < 20 {
+                problems.push(Problem::Truncated("TCP"));
+                return;
+            }
+            let header_length = (bytes[12] >> 4) as usize * 4;
+            if header_length < 20 {
+                problems.push(Problem::BadHeaderLength("TCP"));
+                return;
+            }
+            if header_length > bytes.len() {
+                problems.push(Problem::Truncated("TCP"));
+                return;
+            }
+        }
+        IpNextHeaderProtocols::Udp => {
+            if bytes.len() < 8 {
+                problems.push(Problem::Truncated("UDP"));
+                return;
+            }
+            let length = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+            if length < 8 {
+                problems.push(Problem::BadLength("UDP"));
+                return;
+            }
+            if length > bytes.len() && !ip.truncated {
+                problems.push(Problem::BadLength("UDP"));
+                return;
+            }
+        }
+        IpNextHeaderProtocols::Icmp => {
+            if bytes.len() < 4 {
+                problems.push(Problem::Truncated("ICMP"));
+                return;
+            }
+            if ip.fragment.is_none() && !ip.truncated {
+                if let Some(packet) = IcmpPacket::new(bytes) {
+                    if icmp::checksum(&packet) != packet.get_checksum() {
+                        problems.push(Problem::BadChecksum("ICMP"));
+                    }
+                }
+            }
+            return;
+        }
+        IpNextHeaderProtocols::Icmpv6 => {
+            if bytes.len() < 4 {
+                problems.push(Problem::Truncated("ICMPv6"));
+                return;
+            }
+            if let (IpAddr::V6(source), IpAddr::V6(destination)) = (ip.source, ip.destination) {
+                if ip.fragment.is_none() && !ip.truncated {
+                    if let Some(packet) = Icmpv6Packet::new(bytes) {
+                        if icmpv6::checksum(&packet, &source, &destination) != packet.get_checksum() {
+                            problems.push(Problem::BadChecksum("ICMPv6"));
+                        }
+                    }
+                }
+            }
+            return;
+        }
+        _ => return,
+    }
+
+    // 長さに問題がなければ、疑似ヘッダを含めたチェックサムを確かめる
+    if let Some((transport, _)) = Transport::parse(ip, bytes) {
+        if transport.checksum_valid == Some(false) {
+            problems.push(Problem::BadChecksum(transport.name()));
+        }
+    }
+}
+
+/** 問題の種類ごとのパケット数 */
+#[derive(Default)]
+pub struct Counters {
+    checked: u64,
+    malformed: u64,
+    problems: BTreeMap<String, u64>,
+}
+
+impl Counters {
+    pub fn record(&mut self, problems: &[Problem]) {
+        self.checked += 1;
+        if problems.iter().any(|problem| problem.is_malformed()) {
+            self.malformed += 1;
+        }
+        for problem in problems {
+            *self.problems.entry(problem.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    /** 終了時の集計。標準出力の形式を崩さないようログに出す */
+    pub fn print_summary(&self) {
+        info!("Checked {} packets, {} malformed or truncated", self.checked, self.malformed);
+        for (problem, count) in self.problems.iter() {
+            info!("  {}: {}", problem, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, PROTO_TCP, SOURCE_MAC, TCP_ACK};
+
+    const IP: usize = 14;
+
+    fn problems(frame: &[u8], local_mac: Option<MacAddr>) -> Vec<String> {
+        check(frame, local_mac).iter().map(|problem| problem.to_string()).collect()
+    }
+
+    /** IPv4ヘッダを書き換えた後、ヘッダのチェックサムだけ計算し直す */
+    fn fix_ipv4_checksum(frame: &mut [u8]) {
+        let header_length = (frame[IP] & 0x0f) as usize * 4;
+        frame[IP + 10..IP + 12].copy_from_slice(&[0, 0]);
+        let sum = pnet::util::checksum(&frame[IP..IP + header_length], 5);
+        frame[IP + 10..IP + 12].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    fn tcp_v4() -> Vec<u8> {
+        testutil::tcp_frame("10.0.0.1", 40000, "10.0.0.2", 80, 1, TCP_ACK, b"payload")
+    }
+
+    fn icmp_echo() -> Vec<u8> {
+        let mut message = vec![8, 0, 0, 0, 0, 1, 0, 1, b'p', b'i', b'n', b'g'];
+        let sum = pnet::util::checksum(&message, 1);
+        message[2..4].copy_from_slice(&sum.to_be_bytes());
+        testutil::ip_frame(1, "10.0.0.1", "10.0.0.2", &message)
+    }
+
+    #[test]
+    fn well_formed_packets_have_no_problems() {
+        let frames = [
+            tcp_v4(),
+            testutil::udp_frame("10.0.0.1", 5353, "10.0.0.2", 53, b"query"),
+            testutil::tcp_frame("2001:db8::1", 40000, "2001:db8::2", 443, 1, TCP_ACK, b""),
+            testutil::udp_frame("2001:db8::1", 546, "ff02::1:2", 547, b"solicit"),
+            testutil::vlan(10, &icmp_echo()),
+            testutil::ethernet(0x0806, &[0; 28]),
+        ];
+        for frame in frames.iter() {
+            assert!(problems(frame, None).is_empty(), "{:?}", problems(frame, None));
+        }
+    }
+
+    #[test]
+    fn truncated_link_layer() {
+        assert_eq!(problems(&tcp_v4()[..13], None), vec!["truncated Ethernet"]);
+        assert_eq!(problems(&testutil::ethernet(0x8100, &[0, 10]), None), vec!["truncated VLAN"]);
+    }
+
+    #[test]
+    fn bad_ipv4_header() {
+        let mut frame = tcp_v4();
+        frame[IP] = 0x44;
+        assert_eq!(problems(&frame, None), vec!["bad IPv4 header length"]);
+
+        let mut frame = tcp_v4();
+        frame[IP] = 0x4f;
+        assert_eq!(problems(&frame[..IP + 40], None), vec!["truncated IPv4"]);
+
+        let mut frame = tcp_v4();
+        frame[IP + 2..IP + 4].copy_from_slice(&16u16.to_be_bytes());
+        fix_ipv4_checksum(&mut frame);
+        assert_eq!(problems(&frame, None), vec!["bad IPv4 length", "truncated TCP"]);
+    }
+
+    #[test]
+    fn snaplen_truncation_is_not_a_bad_udp_length() {
+        let frame = testutil::udp_frame("10.0.0.1", 5353, "10.0.0.2", 53, &[0; 100]);
+        assert_eq!(problems(&frame[..IP + 20 + 8 + 10], None), vec!["truncated IPv4"]);
+    }
+
+    #[test]
+    fn bad_transport_lengths() {
+        let mut frame = tcp_v4();
+        frame[IP + 20 + 12] = 4 << 4;
+        assert_eq!(problems(&frame, None), vec!["bad TCP header length"]);
+
+        let mut frame = tcp_v4();
+        frame[IP + 20 + 12] = 15 << 4;
+        assert_eq!(problems(&frame, None), vec!["truncated TCP"]);
+
+        let mut frame = testutil::udp_frame("10.0.0.1", 5353, "10.0.0.2", 53, b"query");
+        frame[IP + 20 + 4..IP + 20 + 6].copy_from_slice(&4u16.to_be_bytes());
+        assert_eq!(problems(&frame, None), vec!["bad UDP length"]);
+        frame[IP + 20 + 4..IP + 20 + 6].copy_from_slice(&100u16.to_be_bytes());
+        assert_eq!(problems(&frame, None), vec!["bad UDP length"]);
+    }
+
+    #[test]
+    fn bad_checksums_and_offload() {
+        let mut frame = tcp_v4();
+        frame[IP + 10] ^= 0xff;
+        let last = frame.len() - 1;
+        frame[last] ^= 0xff;
+        assert_eq!(problems(&frame, None), vec!["bad IPv4 checksum", "bad TCP checksum"]);
+
+        // 自ホストが送信したフレームはオフロードとみなし、壊れたパケットに数えない
+        let local_mac = Some(MacAddr(SOURCE_MAC[0], SOURCE_MAC[1], SOURCE_MAC[2], SOURCE_MAC[3], SOURCE_MAC[4], SOURCE_MAC[5]));
+        assert_eq!(problems(&frame, local_mac), vec!["unverified IPv4 checksum (offloaded)", "unverified TCP checksum (offloaded)"]);
+        assert!(check(&frame, local_mac).iter().all(|problem| !problem.is_malformed()));
+
+        let mut frame = icmp_echo();
+        let last = frame.len() - 1;
+        frame[last] ^= 0xff;
+        assert_eq!(problems(&frame, None), vec!["bad ICMP checksum"]);
+    }
+
+    #[test]
+    fn non_first_fragments_skip_transport_checks() {
+        let fragment = testutil::ipv4_fragment(PROTO_TCP, "10.0.0.1", "10.0.0.2", 5, 8, false, &[0xff; 4]);
+        assert!(problems(&testutil::ethernet(0x0800, &fragment), None).is_empty());
+    }
+
+    #[test]
+    fn counters_count_malformed_packets_once() {
+        let mut counters = Counters::default();
+        counters.record(&[]);
+        counters.record(&[Problem::BadChecksum("IPv4"), Problem::BadChecksum("TCP")]);
+        counters.record(&[Problem::Offloaded("TCP")]);
+        counters.record(&[Problem::BadChecksum("TCP")]);
+        assert_eq!((counters.checked, counters.malformed), (4, 2));
+        assert_eq!(counters.problems.get("bad TCP checksum"), Some(&2));
+        assert_eq!(counters.problems.get("unverified TCP checksum (offloaded)"), Some(&1));
+    }
+}